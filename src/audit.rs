@@ -0,0 +1,301 @@
+use crate::clock::unix_now;
+use crate::config::AuditConfig;
+use crate::errors::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use tokio::sync::oneshot;
+
+/// Record fields that `audit.redact` may name. Topics contain the user and
+/// repository, so redact them too when either must not be logged.
+pub const REDACTABLE_FIELDS: &[&str] = &["client_address", "user", "repository", "topics"];
+
+const REDACTED: &str = "[redacted]";
+
+/// What became of a webhook request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Rejected,
+    Published,
+    PublishFailed,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Unix timestamp at which the request arrived
+    pub time: i64,
+    pub client_address: Option<String>,
+    /// Authenticated user, once known
+    pub user: Option<String>,
+    pub outcome: AuditOutcome,
+    /// Rejection reason, using the same labels as the rejection metrics
+    pub reason: Option<String>,
+    pub repository: Option<String>,
+    pub commits: Vec<String>,
+    pub topics: Vec<String>,
+    /// HTTP status of the response
+    pub status: u16,
+}
+
+impl AuditRecord {
+    pub fn new(time: i64, client_ip: Option<IpAddr>) -> Self {
+        AuditRecord {
+            time,
+            client_address: client_ip.map(|ip| ip.to_string()),
+            user: None,
+            outcome: AuditOutcome::Rejected,
+            reason: None,
+            repository: None,
+            commits: Vec::new(),
+            topics: Vec::new(),
+            status: 0,
+        }
+    }
+
+    pub fn reject(&mut self, reason: &str) {
+        self.outcome = AuditOutcome::Rejected;
+        self.reason = Some(reason.to_string());
+    }
+
+    fn redact(&mut self, fields: &[String]) {
+        for field in fields {
+            match field.as_str() {
+                "client_address" => redact(&mut self.client_address),
+                "user" => redact(&mut self.user),
+                "repository" => redact(&mut self.repository),
+                "topics" => self
+                    .topics
+                    .iter_mut()
+                    .for_each(|t| *t = REDACTED.to_string()),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn redact(value: &mut Option<String>) {
+    if value.is_some() {
+        *value = Some(REDACTED.to_string());
+    }
+}
+
+enum Message {
+    Record(AuditRecord),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Append-only audit log, rotated by size. Rotated files are named after the
+/// time of rotation, so every entry in one is older than its name says.
+/// Records are written by a thread of their own, so requests never wait for
+/// the disk.
+pub struct AuditLog {
+    tx: mpsc::Sender<Message>,
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> Result<Self> {
+        let mut writer = Writer {
+            config: config.clone(),
+            file: open_append(&config.path)?,
+        };
+        writer.prune(unix_now());
+
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || {
+                for message in rx {
+                    match message {
+                        Message::Record(record) => writer.record(record),
+                        Message::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })?;
+        Ok(AuditLog { tx })
+    }
+
+    /// Queue a record for writing.
+    pub fn record(&self, record: AuditRecord) {
+        let _ = self.tx.send(Message::Record(record));
+    }
+
+    /// Wait until every record queued so far is written.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.tx.send(Message::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+struct Writer {
+    config: AuditConfig,
+    file: File,
+}
+
+impl Writer {
+    /// Append a redacted record, rotating the file first if it is full.
+    /// Failures are logged rather than failing the request.
+    fn record(&mut self, mut record: AuditRecord) {
+        record.redact(&self.config.redact);
+        if let Err(e) = self.append(&record) {
+            error!("Failed to write audit record: {}", e);
+        }
+    }
+
+    fn append(&mut self, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        let size = self.file.metadata()?.len();
+        if size > 0 && size + line.len() as u64 > self.config.max_file_bytes {
+            let now = unix_now();
+            let rotated = self.rotated_path(now);
+            fs::rename(&self.config.path, &rotated)?;
+            self.file = open_append(&self.config.path)?;
+            info!("Rotated audit log to {}", rotated.display());
+            self.prune(now);
+        }
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    fn rotated_path(&self, now: i64) -> PathBuf {
+        let mut path = PathBuf::from(format!("{}.{}", self.config.path, now));
+        let mut n = 1;
+        while path.exists() {
+            path = PathBuf::from(format!("{}.{}.{}", self.config.path, now, n));
+            n += 1;
+        }
+        path
+    }
+
+    /// Delete rotated files past the retention period.
+    fn prune(&self, now: i64) {
+        if self.config.retention_days == 0 {
+            return;
+        }
+        let cutoff = now - self.config.retention_days as i64 * 24 * 60 * 60;
+        let path = Path::new(&self.config.path);
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            return;
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let rotated_at = file_name
+                .to_str()
+                .and_then(|f| f.strip_prefix(name)?.strip_prefix('.'))
+                .and_then(|suffix| suffix.split('.').next()?.parse::<i64>().ok());
+            if rotated_at.is_some_and(|rotated_at| rotated_at < cutoff) {
+                match fs::remove_file(entry.path()) {
+                    Ok(()) => info!("Deleted expired audit log {}", entry.path().display()),
+                    Err(e) => error!("Failed to delete {}: {}", entry.path().display(), e),
+                }
+            }
+        }
+    }
+}
+
+fn open_append(path: &str) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn config(dir: &TempDir, max_file_bytes: u64) -> AuditConfig {
+        AuditConfig {
+            enabled: true,
+            path: dir.path().join("audit.jsonl").to_str().unwrap().to_string(),
+            max_file_bytes,
+            retention_days: 30,
+            redact: vec!["client_address".to_string(), "topics".to_string()],
+        }
+    }
+
+    fn published(user: &str) -> AuditRecord {
+        let mut record = AuditRecord::new(1000, Some("192.0.2.7".parse().unwrap()));
+        record.user = Some(user.to_string());
+        record.outcome = AuditOutcome::Published;
+        record.repository = Some("https://github.com/acme/widgets".to_string());
+        record.commits = vec!["abc123".to_string()];
+        record.topics = vec![format!("git-friends/{}/acme/bob", user)];
+        record.status = 200;
+        record
+    }
+
+    #[tokio::test]
+    async fn test_records_are_redacted() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, 1024 * 1024);
+        let log = AuditLog::open(&config).unwrap();
+        log.record(published("alice"));
+
+        let mut rejected = AuditRecord::new(1001, None);
+        rejected.reject("invalid_token");
+        rejected.status = 401;
+        log.record(rejected.clone());
+        log.flush().await;
+
+        let contents = fs::read_to_string(&config.path).unwrap();
+        let records: Vec<AuditRecord> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].client_address.as_deref(), Some(REDACTED));
+        assert_eq!(records[0].topics, vec![REDACTED.to_string()]);
+        assert_eq!(records[0].user.as_deref(), Some("alice"));
+        assert_eq!(records[0].commits, vec!["abc123".to_string()]);
+        assert_eq!(records[1], rejected);
+    }
+
+    #[tokio::test]
+    async fn test_rotation_and_retention() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, 200);
+        let expired = format!("{}.{}", config.path, unix_now() - 31 * 24 * 60 * 60);
+        let recent = format!("{}.{}", config.path, unix_now() - 24 * 60 * 60);
+        fs::write(&expired, "").unwrap();
+        fs::write(&recent, "").unwrap();
+
+        let log = AuditLog::open(&config).unwrap();
+        assert!(!Path::new(&expired).exists());
+        assert!(Path::new(&recent).exists());
+
+        for user in ["alice", "bob", "carol"] {
+            log.record(published(user));
+        }
+        log.flush().await;
+        let rotated: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .flatten()
+            .filter(|e| e.path().to_str() != Some(&config.path))
+            .filter(|e| e.path().to_str() != Some(&recent))
+            .collect();
+        assert_eq!(rotated.len(), 2);
+
+        let current = fs::read_to_string(&config.path).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        assert!(current.contains("carol"));
+    }
+}
@@ -1,26 +1,242 @@
-use crate::config::{AuthConfig, TokenConfig};
+use crate::clock::unix_now;
+use crate::config::{AuthConfig, ClientCertConfig, TokenAccess, TokenConfig, TokenScopes};
 use crate::errors::{GitFriendsError, Result};
+use crate::mqtt::topic_matches;
 use base64::{engine::general_purpose, Engine as _};
-use std::collections::HashMap;
+use log::{error, warn};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
 use uuid::Uuid;
 
+/// Prefix of hashed token entries: `sha256$<salt>$<digest>`, both base64.
+const HASH_PREFIX: &str = "sha256$";
+
+/// What a configured token entry is checked against. Plaintext entries are
+/// hashed without a salt so every comparison takes the same path.
+pub(crate) struct Credential {
+    salt: Vec<u8>,
+    digest: [u8; 32],
+}
+
+impl Credential {
+    pub(crate) fn from_entry(entry: &str) -> Result<Self> {
+        let Some(hash) = entry.strip_prefix(HASH_PREFIX) else {
+            return Ok(Credential {
+                salt: Vec::new(),
+                digest: digest(&[], entry),
+            });
+        };
+
+        let invalid = || GitFriendsError::InvalidConfig("malformed token hash".to_string());
+        let (salt, digest) = hash.split_once('$').ok_or_else(invalid)?;
+        let salt = general_purpose::URL_SAFE_NO_PAD
+            .decode(salt)
+            .map_err(|_| invalid())?;
+        let digest = general_purpose::URL_SAFE_NO_PAD
+            .decode(digest)
+            .map_err(|_| invalid())?
+            .try_into()
+            .map_err(|_| invalid())?;
+
+        Ok(Credential { salt, digest })
+    }
+
+    pub(crate) fn matches(&self, token: &str) -> bool {
+        digest(&self.salt, token).ct_eq(&self.digest).into()
+    }
+}
+
+fn digest(salt: &[u8], token: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(token.as_bytes());
+    hasher.finalize().into()
+}
+
+/// Hash a token secret with a random salt, in the form accepted in
+/// `auth.tokens` entries.
+pub fn hash_token(token: &str) -> String {
+    let salt = *Uuid::new_v4().as_bytes();
+    format!(
+        "{}{}${}",
+        HASH_PREFIX,
+        general_purpose::URL_SAFE_NO_PAD.encode(salt),
+        general_purpose::URL_SAFE_NO_PAD.encode(digest(&salt, token))
+    )
+}
+
+pub fn is_hashed_token(entry: &str) -> bool {
+    entry.starts_with(HASH_PREFIX)
+}
+
+/// Whether a token entry is plaintext or a well-formed hash.
+pub fn is_valid_token_entry(entry: &str) -> bool {
+    Credential::from_entry(entry).is_ok()
+}
+
+/// Short, stable identifier for a token entry that reveals nothing about
+/// the secret; used to track token usage.
+pub fn token_fingerprint(entry: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(&digest(b"fingerprint", entry)[..9])
+}
+
+/// Whether a token may be used at a given time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenValidity {
+    Valid,
+    NotYetValid,
+    Expired,
+}
+
+impl TokenConfig {
+    pub fn validity_at(&self, now: i64) -> TokenValidity {
+        if self.expires_at.is_some_and(|expires_at| now >= expires_at) {
+            TokenValidity::Expired
+        } else if self.not_before.is_some_and(|not_before| now < not_before) {
+            TokenValidity::NotYetValid
+        } else {
+            TokenValidity::Valid
+        }
+    }
+}
+
+/// The part of a token's scopes that an action falls outside of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeViolation {
+    Access,
+    EventType,
+    Repository,
+    Topic,
+}
+
+impl ScopeViolation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ScopeViolation::Access => "access",
+            ScopeViolation::EventType => "event_type",
+            ScopeViolation::Repository => "repository",
+            ScopeViolation::Topic => "topic",
+        }
+    }
+}
+
+impl TokenScopes {
+    /// Whether the token may use the query and streaming APIs.
+    pub fn allows_read(&self) -> bool {
+        self.access != TokenAccess::Write
+    }
+
+    /// Check that the token may publish an event of `event_type` for
+    /// `repository_url` on `topic`.
+    pub fn check_publish(
+        &self,
+        event_type: &str,
+        repository_url: &str,
+        topic: &str,
+    ) -> std::result::Result<(), ScopeViolation> {
+        if self.access == TokenAccess::Read {
+            return Err(ScopeViolation::Access);
+        }
+        if !self.event_types.is_empty() && !self.event_types.iter().any(|t| t == event_type) {
+            return Err(ScopeViolation::EventType);
+        }
+        if !self.repositories.is_empty()
+            && !self
+                .repositories
+                .iter()
+                .any(|pattern| wildcard_match(pattern, repository_url))
+        {
+            return Err(ScopeViolation::Repository);
+        }
+        if !self.topics.is_empty() && !self.topics.iter().any(|f| topic_matches(f, topic)) {
+            return Err(ScopeViolation::Topic);
+        }
+        Ok(())
+    }
+}
+
+/// Match `text` against `pattern`, where `*` matches any run of characters.
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard: the whole text must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+impl ClientCertConfig {
+    /// The mapping as a token entry, so scopes and rate limits apply the
+    /// same way. The entry is never accepted as a bearer token.
+    pub fn to_token_config(&self) -> TokenConfig {
+        TokenConfig {
+            token: format!("cert:{}", self.subject),
+            username: self.username.clone(),
+            rate_limit_per_minute: self.rate_limit_per_minute,
+            scopes: self.scopes.clone(),
+            ..Default::default()
+        }
+    }
+}
+
 pub struct AuthManager {
-    tokens: HashMap<String, String>, // token -> username
+    tokens: Vec<(Credential, TokenConfig)>,
+    client_certs: Vec<ClientCertConfig>,
     require_auth: bool,
 }
 
 impl AuthManager {
     pub fn new(config: &AuthConfig) -> Self {
-        let tokens = config
-            .tokens
-            .iter()
-            .map(|tc| (tc.token.clone(), tc.username.clone()))
-            .collect();
-
-        AuthManager {
-            tokens,
+        let mut auth_manager = AuthManager {
+            tokens: Vec::new(),
+            client_certs: config.client_certs.clone(),
             require_auth: config.require_auth,
+        };
+
+        for tc in &config.tokens {
+            if !is_hashed_token(&tc.token) {
+                warn!(
+                    "Token for user '{}' is stored in plaintext; this is deprecated, \
+                     replace it with the output of `gf-server --hash-token`",
+                    tc.username
+                );
+            }
+            auth_manager.add_token_config(tc.clone());
+        }
+
+        auth_manager
+    }
+
+    /// Add a token entry with all of its settings.
+    pub fn add_token_config(&mut self, tc: TokenConfig) {
+        match Credential::from_entry(&tc.token) {
+            Ok(credential) => self.tokens.push((credential, tc)),
+            Err(e) => error!("Ignoring token for user '{}': {}", tc.username, e),
+        }
+    }
+
+    /// Find the entry matching `token`. Every entry is compared, in constant
+    /// time, so the response time does not reveal which one matched.
+    fn lookup(&self, token: &str) -> Option<&TokenConfig> {
+        let mut found = None;
+        for (credential, tc) in &self.tokens {
+            if credential.matches(token) && found.is_none() {
+                found = Some(tc);
+            }
         }
+        found
     }
 
     pub fn validate_token(&self, token: &str) -> Option<&str> {
@@ -28,7 +244,9 @@ impl AuthManager {
             return Some("anonymous");
         }
 
-        self.tokens.get(token).map(|s| s.as_str())
+        self.lookup(token)
+            .filter(|tc| tc.validity_at(unix_now()) == TokenValidity::Valid)
+            .map(|tc| tc.username.as_str())
     }
 
     pub fn validate_bearer_token(&self, auth_header: &str) -> Result<Option<String>> {
@@ -36,12 +254,39 @@ impl AuthManager {
             return Ok(Some("anonymous".to_string()));
         }
 
-        if !auth_header.starts_with("Bearer ") {
-            return Err(GitFriendsError::AuthenticationFailed);
-        }
+        Ok(self
+            .find_bearer_token(auth_header)?
+            .filter(|tc| tc.validity_at(unix_now()) == TokenValidity::Valid)
+            .map(|tc| tc.username.clone()))
+    }
+
+    /// Look up the configured token presented in an `Authorization: Bearer`
+    /// header, whether or not it is currently valid.
+    pub fn find_bearer_token(&self, auth_header: &str) -> Result<Option<&TokenConfig>> {
+        let token = auth_header
+            .strip_prefix("Bearer ")
+            .ok_or(GitFriendsError::AuthenticationFailed)?;
+        Ok(self.lookup(token))
+    }
+
+    /// Look up the token whose signing secret has `key_id`, whether or not
+    /// it is currently valid.
+    pub fn find_signing_key(&self, key_id: &str) -> Option<&TokenConfig> {
+        self.tokens.iter().map(|(_, tc)| tc).find(|tc| {
+            tc.signing_secret
+                .as_deref()
+                .is_some_and(|secret| crate::signing::key_id(secret) == key_id)
+        })
+    }
 
-        let token = &auth_header[7..]; // Remove "Bearer " prefix
-        Ok(self.tokens.get(token).cloned())
+    /// Find the mapping for the first of a verified client certificate's
+    /// identities that has one.
+    pub fn find_client_cert(&self, identities: &[String]) -> Option<&ClientCertConfig> {
+        identities.iter().find_map(|identity| {
+            self.client_certs
+                .iter()
+                .find(|client_cert| client_cert.subject == *identity)
+        })
     }
 
     pub fn generate_token() -> String {
@@ -53,25 +298,42 @@ impl AuthManager {
         TokenConfig {
             token: Self::generate_token(),
             username: username.to_string(),
+            ..Default::default()
         }
     }
 
+    /// Generate a token, returning the secret and a config entry holding
+    /// only its hash.
+    pub fn generate_hashed_token_config(username: &str) -> (String, TokenConfig) {
+        let secret = Self::generate_token();
+        let token_config = TokenConfig {
+            token: hash_token(&secret),
+            username: username.to_string(),
+            ..Default::default()
+        };
+        (secret, token_config)
+    }
+
+    /// Add a token entry, either hashed or plaintext.
     pub fn add_token(&mut self, token: String, username: String) {
-        self.tokens.insert(token, username);
+        self.add_token_config(TokenConfig {
+            token,
+            username,
+            ..Default::default()
+        });
     }
 
+    /// Remove the entry matching a token secret or the stored entry itself.
     pub fn remove_token(&mut self, token: &str) -> Option<String> {
-        self.tokens.remove(token)
+        let index = self
+            .tokens
+            .iter()
+            .position(|(credential, tc)| tc.token == token || credential.matches(token))?;
+        Some(self.tokens.remove(index).1.username)
     }
 
     pub fn list_tokens(&self) -> Vec<TokenConfig> {
-        self.tokens
-            .iter()
-            .map(|(token, username)| TokenConfig {
-                token: token.clone(),
-                username: username.clone(),
-            })
-            .collect()
+        self.tokens.iter().map(|(_, tc)| tc.clone()).collect()
     }
 }
 
@@ -86,8 +348,10 @@ mod tests {
             tokens: vec![TokenConfig {
                 token: "test-token".to_string(),
                 username: "testuser".to_string(),
+                ..Default::default()
             }],
             require_auth: true,
+            ..Default::default()
         };
 
         let auth_manager = AuthManager::new(&config);
@@ -101,6 +365,7 @@ mod tests {
         let config = AuthConfig {
             tokens: vec![],
             require_auth: false,
+            ..Default::default()
         };
 
         let auth_manager = AuthManager::new(&config);
@@ -115,8 +380,10 @@ mod tests {
             tokens: vec![TokenConfig {
                 token: "test-token".to_string(),
                 username: "testuser".to_string(),
+                ..Default::default()
             }],
             require_auth: true,
+            ..Default::default()
         };
 
         let auth_manager = AuthManager::new(&config);
@@ -155,4 +422,143 @@ mod tests {
         assert_eq!(config.username, "testuser");
         assert!(!config.token.is_empty());
     }
+
+    #[test]
+    fn test_hashed_tokens() {
+        let (secret, hashed) = AuthManager::generate_hashed_token_config("hasheduser");
+        assert!(is_hashed_token(&hashed.token));
+        assert!(!hashed.token.contains(&secret));
+        assert_ne!(hash_token(&secret), hashed.token);
+
+        let config = AuthConfig {
+            tokens: vec![
+                hashed.clone(),
+                TokenConfig {
+                    token: "plain-token".to_string(),
+                    username: "plainuser".to_string(),
+                    ..Default::default()
+                },
+            ],
+            require_auth: true,
+            ..Default::default()
+        };
+        let mut auth_manager = AuthManager::new(&config);
+
+        assert_eq!(auth_manager.validate_token(&secret), Some("hasheduser"));
+        assert_eq!(auth_manager.validate_token(&hashed.token), None);
+        assert_eq!(
+            auth_manager.validate_token("plain-token"),
+            Some("plainuser")
+        );
+
+        assert_eq!(
+            auth_manager.remove_token(&secret),
+            Some("hasheduser".to_string())
+        );
+        assert_eq!(auth_manager.validate_token(&secret), None);
+        assert!(!is_valid_token_entry("sha256$not-base64!$x"));
+    }
+
+    #[test]
+    fn test_token_validity_window() {
+        let mut token = AuthManager::generate_token_config("rotating");
+        token.not_before = Some(100);
+        token.expires_at = Some(200);
+
+        assert_eq!(token.validity_at(99), TokenValidity::NotYetValid);
+        assert_eq!(token.validity_at(100), TokenValidity::Valid);
+        assert_eq!(token.validity_at(200), TokenValidity::Expired);
+        assert_ne!(
+            token_fingerprint(&token.token),
+            token_fingerprint("other-token")
+        );
+    }
+
+    #[test]
+    fn test_token_scopes() {
+        let scopes = TokenScopes {
+            repositories: vec!["https://github.com/acme/*".to_string()],
+            event_types: vec!["commit".to_string()],
+            topics: vec!["git-friends/ci/#".to_string()],
+            access: TokenAccess::Write,
+        };
+        let topic = "git-friends/ci/github.com_acme_widgets/Bob";
+
+        assert!(!scopes.allows_read());
+        assert!(scopes
+            .check_publish("commit", "https://github.com/acme/widgets", topic)
+            .is_ok());
+        assert_eq!(
+            scopes.check_publish("commit", "https://github.com/other/widgets", topic),
+            Err(ScopeViolation::Repository)
+        );
+        assert_eq!(
+            scopes.check_publish("ref", "https://github.com/acme/widgets", topic),
+            Err(ScopeViolation::EventType)
+        );
+        assert_eq!(
+            scopes.check_publish(
+                "commit",
+                "https://github.com/acme/widgets",
+                "git-friends/bob/x/Bob"
+            ),
+            Err(ScopeViolation::Topic)
+        );
+
+        assert!(wildcard_match(
+            "*acme/widgets*",
+            "git@github.com:acme/widgets.git"
+        ));
+        assert!(wildcard_match("exact", "exact"));
+        assert!(!wildcard_match("a*b*a", "ab"));
+    }
+
+    #[test]
+    fn test_find_signing_key() {
+        let mut signed = AuthManager::generate_token_config("ci");
+        signed.signing_secret = Some("0123456789abcdef-signing".to_string());
+        let mut auth_manager = AuthManager::new(&AuthConfig {
+            tokens: vec![signed],
+            require_auth: true,
+            ..Default::default()
+        });
+        auth_manager.add_token("plain".to_string(), "bob".to_string());
+
+        let key_id = crate::signing::key_id("0123456789abcdef-signing");
+        assert_eq!(
+            auth_manager
+                .find_signing_key(&key_id)
+                .map(|tc| tc.username.as_str()),
+            Some("ci")
+        );
+        assert!(auth_manager
+            .find_signing_key(&crate::signing::key_id("plain"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_find_client_cert() {
+        let auth_manager = AuthManager::new(&AuthConfig {
+            tokens: vec![],
+            require_auth: true,
+            client_certs: vec![ClientCertConfig {
+                subject: "builder-01.ci.example.com".to_string(),
+                username: "ci".to_string(),
+                rate_limit_per_minute: Some(5),
+                scopes: TokenScopes::default(),
+            }],
+            ..Default::default()
+        });
+
+        let identities = vec![
+            "builder-01".to_string(),
+            "builder-01.ci.example.com".to_string(),
+        ];
+        let client_cert = auth_manager.find_client_cert(&identities).unwrap();
+        assert_eq!(client_cert.username, "ci");
+        assert_eq!(client_cert.to_token_config().rate_limit_per_minute, Some(5));
+        assert!(auth_manager
+            .find_client_cert(&["builder-02".to_string()])
+            .is_none());
+    }
 }
@@ -0,0 +1,553 @@
+use clap::{Arg, Command};
+use git_friends::{
+    clock::unix_now,
+    config::MqttConfig,
+    doctor::{Check, Hook, Report},
+    git::GitRepository,
+    mqtt::topic_matches,
+    repository::RepositoryRules,
+    signing::RequestSignature,
+    tenant, Config,
+};
+use reqwest::StatusCode;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, SubscribeReasonCode};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Root of the round-trip test topics, outside every commit namespace so
+/// gf-irc's subscriptions never see the test messages.
+const DOCTOR_TOPIC_PREFIX: &str = "gf-doctor";
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let matches = Command::new("gf-doctor")
+        .version("0.1.0")
+        .about("Git Friends diagnostics - checks the hook, server, MQTT and IRC chain")
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("Configuration file path"),
+        )
+        .arg(
+            Arg::new("repo")
+                .short('r')
+                .long("repo")
+                .value_name("PATH")
+                .help("Repository whose hook to check")
+                .default_value("."),
+        )
+        .arg(
+            Arg::new("server-url")
+                .short('s')
+                .long("server-url")
+                .value_name("URL")
+                .help("The URL of the gf-server (defaults to the one the hook uses)"),
+        )
+        .arg(
+            Arg::new("token")
+                .short('t')
+                .long("token")
+                .value_name("TOKEN")
+                .help("Authentication token (defaults to the one the hook uses)"),
+        )
+        .arg(
+            Arg::new("signing-secret")
+                .long("signing-secret")
+                .value_name("SECRET")
+                .help("Sign the dry run with this shared secret instead of sending the token"),
+        )
+        .arg(
+            Arg::new("ca-cert")
+                .long("ca-cert")
+                .value_name("PATH")
+                .help("Additional PEM CA certificate to trust for the server"),
+        )
+        .arg(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .help("How long to wait for the server and the broker")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("5"),
+        )
+        .get_matches();
+
+    if let Some(path) = matches.get_one::<String>("config") {
+        std::env::set_var("GIT_FRIENDS_CONFIG", path);
+    }
+    let repo = Path::new(matches.get_one::<String>("repo").unwrap());
+    let wait = Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap());
+
+    let mut report = Report::default();
+    let config = check_config(&mut report);
+
+    let hook = match Hook::find(repo) {
+        Ok(hook) => {
+            report.add(hook.check());
+            Some(hook)
+        }
+        Err(e) => {
+            report.add(Check::fail(
+                "Git hook",
+                format!("{} is not in a git repository: {}", repo.display(), e),
+                "run gf-doctor in the repository, or pass --repo",
+            ));
+            None
+        }
+    };
+    let hook_variable = |name| hook.as_ref().and_then(|h| h.variable(name));
+
+    // Talk to the server the way the hook does
+    let server_url = matches
+        .get_one::<String>("server-url")
+        .map(String::as_str)
+        .or(hook_variable("GF_SERVER_URL"))
+        .unwrap_or("http://localhost:8080")
+        .trim_end_matches('/')
+        .to_string();
+    let token_env = std::env::var("GIT_FRIENDS_TOKEN").ok();
+    let token = matches
+        .get_one::<String>("token")
+        .map(String::as_str)
+        .or(token_env.as_deref())
+        .or(hook_variable("GF_TOKEN"))
+        .filter(|token| !token.is_empty());
+    let signing_secret_env = std::env::var("GIT_FRIENDS_SIGNING_SECRET").ok();
+    let signing_secret = matches
+        .get_one::<String>("signing-secret")
+        .map(String::as_str)
+        .or(signing_secret_env.as_deref())
+        .filter(|secret| !secret.is_empty());
+
+    let accepted = match http_client(matches.get_one::<String>("ca-cert"), wait) {
+        Ok(client) => {
+            if check_server(&mut report, &client, &server_url).await {
+                let credentials = Credentials {
+                    token,
+                    signing_secret,
+                };
+                check_dry_run(&mut report, &client, &server_url, repo, credentials).await
+            } else {
+                report.add(Check::skip("Dry run", "the server is not reachable"));
+                None
+            }
+        }
+        Err(e) => {
+            report.add(Check::fail(
+                "Server",
+                e,
+                "pass a readable PEM certificate to --ca-cert",
+            ));
+            None
+        }
+    };
+
+    let config = config.unwrap_or_default();
+    check_irc_subscription(&mut report, &config, accepted.as_ref());
+    report.add(mqtt_round_trip(&config.mqtt, wait).await);
+
+    print!("{}", report.render());
+    if !report.passed() {
+        std::process::exit(1);
+    }
+}
+
+/// Load and validate the configuration gf-server and gf-irc would use.
+fn check_config(report: &mut Report) -> Option<Config> {
+    const NAME: &str = "Configuration";
+    let config = match Config::new() {
+        Ok(config) => config,
+        Err(e) => {
+            report.add(Check::fail(
+                NAME,
+                format!("could not load: {}", e),
+                "fix the file named by --config or GIT_FRIENDS_CONFIG",
+            ));
+            return None;
+        }
+    };
+
+    let sources = Config::source_paths();
+    if let Err(e) = config.validate() {
+        report.add(Check::fail(
+            NAME,
+            e.to_string(),
+            "fix the settings named above, see git-friends.toml.example",
+        ));
+    } else if sources.is_empty() {
+        report.add(Check::warn(
+            NAME,
+            "no configuration file found, using defaults",
+            "pass --config FILE or set GIT_FRIENDS_CONFIG",
+        ));
+    } else {
+        let sources: Vec<_> = sources.iter().map(|p| p.display().to_string()).collect();
+        report.add(Check::pass(
+            NAME,
+            format!("{} is valid", sources.join(", ")),
+        ));
+    }
+    Some(config)
+}
+
+fn http_client(ca_cert: Option<&String>, wait: Duration) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder().timeout(wait);
+    if let Some(ca_path) = ca_cert {
+        let pem = std::fs::read(ca_path).map_err(|e| format!("cannot read {}: {}", ca_path, e))?;
+        let ca = reqwest::Certificate::from_pem(&pem)
+            .map_err(|e| format!("invalid CA certificate: {}", e))?;
+        builder = builder.add_root_certificate(ca);
+    }
+    builder
+        .build()
+        .map_err(|e| format!("invalid HTTP client settings: {}", e))
+}
+
+/// Check `/live` and `/ready`. Returns whether the server answered.
+async fn check_server(report: &mut Report, client: &reqwest::Client, server_url: &str) -> bool {
+    const NAME: &str = "Server";
+    match client.get(format!("{}/live", server_url)).send().await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => {
+            report.add(Check::fail(
+                NAME,
+                format!("{}/live answered {}", server_url, response.status()),
+                "check that --server-url points at gf-server",
+            ));
+            return false;
+        }
+        Err(e) => {
+            report.add(Check::fail(
+                NAME,
+                format!("cannot reach {}: {}", server_url, e),
+                "start gf-server, or pass the URL it listens on to --server-url",
+            ));
+            return false;
+        }
+    }
+
+    let response = client.get(format!("{}/ready", server_url)).send().await;
+    let readiness = match response {
+        Ok(response) => {
+            let status = response.status();
+            (status, response.json::<serde_json::Value>().await.ok())
+        }
+        Err(e) => {
+            report.add(Check::fail(
+                NAME,
+                format!("{}/ready failed: {}", server_url, e),
+                "check the gf-server logs",
+            ));
+            return true;
+        }
+    };
+    match readiness {
+        (status, _) if status.is_success() => report.add(Check::pass(
+            NAME,
+            format!("{} is live and ready", server_url),
+        )),
+        (status, Some(readiness)) => {
+            let mut problems = Vec::new();
+            if readiness["shutting_down"] == true {
+                problems.push("shutting down".to_string());
+            }
+            if let Some(error) = readiness["config_error"].as_str() {
+                problems.push(format!("configuration reload failed: {}", error));
+            }
+            for publisher in readiness["publishers"].as_array().into_iter().flatten() {
+                if publisher["connected"] == false {
+                    problems.push(format!(
+                        "publisher {} is disconnected ({})",
+                        publisher["name"].as_str().unwrap_or("?"),
+                        publisher["last_error"]
+                            .as_str()
+                            .unwrap_or("no error recorded")
+                    ));
+                }
+            }
+            report.add(Check::fail(
+                NAME,
+                format!("not ready ({}): {}", status, problems.join("; ")),
+                "check that gf-server can reach the MQTT broker set in [mqtt] or [[publishers]]",
+            ));
+        }
+        (status, None) => report.add(Check::fail(
+            NAME,
+            format!("{}/ready answered {}", server_url, status),
+            "check the gf-server logs",
+        )),
+    }
+    true
+}
+
+struct Credentials<'a> {
+    token: Option<&'a str>,
+    signing_secret: Option<&'a str>,
+}
+
+/// Where gf-server would publish a commit.
+struct Accepted {
+    repository_url: String,
+    topic: String,
+}
+
+/// Send the repository's HEAD commit to `/webhook/dry-run`.
+async fn check_dry_run(
+    report: &mut Report,
+    client: &reqwest::Client,
+    server_url: &str,
+    repo: &Path,
+    credentials: Credentials<'_>,
+) -> Option<Accepted> {
+    const NAME: &str = "Dry run";
+    let commit = match GitRepository::discover(repo).and_then(|r| r.get_head_commit_info()) {
+        Ok(commit) => commit,
+        Err(e) => {
+            report.add(Check::skip(
+                NAME,
+                format!("no commit to send from {}: {}", repo.display(), e),
+            ));
+            return None;
+        }
+    };
+    let body = serde_json::to_vec(&commit).unwrap_or_default();
+
+    let target = "/webhook/dry-run";
+    let mut request = client
+        .post(format!("{}{}", server_url, target))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.clone());
+    if let Some(secret) = credentials.signing_secret {
+        let signature = RequestSignature::sign(
+            secret,
+            "POST",
+            target,
+            unix_now(),
+            &Uuid::new_v4().to_string(),
+            &body,
+        );
+        for (name, value) in signature.headers() {
+            request = request.header(name, value);
+        }
+    } else if let Some(token) = credentials.token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            report.add(Check::fail(
+                NAME,
+                format!("request failed: {}", e),
+                "check the gf-server logs",
+            ));
+            return None;
+        }
+    };
+    let status = response.status();
+    let expired = response
+        .headers()
+        .get(reqwest::header::WWW_AUTHENTICATE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("expired"));
+    let text = response.text().await.unwrap_or_default();
+
+    let check = match status {
+        StatusCode::OK => {
+            let accepted: serde_json::Value = serde_json::from_str(&text).unwrap_or_default();
+            let topic = accepted["topic"].as_str().unwrap_or_default().to_string();
+            let user = accepted["user"].as_str().unwrap_or("?");
+            report.add(Check::pass(
+                NAME,
+                format!(
+                    "{} may send {} commits, published on {}",
+                    user, commit.repository_url, topic
+                ),
+            ));
+            return Some(Accepted {
+                repository_url: commit.repository_url,
+                topic,
+            });
+        }
+        StatusCode::UNAUTHORIZED if expired => Check::fail(
+            NAME,
+            "the token has expired",
+            "ask an administrator to rotate it and update GIT_FRIENDS_TOKEN or the hook",
+        ),
+        StatusCode::UNAUTHORIZED
+            if credentials.token.is_none() && credentials.signing_secret.is_none() =>
+        {
+            Check::fail(
+                NAME,
+                "the server requires authentication",
+                "pass --token or set GIT_FRIENDS_TOKEN; create a token with \
+                 `gf-server --generate-token USER`",
+            )
+        }
+        StatusCode::UNAUTHORIZED => Check::fail(
+            NAME,
+            "the server rejected the credentials",
+            "check that the token or signing secret is one configured in [auth]",
+        ),
+        StatusCode::FORBIDDEN => Check::fail(
+            NAME,
+            format!("{}: {}", commit.repository_url, text.trim()),
+            "check the token's scopes and the user's [[tenants]] repositories",
+        ),
+        StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => Check::fail(
+            NAME,
+            "the server has no dry-run endpoint",
+            "upgrade gf-server",
+        ),
+        status => Check::fail(
+            NAME,
+            format!("server answered {}: {}", status, text.trim()),
+            "check the gf-server logs",
+        ),
+    };
+    report.add(check);
+    None
+}
+
+/// Check that gf-irc subscribes to the topic of the dry run and would
+/// announce the commit somewhere.
+fn check_irc_subscription(report: &mut Report, config: &Config, accepted: Option<&Accepted>) {
+    const NAME: &str = "IRC subscription";
+    let Some(Accepted {
+        repository_url,
+        topic,
+    }) = accepted
+    else {
+        report.add(Check::skip(NAME, "no topic from a dry run to check"));
+        return;
+    };
+
+    let filters = tenant::irc_topic_filters(config);
+    if !filters.iter().any(|filter| topic_matches(filter, topic)) {
+        report.add(Check::fail(
+            NAME,
+            format!(
+                "none of gf-irc's topic filters {:?} match {}",
+                filters, topic
+            ),
+            format!(
+                "add \"{}/#\" to irc.topic_filters",
+                tenant::tenant_for_topic(&config.tenants, topic)
+                    .map_or(&config.mqtt.topic_prefix, |t| &t.topic_prefix)
+            ),
+        ));
+        return;
+    }
+
+    let repository = RepositoryRules::new(&config.git).resolve(repository_url);
+    let channels = tenant::announce_channels(config, topic, &repository);
+    if channels.is_empty() {
+        report.add(Check::warn(
+            NAME,
+            format!("gf-irc receives {} but announces it in no channel", topic),
+            "set irc.channels, or a channel for the repository in git.repository_rules",
+        ));
+    } else {
+        report.add(Check::pass(
+            NAME,
+            format!("gf-irc announces {} in {}", topic, channels.join(", ")),
+        ));
+    }
+}
+
+/// Subscribe to a test topic below `DOCTOR_TOPIC_PREFIX` and publish a
+/// message to it, as gf-server and gf-irc would.
+async fn mqtt_round_trip(config: &MqttConfig, wait: Duration) -> Check {
+    const NAME: &str = "MQTT";
+    let broker = format!("{}:{}", config.broker_host, config.broker_port);
+    let id = Uuid::new_v4().simple().to_string();
+    let topic = format!("{}/{}", DOCTOR_TOPIC_PREFIX, id);
+    let payload = format!("gf-doctor round trip {}", id);
+
+    let mut options = MqttOptions::new(
+        format!("{}/doctor-{}", config.client_id, &id[..8]),
+        &config.broker_host,
+        config.broker_port,
+    );
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    let started = Instant::now();
+    let mut stage = "connect";
+    let result = tokio::time::timeout(wait, async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    stage = "subscribe";
+                    client
+                        .subscribe(&topic, QoS::AtMostOnce)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                }
+                Ok(Event::Incoming(Packet::SubAck(ack))) => {
+                    if ack.return_codes.contains(&SubscribeReasonCode::Failure) {
+                        return Err(format!("the broker refused the subscription to {}", topic));
+                    }
+                    stage = "receive";
+                    client
+                        .publish(&topic, QoS::AtMostOnce, false, payload.clone())
+                        .await
+                        .map_err(|e| e.to_string())?;
+                }
+                Ok(Event::Incoming(Packet::Publish(publish)))
+                    if publish.topic == topic && publish.payload == payload.as_bytes() =>
+                {
+                    return Ok(());
+                }
+                Ok(_) => {}
+                Err(e) => return Err(format!("cannot connect to {}: {}", broker, e)),
+            }
+        }
+    })
+    .await;
+    let _ = client.try_disconnect();
+
+    let access_hint = format!(
+        "let the MQTT user publish and subscribe to {}/#",
+        DOCTOR_TOPIC_PREFIX
+    );
+    match result {
+        Ok(Ok(())) => Check::pass(
+            NAME,
+            format!(
+                "test message on {} came back through {} in {} ms",
+                topic,
+                broker,
+                started.elapsed().as_millis()
+            ),
+        ),
+        Ok(Err(e)) if stage == "connect" => Check::fail(
+            NAME,
+            e,
+            "start the broker, or fix broker_host, broker_port, username and password in [mqtt]",
+        ),
+        Ok(Err(e)) => Check::fail(NAME, e, access_hint),
+        Err(_) if stage == "connect" => Check::fail(
+            NAME,
+            format!("no answer from {} within {}s", broker, wait.as_secs()),
+            "check that broker_host and broker_port in [mqtt] name a running MQTT broker",
+        ),
+        Err(_) => Check::fail(
+            NAME,
+            format!(
+                "test message on {} did not come back within {}s ({} stage)",
+                topic,
+                wait.as_secs(),
+                stage
+            ),
+            access_hint,
+        ),
+    }
+}
@@ -1,6 +1,14 @@
 use clap::{Arg, ArgAction, Command};
-use git_friends::{git::get_git_info_from_env, Result};
-use log::{error, info};
+use git_friends::{
+    clock::unix_now, git::get_git_info_from_env, signing::RequestSignature, GitFriendsError, Result,
+};
+use log::{error, info, warn};
+use reqwest::StatusCode;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Longest we are willing to block a commit waiting on the server.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -24,6 +32,30 @@ async fn main() -> Result<()> {
                 .value_name("TOKEN")
                 .help("Authentication token"),
         )
+        .arg(
+            Arg::new("signing-secret")
+                .long("signing-secret")
+                .value_name("SECRET")
+                .help("Sign requests with this shared secret instead of sending the token"),
+        )
+        .arg(
+            Arg::new("client-cert")
+                .long("client-cert")
+                .value_name("PATH")
+                .help("PEM client certificate to present to the server"),
+        )
+        .arg(
+            Arg::new("client-key")
+                .long("client-key")
+                .value_name("PATH")
+                .help("PKCS#8 PEM private key for the client certificate"),
+        )
+        .arg(
+            Arg::new("ca-cert")
+                .long("ca-cert")
+                .value_name("PATH")
+                .help("Additional PEM CA certificate to trust for the server"),
+        )
         .arg(
             Arg::new("commit")
                 .short('c')
@@ -37,6 +69,21 @@ async fn main() -> Result<()> {
                 .help("Force GitHub Actions mode (auto-detected by default)")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("oidc-audience")
+                .long("oidc-audience")
+                .value_name("AUDIENCE")
+                .help("Audience of the GitHub Actions ID token sent when no token is configured")
+                .default_value("git-friends"),
+        )
+        .arg(
+            Arg::new("max-retries")
+                .long("max-retries")
+                .value_name("COUNT")
+                .help("Retries when the server is rate limiting or unavailable")
+                .value_parser(clap::value_parser!(u32))
+                .default_value("3"),
+        )
         .arg(
             Arg::new("dry-run")
                 .short('d')
@@ -51,10 +98,20 @@ async fn main() -> Result<()> {
     let commit_hash = matches.get_one::<String>("commit");
     let dry_run = matches.get_flag("dry-run");
     let force_github_actions = matches.get_flag("github-actions");
+    let max_retries = *matches.get_one::<u32>("max-retries").unwrap();
 
     // Check for token in environment if not provided
     let token_env = std::env::var("GIT_FRIENDS_TOKEN").ok();
-    let token = token.map(|s| s.as_str()).or(token_env.as_deref());
+    let token = token
+        .map(|s| s.as_str())
+        .or(token_env.as_deref())
+        .filter(|token| !token.is_empty());
+    let signing_secret_env = std::env::var("GIT_FRIENDS_SIGNING_SECRET").ok();
+    let signing_secret = matches
+        .get_one::<String>("signing-secret")
+        .map(|s| s.as_str())
+        .or(signing_secret_env.as_deref())
+        .filter(|secret| !secret.is_empty());
 
     info!("Starting gf-hook");
 
@@ -65,6 +122,21 @@ async fn main() -> Result<()> {
         info!("Running in GitHub Actions mode");
     }
 
+    // Without a configured credential, authenticate with the job's OIDC ID token
+    let id_token = if is_github_actions && token.is_none() && signing_secret.is_none() && !dry_run {
+        let audience = matches.get_one::<String>("oidc-audience").unwrap();
+        match request_github_id_token(audience).await {
+            Ok(id_token) => Some(id_token),
+            Err(e) => {
+                warn!("Could not obtain a GitHub Actions ID token: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let token = token.or(id_token.as_deref());
+
     // Get commit information
     let commit_info = if let Some(hash) = commit_hash {
         // Use specific commit hash
@@ -94,30 +166,195 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    // Send to server
-    let client = reqwest::Client::new();
-    let mut request_builder = client
-        .post(format!("{}/webhook", server_url))
-        .json(&commit_info);
+    // Send to server, retrying on rate limiting and server errors
+    let client = http_client(
+        path_setting(&matches, "client-cert", "GIT_FRIENDS_CLIENT_CERT"),
+        path_setting(&matches, "client-key", "GIT_FRIENDS_CLIENT_KEY"),
+        path_setting(&matches, "ca-cert", "GIT_FRIENDS_CA_CERT"),
+    )?;
+    let url = reqwest::Url::parse(&format!("{}/webhook", server_url.trim_end_matches('/')))
+        .map_err(|e| GitFriendsError::InvalidConfig(format!("Invalid server URL: {}", e)))?;
+    let body = serde_json::to_vec(&commit_info)?;
+    let mut attempt = 0;
 
-    if let Some(token) = token {
-        request_builder = request_builder.header("Authorization", format!("Bearer {}", token));
-    }
+    loop {
+        let mut request_builder = client
+            .post(url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone());
 
-    match request_builder.send().await {
-        Ok(response) => {
-            if response.status().is_success() {
+        if let Some(secret) = signing_secret {
+            // A fresh timestamp and nonce per attempt, so retries are not replays
+            let target = match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            };
+            let signature = RequestSignature::sign(
+                secret,
+                "POST",
+                &target,
+                unix_now(),
+                &Uuid::new_v4().to_string(),
+                &body,
+            );
+            for (name, value) in signature.headers() {
+                request_builder = request_builder.header(name, value);
+            }
+        } else if let Some(token) = token {
+            request_builder = request_builder.header("Authorization", format!("Bearer {}", token));
+        }
+
+        let retry_delay = match request_builder.send().await {
+            Ok(response) if response.status().is_success() => {
                 info!("Successfully sent commit information to server");
-            } else {
-                error!("Server returned error: {}", response.status());
+                break;
+            }
+            Ok(response) if token_expired(&response) => {
+                error!(
+                    "The git-friends token has expired; ask an administrator to rotate it \
+                     and update GIT_FRIENDS_TOKEN or --token"
+                );
                 std::process::exit(1);
             }
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+                if !retryable || attempt >= max_retries {
+                    error!("Server returned error: {}", status);
+                    std::process::exit(1);
+                }
+                warn!("Server returned {}", status);
+                retry_after(&response).unwrap_or_else(|| backoff(attempt))
+            }
+            Err(e) => {
+                if attempt >= max_retries {
+                    error!("Failed to send commit information: {}", e);
+                    std::process::exit(1);
+                }
+                warn!("Failed to send commit information: {}", e);
+                backoff(attempt)
+            }
+        };
+
+        let retry_delay = retry_delay.min(MAX_RETRY_DELAY);
+        attempt += 1;
+        info!(
+            "Retrying in {}s (attempt {} of {})",
+            retry_delay.as_secs(),
+            attempt,
+            max_retries
+        );
+        tokio::time::sleep(retry_delay).await;
+    }
+
+    Ok(())
+}
+
+/// Request an OIDC ID token for this job. GitHub only provides the request
+/// URL when the workflow has the `id-token: write` permission.
+async fn request_github_id_token(audience: &str) -> Result<String> {
+    let missing = || {
+        GitFriendsError::InvalidConfig(
+            "ACTIONS_ID_TOKEN_REQUEST_URL is not set; grant the workflow `id-token: write`"
+                .to_string(),
+        )
+    };
+    let url = std::env::var("ACTIONS_ID_TOKEN_REQUEST_URL").map_err(|_| missing())?;
+    let request_token = std::env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN").map_err(|_| missing())?;
+
+    let mut url = reqwest::Url::parse(&url)
+        .map_err(|e| GitFriendsError::InvalidConfig(format!("Invalid ID token URL: {}", e)))?;
+    url.query_pairs_mut().append_pair("audience", audience);
+
+    let response: serde_json::Value = reqwest::Client::new()
+        .get(url)
+        .bearer_auth(request_token)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| GitFriendsError::ConnectionFailed(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| GitFriendsError::ConnectionFailed(e.to_string()))?;
+
+    response["value"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| {
+            GitFriendsError::ConnectionFailed("ID token response has no value".to_string())
+        })
+}
+
+/// A command line option, falling back to an environment variable.
+fn path_setting(matches: &clap::ArgMatches, name: &str, env: &str) -> Option<String> {
+    matches
+        .get_one::<String>(name)
+        .cloned()
+        .or_else(|| std::env::var(env).ok())
+}
+
+/// HTTP client presenting the configured client certificate and trusting
+/// the configured CA.
+fn http_client(
+    client_cert: Option<String>,
+    client_key: Option<String>,
+    ca_cert: Option<String>,
+) -> Result<reqwest::Client> {
+    let invalid = |what: &str, e: reqwest::Error| {
+        GitFriendsError::InvalidConfig(format!("Invalid {}: {}", what, e))
+    };
+
+    let mut builder = reqwest::Client::builder();
+    match (client_cert, client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let identity = reqwest::Identity::from_pkcs8_pem(
+                &std::fs::read(cert_path)?,
+                &std::fs::read(key_path)?,
+            )
+            .map_err(|e| invalid("client certificate or key", e))?;
+            builder = builder.identity(identity);
         }
-        Err(e) => {
-            error!("Failed to send commit information: {}", e);
-            std::process::exit(1);
+        (None, None) => {}
+        _ => {
+            return Err(GitFriendsError::InvalidConfig(
+                "A client certificate needs both --client-cert and --client-key".to_string(),
+            ))
         }
     }
+    if let Some(ca_path) = ca_cert {
+        let ca = reqwest::Certificate::from_pem(&std::fs::read(ca_path)?)
+            .map_err(|e| invalid("CA certificate", e))?;
+        builder = builder.add_root_certificate(ca);
+    }
+    builder
+        .build()
+        .map_err(|e| invalid("HTTP client settings", e))
+}
 
-    Ok(())
+/// Delay requested by the server through a `Retry-After: <seconds>` header.
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Whether the server rejected the token because it expired, as signalled
+/// by the `WWW-Authenticate` header of a 401 response.
+fn token_expired(response: &reqwest::Response) -> bool {
+    response.status() == StatusCode::UNAUTHORIZED
+        && response
+            .headers()
+            .get(reqwest::header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("expired"))
+}
+
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.min(6))
 }
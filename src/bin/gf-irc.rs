@@ -1,13 +1,17 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgMatches, Command};
 use futures_util::stream::StreamExt;
 use git_friends::{
     mqtt::{extract_commit_from_mqtt_message, format_commit_for_irc, MqttClient},
-    Config, Result,
+    reload::{spawn_reload_watcher, Swappable},
+    repository::RepositoryRules,
+    tenant, Config, Result,
 };
 use irc::client::prelude::*;
 use irc::proto::Command as IrcCommand;
 use log::{error, info, warn};
 use rumqttc::{Event, Packet};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -54,40 +58,17 @@ async fn main() -> Result<()> {
     info!("Starting gf-irc");
 
     // Load configuration
-    let mut config = match matches.get_one::<String>("config") {
-        Some(path) => {
-            std::env::set_var("GIT_FRIENDS_CONFIG", path);
-            Config::new()?
-        }
-        None => Config::new()?,
-    };
-
-    // Modify client_id to append "/irc"
-    config.mqtt.client_id = format!("{}/irc", config.mqtt.client_id);
-
-    // Override configuration with command line arguments
-    if let Some(nick) = matches.get_one::<String>("nick") {
-        config.irc.nick = nick.clone();
-        config.irc.username = nick.clone();
-    }
-
-    if let Some(server) = matches.get_one::<String>("server") {
-        config.irc.server = server.clone();
-    }
-
-    if let Some(channels) = matches.get_one::<String>("channels") {
-        config.irc.channels = channels.split(',').map(|s| s.trim().to_string()).collect();
-    }
-
-    if let Some(topics) = matches.get_one::<String>("mqtt-topics") {
-        config.irc.topic_filters = topics.split(',').map(|s| s.trim().to_string()).collect();
+    if let Some(path) = matches.get_one::<String>("config") {
+        std::env::set_var("GIT_FRIENDS_CONFIG", path);
     }
+    let config = load_config(&matches)?;
+    config.validate_for_irc()?;
 
     info!("Configuration loaded");
     info!("IRC server: {}:{}", config.irc.server, config.irc.port);
     info!("IRC nick: {}", config.irc.nick);
-    info!("IRC channels: {:?}", config.irc.channels);
-    info!("MQTT topics: {:?}", config.irc.topic_filters);
+    info!("IRC channels: {:?}", all_channels(&config));
+    info!("MQTT topics: {:?}", tenant::irc_topic_filters(&config));
 
     // Create IRC client
     let irc_config = irc::client::data::Config {
@@ -97,7 +78,7 @@ async fn main() -> Result<()> {
         server: Some(config.irc.server.clone()),
         port: Some(config.irc.port),
         use_tls: Some(config.irc.use_tls),
-        channels: config.irc.channels.clone(),
+        channels: all_channels(&config),
         ..Default::default()
     };
 
@@ -106,6 +87,7 @@ async fn main() -> Result<()> {
     // Create MQTT client
     let mqtt_config = config.mqtt.clone();
     let (mqtt_client, mut mqtt_events) = MqttClient::new(mqtt_config)?;
+    let mqtt_client = Arc::new(mqtt_client);
 
     // Connect to IRC
     info!("Connecting to IRC...");
@@ -116,14 +98,39 @@ async fn main() -> Result<()> {
     info!("Connecting to MQTT broker...");
     mqtt_client.wait_for_connection().await?;
     mqtt_client
-        .subscribe_to_topics(&config.irc.topic_filters)
+        .subscribe_to_topics(&tenant::irc_topic_filters(&config))
         .await?;
     info!("Subscribed to MQTT topics");
 
     // Clone data for the IRC message handler
-    let irc_channels = config.irc.channels.clone();
+    let reload_interval = config
+        .reload
+        .watch_config
+        .then(|| Duration::from_secs(config.reload.watch_interval_secs.max(1)));
+    let shared_rules = Arc::new(Swappable::new(RepositoryRules::new(&config.git)));
+    let irc_rules = shared_rules.clone();
+    let shared_config = Arc::new(Swappable::new(config));
+    let irc_config = shared_config.clone();
     let irc_client_sender = irc_client.sender();
 
+    // Reload channels and topic filters on SIGHUP or config file changes
+    let mut reloads = spawn_reload_watcher(Config::source_paths(), reload_interval);
+    let reload_sender = irc_client.sender();
+    let reload_mqtt = mqtt_client.clone();
+    tokio::spawn(async move {
+        while let Some(trigger) = reloads.recv().await {
+            info!("Reloading configuration after {}", trigger);
+            reload_config(
+                &matches,
+                &shared_config,
+                &shared_rules,
+                &reload_sender,
+                &reload_mqtt,
+            )
+            .await;
+        }
+    });
+
     // Spawn MQTT message handler
     tokio::spawn(async move {
         while let Some(event) = mqtt_events.recv().await {
@@ -131,11 +138,15 @@ async fn main() -> Result<()> {
                 Event::Incoming(Packet::Publish(publish)) => {
                     match extract_commit_from_mqtt_message(&publish.payload) {
                         Ok(commit_info) => {
-                            let message = format_commit_for_irc(&commit_info);
+                            let repository = irc_rules.load().resolve(&commit_info.repository_url);
+                            let message =
+                                format_commit_for_irc(&commit_info, repository.name.as_deref());
                             info!("Received commit: {}", message);
 
-                            // Send to all configured channels
-                            for channel in &irc_channels {
+                            let config = irc_config.load();
+                            for channel in
+                                tenant::announce_channels(&config, &publish.topic, &repository)
+                            {
                                 if let Err(e) = irc_client_sender.send_privmsg(channel, &message) {
                                     error!(
                                         "Failed to send message to IRC channel {}: {}",
@@ -165,11 +176,9 @@ async fn main() -> Result<()> {
 
     while let Some(message) = irc_stream.next().await.transpose()? {
         match message.command {
-            IrcCommand::PRIVMSG(ref target, ref msg) => {
-                if msg.starts_with("!git-friends") {
-                    let response = "Git Friends IRC bot - monitoring git commits via MQTT";
-                    irc_client.send_privmsg(target, response)?;
-                }
+            IrcCommand::PRIVMSG(ref target, ref msg) if msg.starts_with("!git-friends") => {
+                let response = "Git Friends IRC bot - monitoring git commits via MQTT";
+                irc_client.send_privmsg(target, response)?;
             }
             IrcCommand::Response(Response::RPL_WELCOME, _) => {
                 info!("Successfully connected to IRC server");
@@ -183,3 +192,128 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// `irc.channels`, the channels of every tenant and of the repository rules.
+fn all_channels(config: &Config) -> Vec<String> {
+    let mut channels = config.irc.channels.clone();
+    let rule_channels = config
+        .git
+        .repository_rules
+        .iter()
+        .filter_map(|rule| rule.channel.as_ref());
+    for channel in config
+        .tenants
+        .iter()
+        .flat_map(|t| &t.irc_channels)
+        .chain(rule_channels)
+    {
+        if !channels.contains(channel) {
+            channels.push(channel.clone());
+        }
+    }
+    channels
+}
+
+fn load_config(matches: &ArgMatches) -> Result<Config> {
+    let mut config = Config::new()?;
+
+    // Modify client_id to append "/irc"
+    config.mqtt.client_id = format!("{}/irc", config.mqtt.client_id);
+
+    // Override configuration with command line arguments
+    if let Some(nick) = matches.get_one::<String>("nick") {
+        config.irc.nick = nick.clone();
+        config.irc.username = nick.clone();
+    }
+
+    if let Some(server) = matches.get_one::<String>("server") {
+        config.irc.server = server.clone();
+    }
+
+    if let Some(channels) = matches.get_one::<String>("channels") {
+        config.irc.channels = channels.split(',').map(|s| s.trim().to_string()).collect();
+    }
+
+    if let Some(topics) = matches.get_one::<String>("mqtt-topics") {
+        config.irc.topic_filters = topics.split(',').map(|s| s.trim().to_string()).collect();
+    }
+
+    Ok(config)
+}
+
+/// Load and validate a new configuration, then join/part channels and
+/// adjust MQTT subscriptions to match. Connection settings need a restart.
+async fn reload_config(
+    matches: &ArgMatches,
+    shared_config: &Swappable<Config>,
+    shared_rules: &Swappable<RepositoryRules>,
+    irc_sender: &Sender,
+    mqtt_client: &MqttClient,
+) {
+    let mut new_config = match load_config(matches).and_then(|c| c.validate_for_irc().map(|_| c)) {
+        Ok(config) => config,
+        Err(e) => {
+            error!(
+                "Configuration reload failed, keeping current configuration: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    let current = shared_config.load();
+    for section in new_config.keep_irc_restart_settings(&current) {
+        warn!("Ignoring changes to {} until restart", section);
+    }
+    let changes = current.diff(&new_config);
+    if changes.is_empty() {
+        info!("Configuration unchanged");
+        return;
+    }
+    for change in &changes {
+        info!("Configuration change: {}", change);
+    }
+
+    let (current_channels, new_channels) = (all_channels(&current), all_channels(&new_config));
+    for channel in new_channels
+        .iter()
+        .filter(|c| !current_channels.contains(c))
+    {
+        if let Err(e) = irc_sender.send_join(channel) {
+            error!("Failed to join IRC channel {}: {}", channel, e);
+        }
+    }
+    for channel in current_channels
+        .iter()
+        .filter(|c| !new_channels.contains(c))
+    {
+        if let Err(e) = irc_sender.send_part(channel) {
+            error!("Failed to leave IRC channel {}: {}", channel, e);
+        }
+    }
+
+    let (current_topics, new_topics) = (
+        tenant::irc_topic_filters(&current),
+        tenant::irc_topic_filters(&new_config),
+    );
+    let added_topics: Vec<String> = new_topics
+        .iter()
+        .filter(|t| !current_topics.contains(t))
+        .cloned()
+        .collect();
+    let removed_topics: Vec<String> = current_topics
+        .iter()
+        .filter(|t| !new_topics.contains(t))
+        .cloned()
+        .collect();
+    if let Err(e) = mqtt_client.unsubscribe_from_topics(&removed_topics).await {
+        error!("Failed to unsubscribe from MQTT topics: {}", e);
+    }
+    if let Err(e) = mqtt_client.subscribe_to_topics(&added_topics).await {
+        error!("Failed to subscribe to MQTT topics: {}", e);
+    }
+
+    shared_rules.store(RepositoryRules::new(&new_config.git));
+    shared_config.store(new_config);
+    info!("Configuration reloaded");
+}
@@ -1,15 +1,340 @@
-use clap::{Arg, Command};
-use git_friends::{auth::AuthManager, git::CommitInfo, mqtt::MqttClient, Config, Result};
+use clap::{Arg, ArgAction, Command};
+use futures_util::{SinkExt, Stream, StreamExt};
+use git_friends::{
+    audit::{AuditLog, AuditOutcome, AuditRecord},
+    auth::{hash_token, token_fingerprint, AuthManager, ScopeViolation, TokenValidity},
+    broker::Broker,
+    clock::unix_now,
+    config::{PublisherTransport, TenantConfig, TokenAccess, TokenConfig, TokenScopes},
+    dashboard::{self, DashboardQuery, View},
+    event::{event_json_schema, EventEnvelope, EVENT_TYPE_COMMIT},
+    feed::{self, Feed, FeedFormat, FeedKind},
+    git::CommitInfo,
+    health::Health,
+    history::{CommitPage, CommitQuery, HistoryStore},
+    metrics::Metrics,
+    mirror::{self, Mirrors, ON_DEMAND_FETCH_INTERVAL},
+    mqtt::commit_topic,
+    oidc::{is_jwt, JwksStore, OidcError},
+    outbound::Outbound,
+    publish::{Publication, Publishers},
+    ratelimit::{RateLimited, RateLimiter},
+    reload::{spawn_reload_watcher, Swappable},
+    repository::RepositoryRules,
+    session::{Sessions, SESSION_TTL_SECS},
+    signing::{
+        NonceCache, RequestSignature, HEADER_KEY_ID, HEADER_NONCE, HEADER_SIGNATURE,
+        HEADER_TIMESTAMP,
+    },
+    stream::{parse_topic_filters, EventBus, StreamEvent},
+    tenant::{self, tenant_for_topic, tenant_for_user, TenantScope, TenantViolation},
+    tls::{self, ConnectionInfo, TlsReloader},
+    tokens::{is_config_token_id, summarize_tokens, TokenStore, TokenSummary, UsageTracker},
+    Config, GitFriendsError, Result,
+};
 use log::{error, info, warn};
+use percent_encoding::percent_decode_str;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use warp::http::StatusCode;
+use std::time::Duration;
+use warp::http::{HeaderMap, Method, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::ws::{Message, WebSocket};
 use warp::{Filter, Reply};
 
+/// Maximum number of stored events replayed when a stream resumes.
+const STREAM_RESUME_LIMIT: u32 = 1000;
+
+/// How long a rotated token keeps working unless the request says otherwise.
+const DEFAULT_ROTATION_OVERLAP_SECS: i64 = 24 * 60 * 60;
+
+/// Tokens unused for this many days are reported as stale by default.
+const DEFAULT_STALE_DAYS: i64 = 30;
+
+/// How long shutdown waits for in-flight webhooks to finish publishing.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a webhook waits for a commit to be read from its mirror.
+const MIRROR_ENRICH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long startup waits for required publishers to connect.
+const PUBLISHER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long shutdown waits for queued publishes to reach each publisher.
+const PUBLISHER_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long shutdown waits for outbound webhook deliveries still retrying.
+const OUTBOUND_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Cookie holding the id of a dashboard session.
+const SESSION_COOKIE: &str = "gf_session";
+
 #[derive(Clone)]
 struct ServerState {
-    mqtt_client: Arc<MqttClient>,
-    auth_manager: Arc<AuthManager>,
-    config: Arc<Config>,
+    publishers: Arc<Publishers>,
+    history: Option<Arc<HistoryStore>>,
+    /// Recent commits kept in memory for feeds and the dashboard without `history`
+    recent_commits: Option<Arc<HistoryStore>>,
+    mirrors: Option<Arc<Mirrors>>,
+    audit_log: Option<Arc<AuditLog>>,
+    outbound: Arc<Outbound>,
+    event_bus: EventBus,
+    metrics: Metrics,
+    rate_limiter: Arc<RateLimiter>,
+    token_store: Option<Arc<TokenStore>>,
+    token_usage: Arc<UsageTracker>,
+    nonces: Arc<NonceCache>,
+    sessions: Arc<Sessions>,
+    jwks: Arc<JwksStore>,
+    health: Arc<Health>,
+    settings: Arc<Swappable<Settings>>,
+}
+
+/// Configuration and what is built from it, swapped together on reload.
+struct Settings {
+    config: Config,
+    /// Tokens from the token store, merged into `auth_manager`
+    stored_tokens: Vec<TokenConfig>,
+    auth_manager: AuthManager,
+    repositories: RepositoryRules,
+}
+
+impl Settings {
+    fn new(config: Config, stored_tokens: Vec<TokenConfig>) -> Self {
+        let mut auth_manager = AuthManager::new(&config.auth);
+        for token in &stored_tokens {
+            auth_manager.add_token_config(token.clone());
+        }
+
+        Settings {
+            repositories: RepositoryRules::new(&config.git),
+            config,
+            stored_tokens,
+            auth_manager,
+        }
+    }
+}
+
+/// What a request presented to authenticate itself.
+struct Credentials {
+    authorization: Option<String>,
+    /// Signature headers, or `Err` if they are present but incomplete
+    signature: Option<std::result::Result<RequestSignature, ()>>,
+    method: Method,
+    /// Path and query string, as covered by the signature
+    target: String,
+    /// Request body; empty for routes that do not read one
+    body: Bytes,
+    client_ip: Option<IpAddr>,
+    /// Identities of a verified TLS client certificate
+    client_identities: Vec<String>,
+    /// Id from the dashboard session cookie
+    session_id: Option<String>,
+}
+
+/// The identity a request was authenticated as.
+struct Principal {
+    username: String,
+    /// The configured token or client certificate mapping that was
+    /// presented, if any
+    token: Option<TokenConfig>,
+    /// Issuer of the OIDC ID token the principal authenticated with. Such
+    /// principals may read and publish but are never admins.
+    issuer: Option<String>,
+}
+
+impl Principal {
+    /// Whether the principal may use the query and streaming APIs.
+    fn can_read(&self) -> bool {
+        self.token
+            .as_ref()
+            .is_none_or(|token| token.scopes.allows_read())
+    }
+}
+
+/// Why a request could not be authenticated.
+#[derive(Debug, Clone, Copy)]
+enum AuthRejection {
+    Missing,
+    Malformed,
+    InvalidToken,
+    Expired,
+    InvalidSignature,
+    StaleSignature,
+    Replayed,
+}
+
+impl AuthRejection {
+    /// Label used for the rejection metrics.
+    fn reason(self) -> &'static str {
+        match self {
+            AuthRejection::Missing => "missing_token",
+            AuthRejection::Malformed => "malformed_header",
+            AuthRejection::InvalidToken => "invalid_token",
+            AuthRejection::Expired => "expired_token",
+            AuthRejection::InvalidSignature => "invalid_signature",
+            AuthRejection::StaleSignature => "stale_signature",
+            AuthRejection::Replayed => "replayed_request",
+        }
+    }
+
+    fn into_response(self) -> warp::reply::Response {
+        let message = match self {
+            AuthRejection::Missing => "Authentication required",
+            AuthRejection::Malformed
+            | AuthRejection::InvalidToken
+            | AuthRejection::InvalidSignature => "Authentication failed",
+            AuthRejection::StaleSignature => "Request timestamp outside the allowed clock skew",
+            AuthRejection::Replayed => "Request nonce already used",
+            AuthRejection::Expired => {
+                // RFC 6750 error so clients can tell expiry from a wrong token
+                return warp::reply::with_header(
+                    warp::reply::with_status("Token expired", StatusCode::UNAUTHORIZED),
+                    "www-authenticate",
+                    r#"Bearer error="invalid_token", error_description="The token has expired""#,
+                )
+                .into_response();
+            }
+        };
+        warp::reply::with_status(message, StatusCode::UNAUTHORIZED).into_response()
+    }
+}
+
+/// Why a request to the admin API was refused.
+#[derive(Debug, Clone, Copy)]
+enum AdminRejection {
+    Auth(AuthRejection),
+    NotAdmin,
+    /// A tenant admin named a user outside the tenant
+    OutsideTenant,
+    /// A tenant admin named one of `auth.admin_users`
+    GlobalAdmin,
+}
+
+impl AdminRejection {
+    fn into_response(self) -> warp::reply::Response {
+        match self {
+            AdminRejection::Auth(rejection) => rejection.into_response(),
+            AdminRejection::NotAdmin => {
+                warp::reply::with_status("Admin access required", StatusCode::FORBIDDEN)
+                    .into_response()
+            }
+            AdminRejection::OutsideTenant => {
+                warp::reply::with_status("User is not in your tenant", StatusCode::FORBIDDEN)
+                    .into_response()
+            }
+            AdminRejection::GlobalAdmin => warp::reply::with_status(
+                "Only global admins manage tokens of admin users",
+                StatusCode::FORBIDDEN,
+            )
+            .into_response(),
+        }
+    }
+}
+
+/// Why a feed or stream reader was refused.
+#[derive(Debug, Clone, Copy)]
+enum ReaderRejection {
+    Auth(AuthRejection),
+    Scope,
+    /// A token in the query string that may do more than read
+    QueryToken,
+}
+
+impl ReaderRejection {
+    fn into_response(self) -> warp::reply::Response {
+        match self {
+            ReaderRejection::Auth(rejection) => rejection.into_response(),
+            ReaderRejection::Scope => forbidden_by_scope(),
+            ReaderRejection::QueryToken => warp::reply::with_status(
+                "Tokens in the query string must be read-only (scopes.access = \"read\")",
+                StatusCode::FORBIDDEN,
+            )
+            .into_response(),
+        }
+    }
+}
+
+/// A user allowed to manage tokens: every token for `auth.admin_users`,
+/// only those of the tenant's users for a tenant's `admin_users`.
+struct Admin {
+    username: String,
+    tenant: Option<TenantConfig>,
+    /// All tenants, to tell which one a user belongs to
+    tenants: Vec<TenantConfig>,
+    /// `auth.admin_users`, whose tokens only global admins manage
+    global_admins: Vec<String>,
+}
+
+impl Admin {
+    fn manages(&self, username: &str) -> bool {
+        self.may_manage(username).is_ok()
+    }
+
+    fn may_manage(&self, username: &str) -> std::result::Result<(), AdminRejection> {
+        let Some(tenant) = &self.tenant else {
+            return Ok(());
+        };
+        // Patterns of several tenants may match; the user belongs to the first
+        let user_tenant = tenant_for_user(&self.tenants, username);
+        if user_tenant.map(|t| &t.name) != Some(&tenant.name) {
+            Err(AdminRejection::OutsideTenant)
+        } else if self.global_admins.iter().any(|admin| admin == username) {
+            Err(AdminRejection::GlobalAdmin)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_manages(&self, username: &str) -> std::result::Result<(), AdminRejection> {
+        self.may_manage(username).inspect_err(|_| {
+            warn!(
+                "Tenant admin {} may not manage tokens of user {}",
+                self.username, username
+            )
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenRequest {
+    username: String,
+    /// Unix timestamp at which a created token expires
+    expires_at: Option<i64>,
+    /// Restrictions on a created token; unrestricted when left out
+    #[serde(default)]
+    scopes: TokenScopes,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RenameTokenRequest {
+    /// Label shown in token listings
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RotateRequest {
+    /// How long the old token keeps working, in seconds
+    overlap_secs: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StaleQuery {
+    days: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamQuery {
+    /// Comma-separated MQTT-style topic filters
+    topics: Option<String>,
+    /// Bearer token for clients that cannot set headers (EventSource, browsers)
+    token: Option<String>,
+    last_event_id: Option<i64>,
 }
 
 #[tokio::main]
@@ -39,34 +364,116 @@ async fn main() -> Result<()> {
                 .value_name("USERNAME")
                 .help("Generate a new authentication token for the specified username"),
         )
+        .arg(
+            Arg::new("register")
+                .long("register")
+                .help("Store the generated token in auth.token_store_path instead of printing TOML")
+                .requires("generate-token")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("expires-in")
+                .long("expires-in")
+                .value_name("DAYS")
+                .help("Make the generated token expire after this many days")
+                .requires("generate-token")
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("list-stale-tokens")
+                .long("list-stale-tokens")
+                .help("List tokens that expired or were not used recently, then exit")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("stale-days")
+                .long("stale-days")
+                .value_name("DAYS")
+                .help("Days without use after which a token is stale")
+                .value_parser(clap::value_parser!(i64))
+                .default_value("30"),
+        )
+        .arg(
+            Arg::new("hash-token")
+                .long("hash-token")
+                .help("Read a token from stdin and print the hash to store in the configuration")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("print-schema")
+                .long("print-schema")
+                .help("Print the JSON Schema of published events and exit")
+                .action(ArgAction::SetTrue),
+        )
         .get_matches();
 
+    if matches.get_flag("print-schema") {
+        println!("{}", serde_json::to_string_pretty(&event_json_schema())?);
+        return Ok(());
+    }
+
+    if matches.get_flag("hash-token") {
+        let mut token = String::new();
+        std::io::stdin().read_line(&mut token)?;
+        println!("{}", hash_token(token.trim()));
+        return Ok(());
+    }
+
+    if let Some(path) = matches.get_one::<String>("config") {
+        std::env::set_var("GIT_FRIENDS_CONFIG", path);
+    }
+
+    if matches.get_flag("list-stale-tokens") {
+        let stale_days = *matches.get_one::<i64>("stale-days").unwrap();
+        return list_stale_tokens(stale_days);
+    }
+
     if let Some(username) = matches.get_one::<String>("generate-token") {
-        let token_config = AuthManager::generate_token_config(username);
+        let expires_at = matches
+            .get_one::<u32>("expires-in")
+            .map(|days| unix_now() + *days as i64 * 24 * 60 * 60);
+
+        if matches.get_flag("register") {
+            let config = load_config()?;
+            let store_path = config.auth.token_store_path.ok_or_else(|| {
+                GitFriendsError::InvalidConfig(
+                    "--register needs auth.token_store_path to be set".to_string(),
+                )
+            })?;
+            let (stored, secret) = TokenStore::open(&store_path)?.create(
+                username,
+                expires_at,
+                &TokenScopes::default(),
+            )?;
+            println!(
+                "Registered token {} for user '{}' in {}",
+                stored.id, stored.username, store_path
+            );
+            println!("Token (shown only once): {}", secret);
+            return Ok(());
+        }
+
+        let (secret, mut token_config) = AuthManager::generate_hashed_token_config(username);
+        token_config.expires_at = expires_at;
         println!(
-            "Generated token for user '{}': {}",
-            token_config.username, token_config.token
+            "Generated token for user '{}' (shown only once): {}",
+            token_config.username, secret
         );
-        println!("Add this to your configuration file:");
+        println!("Add its hash to your configuration file:");
         println!("[[auth.tokens]]");
         println!("token = \"{}\"", token_config.token);
         println!("username = \"{}\"", token_config.username);
+        if let Some(expires_at) = token_config.expires_at {
+            println!("expires_at = {}", expires_at);
+        }
         return Ok(());
     }
 
     info!("Starting gf-server");
 
     // Load configuration
-    let mut config = match matches.get_one::<String>("config") {
-        Some(path) => {
-            std::env::set_var("GIT_FRIENDS_CONFIG", path);
-            Config::new()?
-        }
-        None => Config::new()?,
-    };
-
-    // Modify client_id to append "/server"
-    config.mqtt.client_id = format!("{}/server", config.mqtt.client_id);
+    let config = load_config()?;
+    config.validate()?;
 
     // Override bind address if provided
     let bind_address = matches
@@ -76,59 +483,375 @@ async fn main() -> Result<()> {
         .to_string();
 
     info!("Configuration loaded");
-    info!(
-        "MQTT broker: {}:{}",
-        config.mqtt.broker_host, config.mqtt.broker_port
-    );
+    if config.broker.enabled {
+        info!("MQTT broker: embedded");
+    } else {
+        info!(
+            "MQTT broker: {}:{}",
+            config.mqtt.broker_host, config.mqtt.broker_port
+        );
+    }
     info!("Server binding to: {}", bind_address);
 
-    // Create MQTT client
-    let (mqtt_client, mut mqtt_events) = MqttClient::new(config.mqtt.clone())?;
-    let mqtt_client = Arc::new(mqtt_client);
+    // Start the embedded broker; gf-server then publishes to it over loopback
+    let mut mqtt_config = config.mqtt.clone();
+    let _broker = if config.broker.enabled {
+        let broker = Broker::start(&config.broker).await?;
+        let (addr, username, password) = broker.internal_login();
+        mqtt_config.broker_host = addr.ip().to_string();
+        mqtt_config.broker_port = addr.port();
+        mqtt_config.username = Some(username.to_string());
+        mqtt_config.password = Some(password.to_string());
+        Some(broker)
+    } else {
+        None
+    };
+
+    // Open commit history store
+    let history = if config.history.enabled {
+        info!("Commit history database: {}", config.history.database_path);
+        Some(Arc::new(HistoryStore::open(&config.history.database_path)?))
+    } else {
+        None
+    };
+
+    if config.feeds.base_url.is_none() && (history.is_some() || config.feeds.recent_commits > 0) {
+        warn!(
+            "feeds.base_url is not set; feeds link to {}",
+            config.server.bind_address
+        );
+    }
+
+    let recent_commits = if history.is_none() && config.feeds.recent_commits > 0 {
+        Some(Arc::new(HistoryStore::open_recent(
+            config.feeds.recent_commits,
+        )?))
+    } else {
+        None
+    };
+
+    // Open the repository mirrors and keep them fetched
+    let mirrors = if config.mirrors.enabled {
+        info!("Repository mirrors: {}", config.mirrors.path);
+        let mirrors = Arc::new(Mirrors::open(&config.mirrors)?);
+        let (fetched, interval) = (
+            mirrors.clone(),
+            Duration::from_secs(config.mirrors.fetch_interval_secs.max(1)),
+        );
+        tokio::spawn(async move {
+            let mut next_fetch = tokio::time::Instant::now();
+            loop {
+                let mirrors = fetched.clone();
+                // Fetch everything periodically, and sooner the mirrors missing a commit
+                let task = tokio::select! {
+                    () = tokio::time::sleep_until(next_fetch) => {
+                        next_fetch += interval;
+                        tokio::task::spawn_blocking(move || mirrors.fetch_all())
+                    }
+                    () = fetched.wanted() => tokio::task::spawn_blocking(move || {
+                        mirrors.fetch_wanted(ON_DEMAND_FETCH_INTERVAL)
+                    }),
+                };
+                if let Err(e) = task.await {
+                    error!("Mirror fetch task failed: {}", e);
+                }
+            }
+        });
+        Some(mirrors)
+    } else {
+        None
+    };
+
+    // Open the audit log
+    let audit_log = if config.audit.enabled {
+        info!("Audit log: {}", config.audit.path);
+        Some(Arc::new(AuditLog::open(&config.audit)?))
+    } else {
+        None
+    };
+
+    // Open the token store and merge its tokens with the configured ones
+    let (token_store, stored_tokens, token_usage) = match &config.auth.token_store_path {
+        Some(path) => {
+            let store = TokenStore::open(path)?;
+            let stored_tokens = store.token_configs()?;
+            let token_usage = store.load_usage()?;
+            info!("Loaded {} tokens from {}", stored_tokens.len(), path);
+            (Some(Arc::new(store)), stored_tokens, token_usage)
+        }
+        None => (None, Vec::new(), Default::default()),
+    };
+
+    let max_body_bytes = config.rate_limit.max_body_bytes;
+    let tls_config = config.server.tls.clone();
+    let reload_interval = config
+        .reload
+        .watch_config
+        .then(|| Duration::from_secs(config.reload.watch_interval_secs.max(1)));
 
-    // Create auth manager
-    let auth_manager = Arc::new(AuthManager::new(&config.auth));
+    // Create publishers; the event bus also feeds the live stream
+    let metrics = Metrics::new()?;
+    let event_bus = EventBus::new(256);
+    let publishers = Publishers::from_config(
+        &config.publishers(),
+        &mqtt_config,
+        &config.tenants,
+        &event_bus,
+        metrics.clone(),
+    )
+    .await?;
 
     // Create server state
     let state = ServerState {
-        mqtt_client: mqtt_client.clone(),
-        auth_manager: auth_manager.clone(),
-        config: Arc::new(config),
+        publishers: Arc::new(publishers),
+        history,
+        recent_commits,
+        mirrors,
+        audit_log,
+        outbound: Arc::new(Outbound::new(metrics.clone())?),
+        event_bus,
+        metrics,
+        rate_limiter: Arc::new(RateLimiter::new(config.rate_limit.clone())),
+        token_store,
+        token_usage: Arc::new(UsageTracker::new(token_usage)),
+        nonces: Arc::new(NonceCache::new()),
+        sessions: Arc::new(Sessions::new()),
+        jwks: Arc::new(JwksStore::new()),
+        health: Arc::new(Health::new()),
+        settings: Arc::new(Swappable::new(Settings::new(config, stored_tokens))),
     };
 
-    // Wait for MQTT connection
-    info!("Connecting to MQTT broker...");
-    mqtt_client.wait_for_connection().await?;
-    info!("Connected to MQTT broker");
+    // Fetch the signing keys of OIDC issuers, and again periodically
+    state
+        .jwks
+        .refresh(&state.settings.load().config.auth.oidc)
+        .await;
+    let (jwks, settings) = (state.jwks.clone(), state.settings.clone());
+    tokio::spawn(async move {
+        loop {
+            let refresh_secs = settings.load().config.auth.jwks_refresh_secs.max(60);
+            tokio::time::sleep(Duration::from_secs(refresh_secs)).await;
+            jwks.refresh(&settings.load().config.auth.oidc).await;
+        }
+    });
 
-    // Spawn task to handle MQTT events
+    // Reload tokens, mappings and filters on SIGHUP or config file changes
+    let mut reloads = spawn_reload_watcher(Config::source_paths(), reload_interval);
+    let (jwks, settings, health) = (
+        state.jwks.clone(),
+        state.settings.clone(),
+        state.health.clone(),
+    );
     tokio::spawn(async move {
-        while let Some(event) = mqtt_events.recv().await {
-            match event {
-                rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => {
-                    info!("MQTT connection acknowledged");
-                }
-                rumqttc::Event::Incoming(rumqttc::Packet::Disconnect) => {
-                    warn!("MQTT disconnected");
-                }
-                _ => {}
-            }
+        while let Some(trigger) = reloads.recv().await {
+            info!("Reloading configuration after {}", trigger);
+            reload_settings(&settings, &health);
+            jwks.refresh(&settings.load().config.auth.oidc).await;
         }
     });
 
+    // Wait for the publishers to connect
+    info!("Connecting publishers...");
+    state.publishers.connect(PUBLISHER_CONNECT_TIMEOUT).await?;
+
     // Define routes
+    let (webhook_metrics, webhook_audit_log) = (state.metrics.clone(), state.audit_log.clone());
     let webhook_route = warp::path("webhook")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(max_body_bytes))
+        .and(credentials_with_body())
+        .and(with_state(state.clone()))
+        .and_then(handle_webhook)
+        .recover(move |rejection: warp::Rejection| {
+            let (metrics, audit_log) = (webhook_metrics.clone(), webhook_audit_log.clone());
+            async move {
+                if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+                    metrics.webhook_received();
+                    metrics.webhook_rejected("body_too_large");
+                    if let Some(audit_log) = audit_log {
+                        // Rejected before the client address was extracted
+                        let mut audit = AuditRecord::new(unix_now(), None);
+                        audit.reject("body_too_large");
+                        audit.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16();
+                        audit_log.record(audit);
+                    }
+                    Ok(warp::reply::with_status(
+                        "Request body too large",
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                    ))
+                } else {
+                    Err(rejection)
+                }
+            }
+        });
+
+    let dry_run_route = warp::path!("webhook" / "dry-run")
         .and(warp::post())
-        .and(warp::header::optional::<String>("authorization"))
-        .and(warp::body::json())
+        .and(warp::body::content_length_limit(max_body_bytes))
+        .and(credentials_with_body())
+        .and(with_state(state.clone()))
+        .and_then(handle_webhook_dry_run);
+
+    let commits_route = warp::path!("api" / "commits")
+        .and(warp::get())
+        .and(credentials())
+        .and(warp::query::<CommitQuery>())
+        .and(with_state(state.clone()))
+        .and_then(handle_list_commits);
+
+    let sse_route = warp::path!("api" / "stream")
+        .and(warp::get())
+        .and(credentials())
+        .and(warp::header::optional::<i64>("last-event-id"))
+        .and(warp::query::<StreamQuery>())
+        .and(with_state(state.clone()))
+        .and_then(handle_sse_stream);
+
+    let ws_route = warp::path!("api" / "stream" / "ws")
+        .and(warp::ws())
+        .and(credentials())
+        .and(warp::query::<StreamQuery>())
+        .and(with_state(state.clone()))
+        .and_then(handle_ws_stream);
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(handle_metrics);
+
+    let schema_route = warp::path!("api" / "schema")
+        .and(warp::get())
+        .map(|| warp::reply::json(&event_json_schema()));
+
+    let admin_tokens_route = warp::path!("api" / "admin" / "tokens")
+        .and(warp::get())
+        .and(credentials())
         .and(with_state(state.clone()))
-        .and_then(handle_webhook);
+        .and_then(handle_list_tokens)
+        .or(warp::path!("api" / "admin" / "tokens")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(16 * 1024))
+            .and(credentials_with_body())
+            .and(with_state(state.clone()))
+            .and_then(handle_create_token))
+        .or(warp::path!("api" / "admin" / "tokens" / "stale")
+            .and(warp::get())
+            .and(credentials())
+            .and(warp::query::<StaleQuery>())
+            .and(with_state(state.clone()))
+            .and_then(handle_stale_tokens))
+        .or(warp::path!("api" / "admin" / "tokens" / String / "rotate")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(16 * 1024))
+            .and(credentials_with_body())
+            .and(with_state(state.clone()))
+            .and_then(handle_rotate_token))
+        .or(warp::path!("api" / "admin" / "tokens" / String)
+            .and(warp::delete())
+            .and(credentials())
+            .and(with_state(state.clone()))
+            .and_then(handle_revoke_token))
+        .or(warp::path!("api" / "admin" / "tokens" / String)
+            .and(warp::patch())
+            .and(warp::body::content_length_limit(16 * 1024))
+            .and(credentials_with_body())
+            .and(with_state(state.clone()))
+            .and_then(handle_rename_token));
 
-    let health_route = warp::path("health")
+    // `/health` predates `/live` and is kept for existing health checks
+    let live_route = warp::path("live")
+        .or(warp::path("health"))
+        .unify()
+        .and(warp::path::end())
         .and(warp::get())
         .map(|| warp::reply::with_status("OK", StatusCode::OK));
 
-    let routes = webhook_route.or(health_route).with(warp::log("gf-server"));
+    let dashboard_route = warp::path!("dashboard")
+        .and(warp::get())
+        .and(credentials())
+        .and(warp::query::<DashboardQuery>())
+        .and(with_state(state.clone()))
+        .and_then(|credentials, query, state| {
+            handle_dashboard(View::Feed, credentials, query, state)
+        })
+        .or(warp::path!("dashboard" / "repos" / String)
+            .and(warp::get())
+            .and(credentials())
+            .and(warp::query::<DashboardQuery>())
+            .and(with_state(state.clone()))
+            .and_then(|id: String, credentials, query, state| {
+                let view = View::Repository(decode_path_segment(&id));
+                handle_dashboard(view, credentials, query, state)
+            }))
+        .or(warp::path!("dashboard" / "people" / String)
+            .and(warp::get())
+            .and(credentials())
+            .and(warp::query::<DashboardQuery>())
+            .and(with_state(state.clone()))
+            .and_then(|name: String, credentials, query, state| {
+                let view = View::Person(decode_path_segment(&name));
+                handle_dashboard(view, credentials, query, state)
+            }))
+        .or(warp::path!("dashboard" / "login")
+            .and(warp::get())
+            .map(|| warp::reply::html(dashboard::render_login(None))))
+        .or(warp::path!("dashboard" / "login")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(16 * 1024))
+            .and(credentials())
+            .and(warp::body::form())
+            .and(with_state(state.clone()))
+            .and_then(handle_dashboard_login))
+        .or(warp::path!("dashboard" / "logout")
+            .and(warp::post())
+            .and(credentials())
+            .and(with_state(state.clone()))
+            .map(handle_dashboard_logout));
+
+    let feed_request = warp::get()
+        .and(credentials())
+        .and(warp::query::<FeedQuery>())
+        .and(with_state(state.clone()));
+    let feeds_route = warp::path!("feeds" / String)
+        .and(feed_request.clone())
+        .and_then(|segment: String, credentials, query, state| {
+            let feed = FeedFormat::split(&segment)
+                .filter(|(name, _)| *name == "all")
+                .map(|(_, format)| (FeedKind::All, format));
+            handle_feed(feed, credentials, query, state)
+        })
+        .or(warp::path!("feeds" / "repo" / String)
+            .and(feed_request.clone())
+            .and_then(|segment: String, credentials, query, state| {
+                let feed = parse_feed(&segment, FeedKind::Repository);
+                handle_feed(feed, credentials, query, state)
+            }))
+        .or(warp::path!("feeds" / "user" / String)
+            .and(feed_request)
+            .and_then(|segment: String, credentials, query, state| {
+                let feed = parse_feed(&segment, FeedKind::User);
+                handle_feed(feed, credentials, query, state)
+            }));
+
+    let ready_route = warp::path("ready")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(handle_ready);
+
+    let routes = webhook_route
+        .or(dry_run_route)
+        .or(commits_route)
+        .or(sse_route)
+        .or(ws_route)
+        .or(metrics_route)
+        .or(schema_route)
+        .or(admin_tokens_route)
+        .or(dashboard_route)
+        .or(feeds_route)
+        .or(live_route)
+        .or(ready_route)
+        .with(warp::log("gf-server"));
 
     // Parse bind address
     let addr: std::net::SocketAddr = bind_address.parse().map_err(|e| {
@@ -137,81 +860,1867 @@ async fn main() -> Result<()> {
 
     info!("Server starting on {}", addr);
 
-    // Start server
-    warp::serve(routes).run(addr).await;
+    // Serve until SIGTERM or Ctrl-C
+    let server = async move {
+        if let Some(tls_config) = tls_config {
+            let reloader = Arc::new(TlsReloader::new(tls_config.clone())?);
+            reloader.clone().spawn_watcher();
+
+            if let Some(redirect_bind) = &tls_config.http_redirect_bind {
+                let redirect_addr: std::net::SocketAddr = redirect_bind.parse().map_err(|e| {
+                    git_friends::GitFriendsError::InvalidConfig(format!(
+                        "Invalid HTTP redirect address: {}",
+                        e
+                    ))
+                })?;
+                tokio::spawn(tls::serve_redirect(redirect_addr, addr.port()));
+            }
+
+            tls::serve(routes.map(Reply::into_response).boxed(), addr, reloader).await
+        } else {
+            warp::serve(routes).run(addr).await;
+            Ok(())
+        }
+    };
+    tokio::select! {
+        result = server => result?,
+        () = shutdown_signal() => {}
+    }
 
+    shut_down(&state).await;
     Ok(())
 }
 
-fn with_state(
-    state: ServerState,
-) -> impl Filter<Extract = (ServerState,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || state.clone())
-}
+/// Resolve on SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-async fn handle_webhook(
-    auth_header: Option<String>,
-    commit_info: CommitInfo,
-    state: ServerState,
-) -> std::result::Result<impl Reply, warp::Rejection> {
-    // Validate authentication and get username
-    let username = if let Some(header) = auth_header {
-        match state.auth_manager.validate_bearer_token(&header) {
-            Ok(Some(username)) => {
-                info!("Authentication successful for user: {}", username);
-                Some(username)
-            }
-            Ok(None) => {
-                warn!("Authentication failed - invalid token");
-                return Ok(warp::reply::with_status(
-                    "Authentication failed",
-                    StatusCode::UNAUTHORIZED,
-                ));
-            }
-            Err(e) => {
-                warn!("Authentication error: {}", e);
-                return Ok(warp::reply::with_status(
-                    "Authentication failed",
-                    StatusCode::UNAUTHORIZED,
-                ));
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => info!("Received SIGTERM"),
+                    _ = tokio::signal::ctrl_c() => info!("Received Ctrl-C"),
+                }
+                return;
             }
+            Err(e) => error!("Failed to install SIGTERM handler: {}", e),
         }
-    } else if state.config.auth.require_auth {
-        warn!("Authentication required but not provided");
-        return Ok(warp::reply::with_status(
-            "Authentication required",
-            StatusCode::UNAUTHORIZED,
-        ));
+    }
+
+    if tokio::signal::ctrl_c().await.is_ok() {
+        info!("Received Ctrl-C");
+    }
+}
+
+/// The listener has stopped accepting connections. Refuse webhooks still
+/// arriving on open connections, let accepted ones finish publishing, then
+/// disconnect the publishers once their messages are sent and give outbound
+/// deliveries a last chance before writing out the audit log.
+async fn shut_down(state: &ServerState) {
+    info!("Shutting down");
+    state.health.start_draining();
+
+    let in_flight = state.health.in_flight();
+    if in_flight > 0 {
+        info!("Waiting for {} in-flight webhooks", in_flight);
+    }
+    if !state.health.drained(SHUTDOWN_DRAIN_TIMEOUT).await {
+        warn!(
+            "Gave up waiting for {} in-flight webhooks",
+            state.health.in_flight()
+        );
+    }
+
+    state.publishers.close(PUBLISHER_CLOSE_TIMEOUT).await;
+
+    let pending = state.outbound.pending();
+    if pending > 0 {
+        info!("Waiting for {} outbound deliveries", pending);
+        if !state.outbound.drained(OUTBOUND_DRAIN_TIMEOUT).await {
+            warn!(
+                "Gave up on {} outbound deliveries",
+                state.outbound.pending()
+            );
+        }
+    }
+    if let Some(audit_log) = &state.audit_log {
+        audit_log.flush().await;
+    }
+    info!("Shutdown complete");
+}
+
+fn handle_ready(state: ServerState) -> warp::reply::Response {
+    let readiness = state.health.readiness(state.publishers.health());
+    let status = if readiness.ready {
+        StatusCode::OK
     } else {
-        Some("anonymous".to_string())
+        StatusCode::SERVICE_UNAVAILABLE
     };
+    warp::reply::with_status(warp::reply::json(&readiness), status).into_response()
+}
 
-    info!(
-        "Processing commit: {} by {} - {}",
-        commit_info.short_hash,
-        commit_info.author_name,
-        commit_info.message.lines().next().unwrap_or("")
-    );
+fn load_config() -> Result<Config> {
+    let mut config = Config::new()?;
 
-    // Publish to MQTT with username
-    match state
-        .mqtt_client
-        .publish_commit(&commit_info, username.as_deref())
-        .await
-    {
-        Ok(()) => {
-            info!("Published commit to MQTT");
-            Ok(warp::reply::with_status(
-                "Commit processed successfully",
-                StatusCode::OK,
-            ))
+    // Modify client_id to append "/server"
+    config.mqtt.client_id = format!("{}/server", config.mqtt.client_id);
+
+    Ok(config)
+}
+
+/// Print tokens that expired or were not used for `stale_days`, based on
+/// the usage recorded in the token store.
+fn list_stale_tokens(stale_days: i64) -> Result<()> {
+    let config = load_config()?;
+    let (stored, usage) = match &config.auth.token_store_path {
+        Some(path) => {
+            let store = TokenStore::open(path)?;
+            (store.list()?, store.load_usage()?)
         }
+        None => {
+            warn!("auth.token_store_path is not set; token usage is unknown");
+            (Vec::new(), Default::default())
+        }
+    };
+
+    let now = unix_now();
+    let stale: Vec<_> =
+        summarize_tokens(&config.auth.tokens, &stored, &usage, &config.tenants, now)
+            .into_iter()
+            .filter(|token| token.is_stale(now, stale_days * 24 * 60 * 60))
+            .collect();
+    if stale.is_empty() {
+        println!("No stale tokens");
+        return Ok(());
+    }
+
+    let or_never = |t: Option<i64>| t.map_or("never".to_string(), |t| t.to_string());
+    for token in stale {
+        println!(
+            "{}\t{}\t{}\texpired: {}\tlast used: {} from {}",
+            token.id,
+            token.username,
+            serde_json::to_string(&token.source)?.trim_matches('"'),
+            token.expired,
+            or_never(token.last_used_at),
+            token.last_used_ip.as_deref().unwrap_or("unknown"),
+        );
+    }
+    Ok(())
+}
+
+/// Load, validate and swap in a new configuration. Sections that need a
+/// restart keep their current values so the running server stays consistent.
+fn reload_settings(settings: &Swappable<Settings>, health: &Health) {
+    let mut new_config = match load_config().and_then(|c| c.validate().map(|_| c)) {
+        Ok(config) => config,
         Err(e) => {
-            error!("Failed to publish commit to MQTT: {}", e);
-            Ok(warp::reply::with_status(
-                "Failed to process commit",
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ))
+            error!(
+                "Configuration reload failed, keeping current configuration: {}",
+                e
+            );
+            health.set_config_error(Some(e.to_string()));
+            return;
+        }
+    };
+    health.set_config_error(None);
+
+    let _ = settings.update(|current| {
+        for section in new_config.keep_server_restart_settings(&current.config) {
+            warn!("Ignoring changes to {} until restart", section);
+        }
+        let changes = current.config.diff(&new_config);
+        if changes.is_empty() {
+            info!("Configuration unchanged");
+            return Err(());
         }
+        for change in &changes {
+            info!("Configuration change: {}", change);
+        }
+        info!("Configuration reloaded");
+        Ok(Settings::new(new_config, current.stored_tokens.clone()))
+    });
+}
+
+fn with_state(
+    state: ServerState,
+) -> impl Filter<Extract = (ServerState,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+/// The `Authorization` header, signature headers, client address and client
+/// certificate of a request. The address comes from warp for plain HTTP and
+/// from `ConnectionInfo` under TLS.
+fn credentials() -> impl Filter<Extract = (Credentials,), Error = Infallible> + Clone {
+    warp::header::headers_cloned()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::addr::remote())
+        .and(warp::ext::optional::<ConnectionInfo>())
+        .map(
+            |headers: HeaderMap,
+             method: Method,
+             path: warp::path::FullPath,
+             query: String,
+             remote: Option<SocketAddr>,
+             connection: Option<ConnectionInfo>| {
+                let (connection_addr, client_identities) = connection
+                    .map(|c| (Some(c.remote_addr), c.client_identities))
+                    .unwrap_or_default();
+                let target = if query.is_empty() {
+                    path.as_str().to_string()
+                } else {
+                    format!("{}?{}", path.as_str(), query)
+                };
+                Credentials {
+                    authorization: header_value(&headers, "authorization"),
+                    signature: request_signature(&headers),
+                    method,
+                    target,
+                    body: Bytes::new(),
+                    client_ip: remote.or(connection_addr).map(|addr| addr.ip()),
+                    client_identities,
+                    session_id: cookie_value(&headers, SESSION_COOKIE),
+                }
+            },
+        )
+}
+
+/// `credentials()` for routes with a body, which signed requests also cover.
+fn credentials_with_body() -> impl Filter<Extract = (Credentials,), Error = warp::Rejection> + Clone
+{
+    credentials()
+        .and(warp::body::bytes())
+        .map(|mut credentials: Credentials, body: Bytes| {
+            credentials.body = body;
+            credentials
+        })
+}
+
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then(|| percent_decode_str(value).decode_utf8_lossy().into_owned())
+        })
+}
+
+fn request_signature(headers: &HeaderMap) -> Option<std::result::Result<RequestSignature, ()>> {
+    let signature = header_value(headers, HEADER_SIGNATURE)?;
+    let parsed = (|| {
+        Some(RequestSignature {
+            key_id: header_value(headers, HEADER_KEY_ID)?,
+            timestamp: header_value(headers, HEADER_TIMESTAMP)?.parse().ok()?,
+            nonce: header_value(headers, HEADER_NONCE)?,
+            signature,
+        })
+    })();
+    Some(parsed.ok_or(()))
+}
+
+/// Parse a JSON request body read by `credentials_with_body()`.
+fn parse_body<T: DeserializeOwned>(credentials: &Credentials) -> serde_json::Result<T> {
+    serde_json::from_slice(&credentials.body)
+}
+
+fn invalid_body(e: serde_json::Error) -> warp::reply::Response {
+    warp::reply::with_status(
+        format!("Request body deserialize error: {}", e),
+        StatusCode::BAD_REQUEST,
+    )
+    .into_response()
+}
+
+/// Whether authenticating a request uses it up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Attempt {
+    /// Consume the signature nonce and record the token's use
+    Request,
+    /// Leave no trace, so the same credentials still work afterwards
+    DryRun,
+}
+
+fn authenticate(
+    credentials: &Credentials,
+    state: &ServerState,
+) -> std::result::Result<Principal, AuthRejection> {
+    authenticate_attempt(credentials, state, Attempt::Request)
+}
+
+fn authenticate_attempt(
+    credentials: &Credentials,
+    state: &ServerState,
+    attempt: Attempt,
+) -> std::result::Result<Principal, AuthRejection> {
+    let settings = state.settings.load();
+    let now = unix_now();
+    let token = if let Some(signature) = &credentials.signature {
+        let Ok(signature) = signature else {
+            warn!("Authentication failed - incomplete signature headers");
+            return Err(AuthRejection::Malformed);
+        };
+        verify_signed_request(credentials, signature, &settings, state, now, attempt)?
+    } else if let Some(header) = &credentials.authorization {
+        match settings.auth_manager.find_bearer_token(header) {
+            Ok(Some(token)) => token,
+            Ok(None) => match header.strip_prefix("Bearer ").filter(|t| is_jwt(t)) {
+                Some(jwt) if !settings.config.auth.oidc.is_empty() => {
+                    return authenticate_id_token(jwt, &settings, state);
+                }
+                _ => {
+                    warn!("Authentication failed - invalid token");
+                    return Err(AuthRejection::InvalidToken);
+                }
+            },
+            Err(e) => {
+                warn!("Authentication error: {}", e);
+                return Err(AuthRejection::Malformed);
+            }
+        }
+    } else if let Some(client_cert) = settings
+        .auth_manager
+        .find_client_cert(&credentials.client_identities)
+    {
+        info!(
+            "Authentication successful for user: {} (client certificate {})",
+            client_cert.username, client_cert.subject
+        );
+        return Ok(Principal {
+            username: client_cert.username.clone(),
+            token: Some(client_cert.to_token_config()),
+            issuer: None,
+        });
+    } else {
+        if !credentials.client_identities.is_empty() {
+            warn!(
+                "Client certificate ({}) is not mapped to a user",
+                credentials.client_identities.join(", ")
+            );
+        }
+        // Credentials that were sent are checked even when none are required
+        if !settings.config.auth.require_auth {
+            return Ok(Principal {
+                username: "anonymous".to_string(),
+                token: None,
+                issuer: None,
+            });
+        }
+        warn!("Authentication required but not provided");
+        return Err(AuthRejection::Missing);
+    };
+
+    match token.validity_at(now) {
+        TokenValidity::Valid => {}
+        TokenValidity::Expired => {
+            warn!(
+                "Authentication failed - token for {} expired",
+                token.username
+            );
+            return Err(AuthRejection::Expired);
+        }
+        TokenValidity::NotYetValid => {
+            warn!(
+                "Authentication failed - token for {} is not valid yet",
+                token.username
+            );
+            return Err(AuthRejection::InvalidToken);
+        }
+    }
+
+    if attempt == Attempt::Request {
+        record_token_usage(state, &token.token, credentials.client_ip, now);
+    }
+    info!("Authentication successful for user: {}", token.username);
+    Ok(Principal {
+        username: token.username.clone(),
+        token: Some(token.clone()),
+        issuer: None,
+    })
+}
+
+/// Authenticate an OIDC ID token issued to a CI job.
+fn authenticate_id_token(
+    jwt: &str,
+    settings: &Settings,
+    state: &ServerState,
+) -> std::result::Result<Principal, AuthRejection> {
+    match state.jwks.verify(&settings.config.auth.oidc, jwt) {
+        Ok(identity) => {
+            info!(
+                "Authentication successful for user: {} (ID token from {})",
+                identity.token.username, identity.issuer
+            );
+            Ok(Principal {
+                username: identity.token.username.clone(),
+                token: Some(identity.token),
+                issuer: Some(identity.issuer),
+            })
+        }
+        Err(OidcError::Expired) => {
+            warn!("Authentication failed - ID token expired");
+            Err(AuthRejection::Expired)
+        }
+        Err(OidcError::Invalid(reason)) => {
+            warn!("Authentication failed - ID token rejected: {}", reason);
+            Err(AuthRejection::InvalidToken)
+        }
+    }
+}
+
+/// Check an HMAC-signed request and remember its nonce, unless it is a dry
+/// run. The nonce is only recorded once the signature is known to be good,
+/// so forged requests cannot burn nonces of legitimate ones.
+fn verify_signed_request<'a>(
+    credentials: &Credentials,
+    signature: &RequestSignature,
+    settings: &'a Settings,
+    state: &ServerState,
+    now: i64,
+    attempt: Attempt,
+) -> std::result::Result<&'a TokenConfig, AuthRejection> {
+    let Some(token) = settings.auth_manager.find_signing_key(&signature.key_id) else {
+        warn!("Authentication failed - unknown signing key");
+        return Err(AuthRejection::InvalidToken);
+    };
+    let secret = token.signing_secret.as_deref().unwrap_or_default();
+    let max_skew_secs = settings.config.auth.signature_max_skew_secs;
+
+    if !signature.is_fresh(now, max_skew_secs) {
+        warn!(
+            "Authentication failed - signed request from {} is {}s off the server clock",
+            token.username,
+            now - signature.timestamp
+        );
+        return Err(AuthRejection::StaleSignature);
+    }
+    if !signature.verify(
+        secret,
+        credentials.method.as_str(),
+        &credentials.target,
+        &credentials.body,
+    ) {
+        warn!(
+            "Authentication failed - bad request signature for {}",
+            token.username
+        );
+        return Err(AuthRejection::InvalidSignature);
+    }
+    let replayed = match attempt {
+        Attempt::Request => !state.nonces.insert(signature, now, max_skew_secs),
+        Attempt::DryRun => state.nonces.contains(signature),
+    };
+    if replayed {
+        warn!(
+            "Authentication failed - replayed request for {}",
+            token.username
+        );
+        return Err(AuthRejection::Replayed);
+    }
+
+    Ok(token)
+}
+
+/// Track when and from where a token was used, persisting it when due.
+fn record_token_usage(state: &ServerState, token: &str, client_ip: Option<IpAddr>, now: i64) {
+    let fingerprint = token_fingerprint(token);
+    let client_ip = client_ip.map(|ip| ip.to_string());
+    let Some(usage) = state.token_usage.record(&fingerprint, client_ip, now) else {
+        return;
+    };
+
+    if let Some(store) = state.token_store.clone() {
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = store.record_usage(&fingerprint, &usage) {
+                error!("Failed to record token usage: {}", e);
+            }
+        });
+    }
+}
+
+fn forbidden_by_scope() -> warp::reply::Response {
+    warp::reply::with_status(
+        "Token scope does not allow this request",
+        StatusCode::FORBIDDEN,
+    )
+    .into_response()
+}
+
+fn forbidden_by_tenant() -> warp::reply::Response {
+    warp::reply::with_status(
+        "Repository belongs to another tenant",
+        StatusCode::FORBIDDEN,
+    )
+    .into_response()
+}
+
+fn rate_limited_response(limited: RateLimited) -> warp::reply::Response {
+    let retry_after = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    warp::reply::with_header(
+        warp::reply::with_status("Rate limit exceeded", StatusCode::TOO_MANY_REQUESTS),
+        "retry-after",
+        retry_after.to_string(),
+    )
+    .into_response()
+}
+
+async fn handle_webhook(
+    credentials: Credentials,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let mut audit = AuditRecord::new(unix_now(), credentials.client_ip);
+    let response = process_webhook(&credentials, &state, &mut audit).await;
+    audit.status = response.status().as_u16();
+    if let Some(audit_log) = &state.audit_log {
+        audit_log.record(audit);
+    }
+    Ok(response)
+}
+
+/// Run the checks of `/webhook` on a commit without recording or
+/// publishing it, and report where it would be published. Dry runs count
+/// against the rate limits, but are not counted in the metrics or audited,
+/// and neither use up a signed request's nonce nor mark the token as used.
+async fn handle_webhook_dry_run(
+    credentials: Credentials,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let mut audit = AuditRecord::new(unix_now(), credentials.client_ip);
+    let authorized = match authorize_commit(&credentials, &state, &mut audit, Attempt::DryRun) {
+        Ok(authorized) => authorized,
+        Err(refusal) => return Ok(refusal.into_response()),
+    };
+    if let Err(limited) = rate_limit_commit(&state, &authorized) {
+        return Ok(rate_limited_response(limited));
+    }
+    Ok(warp::reply::json(&json!({
+        "user": authorized.principal.username,
+        "tenant": authorized.tenant,
+        "repository": authorized.commit_info.repository_url,
+        "topic": authorized.topic,
+    }))
+    .into_response())
+}
+
+/// Count a rejected webhook and note the reason in its audit record.
+fn reject_webhook(state: &ServerState, audit: &mut AuditRecord, reason: &str) {
+    state.metrics.webhook_rejected(reason);
+    audit.reject(reason);
+}
+
+/// A commit that passed authentication and the tenant and token scope
+/// checks.
+struct AuthorizedCommit {
+    commit_info: CommitInfo,
+    principal: Principal,
+    tenant: Option<String>,
+    topic: String,
+}
+
+/// Why a webhook commit was refused before rate limiting.
+enum CommitRefusal {
+    InvalidBody(serde_json::Error),
+    Auth(AuthRejection),
+    Tenant(TenantViolation),
+    Scope(ScopeViolation),
+}
+
+impl CommitRefusal {
+    /// Label used for the rejection metrics.
+    fn reason(&self) -> String {
+        match self {
+            CommitRefusal::InvalidBody(_) => "invalid_body".to_string(),
+            CommitRefusal::Auth(rejection) => rejection.reason().to_string(),
+            CommitRefusal::Tenant(violation) => format!("forbidden_{}", violation.as_str()),
+            CommitRefusal::Scope(violation) => format!("forbidden_{}", violation.as_str()),
+        }
+    }
+
+    fn into_response(self) -> warp::reply::Response {
+        match self {
+            CommitRefusal::InvalidBody(e) => invalid_body(e),
+            CommitRefusal::Auth(rejection) => rejection.into_response(),
+            CommitRefusal::Tenant(_) => forbidden_by_tenant(),
+            CommitRefusal::Scope(_) => forbidden_by_scope(),
+        }
+    }
+}
+
+/// Take one commit from the rate limits of its token, user and repository.
+fn rate_limit_commit(
+    state: &ServerState,
+    authorized: &AuthorizedCommit,
+) -> std::result::Result<(), RateLimited> {
+    let principal = &authorized.principal;
+    let token = principal
+        .token
+        .as_ref()
+        .map(|tc| (tc.token.as_str(), tc.rate_limit_per_minute));
+    state
+        .rate_limiter
+        .check(
+            token,
+            &principal.username,
+            &authorized.commit_info.repository_url,
+            1,
+        )
+        .inspect_err(|limited| {
+            warn!(
+                "Rate limit ({}) exceeded for user {}, retry after {:?}",
+                limited.scope.as_str(),
+                principal.username,
+                limited.retry_after
+            );
+        })
+}
+
+/// Parse and authorize the commit of a webhook request, noting what is
+/// known in `audit`.
+fn authorize_commit(
+    credentials: &Credentials,
+    state: &ServerState,
+    audit: &mut AuditRecord,
+    attempt: Attempt,
+) -> std::result::Result<AuthorizedCommit, CommitRefusal> {
+    let commit_info: CommitInfo = parse_body(credentials).map_err(CommitRefusal::InvalidBody)?;
+    audit.repository = Some(commit_info.repository_url.clone());
+    audit.commits = vec![commit_info.hash.clone()];
+
+    // Validate authentication and get username
+    let principal =
+        authenticate_attempt(credentials, state, attempt).map_err(CommitRefusal::Auth)?;
+    audit.user = Some(principal.username.clone());
+
+    // Users of a tenant publish into the tenant's namespace, and only for
+    // repositories no other tenant owns
+    let settings = state.settings.load();
+    let tenant = tenant_for_user(&settings.config.tenants, &principal.username);
+    if let Err(violation) = tenant::check_repository(
+        &settings.config.tenants,
+        tenant,
+        &commit_info.repository_url,
+    ) {
+        warn!(
+            "User {} may not publish commits of {} ({})",
+            principal.username,
+            commit_info.repository_url,
+            violation.as_str()
+        );
+        return Err(CommitRefusal::Tenant(violation));
+    }
+    let tenant = tenant.map(|t| t.name.clone());
+    let topic = commit_topic(
+        tenant::topic_prefix(&settings.config, tenant.as_deref()),
+        &commit_info,
+        Some(&principal.username),
+    );
+    drop(settings);
+    if let Some(token) = &principal.token {
+        if let Err(violation) =
+            token
+                .scopes
+                .check_publish(EVENT_TYPE_COMMIT, &commit_info.repository_url, &topic)
+        {
+            warn!(
+                "Token for user {} may not publish to {} ({} not in scope)",
+                principal.username,
+                topic,
+                violation.as_str()
+            );
+            return Err(CommitRefusal::Scope(violation));
+        }
+    }
+
+    Ok(AuthorizedCommit {
+        commit_info,
+        principal,
+        tenant,
+        topic,
+    })
+}
+
+async fn process_webhook(
+    credentials: &Credentials,
+    state: &ServerState,
+    audit: &mut AuditRecord,
+) -> warp::reply::Response {
+    state.metrics.webhook_received();
+
+    // Held until the commit is published, so shutdown can wait for it
+    let Some(_in_flight) = state.health.begin_request() else {
+        reject_webhook(state, audit, "shutting_down");
+        return warp::reply::with_status(
+            "Server is shutting down",
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+        .into_response();
+    };
+
+    let authorized = match authorize_commit(credentials, state, audit, Attempt::Request) {
+        Ok(authorized) => authorized,
+        Err(refusal) => {
+            reject_webhook(state, audit, &refusal.reason());
+            return refusal.into_response();
+        }
+    };
+    if let Err(limited) = rate_limit_commit(state, &authorized) {
+        reject_webhook(
+            state,
+            audit,
+            &format!("rate_limited_{}", limited.scope.as_str()),
+        );
+        return rate_limited_response(limited);
+    }
+    let AuthorizedCommit {
+        mut commit_info,
+        principal,
+        tenant,
+        topic,
+    } = authorized;
+
+    state.metrics.webhook_accepted(&principal.username);
+    let username = Some(principal.username);
+
+    info!(
+        "Processing commit: {} by {} - {}",
+        commit_info.short_hash,
+        commit_info.author_name,
+        commit_info.message.lines().next().unwrap_or("")
+    );
+
+    // Fill in details the producer left out from the repository's mirror
+    if let Some(mirrors) = &state.mirrors {
+        let mirrors = mirrors.clone();
+        commit_info = mirror::enrich_within(commit_info, MIRROR_ENRICH_TIMEOUT, move |commit| {
+            mirrors.enrich(commit)
+        })
+        .await;
+    }
+
+    // Record the accepted commit before publishing; a retried webhook reuses its record
+    let mut event_id = None;
+    if let Some(history) = &state.history {
+        let history = history.clone();
+        let commit = commit_info.clone();
+        let (user, tenant) = (username.clone(), tenant.clone());
+        match tokio::task::spawn_blocking(move || {
+            history.record(&commit, user.as_deref(), tenant.as_deref())
+        })
+        .await
+        {
+            Ok(Ok(stored)) => {
+                info!("Recorded commit in history as event {}", stored.id);
+                event_id = Some(stored.id);
+            }
+            Ok(Err(e)) => error!("Failed to record commit in history: {}", e),
+            Err(e) => error!("History task failed: {}", e),
+        }
+    } else if let Some(recent_commits) = &state.recent_commits {
+        if let Err(e) = recent_commits.record(&commit_info, username.as_deref(), tenant.as_deref())
+        {
+            error!("Failed to keep commit in memory: {}", e);
+        }
+    }
+
+    let settings = state.settings.load();
+    let envelope = EventEnvelope::commit(commit_info, &settings.config.mqtt.client_id);
+    let publication = Publication {
+        id: event_id,
+        topic,
+        username,
+        tenant,
+        envelope,
+    };
+    let published = state.publishers.publish(&publication).await;
+    audit.topics = vec![publication.topic.clone()];
+
+    match published {
+        Ok(()) => {
+            info!("Published commit");
+            audit.outcome = AuditOutcome::Published;
+            // Only published commits are forwarded, so a retried webhook is
+            // not delivered twice; deliveries retry in the background
+            state.outbound.dispatch(
+                &settings.config.outbound,
+                &publication.envelope,
+                publication.username.as_deref(),
+                &publication.topic,
+            );
+            warp::reply::with_status("Commit processed successfully", StatusCode::OK)
+                .into_response()
+        }
+        Err(e) => {
+            error!("Failed to publish commit: {}", e);
+            audit.outcome = AuditOutcome::PublishFailed;
+            warp::reply::with_status(
+                "Failed to process commit",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()
+        }
+    }
+}
+
+async fn handle_list_commits(
+    credentials: Credentials,
+    mut query: CommitQuery,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    match authenticate(&credentials, &state) {
+        Ok(principal) if !principal.can_read() => return Ok(forbidden_by_scope()),
+        Ok(principal) => {
+            query.scope = TenantScope::for_user(&state.settings.load().config, &principal.username)
+        }
+        Err(rejection) => return Ok(rejection.into_response()),
+    }
+
+    let Some(history) = state.history.clone() else {
+        return Ok(warp::reply::with_status(
+            "Commit history is not enabled",
+            StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    };
+
+    match query_history(history, query).await {
+        Ok(page) => Ok(warp::reply::json(&page).into_response()),
+        Err(response) => Ok(response),
+    }
+}
+
+/// Run a history query on the blocking pool. Failures become a `500`.
+async fn query_history(
+    history: Arc<HistoryStore>,
+    query: CommitQuery,
+) -> std::result::Result<CommitPage, warp::reply::Response> {
+    let error = match tokio::task::spawn_blocking(move || history.query(&query)).await {
+        Ok(Ok(page)) => return Ok(page),
+        Ok(Err(e)) => format!("Failed to query commit history: {}", e),
+        Err(e) => format!("History task failed: {}", e),
+    };
+    error!("{}", error);
+    Err(warp::reply::with_status(
+        "Failed to query commit history",
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+    .into_response())
+}
+
+/// Store feeds and the dashboard read: the history database, or the recent
+/// commits kept in memory without one.
+fn commit_store(state: &ServerState) -> Option<Arc<HistoryStore>> {
+    state
+        .history
+        .clone()
+        .or_else(|| state.recent_commits.clone())
+}
+
+async fn handle_dashboard(
+    view: View,
+    credentials: Credentials,
+    query: DashboardQuery,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let username = match authenticate(&with_session_token(credentials, &state), &state) {
+        Ok(principal) if !principal.can_read() => return Ok(forbidden_by_scope()),
+        Ok(principal) => principal.username,
+        Err(_) => return Ok(redirect("/dashboard/login", None)),
+    };
+
+    let mut commit_query = query.commit_query(&view);
+    commit_query.scope = TenantScope::for_user(&state.settings.load().config, &username);
+
+    let commits = match commit_store(&state) {
+        Some(store) => match query_history(store, commit_query).await {
+            Ok(page) => Some(page),
+            Err(response) => return Ok(response),
+        },
+        None => None,
+    };
+
+    let settings = state.settings.load();
+    let page = dashboard::Page {
+        view: &view,
+        query: &query,
+        commits: commits.as_ref(),
+        username: &username,
+        repositories: &settings.repositories,
+    };
+    Ok(warp::reply::html(dashboard::render(&page)).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct FeedQuery {
+    /// Token for feed readers, which cannot set headers
+    token: Option<String>,
+}
+
+/// Split a feed path segment into its name and format, and build the feed
+/// from the percent-decoded name.
+fn parse_feed(
+    segment: &str,
+    kind: impl FnOnce(String) -> FeedKind,
+) -> Option<(FeedKind, FeedFormat)> {
+    let (name, format) = FeedFormat::split(segment)?;
+    Some((kind(decode_path_segment(name)), format))
+}
+
+async fn handle_feed(
+    feed: Option<(FeedKind, FeedFormat)>,
+    credentials: Credentials,
+    query: FeedQuery,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let Some((kind, format)) = feed else {
+        return Err(warp::reject::not_found());
+    };
+    let username = match authenticate_reader(credentials, query.token.as_deref(), &state) {
+        Ok(username) => username,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+
+    let Some(store) = commit_store(&state) else {
+        return Ok(warp::reply::with_status(
+            "Commit history is not enabled",
+            StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    };
+
+    let settings = state.settings.load();
+    // Never the Host header, which the client chooses
+    let base_url = settings.config.feeds.base_url.clone().unwrap_or_else(|| {
+        let scheme = if settings.config.server.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        format!("{}://{}", scheme, settings.config.server.bind_address)
+    });
+    let mut commit_query = kind.commit_query();
+    commit_query.scope = TenantScope::for_user(&settings.config, &username);
+    drop(settings);
+
+    let page = match query_history(store, commit_query).await {
+        Ok(page) => page,
+        Err(response) => return Ok(response),
+    };
+    let body = feed::render(&Feed {
+        kind: &kind,
+        format,
+        base_url: &base_url,
+        commits: &page.commits,
+        repositories: &state.settings.load().repositories,
+    });
+    Ok(warp::reply::with_header(body, "content-type", format.content_type()).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    token: String,
+}
+
+/// Check a token and start a session for it. The cookie only holds the
+/// session id.
+async fn handle_dashboard_login(
+    credentials: Credentials,
+    form: LoginForm,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let token = form.token.trim().to_string();
+    let credentials = Credentials {
+        authorization: Some(format!("Bearer {}", token)),
+        signature: None,
+        ..credentials
+    };
+
+    let (error, status) = match authenticate(&credentials, &state) {
+        Ok(principal) if principal.can_read() => {
+            info!("Dashboard session started for {}", principal.username);
+            let id = state.sessions.create(&token, unix_now());
+            let cookie = session_cookie(&state, &id, Some(SESSION_TTL_SECS));
+            return Ok(redirect("/dashboard", Some(cookie)));
+        }
+        Ok(_) => (
+            "Token scope does not allow reading commits",
+            StatusCode::FORBIDDEN,
+        ),
+        Err(_) => ("Invalid or expired token", StatusCode::UNAUTHORIZED),
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::html(dashboard::render_login(Some(error))),
+        status,
+    )
+    .into_response())
+}
+
+fn handle_dashboard_logout(credentials: Credentials, state: ServerState) -> warp::reply::Response {
+    if let Some(id) = &credentials.session_id {
+        state.sessions.remove(id);
+    }
+    redirect(
+        "/dashboard/login",
+        Some(session_cookie(&state, "", Some(0))),
+    )
+}
+
+/// `Set-Cookie` value for the dashboard session.
+fn session_cookie(state: &ServerState, value: &str, max_age: Option<i64>) -> String {
+    let mut cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict",
+        SESSION_COOKIE, value
+    );
+    if let Some(max_age) = max_age {
+        cookie.push_str(&format!("; Max-Age={}", max_age));
+    }
+    if state.settings.load().config.server.tls.is_some() {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+fn redirect(location: &str, cookie: Option<String>) -> warp::reply::Response {
+    let mut response =
+        warp::reply::with_header(StatusCode::SEE_OTHER, "location", location).into_response();
+    if let Some(cookie) = cookie.and_then(|c| c.parse().ok()) {
+        response.headers_mut().insert("set-cookie", cookie);
+    }
+    response
+}
+
+fn decode_path_segment(segment: &str) -> String {
+    percent_decode_str(segment).decode_utf8_lossy().into_owned()
+}
+
+/// Authenticate a request made with the token of a user in `auth.admin_users`
+/// or in the `admin_users` of a tenant.
+fn authenticate_admin(
+    credentials: &Credentials,
+    state: &ServerState,
+) -> std::result::Result<Admin, AdminRejection> {
+    let principal = authenticate(credentials, state).map_err(AdminRejection::Auth)?;
+    let config = &state.settings.load().config;
+    let Some(tenant) = admin_tenant(&principal, config) else {
+        warn!(
+            "User {} is not allowed to manage tokens",
+            principal.username
+        );
+        return Err(AdminRejection::NotAdmin);
+    };
+
+    Ok(Admin {
+        username: principal.username,
+        tenant,
+        tenants: config.tenants.clone(),
+        global_admins: config.auth.admin_users.clone(),
+    })
+}
+
+/// `Some(None)` for global admins, `Some(tenant)` for a tenant's admins.
+/// Anonymous users and OIDC identities are never admins: an ID token's
+/// username comes from claims, which a CI job can partly choose.
+fn admin_tenant(principal: &Principal, config: &Config) -> Option<Option<TenantConfig>> {
+    if principal.token.is_none() {
+        None
+    } else if let Some(issuer) = &principal.issuer {
+        warn!(
+            "ID token from {} for {} cannot be used for administration",
+            issuer, principal.username
+        );
+        None
+    } else if config.auth.admin_users.contains(&principal.username) {
+        Some(None)
+    } else {
+        config
+            .tenants
+            .iter()
+            .find(|tenant| tenant.is_admin(&principal.username))
+            .map(|tenant| Some(tenant.clone()))
+    }
+}
+
+/// Check that `admin` manages the user of token `id`. Unknown ids pass, so
+/// the operation itself reports them.
+async fn check_token_owner(
+    state: &ServerState,
+    admin: &Admin,
+    id: &str,
+) -> std::result::Result<(), warp::reply::Response> {
+    if admin.tenant.is_none() {
+        return Ok(());
+    }
+    let tokens = token_summaries(state).await?;
+    match tokens.iter().find(|token| token.id == id) {
+        Some(token) => admin
+            .check_manages(&token.username)
+            .map_err(AdminRejection::into_response),
+        None => Ok(()),
+    }
+}
+
+/// Run `op` against the token store off the async runtime, then rebuild the
+/// auth manager so the change takes effect immediately.
+async fn modify_token_store<T, F>(
+    state: &ServerState,
+    op: F,
+) -> std::result::Result<T, warp::reply::Response>
+where
+    T: Send + 'static,
+    F: FnOnce(&TokenStore) -> Result<T> + Send + 'static,
+{
+    let Some(store) = state.token_store.clone() else {
+        return Err(warp::reply::with_status(
+            "Token store is not configured",
+            StatusCode::NOT_FOUND,
+        )
+        .into_response());
+    };
+
+    let settings = state.settings.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let value = op(&store)?;
+        settings.update(|current| {
+            Ok::<_, GitFriendsError>(Settings::new(
+                current.config.clone(),
+                store.token_configs()?,
+            ))
+        })?;
+        Ok::<_, GitFriendsError>(value)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => {
+            error!("Token store operation failed: {}", e);
+            Err(warp::reply::with_status(
+                "Failed to update tokens",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response())
+        }
+        Err(e) => {
+            error!("Token store task failed: {}", e);
+            Err(warp::reply::with_status(
+                "Failed to update tokens",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response())
+        }
+    }
+}
+
+fn config_token_conflict() -> warp::reply::Response {
+    warp::reply::with_status(
+        "Token is defined in the configuration file",
+        StatusCode::CONFLICT,
+    )
+    .into_response()
+}
+
+/// Summaries of every configured and stored token, with their usage.
+async fn token_summaries(
+    state: &ServerState,
+) -> std::result::Result<Vec<TokenSummary>, warp::reply::Response> {
+    let stored = match state.token_store.clone() {
+        Some(store) => match tokio::task::spawn_blocking(move || store.list()).await {
+            Ok(Ok(stored)) => stored,
+            Ok(Err(e)) => {
+                error!("Failed to list stored tokens: {}", e);
+                return Err(warp::reply::with_status(
+                    "Failed to list tokens",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+                .into_response());
+            }
+            Err(e) => {
+                error!("Token store task failed: {}", e);
+                return Err(warp::reply::with_status(
+                    "Failed to list tokens",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                )
+                .into_response());
+            }
+        },
+        None => Vec::new(),
+    };
+
+    let settings = state.settings.load();
+    Ok(summarize_tokens(
+        &settings.config.auth.tokens,
+        &stored,
+        &state.token_usage.snapshot(),
+        &settings.config.tenants,
+        unix_now(),
+    ))
+}
+
+async fn handle_list_tokens(
+    credentials: Credentials,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let admin = match authenticate_admin(&credentials, &state) {
+        Ok(admin) => admin,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+
+    match token_summaries(&state).await {
+        Ok(tokens) => {
+            let tokens: Vec<_> = tokens
+                .into_iter()
+                .filter(|token| admin.manages(&token.username))
+                .collect();
+            Ok(warp::reply::json(&tokens).into_response())
+        }
+        Err(response) => Ok(response),
+    }
+}
+
+async fn handle_stale_tokens(
+    credentials: Credentials,
+    query: StaleQuery,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let admin = match authenticate_admin(&credentials, &state) {
+        Ok(admin) => admin,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+
+    let max_idle_secs = query.days.unwrap_or(DEFAULT_STALE_DAYS) * 24 * 60 * 60;
+    let now = unix_now();
+    match token_summaries(&state).await {
+        Ok(tokens) => {
+            let stale: Vec<_> = tokens
+                .into_iter()
+                .filter(|token| {
+                    admin.manages(&token.username) && token.is_stale(now, max_idle_secs)
+                })
+                .collect();
+            Ok(warp::reply::json(&stale).into_response())
+        }
+        Err(response) => Ok(response),
+    }
+}
+
+async fn handle_create_token(
+    credentials: Credentials,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let request: TokenRequest = match parse_body(&credentials) {
+        Ok(request) => request,
+        Err(e) => return Ok(invalid_body(e)),
+    };
+    let admin = match authenticate_admin(&credentials, &state) {
+        Ok(admin) => admin,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+
+    let username = request.username.trim().to_string();
+    if username.is_empty() {
+        return Ok(
+            warp::reply::with_status("Username must not be empty", StatusCode::BAD_REQUEST)
+                .into_response(),
+        );
+    }
+    if let Err(rejection) = admin.check_manages(&username) {
+        return Ok(rejection.into_response());
+    }
+    let mut problems = Vec::new();
+    request.scopes.validate("scopes", &mut problems);
+    if !problems.is_empty() {
+        return Ok(
+            warp::reply::with_status(problems.join("; "), StatusCode::BAD_REQUEST).into_response(),
+        );
+    }
+
+    let (expires_at, scopes) = (request.expires_at, request.scopes);
+    match modify_token_store(&state, move |store| {
+        store.create(&username, expires_at, &scopes)
+    })
+    .await
+    {
+        Ok((stored, secret)) => {
+            info!(
+                "{} created token {} for user {}",
+                admin.username, stored.id, stored.username
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&json!({
+                    "id": stored.id,
+                    "username": stored.username,
+                    "token": secret,
+                    "scopes": stored.scopes,
+                })),
+                StatusCode::CREATED,
+            )
+            .into_response())
+        }
+        Err(response) => Ok(response),
+    }
+}
+
+async fn handle_rotate_token(
+    id: String,
+    credentials: Credentials,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let request: RotateRequest = match parse_body(&credentials) {
+        Ok(request) => request,
+        Err(e) => return Ok(invalid_body(e)),
+    };
+    let admin = match authenticate_admin(&credentials, &state) {
+        Ok(admin) => admin,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    if is_config_token_id(&id) {
+        return Ok(config_token_conflict());
+    }
+    if let Err(response) = check_token_owner(&state, &admin, &id).await {
+        return Ok(response);
+    }
+
+    let overlap_secs = request
+        .overlap_secs
+        .unwrap_or(DEFAULT_ROTATION_OVERLAP_SECS)
+        .max(0);
+    let old_expires_at = unix_now() + overlap_secs;
+    let token_id = id.clone();
+    match modify_token_store(&state, move |store| store.rotate(&token_id, old_expires_at)).await {
+        Ok(Some((stored, secret))) => {
+            info!(
+                "{} rotated token {} for user {} (new token {})",
+                admin.username, id, stored.username, stored.id
+            );
+            Ok(warp::reply::with_status(
+                warp::reply::json(&json!({
+                    "id": stored.id,
+                    "username": stored.username,
+                    "token": secret,
+                    "expires_at": stored.expires_at,
+                    "previous_id": id,
+                    "previous_expires_at": old_expires_at,
+                })),
+                StatusCode::CREATED,
+            )
+            .into_response())
+        }
+        Ok(None) => {
+            Ok(warp::reply::with_status("Token not found", StatusCode::NOT_FOUND).into_response())
+        }
+        Err(response) => Ok(response),
+    }
+}
+
+async fn handle_revoke_token(
+    id: String,
+    credentials: Credentials,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let admin = match authenticate_admin(&credentials, &state) {
+        Ok(admin) => admin,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    if is_config_token_id(&id) {
+        return Ok(config_token_conflict());
+    }
+    if let Err(response) = check_token_owner(&state, &admin, &id).await {
+        return Ok(response);
+    }
+
+    let token_id = id.clone();
+    match modify_token_store(&state, move |store| store.revoke(&token_id)).await {
+        Ok(true) => {
+            info!("{} revoked token {}", admin.username, id);
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        Ok(false) => {
+            Ok(warp::reply::with_status("Token not found", StatusCode::NOT_FOUND).into_response())
+        }
+        Err(response) => Ok(response),
+    }
+}
+
+async fn handle_rename_token(
+    id: String,
+    credentials: Credentials,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let request: RenameTokenRequest = match parse_body(&credentials) {
+        Ok(request) => request,
+        Err(e) => return Ok(invalid_body(e)),
+    };
+    let admin = match authenticate_admin(&credentials, &state) {
+        Ok(admin) => admin,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    if is_config_token_id(&id) {
+        return Ok(config_token_conflict());
+    }
+    if let Err(response) = check_token_owner(&state, &admin, &id).await {
+        return Ok(response);
+    }
+
+    let name = request.name.trim().to_string();
+    if name.is_empty() {
+        return Ok(
+            warp::reply::with_status("Name must not be empty", StatusCode::BAD_REQUEST)
+                .into_response(),
+        );
+    }
+
+    let (token_id, new_name) = (id.clone(), name.clone());
+    match modify_token_store(&state, move |store| store.rename(&token_id, &new_name)).await {
+        Ok(true) => {
+            info!("{} renamed token {} to {}", admin.username, id, name);
+            Ok(warp::reply::json(&json!({ "id": id, "name": name })).into_response())
+        }
+        Ok(false) => {
+            Ok(warp::reply::with_status("Token not found", StatusCode::NOT_FOUND).into_response())
+        }
+        Err(response) => Ok(response),
+    }
+}
+
+fn handle_metrics(state: ServerState) -> warp::reply::Response {
+    for publisher in state.publishers.health() {
+        if publisher.transport == PublisherTransport::Mqtt {
+            state.metrics.set_mqtt_connected(publisher.connected);
+        }
+        state
+            .metrics
+            .set_publisher_connected(&publisher.name, publisher.connected);
+    }
+
+    match state.metrics.render() {
+        Ok(body) => warp::reply::with_header(
+            body,
+            "content-type",
+            "text/plain; version=0.0.4; charset=utf-8",
+        )
+        .into_response(),
+        Err(e) => {
+            error!("Failed to render metrics: {}", e);
+            warp::reply::with_status(
+                "Failed to render metrics",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response()
+        }
+    }
+}
+
+/// Subscribe to the live feed, replaying stored events after `last_event_id`
+/// when a history store is available. Only events `scope` allows are sent,
+/// with their repository named as the dashboard names it.
+async fn open_event_stream(
+    state: &ServerState,
+    query: &StreamQuery,
+    last_event_id: Option<i64>,
+    scope: TenantScope,
+) -> impl Stream<Item = StreamEvent> {
+    let subscription = state
+        .event_bus
+        .subscribe(parse_topic_filters(query.topics.as_deref()));
+
+    let mut backlog = Vec::new();
+    if let (Some(history), Some(after)) = (state.history.clone(), last_event_id) {
+        match tokio::task::spawn_blocking(move || history.events_after(after, STREAM_RESUME_LIMIT))
+            .await
+        {
+            Ok(Ok(stored)) => {
+                info!("Resuming stream with {} stored events", stored.len());
+                let settings = state.settings.load();
+                backlog = stored
+                    .into_iter()
+                    .filter(|s| scope.allows(s.tenant.as_deref()))
+                    .map(|s| StreamEvent {
+                        id: Some(s.id),
+                        topic: commit_topic(
+                            tenant::topic_prefix(&settings.config, s.tenant.as_deref()),
+                            &s.commit,
+                            s.username.as_deref(),
+                        ),
+                        username: s.username,
+                        commit: s.commit,
+                        repository: None,
+                    })
+                    .collect();
+            }
+            Ok(Err(e)) => error!("Failed to load stream backlog: {}", e),
+            Err(e) => error!("History task failed: {}", e),
+        }
+    }
+
+    let tenants = state.settings.load().config.tenants.clone();
+    let settings = state.settings.clone();
+    subscription
+        .into_stream(backlog)
+        .filter(move |event| {
+            let tenant = tenant_for_topic(&tenants, &event.topic).map(|t| t.name.as_str());
+            futures_util::future::ready(scope.allows(tenant))
+        })
+        .map(move |event| event.labelled(&settings.load().repositories))
+}
+
+/// Authenticate a feed or stream reader, who may send the token in the
+/// query string or the dashboard session cookie instead of a header. Query
+/// strings end up in logs and browser history, so only read-only tokens are
+/// accepted there.
+fn authenticate_reader(
+    credentials: Credentials,
+    query_token: Option<&str>,
+    state: &ServerState,
+) -> std::result::Result<String, ReaderRejection> {
+    let from_query = credentials.authorization.is_none() && query_token.is_some();
+    let credentials = with_session_token(with_query_token(credentials, query_token), state);
+    let principal = authenticate(&credentials, state).map_err(ReaderRejection::Auth)?;
+    if !principal.can_read() {
+        return Err(ReaderRejection::Scope);
+    }
+    let read_only = principal
+        .token
+        .as_ref()
+        .is_some_and(|token| token.scopes.access == TokenAccess::Read);
+    if from_query && !read_only {
+        warn!(
+            "Refused a token in the query string for {}: it is not read-only",
+            principal.username
+        );
+        return Err(ReaderRejection::QueryToken);
+    }
+    Ok(principal.username)
+}
+
+/// Accept the token from the query string for clients that cannot set headers.
+fn with_query_token(credentials: Credentials, token: Option<&str>) -> Credentials {
+    Credentials {
+        authorization: credentials
+            .authorization
+            .or_else(|| token.map(|t| format!("Bearer {}", t))),
+        ..credentials
+    }
+}
+
+/// Fall back to the token of the dashboard session. Only routes a browser
+/// reads accept it, so a forged cross-site form cannot act on the API.
+fn with_session_token(credentials: Credentials, state: &ServerState) -> Credentials {
+    let authorization = credentials.authorization.clone().or_else(|| {
+        let id = credentials.session_id.as_deref()?;
+        let token = state.sessions.token(id, unix_now())?;
+        Some(format!("Bearer {}", token))
+    });
+    Credentials {
+        authorization,
+        ..credentials
+    }
+}
+
+async fn handle_sse_stream(
+    credentials: Credentials,
+    last_event_id: Option<i64>,
+    query: StreamQuery,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let username = match authenticate_reader(credentials, query.token.as_deref(), &state) {
+        Ok(username) => username,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+    info!("SSE stream opened by {}", username);
+
+    let scope = TenantScope::for_user(&state.settings.load().config, &username);
+    let last_event_id = last_event_id.or(query.last_event_id);
+    let events = open_event_stream(&state, &query, last_event_id, scope)
+        .await
+        .map(|event| {
+            let mut sse_event = warp::sse::Event::default().event("commit");
+            if let Some(id) = event.id {
+                sse_event = sse_event.id(id.to_string());
+            }
+            Ok::<_, Infallible>(
+                sse_event
+                    .json_data(&event)
+                    .unwrap_or_else(|_| warp::sse::Event::default().comment("invalid event")),
+            )
+        });
+
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response())
+}
+
+async fn handle_ws_stream(
+    ws: warp::ws::Ws,
+    credentials: Credentials,
+    query: StreamQuery,
+    state: ServerState,
+) -> std::result::Result<warp::reply::Response, warp::Rejection> {
+    let username = match authenticate_reader(credentials, query.token.as_deref(), &state) {
+        Ok(username) => username,
+        Err(rejection) => return Ok(rejection.into_response()),
+    };
+
+    Ok(ws
+        .on_upgrade(move |socket| async move {
+            info!("WebSocket stream opened by {}", username);
+            let last_event_id = query.last_event_id;
+            let scope = TenantScope::for_user(&state.settings.load().config, &username);
+            let events = open_event_stream(&state, &query, last_event_id, scope).await;
+            forward_to_websocket(socket, events).await;
+            info!("WebSocket stream closed for {}", username);
+        })
+        .into_response())
+}
+
+async fn forward_to_websocket(socket: WebSocket, events: impl Stream<Item = StreamEvent>) {
+    let (mut sink, mut incoming) = socket.split();
+    let mut events = Box::pin(events);
+
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else { break };
+                let payload = match serde_json::to_string(&event) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        error!("Failed to serialize stream event: {}", e);
+                        continue;
+                    }
+                };
+                if sink.send(Message::text(payload)).await.is_err() {
+                    break;
+                }
+            }
+            message = incoming.next() => {
+                // Clients only ever close the socket; anything else is ignored
+                match message {
+                    Some(Ok(message)) if !message.is_close() => {}
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    let _ = sink.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state(config: Config) -> ServerState {
+        let metrics = Metrics::new().unwrap();
+        ServerState {
+            publishers: Arc::new(Publishers::new(metrics.clone())),
+            history: None,
+            recent_commits: None,
+            mirrors: None,
+            audit_log: None,
+            outbound: Arc::new(Outbound::new(metrics.clone()).unwrap()),
+            event_bus: EventBus::new(16),
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limit.clone())),
+            metrics,
+            token_store: None,
+            token_usage: Arc::new(UsageTracker::new(Default::default())),
+            nonces: Arc::new(NonceCache::new()),
+            sessions: Arc::new(Sessions::new()),
+            jwks: Arc::new(JwksStore::new()),
+            health: Arc::new(Health::new()),
+            settings: Arc::new(Swappable::new(Settings::new(config, vec![]))),
+        }
+    }
+
+    fn bearer(token: Option<&str>) -> Credentials {
+        Credentials {
+            authorization: token.map(|token| format!("Bearer {}", token)),
+            signature: None,
+            method: Method::POST,
+            target: "/webhook".to_string(),
+            body: Bytes::new(),
+            client_ip: None,
+            client_identities: vec![],
+            session_id: None,
+        }
+    }
+
+    fn token(token: &str, username: &str) -> TokenConfig {
+        TokenConfig {
+            token: token.to_string(),
+            ..AuthManager::generate_token_config(username)
+        }
+    }
+
+    #[test]
+    fn test_open_server_still_checks_tokens() {
+        let mut config = Config::default();
+        config.auth.require_auth = false;
+        config.auth.tokens = vec![token("tok", "bob")];
+        let state = test_state(config);
+
+        let principal = authenticate(&bearer(Some("tok")), &state).ok().unwrap();
+        assert_eq!(principal.username, "bob");
+        assert!(principal.token.is_some());
+        assert!(matches!(
+            authenticate(&bearer(Some("wrong")), &state),
+            Err(AuthRejection::InvalidToken)
+        ));
+        let anonymous = authenticate(&bearer(None), &state).ok().unwrap();
+        assert_eq!(anonymous.username, "anonymous");
+    }
+
+    #[test]
+    fn test_query_string_takes_read_only_tokens() {
+        let mut config = Config::default();
+        let mut reader = token("readtok", "feeds");
+        reader.scopes.access = TokenAccess::Read;
+        config.auth.tokens = vec![reader, token("tok", "bob")];
+        let state = test_state(config);
+
+        let read = authenticate_reader(bearer(None), Some("readtok"), &state);
+        assert_eq!(read.unwrap(), "feeds");
+        assert!(matches!(
+            authenticate_reader(bearer(None), Some("tok"), &state),
+            Err(ReaderRejection::QueryToken)
+        ));
+        // The header is not logged, so any token that may read works there
+        let header = authenticate_reader(bearer(Some("tok")), None, &state);
+        assert_eq!(header.unwrap(), "bob");
+    }
+
+    #[test]
+    fn test_id_tokens_are_never_admins() {
+        let mut config = Config::default();
+        config.auth.admin_users = vec!["bob".to_string()];
+        let mut principal = Principal {
+            username: "bob".to_string(),
+            token: Some(token("tok", "bob")),
+            issuer: None,
+        };
+        assert!(matches!(admin_tenant(&principal, &config), Some(None)));
+
+        // A CI job whose claims map to an admin's name
+        principal.issuer = Some("https://token.actions.githubusercontent.com".to_string());
+        assert!(admin_tenant(&principal, &config).is_none());
+    }
+
+    #[test]
+    fn test_tenant_admins_cannot_manage_global_admins() {
+        let mut config = Config::default();
+        config.auth.admin_users = vec!["bob".to_string()];
+        config.auth.tokens = vec![token("atok", "alice"), token("tok", "bob")];
+        config.tenants = vec![TenantConfig {
+            name: "acme".to_string(),
+            topic_prefix: "acme".to_string(),
+            users: vec!["alice".to_string(), "bob".to_string(), "carol".to_string()],
+            repositories: vec![],
+            admin_users: vec!["alice".to_string()],
+            mqtt_username: None,
+            mqtt_password: None,
+            irc_channels: vec![],
+        }];
+        let state = test_state(config);
+
+        let Ok(tenant_admin) = authenticate_admin(&bearer(Some("atok")), &state) else {
+            panic!("alice is a tenant admin");
+        };
+        assert!(tenant_admin.check_manages("carol").is_ok());
+        assert!(matches!(
+            tenant_admin.check_manages("bob"),
+            Err(AdminRejection::GlobalAdmin)
+        ));
+        assert!(matches!(
+            tenant_admin.check_manages("dave"),
+            Err(AdminRejection::OutsideTenant)
+        ));
+
+        let Ok(global_admin) = authenticate_admin(&bearer(Some("tok")), &state) else {
+            panic!("bob is a global admin");
+        };
+        assert!(global_admin.check_manages("bob").is_ok());
+    }
+
+    #[test]
+    fn test_tenant_admins_only_manage_their_own_users() {
+        let mut config = Config::default();
+        config.auth.tokens = vec![token("atok", "alice")];
+        let tenant = |name: &str, users: &[&str], admin_users: Vec<String>| TenantConfig {
+            name: name.to_string(),
+            topic_prefix: name.to_string(),
+            users: users.iter().map(|u| u.to_string()).collect(),
+            repositories: vec![],
+            admin_users,
+            mqtt_username: None,
+            mqtt_password: None,
+            irc_channels: vec![],
+        };
+        // ci-bob matches both; the first tenant listed owns him
+        config.tenants = vec![
+            tenant("ops", &["ci-*"], vec![]),
+            tenant("acme", &["alice", "ci-*"], vec!["alice".to_string()]),
+        ];
+        let state = test_state(config);
+
+        let Ok(tenant_admin) = authenticate_admin(&bearer(Some("atok")), &state) else {
+            panic!("alice is a tenant admin");
+        };
+        assert!(tenant_admin.check_manages("alice").is_ok());
+        assert!(matches!(
+            tenant_admin.check_manages("ci-bob"),
+            Err(AdminRejection::OutsideTenant)
+        ));
+    }
+
+    #[test]
+    fn test_rename_request_only_takes_a_name() {
+        let request: RenameTokenRequest = serde_json::from_str(r#"{"name": "ci"}"#).unwrap();
+        assert_eq!(request.name, "ci");
+        for body in [
+            r#"{"username": "dave"}"#,
+            r#"{"name": "ci", "expires_at": 1}"#,
+        ] {
+            assert!(serde_json::from_str::<RenameTokenRequest>(body).is_err());
+        }
+    }
+
+    #[test]
+    fn test_dry_runs_leave_signed_requests_usable() {
+        let mut config = Config::default();
+        let mut signer = token("tok", "bob");
+        signer.signing_secret = Some("s3cret".to_string());
+        config.auth.tokens = vec![signer];
+        let state = test_state(config);
+
+        let credentials = Credentials {
+            authorization: None,
+            signature: Some(Ok(RequestSignature::sign(
+                "s3cret",
+                "POST",
+                "/webhook",
+                unix_now(),
+                "n1",
+                b"",
+            ))),
+            ..bearer(None)
+        };
+        for _ in 0..2 {
+            let dry_run = authenticate_attempt(&credentials, &state, Attempt::DryRun);
+            assert_eq!(dry_run.ok().unwrap().username, "bob");
+        }
+        assert!(state.token_usage.snapshot().is_empty());
+        assert!(authenticate(&credentials, &state).is_ok());
+        assert!(matches!(
+            authenticate_attempt(&credentials, &state, Attempt::DryRun),
+            Err(AuthRejection::Replayed)
+        ));
     }
 }
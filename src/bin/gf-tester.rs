@@ -1,7 +1,6 @@
 use clap::{Arg, Command};
-use git_friends::{git::CommitInfo, mqtt::MqttClient, Config, Result};
+use git_friends::{clock::unix_now, git::CommitInfo, mqtt::MqttClient, Config, Result};
 use log::{error, info};
-use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::time::{sleep, Duration};
 use uuid::Uuid;
 
@@ -210,10 +209,7 @@ fn generate_mock_commit(repo_url: &str, author: &str, branch: &str, iteration: u
     let commit_id = Uuid::new_v4().to_string().replace("-", "");
     let short_hash = format!("{:.7}", commit_id);
 
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64;
+    let timestamp = unix_now();
 
     let messages = vec![
         "Add new feature for user authentication",
@@ -288,5 +284,8 @@ fn generate_mock_commit(repo_url: &str, author: &str, branch: &str, iteration: u
         repository_url: repo_url.to_string(),
         branch: branch.to_string(),
         files_changed: files_sets[files_index].clone(),
+        parents: vec![],
+        stats: None,
+        signature: None,
     }
 }
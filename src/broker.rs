@@ -0,0 +1,791 @@
+use crate::auth::Credential;
+use crate::config::{BrokerConfig, BrokerUserConfig};
+use crate::errors::{GitFriendsError, Result};
+use crate::mqtt::topic_matches;
+use crate::tls::TlsReloader;
+use bytes::BytesMut;
+use log::{debug, error, info, warn};
+use rumqttc::mqttbytes::v4::{
+    self, ConnAck, Connect, ConnectReturnCode, Packet, PingResp, PubAck, PubComp, PubRec, Publish,
+    SubAck, SubscribeReasonCode, UnsubAck,
+};
+use rumqttc::mqttbytes::{self, valid_filter, valid_topic, QoS};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+use uuid::Uuid;
+
+/// How long a client has to send CONNECT after opening a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages queued for a slow client before further ones are dropped.
+const CLIENT_QUEUE: usize = 1024;
+
+/// Username of gf-server's own client on the loopback listener.
+const INTERNAL_USERNAME: &str = "gf-server";
+
+/// A minimal MQTT 3.1.1 broker. Every delivery is downgraded to QoS 0 and
+/// subscriptions are granted at most once, sessions are not persisted, and
+/// retained messages are kept in memory up to the configured limits.
+pub struct Broker {
+    internal_addr: SocketAddr,
+    internal_password: String,
+    listen_addrs: Vec<SocketAddr>,
+}
+
+/// Who a connected client logged in as.
+#[derive(Clone)]
+enum Identity {
+    Internal,
+    Anonymous(Arc<AnonymousAcl>),
+    User(Arc<BrokerUserConfig>),
+}
+
+/// Topics clients that did not log in may use. They may never retain.
+struct AnonymousAcl {
+    /// Empty allows none
+    publish: Vec<String>,
+    /// Empty allows all
+    subscribe: Vec<String>,
+}
+
+impl Identity {
+    fn name(&self) -> &str {
+        match self {
+            Identity::Internal => INTERNAL_USERNAME,
+            Identity::Anonymous(_) => "anonymous",
+            Identity::User(user) => &user.username,
+        }
+    }
+
+    /// Whether the client may publish this message, and retain it if asked.
+    fn may_publish(&self, publish: &Publish) -> bool {
+        let topic = &publish.topic;
+        let may_retain = match self {
+            Identity::Internal => true,
+            Identity::Anonymous(_) => false,
+            Identity::User(user) => matches_any(&user.retain, topic),
+        };
+        let may_publish = match self {
+            Identity::Internal => true,
+            Identity::Anonymous(acl) => matches_any(&acl.publish, topic),
+            Identity::User(user) => allowed(&user.publish, topic),
+        };
+        may_publish && (!publish.retain || may_retain)
+    }
+
+    fn may_receive(&self, topic: &str) -> bool {
+        match self {
+            Identity::Internal => true,
+            Identity::Anonymous(acl) => allowed(&acl.subscribe, topic),
+            Identity::User(user) => allowed(&user.subscribe, topic),
+        }
+    }
+}
+
+/// Whether `topic` matches one of `filters`, or there are none.
+fn allowed(filters: &[String], topic: &str) -> bool {
+    filters.is_empty() || matches_any(filters, topic)
+}
+
+fn matches_any(filters: &[String], topic: &str) -> bool {
+    filters.iter().any(|f| topic_matches(f, topic))
+}
+
+enum Outgoing {
+    Publish(Publish),
+    /// Another connection took over the client id
+    TakenOver,
+}
+
+struct Session {
+    client_id: String,
+    identity: Identity,
+    filters: Vec<String>,
+    tx: mpsc::Sender<Outgoing>,
+}
+
+struct Shared {
+    accounts: Vec<(Credential, Arc<BrokerUserConfig>)>,
+    /// Set when anonymous clients are allowed
+    anonymous: Option<Arc<AnonymousAcl>>,
+    internal_password: String,
+    max_packet_bytes: usize,
+    max_retained_messages: usize,
+    max_retained_payload_bytes: usize,
+    sessions: RwLock<HashMap<u64, Session>>,
+    retained: RwLock<HashMap<String, Publish>>,
+    next_id: AtomicU64,
+}
+
+impl Broker {
+    /// Bind the configured listeners and a loopback listener for gf-server
+    /// itself, then accept clients in the background.
+    pub async fn start(config: &BrokerConfig) -> Result<Self> {
+        let internal_password = Uuid::new_v4().to_string();
+        let shared = Arc::new(Shared::new(config, internal_password.clone())?);
+
+        let internal = TcpListener::bind("127.0.0.1:0").await?;
+        let internal_addr = internal.local_addr()?;
+        tokio::spawn(accept_loop(shared.clone(), internal, None, true));
+
+        let mut listen_addrs = Vec::new();
+        for listener_config in &config.listeners {
+            let bind: SocketAddr = listener_config.bind.parse().map_err(|e| {
+                GitFriendsError::InvalidConfig(format!("Invalid broker listener address: {}", e))
+            })?;
+            let tls = match &listener_config.tls {
+                Some(tls) => {
+                    let reloader = Arc::new(TlsReloader::new(tls.clone())?);
+                    reloader.clone().spawn_watcher();
+                    Some(reloader)
+                }
+                None => None,
+            };
+            let listener = TcpListener::bind(bind).await?;
+            let addr = listener.local_addr()?;
+            info!(
+                "Embedded MQTT broker listening on {}{}",
+                addr,
+                if tls.is_some() { " (TLS)" } else { "" }
+            );
+            listen_addrs.push(addr);
+            tokio::spawn(accept_loop(shared.clone(), listener, tls, false));
+        }
+
+        Ok(Broker {
+            internal_addr,
+            internal_password,
+            listen_addrs,
+        })
+    }
+
+    /// Address and credentials gf-server's own client connects with.
+    pub fn internal_login(&self) -> (SocketAddr, &str, &str) {
+        (
+            self.internal_addr,
+            INTERNAL_USERNAME,
+            &self.internal_password,
+        )
+    }
+
+    /// Bound addresses of the configured listeners.
+    pub fn listen_addrs(&self) -> &[SocketAddr] {
+        &self.listen_addrs
+    }
+}
+
+async fn accept_loop(
+    shared: Arc<Shared>,
+    listener: TcpListener,
+    tls: Option<Arc<TlsReloader>>,
+    internal: bool,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Embedded MQTT broker failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let shared = shared.clone();
+        let acceptor = tls.as_ref().map(|tls| tls.acceptor());
+        tokio::spawn(async move {
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle_connection(shared, stream, peer, internal).await,
+                    Err(e) => {
+                        debug!("TLS handshake with MQTT client {} failed: {}", peer, e);
+                        return;
+                    }
+                },
+                None => handle_connection(shared, stream, peer, internal).await,
+            };
+            if let Err(e) = result {
+                debug!("MQTT client {} disconnected: {}", peer, e);
+            }
+        });
+    }
+}
+
+impl Shared {
+    fn new(config: &BrokerConfig, internal_password: String) -> Result<Self> {
+        let mut accounts = Vec::new();
+        for user in &config.users {
+            accounts.push((
+                Credential::from_entry(&user.password)?,
+                Arc::new(user.clone()),
+            ));
+        }
+        let anonymous = config.allow_anonymous.then(|| {
+            Arc::new(AnonymousAcl {
+                publish: config.anonymous_publish.clone(),
+                subscribe: config.anonymous_subscribe.clone(),
+            })
+        });
+        Ok(Shared {
+            accounts,
+            anonymous,
+            internal_password,
+            max_packet_bytes: config.max_packet_bytes,
+            max_retained_messages: config.max_retained_messages,
+            max_retained_payload_bytes: config.max_retained_payload_bytes,
+            sessions: RwLock::new(HashMap::new()),
+            retained: RwLock::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    fn authenticate(&self, connect: &Connect, internal: bool) -> Option<Identity> {
+        let Some(login) = &connect.login else {
+            return self.anonymous.clone().map(Identity::Anonymous);
+        };
+        if internal && login.username == INTERNAL_USERNAME {
+            let matches: bool = login
+                .password
+                .as_bytes()
+                .ct_eq(self.internal_password.as_bytes())
+                .into();
+            return matches.then_some(Identity::Internal);
+        }
+        self.accounts
+            .iter()
+            .find(|(_, user)| user.username == login.username)
+            .filter(|(credential, _)| credential.matches(&login.password))
+            .map(|(_, user)| Identity::User(user.clone()))
+    }
+
+    /// Register a session, disconnecting any other with the same client id.
+    fn register(&self, id: u64, session: Session) {
+        let mut sessions = self.sessions.write().unwrap();
+        for other in sessions.values() {
+            if other.client_id == session.client_id {
+                let _ = other.tx.try_send(Outgoing::TakenOver);
+            }
+        }
+        sessions.insert(id, session);
+    }
+
+    /// Deliver a message to every subscribed client allowed to receive it.
+    fn route(&self, publish: &Publish) {
+        if publish.retain {
+            let mut retained = self.retained.write().unwrap();
+            if publish.payload.is_empty() {
+                retained.remove(&publish.topic);
+            } else if publish.payload.len() > self.max_retained_payload_bytes {
+                // Dropping the previous message too, so subscribers do not get a stale one
+                retained.remove(&publish.topic);
+                warn!(
+                    "Not retaining {} byte message on {}, the limit is {} bytes",
+                    publish.payload.len(),
+                    publish.topic,
+                    self.max_retained_payload_bytes
+                );
+            } else if retained.len() >= self.max_retained_messages
+                && !retained.contains_key(&publish.topic)
+            {
+                warn!(
+                    "Not retaining message on {}, {} messages are retained already",
+                    publish.topic,
+                    retained.len()
+                );
+            } else {
+                retained.insert(publish.topic.clone(), outgoing(publish, true));
+            }
+        }
+
+        let message = outgoing(publish, false);
+        for session in self.sessions.read().unwrap().values() {
+            if !session
+                .filters
+                .iter()
+                .any(|filter| topic_matches(filter, &publish.topic))
+                || !session.identity.may_receive(&publish.topic)
+            {
+                continue;
+            }
+            if session
+                .tx
+                .try_send(Outgoing::Publish(message.clone()))
+                .is_err()
+            {
+                warn!(
+                    "Dropping message on {} for slow MQTT client {}",
+                    publish.topic, session.client_id
+                );
+            }
+        }
+    }
+}
+
+/// A copy of `publish` as sent to subscribers: QoS 0, retain flag only for
+/// retained messages replayed on subscription.
+fn outgoing(publish: &Publish, retain: bool) -> Publish {
+    let mut message = Publish::new(&publish.topic, QoS::AtMostOnce, publish.payload.to_vec());
+    message.retain = retain;
+    message
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut BytesMut,
+    max_packet_bytes: usize,
+) -> Result<Option<Packet>> {
+    loop {
+        match v4::read(buffer, max_packet_bytes) {
+            Ok(packet) => return Ok(Some(packet)),
+            Err(mqttbytes::Error::InsufficientBytes(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+        if reader.read_buf(buffer).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+async fn write_packet<W: AsyncWrite + Unpin>(writer: &mut W, packet: Packet) -> Result<()> {
+    let mut buffer = BytesMut::new();
+    match packet {
+        Packet::ConnAck(p) => p.write(&mut buffer),
+        Packet::Publish(p) => p.write(&mut buffer),
+        Packet::PubAck(p) => p.write(&mut buffer),
+        Packet::PubRec(p) => p.write(&mut buffer),
+        Packet::PubComp(p) => p.write(&mut buffer),
+        Packet::SubAck(p) => p.write(&mut buffer),
+        Packet::UnsubAck(p) => p.write(&mut buffer),
+        Packet::PingResp => PingResp.write(&mut buffer),
+        other => unreachable!("broker does not send {:?}", other),
+    }?;
+    writer.write_all(&buffer).await?;
+    Ok(())
+}
+
+/// Per-connection state for handling the packets a client sends.
+struct Client<'a> {
+    id: u64,
+    identity: &'a Identity,
+    /// QoS 2 packet ids received but not yet released
+    awaiting_release: HashSet<u16>,
+}
+
+impl Client<'_> {
+    /// Handle one packet, returning whether the connection ended and if it
+    /// ended cleanly.
+    async fn handle<W: AsyncWrite + Unpin>(
+        &mut self,
+        shared: &Shared,
+        writer: &mut W,
+        packet: Packet,
+    ) -> Result<Option<bool>> {
+        match packet {
+            Packet::Publish(publish) => {
+                if !valid_topic(&publish.topic) {
+                    return Err(mqttbytes::Error::IncorrectPacketFormat.into());
+                }
+                let duplicate =
+                    publish.qos == QoS::ExactlyOnce && !self.awaiting_release.insert(publish.pkid);
+                if !self.identity.may_publish(&publish) {
+                    warn!(
+                        "MQTT user {} may not publish{} to {}",
+                        self.identity.name(),
+                        if publish.retain {
+                            " retained messages"
+                        } else {
+                            ""
+                        },
+                        publish.topic
+                    );
+                } else if !duplicate {
+                    shared.route(&publish);
+                }
+                match publish.qos {
+                    QoS::AtMostOnce => {}
+                    QoS::AtLeastOnce => {
+                        write_packet(writer, Packet::PubAck(PubAck::new(publish.pkid))).await?
+                    }
+                    QoS::ExactlyOnce => {
+                        write_packet(writer, Packet::PubRec(PubRec::new(publish.pkid))).await?
+                    }
+                }
+            }
+            Packet::PubRel(release) => {
+                self.awaiting_release.remove(&release.pkid);
+                write_packet(writer, Packet::PubComp(PubComp::new(release.pkid))).await?;
+            }
+            Packet::Subscribe(subscribe) => {
+                let mut codes = Vec::new();
+                let mut retained = Vec::new();
+                for filter in &subscribe.filters {
+                    if !valid_filter(&filter.path) {
+                        codes.push(SubscribeReasonCode::Failure);
+                        continue;
+                    }
+                    // Everything is delivered at QoS 0, whatever was asked for
+                    codes.push(SubscribeReasonCode::Success(QoS::AtMostOnce));
+                    retained.extend(
+                        shared
+                            .retained
+                            .read()
+                            .unwrap()
+                            .values()
+                            .filter(|r| topic_matches(&filter.path, &r.topic))
+                            .filter(|r| self.identity.may_receive(&r.topic))
+                            .cloned(),
+                    );
+                    if let Some(session) = shared.sessions.write().unwrap().get_mut(&self.id) {
+                        if !session.filters.contains(&filter.path) {
+                            session.filters.push(filter.path.clone());
+                        }
+                    }
+                }
+                write_packet(writer, Packet::SubAck(SubAck::new(subscribe.pkid, codes))).await?;
+                for message in retained {
+                    write_packet(writer, Packet::Publish(message)).await?;
+                }
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                if let Some(session) = shared.sessions.write().unwrap().get_mut(&self.id) {
+                    session.filters.retain(|f| !unsubscribe.topics.contains(f));
+                }
+                write_packet(writer, Packet::UnsubAck(UnsubAck::new(unsubscribe.pkid))).await?;
+            }
+            Packet::PingReq => write_packet(writer, Packet::PingResp).await?,
+            Packet::Disconnect => return Ok(Some(true)),
+            // Only QoS 0 is sent, so there is nothing to acknowledge
+            Packet::PubAck(_) | Packet::PubRec(_) | Packet::PubComp(_) => {}
+            _ => return Err(mqttbytes::Error::IncorrectPacketFormat.into()),
+        }
+        Ok(None)
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    shared: Arc<Shared>,
+    stream: S,
+    peer: SocketAddr,
+    internal: bool,
+) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buffer = BytesMut::with_capacity(4096);
+    let max = shared.max_packet_bytes;
+
+    let connect = match timeout(CONNECT_TIMEOUT, read_packet(&mut reader, &mut buffer, max)).await {
+        Ok(Ok(Some(Packet::Connect(connect)))) => connect,
+        Ok(Ok(Some(_))) => return Err(mqttbytes::Error::IncorrectPacketFormat.into()),
+        Ok(result) => return result.map(|_| ()),
+        Err(_) => return Ok(()),
+    };
+
+    let Some(identity) = shared.authenticate(&connect, internal) else {
+        warn!("Embedded MQTT broker refused login from {}", peer);
+        let refused = ConnAck::new(ConnectReturnCode::BadUserNamePassword, false);
+        return write_packet(&mut writer, Packet::ConnAck(refused)).await;
+    };
+
+    let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+    let client_id = if !connect.client_id.is_empty() {
+        connect.client_id.clone()
+    } else if connect.clean_session {
+        format!("auto-{}", id)
+    } else {
+        let refused = ConnAck::new(ConnectReturnCode::BadClientId, false);
+        return write_packet(&mut writer, Packet::ConnAck(refused)).await;
+    };
+
+    let (tx, mut rx) = mpsc::channel(CLIENT_QUEUE);
+    shared.register(
+        id,
+        Session {
+            client_id: client_id.clone(),
+            identity: identity.clone(),
+            filters: Vec::new(),
+            tx,
+        },
+    );
+    debug!(
+        "MQTT client {} connected from {} as {}",
+        client_id,
+        peer,
+        identity.name()
+    );
+
+    let result = async {
+        write_packet(
+            &mut writer,
+            Packet::ConnAck(ConnAck::new(ConnectReturnCode::Success, false)),
+        )
+        .await?;
+
+        // Clients must send something within 1.5 times their keep-alive
+        let keep_alive = (connect.keep_alive > 0)
+            .then(|| Duration::from_millis(connect.keep_alive as u64 * 1500));
+        let mut deadline = keep_alive.map(|k| Instant::now() + k);
+        let mut client = Client {
+            id,
+            identity: &identity,
+            awaiting_release: HashSet::new(),
+        };
+
+        loop {
+            tokio::select! {
+                packet = read_packet(&mut reader, &mut buffer, max) => {
+                    let Some(packet) = packet? else {
+                        return Ok(false);
+                    };
+                    deadline = keep_alive.map(|k| Instant::now() + k);
+                    if let Some(clean) = client.handle(&shared, &mut writer, packet).await? {
+                        return Ok(clean);
+                    }
+                }
+                outgoing = rx.recv() => match outgoing {
+                    Some(Outgoing::Publish(publish)) => {
+                        write_packet(&mut writer, Packet::Publish(publish)).await?
+                    }
+                    Some(Outgoing::TakenOver) | None => {
+                        info!("MQTT client id {} was taken over by a new connection", client_id);
+                        return Ok(false);
+                    }
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    debug!("MQTT client {} missed its keep-alive", client_id);
+                    return Ok(false);
+                }
+            }
+        }
+    }
+    .await;
+
+    shared.sessions.write().unwrap().remove(&id);
+    let clean = matches!(result, Ok(true));
+    if !clean {
+        if let Some(will) = &connect.last_will {
+            let mut publish = Publish::new(&will.topic, will.qos, will.message.to_vec());
+            publish.retain = will.retain;
+            if identity.may_publish(&publish) && valid_topic(&will.topic) {
+                shared.route(&publish);
+            }
+        }
+    }
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BrokerListenerConfig;
+    use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions};
+
+    fn broker_config() -> BrokerConfig {
+        BrokerConfig {
+            enabled: true,
+            listeners: vec![BrokerListenerConfig {
+                bind: "127.0.0.1:0".to_string(),
+                tls: None,
+            }],
+            users: vec![
+                BrokerUserConfig {
+                    username: "irc".to_string(),
+                    password: crate::auth::hash_token("irc-secret"),
+                    publish: vec!["nothing".to_string()],
+                    subscribe: vec!["git-friends/#".to_string()],
+                    retain: vec![],
+                },
+                BrokerUserConfig {
+                    username: "ops".to_string(),
+                    password: "ops-secret".to_string(),
+                    publish: vec![],
+                    subscribe: vec![],
+                    retain: vec!["status/#".to_string()],
+                },
+            ],
+            max_packet_bytes: 64 * 1024,
+            ..BrokerConfig::default()
+        }
+    }
+
+    async fn start_broker() -> Broker {
+        Broker::start(&broker_config()).await.unwrap()
+    }
+
+    fn client(addr: SocketAddr, id: &str, user: &str, password: &str) -> (AsyncClient, EventLoop) {
+        let mut options = MqttOptions::new(id, addr.ip().to_string(), addr.port());
+        options.set_credentials(user, password);
+        AsyncClient::new(options, 10)
+    }
+
+    /// Poll until a packet matching `wanted` arrives, or fail after a second.
+    async fn next_incoming<T>(
+        eventloop: &mut EventLoop,
+        mut wanted: impl FnMut(rumqttc::Packet) -> Option<T>,
+    ) -> Option<T> {
+        timeout(Duration::from_secs(1), async {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(packet)) => {
+                        if let Some(found) = wanted(packet) {
+                            return Some(found);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(_) => return None,
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    #[tokio::test]
+    async fn test_routes_messages_by_subscription_and_acl() {
+        let broker = start_broker().await;
+        let addr = broker.listen_addrs()[0];
+
+        let (irc, mut irc_events) = client(addr, "irc", "irc", "irc-secret");
+        irc.subscribe("#", rumqttc::QoS::AtMostOnce).await.unwrap();
+        next_incoming(&mut irc_events, |p| {
+            matches!(p, rumqttc::Packet::SubAck(_)).then_some(())
+        })
+        .await
+        .unwrap();
+
+        let (internal_addr, user, password) = broker.internal_login();
+        let (server, mut server_events) = client(internal_addr, "server", user, password);
+        server
+            .publish("other/topic", rumqttc::QoS::AtLeastOnce, false, "hidden")
+            .await
+            .unwrap();
+        server
+            .publish(
+                "git-friends/bob/repo/Bob",
+                rumqttc::QoS::AtLeastOnce,
+                true,
+                "commit",
+            )
+            .await
+            .unwrap();
+        tokio::spawn(async move { while server_events.poll().await.is_ok() {} });
+
+        // The irc user subscribed to everything but may only receive git-friends/#
+        let received = next_incoming(&mut irc_events, |p| match p {
+            rumqttc::Packet::Publish(publish) => Some(publish),
+            _ => None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(received.topic, "git-friends/bob/repo/Bob");
+        assert_eq!(&received.payload[..], b"commit");
+
+        // Publishing outside the irc user's publish filters is dropped
+        irc.publish("git-friends/fake", rumqttc::QoS::AtMostOnce, false, "x")
+            .await
+            .unwrap();
+
+        // Retained messages are replayed to new subscribers
+        let (ops, mut ops_events) = client(addr, "ops", "ops", "ops-secret");
+        ops.subscribe("git-friends/+/+/+", rumqttc::QoS::AtMostOnce)
+            .await
+            .unwrap();
+        let replayed = next_incoming(&mut ops_events, |p| match p {
+            rumqttc::Packet::Publish(publish) => Some(publish),
+            _ => None,
+        })
+        .await
+        .unwrap();
+        assert!(replayed.retain);
+        assert_eq!(replayed.topic, "git-friends/bob/repo/Bob");
+
+        let unexpected = next_incoming(&mut irc_events, |p| match p {
+            rumqttc::Packet::Publish(publish) => Some(publish),
+            _ => None,
+        })
+        .await;
+        assert!(unexpected.is_none());
+    }
+
+    #[test]
+    fn test_retain_limits_and_anonymous_acl() {
+        let shared = Shared::new(
+            &BrokerConfig {
+                allow_anonymous: true,
+                anonymous_publish: vec!["public/#".to_string()],
+                anonymous_subscribe: vec!["git-friends/#".to_string()],
+                max_retained_messages: 1,
+                max_retained_payload_bytes: 8,
+                ..broker_config()
+            },
+            "internal".to_string(),
+        )
+        .unwrap();
+        let retained = |topic: &str, payload: &str| {
+            let mut publish = Publish::new(topic, QoS::AtMostOnce, payload);
+            publish.retain = true;
+            publish
+        };
+
+        let anonymous = shared.authenticate(&Connect::new("anon"), false).unwrap();
+        assert!(anonymous.may_publish(&Publish::new("public/x", QoS::AtMostOnce, "x")));
+        assert!(!anonymous.may_publish(&Publish::new("git-friends/x", QoS::AtMostOnce, "x")));
+        assert!(!anonymous.may_publish(&retained("public/x", "x")));
+        assert!(anonymous.may_receive("git-friends/bob/repo/Bob"));
+        assert!(!anonymous.may_receive("status/ops"));
+
+        let mut connect = Connect::new("ops");
+        connect.set_login("ops", "ops-secret");
+        let ops = shared.authenticate(&connect, false).unwrap();
+        assert!(ops.may_publish(&retained("status/a", "up")));
+        assert!(!ops.may_publish(&retained("git-friends/a", "up")));
+
+        shared.route(&retained("status/a", "up"));
+        shared.route(&retained("status/b", "up"));
+        let topics = |shared: &Shared| {
+            let mut topics: Vec<_> = shared.retained.read().unwrap().keys().cloned().collect();
+            topics.sort();
+            topics
+        };
+        assert_eq!(topics(&shared), vec!["status/a"]);
+
+        // An oversized message replaces the retained one with nothing
+        shared.route(&retained("status/a", "far too long"));
+        assert!(topics(&shared).is_empty());
+        shared.route(&retained("status/b", "up"));
+        assert_eq!(topics(&shared), vec!["status/b"]);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_bad_credentials() {
+        let broker = start_broker().await;
+        let addr = broker.listen_addrs()[0];
+
+        let connack = |code| {
+            move |p| match p {
+                rumqttc::Packet::ConnAck(ack) if ack.code == code => Some(()),
+                _ => None,
+            }
+        };
+
+        let (_ops, mut events) = client(addr, "ops", "ops", "ops-secret");
+        assert!(
+            next_incoming(&mut events, connack(ConnectReturnCode::Success))
+                .await
+                .is_some()
+        );
+
+        let (_wrong, mut events) = client(addr, "wrong", "ops", "guess");
+        assert!(next_incoming(&mut events, |_| Some(())).await.is_none());
+
+        // The internal login only works on the loopback listener
+        let (_, user, password) = broker.internal_login();
+        let (_internal, mut events) = client(addr, "internal", user, password);
+        assert!(next_incoming(&mut events, |_| Some(())).await.is_none());
+    }
+}
@@ -1,7 +1,9 @@
+use crate::errors::{GitFriendsError, Result as GfResult};
 use config::{Config as ConfigImpl, ConfigError, Environment, File};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::path::PathBuf;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
@@ -10,6 +12,29 @@ pub struct Config {
     pub irc: IrcConfig,
     pub git: GitConfig,
     pub auth: AuthConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub feeds: FeedsConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub reload: ReloadConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
+    /// HTTP endpoints accepted events are forwarded to, next to MQTT
+    #[serde(default)]
+    pub outbound: Vec<OutboundWebhookConfig>,
+    #[serde(default)]
+    pub broker: BrokerConfig,
+    /// Transports accepted events are published to; the `[mqtt]` broker when empty
+    #[serde(default)]
+    pub publishers: Vec<PublisherConfig>,
+    #[serde(default)]
+    pub mirrors: MirrorConfig,
+    /// Organizations that each own users, repositories and a topic namespace
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -17,6 +42,30 @@ pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub bind_address: String,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    /// CA bundle used to verify client certificates
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+    /// Reject clients that do not present a certificate signed by `client_ca_path`
+    #[serde(default)]
+    pub require_client_cert: bool,
+    /// How often certificate files are checked for changes; 0 disables reloading
+    #[serde(default = "default_tls_reload_interval")]
+    pub reload_interval_secs: u64,
+    /// Plain HTTP listener that redirects to HTTPS; plain HTTP is refused when unset
+    #[serde(default)]
+    pub http_redirect_bind: Option<String>,
+}
+
+fn default_tls_reload_interval() -> u64 {
+    60
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -27,6 +76,10 @@ pub struct MqttConfig {
     pub username: Option<String>,
     pub password: Option<String>,
     pub topic_prefix: String,
+    /// Publish commits in the versioned event envelope instead of the bare
+    /// commit JSON that consumers of older versions expect
+    #[serde(default)]
+    pub event_envelope: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -44,19 +97,565 @@ pub struct IrcConfig {
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct GitConfig {
     pub hook_script_path: Option<String>,
-    pub repository_mappings: HashMap<String, String>, // repo_url -> topic_suffix
+    pub repository_mappings: HashMap<String, String>, // repo_url -> display name
+    /// Ordered pattern rules, tried after the exact `repository_mappings`
+    #[serde(default)]
+    pub repository_rules: Vec<RepositoryRule>,
+}
+
+/// Attributes of the repositories whose URL matches `pattern` or `regex`.
+/// Both are matched against the URL without scheme, user and `.git`, such
+/// as `github.com/acme/widgets`.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RepositoryRule {
+    /// Glob; `*` matches within one path segment and `**` across segments,
+    /// each is a numbered capture
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    /// Display name; `{1}` and `{group}` insert captures, `{name}` the last
+    /// path segment and `{path}` the whole URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// IRC channel the repository's commits are announced in instead of
+    /// `irc.channels`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    /// Only announced in the rule's own channel
+    Private,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct AuthConfig {
     pub tokens: Vec<TokenConfig>,
     pub require_auth: bool,
+    /// Users whose tokens may manage tokens through the admin API
+    #[serde(default)]
+    pub admin_users: Vec<String>,
+    /// SQLite database for tokens created through the admin API
+    #[serde(default)]
+    pub token_store_path: Option<String>,
+    /// How far the timestamp of a signed request may be from the server clock
+    #[serde(default = "default_signature_max_skew")]
+    pub signature_max_skew_secs: u64,
+    /// Usernames for verified TLS client certificates
+    #[serde(default)]
+    pub client_certs: Vec<ClientCertConfig>,
+    /// Issuers whose OIDC ID tokens are accepted as bearer tokens
+    #[serde(default)]
+    pub oidc: Vec<OidcConfig>,
+    /// How often JSON Web Key Sets are fetched again
+    #[serde(default = "default_jwks_refresh")]
+    pub jwks_refresh_secs: u64,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            tokens: vec![],
+            require_auth: true,
+            admin_users: vec![],
+            token_store_path: None,
+            signature_max_skew_secs: default_signature_max_skew(),
+            client_certs: vec![],
+            oidc: vec![],
+            jwks_refresh_secs: default_jwks_refresh(),
+        }
+    }
+}
+
+fn default_jwks_refresh() -> u64 {
+    3600
+}
+
+fn default_signature_max_skew() -> u64 {
+    crate::signing::DEFAULT_MAX_SKEW_SECS
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct TokenConfig {
     pub token: String,
     pub username: String,
+    /// Overrides `rate_limit.per_token_per_minute` for this token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_per_minute: Option<u32>,
+    #[serde(default, skip_serializing_if = "TokenScopes::is_unrestricted")]
+    pub scopes: TokenScopes,
+    /// Unix timestamp from which the token is rejected as expired
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// Unix timestamp before which the token is not accepted yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<i64>,
+    /// Shared secret for HMAC-signed requests made on behalf of this token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_secret: Option<String>,
+}
+
+/// Maps a client certificate to a user, like a token entry does.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ClientCertConfig {
+    /// Subject common name or a DNS, email or URI subject alternative name
+    pub subject: String,
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_per_minute: Option<u32>,
+    #[serde(default, skip_serializing_if = "TokenScopes::is_unrestricted")]
+    pub scopes: TokenScopes,
+}
+
+/// Accepts OIDC ID tokens from one issuer, e.g. GitHub Actions, and maps
+/// them to a user.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OidcConfig {
+    pub issuer: String,
+    /// Expected `aud` claim
+    pub audience: String,
+    /// URL or file path of the issuer's JSON Web Key Set
+    pub jwks: String,
+    /// Patterns claims must match, e.g. `repository`; `*` matches any run of characters
+    #[serde(default)]
+    pub claims: HashMap<String, String>,
+    /// Username template; `{claim}` is replaced with the claim's value
+    #[serde(default = "default_oidc_username")]
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit_per_minute: Option<u32>,
+    #[serde(default, skip_serializing_if = "TokenScopes::is_unrestricted")]
+    pub scopes: TokenScopes,
+}
+
+fn default_oidc_username() -> String {
+    "{sub}".to_string()
+}
+
+/// Restrictions on what a token may do. Empty lists allow everything.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TokenScopes {
+    /// Repository URL patterns; `*` matches any run of characters
+    pub repositories: Vec<String>,
+    /// Event types the token may publish (`commit`, `ref`, `ci-status`)
+    pub event_types: Vec<String>,
+    /// MQTT topic filters the published topic must match
+    pub topics: Vec<String>,
+    pub access: TokenAccess,
+}
+
+impl TokenScopes {
+    pub fn is_unrestricted(&self) -> bool {
+        *self == TokenScopes::default()
+    }
+
+    /// Add unknown event types and malformed topic filters to `problems`.
+    pub fn validate(&self, owner: &str, problems: &mut Vec<String>) {
+        for event_type in &self.event_types {
+            if !crate::event::EVENT_TYPES.contains(&event_type.as_str()) {
+                problems.push(format!("{} has unknown event type '{}'", owner, event_type));
+            }
+        }
+        for filter in &self.topics {
+            if !is_valid_topic_filter(filter) {
+                problems.push(format!("{} has invalid topic filter '{}'", owner, filter));
+            }
+        }
+    }
+}
+
+/// Whether a token may publish webhooks, use the query APIs, or both.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenAccess {
+    Read,
+    Write,
+    #[default]
+    ReadWrite,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    pub database_path: String,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: false,
+            database_path: "git-friends.db".to_string(),
+        }
+    }
+}
+
+/// Atom and RSS feeds of recent commits.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct FeedsConfig {
+    /// Commits kept in memory for feeds and the dashboard while `[history]`
+    /// is disabled; 0 keeps none
+    pub recent_commits: u32,
+    /// Public address of gf-server that feeds link to, e.g.
+    /// `https://git.example.com`; `server.bind_address` when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_url: Option<String>,
+}
+
+impl Default for FeedsConfig {
+    fn default() -> Self {
+        FeedsConfig {
+            recent_commits: 200,
+            base_url: None,
+        }
+    }
+}
+
+/// Bare mirrors gf-server keeps to fill in commit details that producers
+/// left out.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct MirrorConfig {
+    pub enabled: bool,
+    /// Directory holding one bare repository per mirrored repository
+    pub path: String,
+    /// How often every mirror is fetched; unknown commits also trigger a fetch
+    pub fetch_interval_secs: u64,
+    pub repositories: Vec<MirrorRepositoryConfig>,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        MirrorConfig {
+            enabled: false,
+            path: "git-friends-mirrors".to_string(),
+            fetch_interval_secs: 300,
+            repositories: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MirrorRepositoryConfig {
+    /// `repository_url` of the commits this mirror serves
+    pub url: String,
+    /// Local path or remote URL to fetch from; `url` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// An organization with its own users, repositories and topic namespace.
+/// Events of its users are published under `topic_prefix` instead of
+/// `mqtt.topic_prefix`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TenantConfig {
+    pub name: String,
+    pub topic_prefix: String,
+    /// Username patterns of the tenant's tokens, certificates and ID tokens;
+    /// `*` matches any run of characters
+    pub users: Vec<String>,
+    /// Repository URL patterns the tenant owns; empty allows any repository
+    /// no other tenant owns
+    #[serde(default)]
+    pub repositories: Vec<String>,
+    /// Users who may manage the tenant's tokens through the admin API
+    #[serde(default)]
+    pub admin_users: Vec<String>,
+    /// Credentials gf-server publishes the tenant's events with, so broker
+    /// ACLs can confine them to `topic_prefix`; `[mqtt]` credentials when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt_password: Option<String>,
+    /// Channels gf-irc announces the tenant's events in, instead of
+    /// `irc.channels`
+    #[serde(default)]
+    pub irc_channels: Vec<String>,
+}
+
+/// Webhook limits enforced by gf-server. Unset limits are not enforced.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub global_per_minute: Option<u32>,
+    pub per_user_per_minute: Option<u32>,
+    pub per_token_per_minute: Option<u32>,
+    pub per_repo_commits_per_minute: Option<u32>,
+    pub max_body_bytes: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            global_per_minute: None,
+            per_user_per_minute: None,
+            per_token_per_minute: None,
+            per_repo_commits_per_minute: None,
+            max_body_bytes: 1024 * 1024, // matches the MQTT max packet size
+        }
+    }
+}
+
+/// How gf-server and gf-irc pick up configuration changes. SIGHUP always
+/// triggers a reload; file watching polls the modification time.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ReloadConfig {
+    pub watch_config: bool,
+    pub watch_interval_secs: u64,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        ReloadConfig {
+            watch_config: true,
+            watch_interval_secs: 5,
+        }
+    }
+}
+
+/// MQTT broker run inside gf-server, for setups without a separate broker.
+/// gf-server publishes to it directly; `mqtt.broker_host` and `broker_port`
+/// are only used by the other clients.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct BrokerConfig {
+    pub enabled: bool,
+    pub listeners: Vec<BrokerListenerConfig>,
+    /// Accounts clients log in with
+    pub users: Vec<BrokerUserConfig>,
+    /// Accept clients that do not log in
+    pub allow_anonymous: bool,
+    /// Topic filters anonymous clients may publish to; empty allows none
+    pub anonymous_publish: Vec<String>,
+    /// Topic filters anonymous clients receive messages from; empty allows all
+    pub anonymous_subscribe: Vec<String>,
+    pub max_packet_bytes: usize,
+    /// Retained messages kept at once; further topics are not retained
+    pub max_retained_messages: usize,
+    /// Larger payloads are delivered but not retained
+    pub max_retained_payload_bytes: usize,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            enabled: false,
+            listeners: vec![BrokerListenerConfig {
+                bind: "0.0.0.0:1883".to_string(),
+                tls: None,
+            }],
+            users: vec![],
+            allow_anonymous: false,
+            anonymous_publish: vec![],
+            anonymous_subscribe: vec![],
+            max_packet_bytes: 1024 * 1024, // matches the MQTT client's limit
+            max_retained_messages: 1000,
+            max_retained_payload_bytes: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BrokerListenerConfig {
+    pub bind: String,
+    /// Serve MQTT over TLS on this listener
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BrokerUserConfig {
+    pub username: String,
+    /// Plaintext, or a hash from `gf-server --hash-token`
+    pub password: String,
+    /// Topic filters the user may publish to; empty allows all
+    #[serde(default)]
+    pub publish: Vec<String>,
+    /// Topic filters the user receives messages from; empty allows all
+    #[serde(default)]
+    pub subscribe: Vec<String>,
+    /// Topic filters the user may publish retained messages to; empty allows
+    /// none
+    #[serde(default)]
+    pub retain: Vec<String>,
+}
+
+/// A transport gf-server publishes accepted events to.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PublisherConfig {
+    /// Identifies the publisher in logs, metrics and `/ready`
+    pub name: String,
+    #[serde(rename = "type")]
+    pub transport: PublisherTransport,
+    /// Fail webhooks, and report the server as not ready, while this
+    /// publisher is down
+    #[serde(default = "default_publisher_required")]
+    pub required: bool,
+    /// Server to connect to, for Redis (`redis://`) and NATS (`nats://`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Redis only: PUBLISH on the event's topic, or XADD to `stream_key`
+    #[serde(default)]
+    pub redis_mode: RedisMode,
+    #[serde(default = "default_redis_stream_key")]
+    pub stream_key: String,
+    /// Approximate length the stream is trimmed to; 0 never trims
+    #[serde(default = "default_redis_stream_max_len")]
+    pub stream_max_len: u64,
+}
+
+impl PublisherConfig {
+    pub fn new(name: &str, transport: PublisherTransport) -> Self {
+        PublisherConfig {
+            name: name.to_string(),
+            transport,
+            required: default_publisher_required(),
+            url: None,
+            redis_mode: RedisMode::default(),
+            stream_key: default_redis_stream_key(),
+            stream_max_len: default_redis_stream_max_len(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PublisherTransport {
+    /// The broker configured in `[mqtt]`, or the embedded broker
+    Mqtt,
+    /// Only the in-process bus behind the live event stream
+    Memory,
+    Redis,
+    Nats,
+}
+
+impl PublisherTransport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PublisherTransport::Mqtt => "mqtt",
+            PublisherTransport::Memory => "memory",
+            PublisherTransport::Redis => "redis",
+            PublisherTransport::Nats => "nats",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedisMode {
+    #[default]
+    Pubsub,
+    Stream,
+}
+
+fn default_publisher_required() -> bool {
+    true
+}
+
+fn default_redis_stream_key() -> String {
+    "git-friends:events".to_string()
+}
+
+fn default_redis_stream_max_len() -> u64 {
+    10_000
+}
+
+/// An HTTP endpoint gf-server forwards matching events to.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OutboundWebhookConfig {
+    /// Identifies the endpoint in logs, metrics and the dead-letter file
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub filter: OutboundFilter,
+    /// Request body with `{field}` placeholders; the event envelope as JSON when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default = "default_outbound_content_type")]
+    pub content_type: String,
+    /// Signs deliveries the same way gf-hook signs its requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_secret: Option<String>,
+    #[serde(default = "default_outbound_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry; doubled for each further retry
+    #[serde(default = "default_outbound_initial_backoff")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_outbound_max_backoff")]
+    pub max_backoff_secs: u64,
+    #[serde(default = "default_outbound_timeout")]
+    pub timeout_secs: u64,
+    /// JSON lines file receiving deliveries that could not be made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dead_letter_path: Option<String>,
+}
+
+fn default_outbound_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn default_outbound_max_attempts() -> u32 {
+    5
+}
+
+fn default_outbound_initial_backoff() -> u64 {
+    1000
+}
+
+fn default_outbound_max_backoff() -> u64 {
+    300
+}
+
+fn default_outbound_timeout() -> u64 {
+    10
+}
+
+/// Which events an outbound endpoint receives. Empty lists match everything;
+/// `*` matches any run of characters.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct OutboundFilter {
+    pub repositories: Vec<String>,
+    pub branches: Vec<String>,
+    /// Author names or email addresses
+    pub authors: Vec<String>,
+    pub event_types: Vec<String>,
+}
+
+/// Append-only JSON lines log of every webhook decision.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    pub path: String,
+    /// Size at which the log is rotated to `<path>.<unix time>`
+    pub max_file_bytes: u64,
+    /// Rotated files older than this are deleted; 0 keeps them forever
+    pub retention_days: u32,
+    /// Record fields replaced by "[redacted]": any of `crate::audit::REDACTABLE_FIELDS`
+    pub redact: Vec<String>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            enabled: false,
+            path: "git-friends-audit.jsonl".to_string(),
+            max_file_bytes: 10 * 1024 * 1024,
+            retention_days: 90,
+            redact: vec![],
+        }
+    }
 }
 
 impl Default for Config {
@@ -66,6 +665,7 @@ impl Default for Config {
                 host: "localhost".to_string(),
                 port: 8080,
                 bind_address: "0.0.0.0:8080".to_string(),
+                tls: None,
             },
             mqtt: MqttConfig {
                 broker_host: "localhost".to_string(),
@@ -74,6 +674,7 @@ impl Default for Config {
                 username: None,
                 password: None,
                 topic_prefix: "git-friends".to_string(),
+                event_envelope: false,
             },
             irc: IrcConfig {
                 server: "irc.libera.chat".to_string(),
@@ -88,11 +689,19 @@ impl Default for Config {
             git: GitConfig {
                 hook_script_path: None,
                 repository_mappings: HashMap::new(),
+                repository_rules: vec![],
             },
-            auth: AuthConfig {
-                tokens: vec![],
-                require_auth: true,
-            },
+            auth: AuthConfig::default(),
+            history: HistoryConfig::default(),
+            feeds: FeedsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            reload: ReloadConfig::default(),
+            audit: AuditConfig::default(),
+            outbound: vec![],
+            broker: BrokerConfig::default(),
+            publishers: vec![],
+            mirrors: MirrorConfig::default(),
+            tenants: vec![],
         }
     }
 }
@@ -132,6 +741,681 @@ impl Config {
         config.build()?.try_deserialize()
     }
 
+    /// Configuration files `new()` reads from, for watching them for changes.
+    pub fn source_paths() -> Vec<PathBuf> {
+        let candidates = match env::var("GIT_FRIENDS_CONFIG") {
+            Ok(config_path) => vec![
+                PathBuf::from(&config_path),
+                PathBuf::from(format!("{}.toml", config_path)),
+            ],
+            Err(_) => vec![
+                PathBuf::from("git-friends.toml"),
+                PathBuf::from("config/git-friends.toml"),
+                PathBuf::from("/etc/git-friends.toml"),
+            ],
+        };
+
+        candidates.into_iter().filter(|p| p.is_file()).collect()
+    }
+
+    /// Check the configuration for mistakes that would only surface at runtime.
+    pub fn validate(&self) -> GfResult<()> {
+        let mut problems = Vec::new();
+        self.check_server_sections(&mut problems);
+        self.check_shared_sections(&mut problems);
+        problems_to_result(problems)
+    }
+
+    /// Check only the sections gf-irc uses: `mqtt`, `git`, `irc` and
+    /// `tenants`, so mistakes in gf-server's settings do not stop it.
+    pub fn validate_for_irc(&self) -> GfResult<()> {
+        let mut problems = Vec::new();
+        self.check_shared_sections(&mut problems);
+        problems_to_result(problems)
+    }
+
+    /// Sections only gf-server uses.
+    fn check_server_sections(&self, problems: &mut Vec<String>) {
+        if self
+            .server
+            .bind_address
+            .parse::<std::net::SocketAddr>()
+            .is_err()
+        {
+            problems.push(format!(
+                "server.bind_address '{}' is not a valid address",
+                self.server.bind_address
+            ));
+        }
+
+        let mut seen_tokens = HashSet::new();
+        let mut seen_signing_secrets = HashSet::new();
+        for token in &self.auth.tokens {
+            if token.token.is_empty() || token.username.is_empty() {
+                problems.push("auth.tokens entries need a token and a username".to_string());
+            } else if !crate::auth::is_valid_token_entry(&token.token) {
+                problems.push(format!(
+                    "auth.tokens entry for user '{}' has a malformed hash",
+                    token.username
+                ));
+            } else if !seen_tokens.insert(&token.token) {
+                problems.push(format!(
+                    "auth.tokens contains a duplicate token (user '{}')",
+                    token.username
+                ));
+            }
+            if let Some(secret) = &token.signing_secret {
+                if secret.len() < 16 {
+                    problems.push(format!(
+                        "auth.tokens entry for user '{}' has a signing secret shorter than 16 characters",
+                        token.username
+                    ));
+                } else if !seen_signing_secrets.insert(secret) {
+                    problems.push(format!(
+                        "auth.tokens contains a duplicate signing secret (user '{}')",
+                        token.username
+                    ));
+                }
+            }
+            if let (Some(not_before), Some(expires_at)) = (token.not_before, token.expires_at) {
+                if expires_at <= not_before {
+                    problems.push(format!(
+                        "auth.tokens entry for user '{}' expires before it becomes valid",
+                        token.username
+                    ));
+                }
+            }
+            token.scopes.validate(
+                &format!("auth.tokens entry for user '{}'", token.username),
+                problems,
+            );
+        }
+
+        for oidc in &self.auth.oidc {
+            if oidc.issuer.is_empty() || oidc.audience.is_empty() || oidc.jwks.is_empty() {
+                problems.push("auth.oidc entries need an issuer, audience and jwks".to_string());
+            }
+            if oidc.username.is_empty()
+                || oidc.username.matches('{').count() != oidc.username.matches('}').count()
+            {
+                problems.push(format!(
+                    "auth.oidc entry for '{}' has an invalid username template '{}'",
+                    oidc.issuer, oidc.username
+                ));
+            }
+            oidc.scopes
+                .validate(&format!("auth.oidc entry for '{}'", oidc.issuer), problems);
+        }
+
+        let mut seen_subjects = HashSet::new();
+        for client_cert in &self.auth.client_certs {
+            if client_cert.subject.is_empty() || client_cert.username.is_empty() {
+                problems
+                    .push("auth.client_certs entries need a subject and a username".to_string());
+            } else if !seen_subjects.insert(&client_cert.subject) {
+                problems.push(format!(
+                    "auth.client_certs contains subject '{}' more than once",
+                    client_cert.subject
+                ));
+            }
+            client_cert.scopes.validate(
+                &format!("auth.client_certs entry '{}'", client_cert.subject),
+                problems,
+            );
+        }
+        if !self.auth.client_certs.is_empty()
+            && self
+                .server
+                .tls
+                .as_ref()
+                .is_none_or(|tls| tls.client_ca_path.is_none())
+        {
+            problems.push(
+                "auth.client_certs requires server.tls.client_ca_path to verify certificates"
+                    .to_string(),
+            );
+        }
+
+        if self.broker.enabled {
+            if self.broker.listeners.is_empty() {
+                problems.push("broker.listeners must not be empty".to_string());
+            }
+            if self.broker.users.is_empty() && !self.broker.allow_anonymous {
+                problems.push("broker needs users unless allow_anonymous is set".to_string());
+            }
+        }
+        for listener in &self.broker.listeners {
+            if listener.bind.parse::<std::net::SocketAddr>().is_err() {
+                problems.push(format!(
+                    "broker.listeners bind '{}' is not a valid address",
+                    listener.bind
+                ));
+            }
+        }
+        for filter in self
+            .broker
+            .anonymous_publish
+            .iter()
+            .chain(&self.broker.anonymous_subscribe)
+        {
+            if !is_valid_topic_filter(filter) {
+                problems.push(format!(
+                    "broker anonymous ACL has invalid topic filter '{}'",
+                    filter
+                ));
+            }
+        }
+        let mut seen_broker_users = HashSet::new();
+        for user in &self.broker.users {
+            if user.username.is_empty() || user.password.is_empty() {
+                problems.push("broker.users entries need a username and a password".to_string());
+            } else if !seen_broker_users.insert(&user.username) {
+                problems.push(format!(
+                    "broker.users contains user '{}' more than once",
+                    user.username
+                ));
+            } else if !crate::auth::is_valid_token_entry(&user.password) {
+                problems.push(format!(
+                    "broker.users entry '{}' has a malformed password hash",
+                    user.username
+                ));
+            }
+            for filter in user
+                .publish
+                .iter()
+                .chain(&user.subscribe)
+                .chain(&user.retain)
+            {
+                if !is_valid_topic_filter(filter) {
+                    problems.push(format!(
+                        "broker.users entry '{}' has invalid topic filter '{}'",
+                        user.username, filter
+                    ));
+                }
+            }
+        }
+
+        let mut seen_outbound = HashSet::new();
+        for outbound in &self.outbound {
+            let owner = format!("outbound entry '{}'", outbound.name);
+            if outbound.name.is_empty() {
+                problems.push("outbound entries need a name".to_string());
+            } else if !seen_outbound.insert(&outbound.name) {
+                problems.push(format!("{} is defined more than once", owner));
+            }
+            if !(outbound.url.starts_with("https://") || outbound.url.starts_with("http://")) {
+                problems.push(format!("{} needs an http(s) url", owner));
+            }
+            if outbound.max_attempts == 0 {
+                problems.push(format!("{} needs max_attempts of at least 1", owner));
+            }
+            if outbound
+                .signing_secret
+                .as_ref()
+                .is_some_and(|secret| secret.len() < 16)
+            {
+                problems.push(format!(
+                    "{} has a signing secret shorter than 16 characters",
+                    owner
+                ));
+            }
+            if let Some(template) = &outbound.template {
+                if let Err(e) = crate::outbound::check_template(template) {
+                    problems.push(format!("{} has an invalid template: {}", owner, e));
+                }
+            }
+            for event_type in &outbound.filter.event_types {
+                if !crate::event::EVENT_TYPES.contains(&event_type.as_str()) {
+                    problems.push(format!("{} has unknown event type '{}'", owner, event_type));
+                }
+            }
+        }
+
+        let mut seen_publishers = HashSet::new();
+        for publisher in &self.publishers {
+            let owner = format!("publisher '{}'", publisher.name);
+            if publisher.name.is_empty() {
+                problems.push("publishers need a name".to_string());
+            } else if !seen_publishers.insert(&publisher.name) {
+                problems.push(format!("{} is defined more than once", owner));
+            }
+            let schemes: &[&str] = match publisher.transport {
+                PublisherTransport::Mqtt | PublisherTransport::Memory => &[],
+                PublisherTransport::Redis => &["redis://", "rediss://"],
+                PublisherTransport::Nats => &["nats://", "tls://"],
+            };
+            if !schemes.is_empty()
+                && !publisher
+                    .url
+                    .as_ref()
+                    .is_some_and(|url| schemes.iter().any(|s| url.starts_with(s)))
+            {
+                problems.push(format!(
+                    "{} needs a url starting with {}",
+                    owner,
+                    schemes.join(" or ")
+                ));
+            }
+            if (publisher.transport == PublisherTransport::Redis && !cfg!(feature = "redis"))
+                || (publisher.transport == PublisherTransport::Nats && !cfg!(feature = "nats"))
+            {
+                problems.push(format!(
+                    "{} uses {}, which this build does not include",
+                    owner,
+                    publisher.transport.as_str()
+                ));
+            }
+        }
+        for transport in [PublisherTransport::Mqtt, PublisherTransport::Memory] {
+            let count = self
+                .publishers
+                .iter()
+                .filter(|p| p.transport == transport)
+                .count();
+            if count > 1 {
+                problems.push(format!(
+                    "only one {} publisher may be configured",
+                    transport.as_str()
+                ));
+            }
+        }
+
+        if let Some(base_url) = &self.feeds.base_url {
+            if !(base_url.starts_with("https://") || base_url.starts_with("http://")) {
+                problems.push(format!(
+                    "feeds.base_url '{}' must be an http(s) URL",
+                    base_url
+                ));
+            }
+        }
+
+        if self.mirrors.enabled {
+            if self.mirrors.path.is_empty() {
+                problems.push("mirrors.path must not be empty".to_string());
+            }
+            if self.mirrors.fetch_interval_secs == 0 {
+                problems.push("mirrors.fetch_interval_secs must be greater than 0".to_string());
+            }
+        }
+        let mut seen_mirrors = HashSet::new();
+        for mirror in &self.mirrors.repositories {
+            if mirror.url.is_empty() {
+                problems.push("mirrors.repositories entries need a url".to_string());
+            } else if !seen_mirrors
+                .insert(crate::repository::normalize_url(&mirror.url).to_lowercase())
+            {
+                problems.push(format!(
+                    "mirror for {} is defined more than once",
+                    mirror.url
+                ));
+            }
+        }
+
+        if self.audit.enabled {
+            if self.audit.path.is_empty() {
+                problems.push("audit.path must not be empty".to_string());
+            }
+            if self.audit.max_file_bytes == 0 {
+                problems.push("audit.max_file_bytes must be greater than 0".to_string());
+            }
+        }
+        for field in &self.audit.redact {
+            if !crate::audit::REDACTABLE_FIELDS.contains(&field.as_str()) {
+                problems.push(format!(
+                    "audit.redact has unknown field '{}' (expected one of {})",
+                    field,
+                    crate::audit::REDACTABLE_FIELDS.join(", ")
+                ));
+            }
+        }
+    }
+
+    /// Sections gf-server and gf-irc both use.
+    fn check_shared_sections(&self, problems: &mut Vec<String>) {
+        if self.mqtt.topic_prefix.is_empty() || self.mqtt.topic_prefix.contains(['+', '#']) {
+            problems
+                .push("mqtt.topic_prefix must be non-empty and contain no wildcards".to_string());
+        }
+
+        for (i, rule) in self.git.repository_rules.iter().enumerate() {
+            if let Err(e) = crate::repository::check_rule(rule) {
+                problems.push(format!("git.repository_rules[{}] {}", i, e));
+            }
+            if let Some(channel) = rule.channel.as_deref().filter(|c| !is_channel_name(c)) {
+                problems.push(format!(
+                    "git.repository_rules[{}] channel '{}' is not a channel",
+                    i, channel
+                ));
+            }
+        }
+
+        for channel in &self.irc.channels {
+            if !is_channel_name(channel) {
+                problems.push(format!("irc.channels entry '{}' is not a channel", channel));
+            }
+        }
+
+        for filter in &self.irc.topic_filters {
+            if !is_valid_topic_filter(filter) {
+                problems.push(format!(
+                    "irc.topic_filters entry '{}' is not a valid MQTT filter",
+                    filter
+                ));
+            }
+        }
+
+        let mut seen_tenants = HashSet::new();
+        let mut tenant_users: HashMap<&str, &str> = HashMap::new();
+        let mut prefixes = vec![(
+            "mqtt.topic_prefix".to_string(),
+            self.mqtt.topic_prefix.as_str(),
+        )];
+        for tenant in &self.tenants {
+            let owner = format!("tenant '{}'", tenant.name);
+            if tenant.name.is_empty() {
+                problems.push("tenants need a name".to_string());
+            } else if !seen_tenants.insert(&tenant.name) {
+                problems.push(format!("{} is defined more than once", owner));
+            }
+            if tenant.topic_prefix.is_empty() || tenant.topic_prefix.contains(['+', '#']) {
+                problems.push(format!("{} needs a topic_prefix without wildcards", owner));
+            } else {
+                if let Some((other, _)) = prefixes.iter().find(|(_, prefix)| {
+                    crate::tenant::prefixes_overlap(prefix, &tenant.topic_prefix)
+                }) {
+                    problems.push(format!(
+                        "{} has topic_prefix '{}', which overlaps {}",
+                        owner, tenant.topic_prefix, other
+                    ));
+                }
+                prefixes.push((owner.clone(), tenant.topic_prefix.as_str()));
+            }
+            if tenant.users.is_empty() {
+                problems.push(format!("{} needs users", owner));
+            }
+            // Patterns may overlap; the first tenant listed wins
+            for user in tenant.users.iter().filter(|u| !u.contains('*')) {
+                match tenant_users.insert(user, &tenant.name) {
+                    Some(other) if other != tenant.name => problems.push(format!(
+                        "user '{}' belongs to tenants '{}' and '{}'",
+                        user, other, tenant.name
+                    )),
+                    _ => {}
+                }
+            }
+            if tenant.mqtt_username.is_some() != tenant.mqtt_password.is_some() {
+                problems.push(format!(
+                    "{} needs both mqtt_username and mqtt_password",
+                    owner
+                ));
+            }
+            for channel in &tenant.irc_channels {
+                if !is_channel_name(channel) {
+                    problems.push(format!(
+                        "{} has irc_channels entry '{}', which is not a channel",
+                        owner, channel
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Human-readable description of what changed between `self` and `new`,
+    /// empty only when nothing did. Token values are never included.
+    pub fn diff(&self, new: &Config) -> Vec<String> {
+        let mut changes = Vec::new();
+
+        let old_tokens: HashMap<&str, &TokenConfig> = self
+            .auth
+            .tokens
+            .iter()
+            .map(|t| (t.token.as_str(), t))
+            .collect();
+        let new_tokens: HashMap<&str, &TokenConfig> = new
+            .auth
+            .tokens
+            .iter()
+            .map(|t| (t.token.as_str(), t))
+            .collect();
+        for (token, config) in &new_tokens {
+            match old_tokens.get(token) {
+                None => changes.push(format!("token added for user '{}'", config.username)),
+                Some(old) if !same(old, config) => {
+                    changes.push(format!("token changed for user '{}'", config.username))
+                }
+                Some(_) => {}
+            }
+        }
+        for (token, config) in &old_tokens {
+            if !new_tokens.contains_key(token) {
+                changes.push(format!("token removed for user '{}'", config.username));
+            }
+        }
+        if self.auth.require_auth != new.auth.require_auth {
+            changes.push(format!(
+                "auth.require_auth changed to {}",
+                new.auth.require_auth
+            ));
+        }
+
+        list_diff(
+            &mut changes,
+            "admin user",
+            &self.auth.admin_users,
+            &new.auth.admin_users,
+        );
+        if self.auth.signature_max_skew_secs != new.auth.signature_max_skew_secs {
+            changes.push(format!(
+                "auth.signature_max_skew_secs changed to {}",
+                new.auth.signature_max_skew_secs
+            ));
+        }
+        for client_cert in &new.auth.client_certs {
+            match self
+                .auth
+                .client_certs
+                .iter()
+                .find(|old| old.subject == client_cert.subject)
+            {
+                None => changes.push(format!(
+                    "client certificate mapping added: {} -> {}",
+                    client_cert.subject, client_cert.username
+                )),
+                Some(old) if !same(old, client_cert) => changes.push(format!(
+                    "client certificate mapping changed: {} -> {}",
+                    client_cert.subject, client_cert.username
+                )),
+                Some(_) => {}
+            }
+        }
+        for client_cert in &self.auth.client_certs {
+            if !new
+                .auth
+                .client_certs
+                .iter()
+                .any(|c| c.subject == client_cert.subject)
+            {
+                changes.push(format!(
+                    "client certificate mapping removed: {}",
+                    client_cert.subject
+                ));
+            }
+        }
+        if !same(&self.auth.oidc, &new.auth.oidc) {
+            changes.push("auth.oidc issuers changed".to_string());
+        }
+        if self.auth.jwks_refresh_secs != new.auth.jwks_refresh_secs {
+            changes.push(format!(
+                "auth.jwks_refresh_secs changed to {}",
+                new.auth.jwks_refresh_secs
+            ));
+        }
+        if self.feeds.base_url != new.feeds.base_url {
+            changes.push(format!(
+                "feeds.base_url changed to {}",
+                new.feeds.base_url.as_deref().unwrap_or("unset")
+            ));
+        }
+
+        for (url, suffix) in &new.git.repository_mappings {
+            match self.git.repository_mappings.get(url) {
+                None => changes.push(format!("repository mapping added: {} -> {}", url, suffix)),
+                Some(old) if old != suffix => changes.push(format!(
+                    "repository mapping changed: {} -> {} (was {})",
+                    url, suffix, old
+                )),
+                Some(_) => {}
+            }
+        }
+        for url in self.git.repository_mappings.keys() {
+            if !new.git.repository_mappings.contains_key(url) {
+                changes.push(format!("repository mapping removed: {}", url));
+            }
+        }
+
+        if !same(&self.git.repository_rules, &new.git.repository_rules) {
+            changes.push("repository rules changed".to_string());
+        }
+
+        list_diff(
+            &mut changes,
+            "irc channel",
+            &self.irc.channels,
+            &new.irc.channels,
+        );
+        list_diff(
+            &mut changes,
+            "irc topic filter",
+            &self.irc.topic_filters,
+            &new.irc.topic_filters,
+        );
+        for outbound in &new.outbound {
+            match self.outbound.iter().find(|old| old.name == outbound.name) {
+                None => changes.push(format!("outbound webhook added: {}", outbound.name)),
+                Some(old) if !same(old, outbound) => {
+                    changes.push(format!("outbound webhook changed: {}", outbound.name))
+                }
+                Some(_) => {}
+            }
+        }
+        for outbound in &self.outbound {
+            if !new.outbound.iter().any(|o| o.name == outbound.name) {
+                changes.push(format!("outbound webhook removed: {}", outbound.name));
+            }
+        }
+
+        for tenant in &new.tenants {
+            match self.tenants.iter().find(|old| old.name == tenant.name) {
+                None => changes.push(format!("tenant added: {}", tenant.name)),
+                Some(old) if !same(old, tenant) => {
+                    changes.push(format!("tenant changed: {}", tenant.name))
+                }
+                Some(_) => {}
+            }
+        }
+        for tenant in &self.tenants {
+            if !new.tenants.iter().any(|t| t.name == tenant.name) {
+                changes.push(format!("tenant removed: {}", tenant.name));
+            }
+        }
+
+        // Anything not listed above still counts, so no edit is dropped
+        if changes.is_empty() && !same(self, new) {
+            changes.push("other settings changed".to_string());
+        }
+        changes
+    }
+
+    /// Put back `current`'s settings that gf-server only reads at startup,
+    /// returning the sections whose changes are ignored until a restart.
+    pub fn keep_server_restart_settings(&mut self, current: &Config) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+        keep(&mut ignored, "server", &mut self.server, &current.server);
+        keep(&mut ignored, "mqtt", &mut self.mqtt, &current.mqtt);
+        keep(&mut ignored, "broker", &mut self.broker, &current.broker);
+        keep(
+            &mut ignored,
+            "publishers",
+            &mut self.publishers,
+            &current.publishers,
+        );
+        keep(&mut ignored, "history", &mut self.history, &current.history);
+        keep(
+            &mut ignored,
+            "feeds.recent_commits",
+            &mut self.feeds.recent_commits,
+            &current.feeds.recent_commits,
+        );
+        keep(&mut ignored, "mirrors", &mut self.mirrors, &current.mirrors);
+        keep(
+            &mut ignored,
+            "rate_limit",
+            &mut self.rate_limit,
+            &current.rate_limit,
+        );
+        keep(&mut ignored, "audit", &mut self.audit, &current.audit);
+        keep(
+            &mut ignored,
+            "auth.token_store_path",
+            &mut self.auth.token_store_path,
+            &current.auth.token_store_path,
+        );
+
+        // Tenants with their own credentials have their own MQTT connection
+        let mut credentials_changed = false;
+        for tenant in &mut self.tenants {
+            let kept = current
+                .tenants
+                .iter()
+                .find(|t| t.name == tenant.name)
+                .map(|t| (t.mqtt_username.clone(), t.mqtt_password.clone()))
+                .unwrap_or_default();
+            if (tenant.mqtt_username.clone(), tenant.mqtt_password.clone()) != kept {
+                credentials_changed = true;
+                (tenant.mqtt_username, tenant.mqtt_password) = kept;
+            }
+        }
+        if credentials_changed {
+            ignored.push("tenant MQTT credentials");
+        }
+        ignored
+    }
+
+    /// Put back `current`'s settings that gf-irc only reads at startup,
+    /// returning the sections whose changes are ignored until a restart.
+    pub fn keep_irc_restart_settings(&mut self, current: &Config) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+        keep(&mut ignored, "mqtt", &mut self.mqtt, &current.mqtt);
+        let irc = &mut self.irc;
+        let connection_changed = (&irc.server, irc.port, &irc.nick, &irc.username)
+            != (
+                &current.irc.server,
+                current.irc.port,
+                &current.irc.nick,
+                &current.irc.username,
+            )
+            || (&irc.real_name, irc.use_tls) != (&current.irc.real_name, current.irc.use_tls);
+        if connection_changed {
+            ignored.push("irc connection settings");
+            irc.server = current.irc.server.clone();
+            irc.port = current.irc.port;
+            irc.nick = current.irc.nick.clone();
+            irc.username = current.irc.username.clone();
+            irc.real_name = current.irc.real_name.clone();
+            irc.use_tls = current.irc.use_tls;
+        }
+        ignored
+    }
+
+    /// The configured publishers, or the `[mqtt]` broker when none are.
+    pub fn publishers(&self) -> Vec<PublisherConfig> {
+        if self.publishers.is_empty() {
+            vec![PublisherConfig::new("mqtt", PublisherTransport::Mqtt)]
+        } else {
+            self.publishers.clone()
+        }
+    }
+
     pub fn mqtt_topic_for_repo(
         &self,
         repo_url: &str,
@@ -165,3 +1449,144 @@ impl Config {
             .replace(".", "_")
     }
 }
+
+fn problems_to_result(problems: Vec<String>) -> GfResult<()> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(GitFriendsError::InvalidConfig(problems.join("; ")))
+    }
+}
+
+/// Replace `new` with `current` if they differ, noting `section` in `ignored`.
+fn keep<T: Serialize + Clone>(
+    ignored: &mut Vec<&'static str>,
+    section: &'static str,
+    new: &mut T,
+    current: &T,
+) {
+    if !same(new, current) {
+        ignored.push(section);
+        *new = current.clone();
+    }
+}
+
+fn same<T: Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+fn list_diff(changes: &mut Vec<String>, what: &str, old: &[String], new: &[String]) {
+    for item in new.iter().filter(|i| !old.contains(i)) {
+        changes.push(format!("{} added: {}", what, item));
+    }
+    for item in old.iter().filter(|i| !new.contains(i)) {
+        changes.push(format!("{} removed: {}", what, item));
+    }
+}
+
+fn is_channel_name(channel: &str) -> bool {
+    (channel.starts_with('#') || channel.starts_with('&')) && !channel.contains([' ', ','])
+}
+
+/// `+` and `#` must occupy a whole level, and `#` may only be the last one.
+fn is_valid_topic_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == levels.len() - 1,
+        "+" => true,
+        level => !level.contains(['+', '#']),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+
+        config.irc.topic_filters = vec!["git-friends/#/x".to_string()];
+        config.irc.channels = vec!["general".to_string()];
+        config.publishers = vec![PublisherConfig::new("cache", PublisherTransport::Redis)];
+        config.feeds.base_url = Some("git.example.com".to_string());
+        config.git.repository_rules = vec![RepositoryRule {
+            regex: Some("github.com/(acme/".to_string()),
+            channel: Some("acme".to_string()),
+            ..Default::default()
+        }];
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("git-friends/#/x"));
+        assert!(err.contains("general"));
+        assert!(err.contains("publisher 'cache' needs a url"));
+        assert!(err.contains("feeds.base_url 'git.example.com' must be an http(s) URL"));
+        assert!(err.contains("git.repository_rules[0] has an invalid regex"));
+        assert!(err.contains("git.repository_rules[0] channel 'acme' is not a channel"));
+
+        let mut config = Config::default();
+        let tenant = TenantConfig {
+            name: "acme".to_string(),
+            topic_prefix: "git-friends/acme".to_string(),
+            users: vec!["alice".to_string()],
+            repositories: vec![],
+            admin_users: vec![],
+            mqtt_username: Some("acme".to_string()),
+            mqtt_password: None,
+            irc_channels: vec![],
+        };
+        config.tenants = vec![tenant.clone(), tenant];
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("tenant 'acme' is defined more than once"));
+        assert!(err.contains("overlaps mqtt.topic_prefix"));
+        assert!(err.contains("needs both mqtt_username and mqtt_password"));
+    }
+
+    #[test]
+    fn test_diff_hides_token_values() {
+        let old = Config::default();
+        let mut new = Config::default();
+        new.auth.tokens.push(TokenConfig {
+            token: "secret-value".to_string(),
+            username: "alice".to_string(),
+            ..Default::default()
+        });
+        new.irc.channels.push("#ops".to_string());
+        new.mqtt.broker_port = 8883;
+        new.server.bind_address = "0.0.0.0:9090".to_string();
+
+        assert_eq!(
+            new.keep_server_restart_settings(&old),
+            vec!["server", "mqtt"]
+        );
+        assert_eq!(new.mqtt.broker_port, old.mqtt.broker_port);
+        let changes = old.diff(&new);
+        assert_eq!(changes.len(), 2);
+        assert!(changes.contains(&"token added for user 'alice'".to_string()));
+        assert!(changes.contains(&"irc channel added: #ops".to_string()));
+        assert!(!changes.iter().any(|c| c.contains("secret-value")));
+    }
+
+    #[test]
+    fn test_diff_reports_every_change() {
+        let old = Config::default();
+        let mut new = Config::default();
+        new.feeds.base_url = Some("https://git.example.com".to_string());
+        new.auth.jwks_refresh_secs = 600;
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                "auth.jwks_refresh_secs changed to 600",
+                "feeds.base_url changed to https://git.example.com",
+            ]
+        );
+
+        let mut new = Config::default();
+        new.reload.watch_interval_secs = 30;
+        assert_eq!(old.diff(&new), vec!["other settings changed"]);
+        assert!(old.diff(&Config::default()).is_empty());
+    }
+}
@@ -0,0 +1,482 @@
+use crate::feed::{FeedFormat, FeedKind};
+use crate::history::{CommitPage, CommitQuery, StoredCommit};
+use crate::mqtt::repository_id;
+use crate::repository::RepositoryRules;
+use chrono::DateTime;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::fmt::Write;
+
+/// Commits listed per page.
+const PAGE_SIZE: u32 = 50;
+
+/// Characters escaped in URL path segments and query values.
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Filters of a dashboard page, from its query string. Empty form fields
+/// count as unset.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DashboardQuery {
+    pub repo: Option<String>,
+    pub author: Option<String>,
+    pub branch: Option<String>,
+    pub offset: Option<u32>,
+}
+
+impl DashboardQuery {
+    /// The history query for `view`, narrowed by these filters.
+    pub fn commit_query(&self, view: &View) -> CommitQuery {
+        let mut query = CommitQuery {
+            repo: non_empty(&self.repo),
+            author: non_empty(&self.author),
+            branch: non_empty(&self.branch),
+            limit: Some(PAGE_SIZE),
+            offset: self.offset,
+            ..Default::default()
+        };
+        match view {
+            View::Feed => {}
+            View::Repository(id) => query.repo_id = Some(id.clone()),
+            View::Person(name) => query.author = Some(name.clone()),
+        }
+        query
+    }
+}
+
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value
+        .as_deref()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+/// What a dashboard page lists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum View {
+    /// Every commit the reader may see
+    Feed,
+    /// Commits of the repository with this id
+    Repository(String),
+    /// Commits whose author name or email contains this
+    Person(String),
+}
+
+impl View {
+    fn title(&self) -> String {
+        match self {
+            View::Feed => "Recent commits".to_string(),
+            View::Repository(id) => format!("Repository {}", id),
+            View::Person(name) => format!("Commits by {}", name),
+        }
+    }
+
+    fn path(&self) -> String {
+        match self {
+            View::Feed => "/dashboard".to_string(),
+            View::Repository(id) => format!("/dashboard/repos/{}", encode(id)),
+            View::Person(name) => format!("/dashboard/people/{}", encode(name)),
+        }
+    }
+
+    /// Atom feed of the same commits, if there is one.
+    fn feed_path(&self) -> Option<String> {
+        let kind = match self {
+            View::Feed => FeedKind::All,
+            View::Repository(id) => FeedKind::Repository(id.clone()),
+            View::Person(_) => return None,
+        };
+        Some(kind.path(FeedFormat::Atom))
+    }
+}
+
+/// A dashboard page to render.
+pub struct Page<'a> {
+    pub view: &'a View,
+    pub query: &'a DashboardQuery,
+    /// Stored commits, or `None` when gf-server keeps no history
+    pub commits: Option<&'a CommitPage>,
+    pub username: &'a str,
+    /// Display names of repositories
+    pub repositories: &'a RepositoryRules,
+}
+
+/// Escape text for use in HTML content and quoted attributes.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn encode(component: &str) -> String {
+    utf8_percent_encode(component, URL_COMPONENT).to_string()
+}
+
+/// Commit time as shown on the dashboard.
+fn format_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 0 auto; max-width: 72rem; padding: 1rem; color: #222; }
+header { display: flex; justify-content: space-between; align-items: baseline; }
+header a { color: inherit; text-decoration: none; }
+form.filters { display: flex; gap: .5rem; flex-wrap: wrap; margin: 1rem 0; }
+table { border-collapse: collapse; width: 100%; }
+th, td { text-align: left; padding: .3rem .5rem; border-bottom: 1px solid #ddd; vertical-align: top; }
+td.when, td.hash { white-space: nowrap; font-family: ui-monospace, monospace; }
+.note { color: #666; }
+";
+
+/// Adds commits from the live stream to the top of the table when they
+/// match the page's filters. The page works without it.
+const LIVE_SCRIPT: &str = r#"
+(function () {
+  var rows = document.getElementById("commits");
+  if (!rows || !rows.dataset.live || !window.EventSource) return;
+  var f = rows.dataset;
+  function has(text, part) { return !part || text.toLowerCase().indexOf(part.toLowerCase()) >= 0; }
+  function webUrl(c) {
+    var url = c.repository_url.replace(/\/+$/, "").replace(/\.git$/, "");
+    return /^https?:\/\//.test(url) ? url + "/commit/" + c.hash : null;
+  }
+  function cell(row, cls, text, href) {
+    var td = row.insertCell(-1);
+    td.className = cls;
+    var node = td;
+    if (href) { node = document.createElement("a"); node.href = href; td.appendChild(node); }
+    node.textContent = text;
+  }
+  new EventSource("/api/stream").addEventListener("commit", function (e) {
+    var event = JSON.parse(e.data), c = event.commit, repo = event.repository;
+    if (!has(c.repository_url, f.repo) || (f.repoId && repo.id !== f.repoId)) return;
+    if (!(has(c.author_name, f.author) || has(c.author_email, f.author))) return;
+    if (f.branch && c.branch !== f.branch) return;
+    var empty = document.getElementById("empty");
+    if (empty) empty.remove();
+    var row = rows.insertRow(0);
+    cell(row, "when", new Date(c.timestamp * 1000).toISOString().slice(0, 16).replace("T", " ") + " UTC");
+    cell(row, "repo", repo.name, "/dashboard/repos/" + encodeURIComponent(repo.id));
+    cell(row, "branch", c.branch, "?branch=" + encodeURIComponent(c.branch));
+    cell(row, "author", c.author_name, "/dashboard/people/" + encodeURIComponent(c.author_name));
+    cell(row, "hash", c.short_hash, webUrl(c));
+    cell(row, "message", c.message.split("\n")[0]);
+  });
+})();
+"#;
+
+fn layout(
+    title: &str,
+    username: Option<&str>,
+    refresh: bool,
+    feed: Option<&str>,
+    body: &str,
+) -> String {
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{} - git-friends</title>\n<style>{}</style>\n",
+        escape(title),
+        STYLE
+    );
+    if let Some(feed) = feed {
+        let _ = writeln!(
+            html,
+            "<link rel=\"alternate\" type=\"application/atom+xml\" href=\"{}\">",
+            escape(feed)
+        );
+    }
+    if refresh {
+        html.push_str("<noscript><meta http-equiv=\"refresh\" content=\"60\"></noscript>\n");
+    }
+    html.push_str("</head>\n<body>\n<header><h1><a href=\"/dashboard\">git-friends</a></h1>");
+    if let Some(username) = username {
+        let _ = write!(
+            html,
+            "<form method=\"post\" action=\"/dashboard/logout\">{} \
+             <button type=\"submit\">Sign out</button></form>",
+            escape(username)
+        );
+    }
+    html.push_str("</header>\n");
+    html.push_str(body);
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn commit_row(html: &mut String, page: &Page, stored: &StoredCommit) {
+    let commit = &stored.commit;
+    let repo = repository_id(&commit.repository_url);
+    let name = page.repositories.resolve(&commit.repository_url).name;
+    let _ = write!(
+        html,
+        "<tr><td class=\"when\">{}</td>\
+         <td class=\"repo\"><a href=\"/dashboard/repos/{}\" title=\"{}\">{}</a></td>\
+         <td class=\"branch\"><a href=\"{}?branch={}\">{}</a></td>\
+         <td class=\"author\"><a href=\"/dashboard/people/{}\">{}</a></td>",
+        format_time(commit.timestamp),
+        encode(&repo),
+        escape(&commit.repository_url),
+        escape(name.as_deref().unwrap_or(&repo)),
+        page.view.path(),
+        encode(&commit.branch),
+        escape(&commit.branch),
+        encode(&commit.author_name),
+        escape(&commit.author_name),
+    );
+    match commit.web_url() {
+        Some(url) => {
+            let _ = write!(
+                html,
+                "<td class=\"hash\"><a href=\"{}\">{}</a></td>",
+                escape(&url),
+                escape(&commit.short_hash)
+            );
+        }
+        None => {
+            let _ = write!(
+                html,
+                "<td class=\"hash\">{}</td>",
+                escape(&commit.short_hash)
+            );
+        }
+    }
+    let _ = writeln!(
+        html,
+        "<td class=\"message\">{}</td></tr>",
+        escape(commit.subject())
+    );
+}
+
+/// Query string of the page's filters, with `offset`.
+fn page_link(view: &View, query: &DashboardQuery, offset: u32) -> String {
+    let mut link = format!("{}?offset={}", view.path(), offset);
+    for (name, value) in [
+        ("repo", &query.repo),
+        ("author", &query.author),
+        ("branch", &query.branch),
+    ] {
+        if let Some(value) = non_empty(value) {
+            let _ = write!(link, "&{}={}", name, encode(&value));
+        }
+    }
+    link
+}
+
+/// Render a list of commits with its filter form.
+pub fn render(page: &Page) -> String {
+    let view = page.view;
+    let query = page.query;
+    let effective = query.commit_query(view);
+    let live = query.offset.unwrap_or(0) == 0;
+
+    let mut body = String::new();
+    let _ = writeln!(body, "<h2>{}</h2>", escape(&view.title()));
+    let _ = write!(
+        body,
+        "<form class=\"filters\" method=\"get\" action=\"{}\">",
+        view.path()
+    );
+    for (name, label, value) in [
+        ("repo", "Repository", &query.repo),
+        ("author", "Person", &query.author),
+        ("branch", "Branch", &query.branch),
+    ] {
+        let _ = write!(
+            body,
+            "<input name=\"{}\" placeholder=\"{}\" value=\"{}\">",
+            name,
+            label,
+            escape(value.as_deref().unwrap_or(""))
+        );
+    }
+    body.push_str("<button type=\"submit\">Filter</button></form>\n");
+
+    let _ = write!(
+        body,
+        "<table>\n<thead><tr><th>When</th><th>Repository</th><th>Branch</th>\
+         <th>Author</th><th>Commit</th><th>Message</th></tr></thead>\n\
+         <tbody id=\"commits\" data-repo=\"{}\" data-repo-id=\"{}\" data-author=\"{}\" \
+         data-branch=\"{}\"{}>\n",
+        escape(effective.repo.as_deref().unwrap_or("")),
+        escape(effective.repo_id.as_deref().unwrap_or("")),
+        escape(effective.author.as_deref().unwrap_or("")),
+        escape(effective.branch.as_deref().unwrap_or("")),
+        if live { " data-live=\"1\"" } else { "" }
+    );
+    let commits = page.commits.map(|p| p.commits.as_slice()).unwrap_or(&[]);
+    for stored in commits {
+        commit_row(&mut body, page, stored);
+    }
+    if commits.is_empty() {
+        let note = if page.commits.is_some() {
+            "No commits yet."
+        } else {
+            "Commit history is not enabled; new commits appear here as they arrive."
+        };
+        let _ = writeln!(
+            body,
+            "<tr id=\"empty\"><td colspan=\"6\" class=\"note\">{}</td></tr>",
+            note
+        );
+    }
+    body.push_str("</tbody>\n</table>\n");
+
+    if let Some(commits) = page.commits {
+        body.push_str("<p>");
+        if commits.offset > 0 {
+            let _ = write!(
+                body,
+                "<a href=\"{}\">Newest</a> ",
+                escape(&page_link(view, query, 0))
+            );
+        }
+        if let Some(next) = commits.next_offset {
+            let _ = write!(
+                body,
+                "<a href=\"{}\">Older commits</a>",
+                escape(&page_link(view, query, next))
+            );
+        }
+        body.push_str("</p>\n");
+    }
+    let _ = writeln!(body, "<script>{}</script>", LIVE_SCRIPT);
+
+    let feed = view.feed_path();
+    layout(
+        &view.title(),
+        Some(page.username),
+        live,
+        feed.as_deref(),
+        &body,
+    )
+}
+
+/// Render the form that signs in with a token.
+pub fn render_login(error: Option<&str>) -> String {
+    let mut body = String::from("<h2>Sign in</h2>\n");
+    if let Some(error) = error {
+        let _ = writeln!(body, "<p class=\"note\">{}</p>", escape(error));
+    }
+    body.push_str(
+        "<form method=\"post\" action=\"/dashboard/login\">\
+         <input type=\"password\" name=\"token\" placeholder=\"Token\" autofocus required> \
+         <button type=\"submit\">Sign in</button></form>\n",
+    );
+    layout("Sign in", None, false, None, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GitConfig;
+    use crate::git::CommitInfo;
+    use std::collections::HashMap;
+
+    fn stored(message: &str) -> StoredCommit {
+        StoredCommit {
+            id: 1,
+            received_at: 1700000000,
+            username: Some("bob".to_string()),
+            tenant: None,
+            commit: CommitInfo {
+                author_name: "Bob Smith".to_string(),
+                author_email: "bob@example.com".to_string(),
+                message: message.to_string(),
+                repository_url: "https://github.com/acme/widgets.git".to_string(),
+                branch: "feature/x".to_string(),
+                ..CommitInfo::fixture("abcdef1234567890")
+            },
+        }
+    }
+
+    #[test]
+    fn test_render_escapes_and_links() {
+        let commits = CommitPage {
+            commits: vec![stored("Fix <script>alert(1)</script>\n\nBody")],
+            total: 120,
+            limit: 50,
+            offset: 0,
+            next_offset: Some(50),
+        };
+        let query = DashboardQuery {
+            branch: Some("main".to_string()),
+            ..Default::default()
+        };
+        let repositories = RepositoryRules::new(&GitConfig {
+            hook_script_path: None,
+            repository_mappings: HashMap::from([(
+                "https://github.com/acme/widgets".to_string(),
+                "Widgets".to_string(),
+            )]),
+            repository_rules: vec![],
+        });
+        let html = render(&Page {
+            view: &View::Feed,
+            query: &query,
+            commits: Some(&commits),
+            username: "bob",
+            repositories: &repositories,
+        });
+
+        assert!(html.contains("Fix &lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!html.contains("Body"));
+        assert!(html.contains("2023-11-14 22:13 UTC"));
+        assert!(html.contains("href=\"/dashboard/repos/github.com_acme_widgets.git\""));
+        assert!(html.contains("\">Widgets</a>"));
+        assert!(html.contains("href=\"/dashboard/people/Bob%20Smith\""));
+        assert!(html.contains("href=\"https://github.com/acme/widgets/commit/abcdef1234567890\""));
+        assert!(html.contains("href=\"/dashboard?offset=50&amp;branch=main\""));
+        assert!(html.contains("data-branch=\"main\" data-live=\"1\""));
+        assert!(html.contains("type=\"application/atom+xml\" href=\"/feeds/all.atom\""));
+
+        let html = render(&Page {
+            view: &View::Person("Bob".to_string()),
+            query: &DashboardQuery::default(),
+            commits: None,
+            username: "bob",
+            repositories: &RepositoryRules::default(),
+        });
+        assert!(html.contains("Commit history is not enabled"));
+        assert!(html.contains("data-author=\"Bob\""));
+    }
+
+    #[test]
+    fn test_commit_query_for_views() {
+        let query = DashboardQuery {
+            repo: Some("  ".to_string()),
+            author: Some("alice".to_string()),
+            branch: Some(String::new()),
+            offset: Some(50),
+        };
+
+        let feed = query.commit_query(&View::Feed);
+        assert_eq!(feed.repo, None);
+        assert_eq!(feed.author.as_deref(), Some("alice"));
+        assert_eq!(feed.branch, None);
+        assert_eq!((feed.limit, feed.offset), (Some(PAGE_SIZE), Some(50)));
+
+        let repo = query.commit_query(&View::Repository("github.com_acme_widgets".to_string()));
+        assert_eq!(repo.repo_id.as_deref(), Some("github.com_acme_widgets"));
+
+        let person = query.commit_query(&View::Person("Bob".to_string()));
+        assert_eq!(person.author.as_deref(), Some("Bob"));
+    }
+}
@@ -0,0 +1,9 @@
+use crate::errors::Result;
+use rusqlite::{params, Connection};
+
+/// Whether `table` has `column`, for adding columns to older databases.
+pub(crate) fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    Ok(conn
+        .prepare("SELECT name FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists(params![table, column])?)
+}
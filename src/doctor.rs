@@ -0,0 +1,265 @@
+use crate::errors::Result;
+use git2::Repository;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Outcome of one diagnostic check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+    /// Not run, usually because an earlier check failed
+    Skip,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+            Status::Skip => "SKIP",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// How to fix a failure or warning
+    pub hint: Option<String>,
+}
+
+impl Check {
+    pub fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    pub fn warn(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check {
+            status: Status::Warn,
+            hint: Some(hint.into()),
+            ..Check::pass(name, detail)
+        }
+    }
+
+    pub fn fail(name: &'static str, detail: impl Into<String>, hint: impl Into<String>) -> Self {
+        Check {
+            status: Status::Fail,
+            hint: Some(hint.into()),
+            ..Check::pass(name, detail)
+        }
+    }
+
+    pub fn skip(name: &'static str, detail: impl Into<String>) -> Self {
+        Check {
+            status: Status::Skip,
+            ..Check::pass(name, detail)
+        }
+    }
+}
+
+/// Checks in the order they ran.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn add(&mut self, check: Check) {
+        self.checks.push(check);
+    }
+
+    pub fn count(&self, status: Status) -> usize {
+        self.checks.iter().filter(|c| c.status == status).count()
+    }
+
+    pub fn passed(&self) -> bool {
+        self.count(Status::Fail) == 0
+    }
+
+    /// One line per check, hints below the checks they belong to, and a
+    /// summary line.
+    pub fn render(&self) -> String {
+        let width = self.checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+        let mut out = String::new();
+        for check in &self.checks {
+            let _ = writeln!(
+                out,
+                "[{}] {:width$}  {}",
+                check.status.as_str(),
+                check.name,
+                check.detail
+            );
+            if let Some(hint) = &check.hint {
+                let _ = writeln!(out, "       {:width$}  hint: {}", "", hint);
+            }
+        }
+        let _ = writeln!(
+            out,
+            "\n{} passed, {} warnings, {} failed, {} skipped",
+            self.count(Status::Pass),
+            self.count(Status::Warn),
+            self.count(Status::Fail),
+            self.count(Status::Skip)
+        );
+        out
+    }
+}
+
+/// The post-commit hook of a repository.
+#[derive(Debug)]
+pub struct Hook {
+    pub path: PathBuf,
+    /// Contents of the hook, `None` when there is none
+    pub script: Option<String>,
+    pub executable: bool,
+}
+
+impl Hook {
+    /// Find the post-commit hook of the repository containing `dir`,
+    /// honouring `core.hooksPath`.
+    pub fn find(dir: &Path) -> Result<Hook> {
+        let repo = Repository::discover(dir)?;
+        let hooks_dir = match repo.config()?.get_path("core.hooksPath") {
+            Ok(path) if path.is_relative() => repo.workdir().unwrap_or(repo.path()).join(path),
+            Ok(path) => path,
+            Err(_) => repo.path().join("hooks"),
+        };
+        let path = hooks_dir.join("post-commit");
+        let script = std::fs::read_to_string(&path).ok();
+        let executable = is_executable(&path);
+        Ok(Hook {
+            path,
+            script,
+            executable,
+        })
+    }
+
+    /// Value of a `NAME="value"` assignment in the hook, as written by
+    /// install-gf-hook.sh.
+    pub fn variable(&self, name: &str) -> Option<&str> {
+        self.script.as_deref()?.lines().find_map(|line| {
+            let value = line.trim().strip_prefix(name)?.strip_prefix('=')?;
+            let value = value.trim_matches(|c| c == '"' || c == '\'');
+            (!value.is_empty()).then_some(value)
+        })
+    }
+
+    pub fn check(&self) -> Check {
+        const NAME: &str = "Git hook";
+        let path = self.path.display();
+        let Some(script) = &self.script else {
+            return Check::fail(
+                NAME,
+                format!("no post-commit hook at {}", path),
+                "run install-gf-hook.sh --server-url URL --token TOKEN in the repository",
+            );
+        };
+        if !script.contains("gf-hook") {
+            return Check::fail(
+                NAME,
+                format!("{} does not run gf-hook", path),
+                "call gf-hook from the hook, see example-post-commit",
+            );
+        }
+        if !self.executable {
+            return Check::fail(
+                NAME,
+                format!("{} is not executable", path),
+                format!("chmod +x {}", path),
+            );
+        }
+        match self.variable("GF_HOOK_PATH") {
+            Some(gf_hook) if !is_executable(Path::new(gf_hook)) => Check::fail(
+                NAME,
+                format!("{} runs {}, which is missing", path, gf_hook),
+                "build gf-hook with `cargo build --release` or reinstall the hook with \
+                 --hook-path",
+            ),
+            _ => Check::pass(NAME, format!("{} runs gf-hook", path)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_report_render() {
+        let mut report = Report::default();
+        report.add(Check::pass("Configuration", "git-friends.toml is valid"));
+        report.add(Check::fail(
+            "MQTT",
+            "connection refused",
+            "start the broker",
+        ));
+        report.add(Check::skip("IRC", "no topic to check"));
+
+        assert!(!report.passed());
+        assert_eq!(
+            report.render(),
+            "[PASS] Configuration  git-friends.toml is valid\n\
+             [FAIL] MQTT           connection refused\n\
+             \x20                     hint: start the broker\n\
+             [SKIP] IRC            no topic to check\n\
+             \n1 passed, 0 warnings, 1 failed, 1 skipped\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hook_check() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        Repository::init(temp_dir.path()).unwrap();
+        let hook = Hook::find(temp_dir.path()).unwrap();
+        assert_eq!(hook.check().status, Status::Fail);
+
+        let path = temp_dir.path().join(".git/hooks/post-commit");
+        let gf_hook = temp_dir.path().join("gf-hook");
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/bash\nGF_HOOK_PATH=\"{}\"\nGF_SERVER_URL=\"http://gf:8080\"\n\
+                 \"$GF_HOOK_PATH\" --server-url \"$GF_SERVER_URL\"\n",
+                gf_hook.display()
+            ),
+        )
+        .unwrap();
+        let hook = Hook::find(temp_dir.path()).unwrap();
+        assert!(hook.check().detail.ends_with("is not executable"));
+        assert_eq!(hook.variable("GF_SERVER_URL"), Some("http://gf:8080"));
+        assert_eq!(hook.variable("GF_TOKEN"), None);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let hook = Hook::find(temp_dir.path()).unwrap();
+        assert!(hook.check().detail.ends_with("which is missing"));
+
+        std::fs::write(&gf_hook, "").unwrap();
+        std::fs::set_permissions(&gf_hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(hook.check().status, Status::Pass);
+    }
+}
@@ -10,6 +10,9 @@ pub enum GitFriendsError {
     #[error("MQTT error: {0}")]
     Mqtt(#[from] rumqttc::ClientError),
 
+    #[error("MQTT protocol error: {0}")]
+    MqttProtocol(#[from] rumqttc::mqttbytes::Error),
+
     #[error("IRC error: {0}")]
     Irc(#[from] irc::error::Error),
 
@@ -22,6 +25,12 @@ pub enum GitFriendsError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    #[error("Metrics error: {0}")]
+    Metrics(#[from] prometheus::Error),
+
     #[error("Config error: {0}")]
     Config(#[from] config::ConfigError),
 
@@ -31,6 +40,9 @@ pub enum GitFriendsError {
     #[error("Invalid configuration: {0}")]
     InvalidConfig(String),
 
+    #[error("Publish failed: {0}")]
+    Publish(String),
+
     #[error("Connection failed: {0}")]
     ConnectionFailed(String),
 
@@ -0,0 +1,120 @@
+use crate::clock::unix_now;
+use crate::errors::{GitFriendsError, Result};
+use crate::git::CommitInfo;
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Version of the envelope and payload format published on MQTT. Bump it
+/// whenever a field is removed or changes meaning; adding optional fields
+/// does not require a new version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+pub const EVENT_TYPE_COMMIT: &str = "commit";
+
+/// Event types tokens can be scoped to. gf-server currently only accepts
+/// commits; the others are reserved so scopes can be written ahead of time.
+pub const EVENT_TYPES: &[&str] = &[EVENT_TYPE_COMMIT, "ref", "ci-status"];
+
+/// Wrapper published around every event so consumers can detect the format.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct EventEnvelope {
+    pub schema_version: u32,
+    /// Unique id of this event
+    pub event_id: String,
+    pub event_type: String,
+    /// Identifies the gf-server instance that accepted the event
+    pub producer: String,
+    /// Unix timestamp at which the producer accepted the event
+    pub received_at: i64,
+    pub payload: CommitInfo,
+}
+
+impl EventEnvelope {
+    pub fn commit(commit_info: CommitInfo, producer: &str) -> Self {
+        EventEnvelope {
+            schema_version: SCHEMA_VERSION,
+            event_id: Uuid::new_v4().to_string(),
+            event_type: EVENT_TYPE_COMMIT.to_string(),
+            producer: producer.to_string(),
+            received_at: unix_now(),
+            payload: commit_info,
+        }
+    }
+
+    /// Parse either an enveloped event or a legacy bare `CommitInfo` payload.
+    /// Legacy payloads are returned with `schema_version` 0.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(json)?;
+
+        if value.get("schema_version").is_some() && value.get("payload").is_some() {
+            let envelope: EventEnvelope = serde_json::from_value(value)?;
+            if envelope.event_type != EVENT_TYPE_COMMIT {
+                return Err(GitFriendsError::Unknown(format!(
+                    "Unsupported event type: {}",
+                    envelope.event_type
+                )));
+            }
+            if envelope.schema_version > SCHEMA_VERSION {
+                log::warn!(
+                    "Received event with newer schema version {} (supported: {})",
+                    envelope.schema_version,
+                    SCHEMA_VERSION
+                );
+            }
+            return Ok(envelope);
+        }
+
+        let commit_info: CommitInfo = serde_json::from_value(value)?;
+        Ok(EventEnvelope {
+            schema_version: 0,
+            event_id: String::new(),
+            event_type: EVENT_TYPE_COMMIT.to_string(),
+            producer: String::new(),
+            received_at: 0,
+            payload: commit_info,
+        })
+    }
+}
+
+/// JSON Schema describing `EventEnvelope`, as served by gf-server.
+pub fn event_json_schema() -> serde_json::Value {
+    serde_json::to_value(schema_for!(EventEnvelope)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_roundtrip() {
+        let envelope = EventEnvelope::commit(
+            CommitInfo::fixture("abcdef1234567890"),
+            "git-friends/server",
+        );
+        let json = serde_json::to_string(&envelope).unwrap();
+
+        let parsed = EventEnvelope::from_json(&json).unwrap();
+        assert_eq!(parsed.schema_version, SCHEMA_VERSION);
+        assert_eq!(parsed.event_id, envelope.event_id);
+        assert_eq!(parsed.producer, "git-friends/server");
+        assert_eq!(parsed.payload.hash, "abcdef1234567890");
+    }
+
+    #[test]
+    fn test_legacy_payload() {
+        let json = serde_json::to_string(&CommitInfo::fixture("abcdef1234567890")).unwrap();
+
+        let parsed = EventEnvelope::from_json(&json).unwrap();
+        assert_eq!(parsed.schema_version, 0);
+        assert_eq!(parsed.payload.short_hash, "abcdef1");
+    }
+
+    #[test]
+    fn test_schema_describes_payload() {
+        let schema = event_json_schema();
+        assert_eq!(schema["title"], "EventEnvelope");
+        assert!(schema["properties"]["payload"].is_object());
+        assert!(schema["$defs"]["CommitInfo"]["properties"]["files_changed"].is_object());
+    }
+}
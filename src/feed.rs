@@ -0,0 +1,340 @@
+use crate::dashboard::{encode, escape};
+use crate::history::{CommitQuery, StoredCommit};
+use crate::mqtt::repository_id;
+use crate::repository::RepositoryRules;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// Entries per feed.
+pub const FEED_SIZE: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+}
+
+impl FeedFormat {
+    /// Split a path segment such as `github.com_acme_widgets.atom` into the
+    /// name and the format its extension asks for.
+    pub fn split(segment: &str) -> Option<(&str, FeedFormat)> {
+        if let Some(name) = segment.strip_suffix(".atom") {
+            Some((name, FeedFormat::Atom))
+        } else {
+            segment
+                .strip_suffix(".rss")
+                .map(|name| (name, FeedFormat::Rss))
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "application/atom+xml; charset=utf-8",
+            FeedFormat::Rss => "application/rss+xml; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            FeedFormat::Atom => "atom",
+            FeedFormat::Rss => "rss",
+        }
+    }
+}
+
+/// Which commits a feed lists.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeedKind {
+    /// Every commit the reader may see
+    All,
+    /// Commits of the repository with this id
+    Repository(String),
+    /// Commits sent by this gf-server user
+    User(String),
+}
+
+impl FeedKind {
+    pub fn commit_query(&self) -> CommitQuery {
+        let mut query = CommitQuery {
+            limit: Some(FEED_SIZE),
+            ..Default::default()
+        };
+        match self {
+            FeedKind::All => {}
+            FeedKind::Repository(id) => query.repo_id = Some(id.clone()),
+            FeedKind::User(name) => query.user = Some(name.clone()),
+        }
+        query
+    }
+
+    fn title(&self) -> String {
+        match self {
+            FeedKind::All => "git-friends: recent commits".to_string(),
+            FeedKind::Repository(id) => format!("git-friends: commits to {}", id),
+            FeedKind::User(name) => format!("git-friends: commits sent by {}", name),
+        }
+    }
+
+    pub fn path(&self, format: FeedFormat) -> String {
+        match self {
+            FeedKind::All => format!("/feeds/all.{}", format.extension()),
+            FeedKind::Repository(id) => {
+                format!("/feeds/repo/{}.{}", encode(id), format.extension())
+            }
+            FeedKind::User(name) => {
+                format!("/feeds/user/{}.{}", encode(name), format.extension())
+            }
+        }
+    }
+
+    /// Dashboard page listing the same commits.
+    fn dashboard_path(&self) -> String {
+        match self {
+            FeedKind::Repository(id) => format!("/dashboard/repos/{}", encode(id)),
+            FeedKind::All | FeedKind::User(_) => "/dashboard".to_string(),
+        }
+    }
+}
+
+/// A feed to render.
+pub struct Feed<'a> {
+    pub kind: &'a FeedKind,
+    pub format: FeedFormat,
+    /// Scheme and host that links in the feed start with
+    pub base_url: &'a str,
+    /// Newest first
+    pub commits: &'a [StoredCommit],
+    /// Display names of repositories
+    pub repositories: &'a RepositoryRules,
+}
+
+/// Stable entry id of a commit. A commit sent again, e.g. when pushed to a
+/// second branch, keeps its id.
+fn entry_id(stored: &StoredCommit) -> String {
+    format!(
+        "urn:git-friends:commit:{}:{}",
+        repository_id(&stored.commit.repository_url),
+        stored.commit.hash
+    )
+}
+
+/// Escape text for XML, dropping the control characters XML 1.0 forbids.
+fn xml(text: &str) -> String {
+    let allowed: String = text
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    escape(&allowed)
+}
+
+fn time(timestamp: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(timestamp, 0).unwrap_or_default()
+}
+
+fn entry_text(stored: &StoredCommit, repositories: &RepositoryRules) -> String {
+    let commit = &stored.commit;
+    let repository = match repositories.resolve(&commit.repository_url).name {
+        Some(name) => format!("{} ({})", name, commit.repository_url),
+        None => commit.repository_url.clone(),
+    };
+    let mut text = format!(
+        "{}\n\nRepository: {}\nBranch: {}\nCommit: {}",
+        commit.message.trim_end(),
+        repository,
+        commit.branch,
+        commit.hash
+    );
+    if let Some(username) = &stored.username {
+        let _ = write!(text, "\nSent by: {}", username);
+    }
+    text
+}
+
+/// Render the feed as Atom or RSS 2.0.
+pub fn render(feed: &Feed) -> String {
+    let base = feed.base_url.trim_end_matches('/');
+    let self_url = format!("{}{}", base, feed.kind.path(feed.format));
+    let page_url = format!("{}{}", base, feed.kind.dashboard_path());
+    let updated = time(
+        feed.commits
+            .iter()
+            .map(|stored| stored.received_at)
+            .max()
+            .unwrap_or(0),
+    );
+
+    // The same commit can be recorded once per branch it reaches
+    let mut seen = HashSet::new();
+    let commits = feed
+        .commits
+        .iter()
+        .filter(|stored| seen.insert(entry_id(stored)));
+
+    let mut xml_out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    match feed.format {
+        FeedFormat::Atom => {
+            let _ = write!(
+                xml_out,
+                "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n<id>{}</id>\n<title>{}</title>\n\
+                 <updated>{}</updated>\n<link rel=\"self\" href=\"{}\"/>\n\
+                 <link rel=\"alternate\" type=\"text/html\" href=\"{}\"/>\n\
+                 <generator>git-friends</generator>\n",
+                xml(&self_url),
+                xml(&feed.kind.title()),
+                updated.to_rfc3339_opts(SecondsFormat::Secs, true),
+                xml(&self_url),
+                xml(&page_url)
+            );
+            for stored in commits {
+                let commit = &stored.commit;
+                let link = commit.web_url().unwrap_or_else(|| {
+                    format!(
+                        "{}/dashboard/repos/{}",
+                        base,
+                        encode(&repository_id(&commit.repository_url))
+                    )
+                });
+                let _ = write!(
+                    xml_out,
+                    "<entry>\n<id>{}</id>\n<title>{} {}</title>\n<updated>{}</updated>\n\
+                     <author><name>{}</name><email>{}</email></author>\n\
+                     <link rel=\"alternate\" href=\"{}\"/>\n<category term=\"{}\"/>\n\
+                     <content type=\"text\">{}</content>\n</entry>\n",
+                    xml(&entry_id(stored)),
+                    xml(&commit.short_hash),
+                    xml(commit.subject()),
+                    time(commit.timestamp).to_rfc3339_opts(SecondsFormat::Secs, true),
+                    xml(&commit.author_name),
+                    xml(&commit.author_email),
+                    xml(&link),
+                    xml(&commit.branch),
+                    xml(&entry_text(stored, feed.repositories))
+                );
+            }
+            xml_out.push_str("</feed>\n");
+        }
+        FeedFormat::Rss => {
+            let _ = write!(
+                xml_out,
+                "<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n<channel>\n\
+                 <title>{}</title>\n<link>{}</link>\n<description>{}</description>\n\
+                 <atom:link rel=\"self\" type=\"application/rss+xml\" href=\"{}\"/>\n\
+                 <lastBuildDate>{}</lastBuildDate>\n<generator>git-friends</generator>\n",
+                xml(&feed.kind.title()),
+                xml(&page_url),
+                xml(&feed.kind.title()),
+                xml(&self_url),
+                updated.to_rfc2822()
+            );
+            for stored in commits {
+                let commit = &stored.commit;
+                let _ = write!(
+                    xml_out,
+                    "<item>\n<guid isPermaLink=\"false\">{}</guid>\n<title>{} {}</title>\n",
+                    xml(&entry_id(stored)),
+                    xml(&commit.short_hash),
+                    xml(commit.subject())
+                );
+                if let Some(url) = commit.web_url() {
+                    let _ = writeln!(xml_out, "<link>{}</link>", xml(&url));
+                }
+                let _ = write!(
+                    xml_out,
+                    "<pubDate>{}</pubDate>\n<author>{} ({})</author>\n\
+                     <category>{}</category>\n<description>{}</description>\n</item>\n",
+                    time(commit.timestamp).to_rfc2822(),
+                    xml(&commit.author_email),
+                    xml(&commit.author_name),
+                    xml(&commit.branch),
+                    xml(&entry_text(stored, feed.repositories))
+                );
+            }
+            xml_out.push_str("</channel>\n</rss>\n");
+        }
+    }
+    xml_out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::CommitInfo;
+
+    fn stored(hash: &str, branch: &str) -> StoredCommit {
+        StoredCommit {
+            id: 1,
+            received_at: 1700000100,
+            username: Some("bob".to_string()),
+            tenant: None,
+            commit: CommitInfo {
+                author_name: "Bob <Smith>".to_string(),
+                author_email: "bob@example.com".to_string(),
+                message: "Fix widgets & gadgets\u{1b}\n\nLonger body".to_string(),
+                branch: branch.to_string(),
+                ..CommitInfo::fixture(hash)
+            },
+        }
+    }
+
+    #[test]
+    fn test_render_atom() {
+        let kind = FeedKind::Repository("github.com_acme_widgets".to_string());
+        let commits = [
+            stored("abcdef1234567890", "main"),
+            stored("abcdef1234567890", "release"),
+            stored("1234567abcdef890", "main"),
+        ];
+        let atom = render(&Feed {
+            kind: &kind,
+            format: FeedFormat::Atom,
+            base_url: "https://git.example.com/",
+            commits: &commits,
+            repositories: &RepositoryRules::default(),
+        });
+
+        assert!(atom.contains(
+            "<link rel=\"self\" href=\"https://git.example.com/feeds/repo/github.com_acme_widgets.atom\"/>"
+        ));
+        assert_eq!(
+            atom.matches(
+                "<id>urn:git-friends:commit:github.com_acme_widgets:abcdef1234567890</id>"
+            )
+            .count(),
+            1
+        );
+        assert_eq!(atom.matches("<entry>").count(), 2);
+        assert!(atom.contains("<title>abcdef1 Fix widgets &amp; gadgets</title>"));
+        assert!(atom.contains("<updated>2023-11-14T22:13:20Z</updated>"));
+        assert!(atom.contains("<name>Bob &lt;Smith&gt;</name>"));
+        assert!(atom.contains(
+            "<link rel=\"alternate\" href=\"https://github.com/acme/widgets/commit/abcdef1234567890\"/>"
+        ));
+        assert!(!atom.contains('\u{1b}'));
+    }
+
+    #[test]
+    fn test_render_rss_and_paths() {
+        let kind = FeedKind::User("Bob Smith".to_string());
+        assert_eq!(kind.path(FeedFormat::Rss), "/feeds/user/Bob%20Smith.rss");
+        assert_eq!(kind.commit_query().user.as_deref(), Some("Bob Smith"));
+        assert_eq!(
+            FeedFormat::split("widgets.atom"),
+            Some(("widgets", FeedFormat::Atom))
+        );
+        assert_eq!(FeedFormat::split("widgets.json"), None);
+
+        let commits = [stored("abcdef1234567890", "main")];
+        let rss = render(&Feed {
+            kind: &kind,
+            format: FeedFormat::Rss,
+            base_url: "http://localhost:8080",
+            commits: &commits,
+            repositories: &RepositoryRules::default(),
+        });
+        assert!(rss.contains("<rss version=\"2.0\""));
+        assert!(rss.contains("<pubDate>Tue, 14 Nov 2023 22:13:20 +0000</pubDate>"));
+        assert!(rss.contains("<author>bob@example.com (Bob &lt;Smith&gt;)</author>"));
+        assert!(rss.contains("Sent by: bob"));
+    }
+}
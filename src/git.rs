@@ -1,9 +1,11 @@
+use crate::clock::unix_now;
 use crate::errors::Result;
 use git2::{Commit, Oid, Repository};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct CommitInfo {
     pub hash: String,
     pub short_hash: String,
@@ -16,6 +18,84 @@ pub struct CommitInfo {
     pub repository_url: String,
     pub branch: String,
     pub files_changed: Vec<String>,
+    /// Hashes of the parent commits, first parent first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parents: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<DiffStats>,
+    /// Format of the commit's signature; not verified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SignatureFormat>,
+}
+
+impl CommitInfo {
+    /// First line of the commit message.
+    pub fn subject(&self) -> &str {
+        self.message.lines().next().unwrap_or("")
+    }
+
+    /// Web page of the commit, for repositories hosted on GitHub, GitLab,
+    /// Gitea and other forges that serve `<repository>/commit/<hash>`.
+    pub fn web_url(&self) -> Option<String> {
+        let url = self.repository_url.trim_end_matches('/');
+        let url = url.strip_suffix(".git").unwrap_or(url);
+        (url.starts_with("https://") || url.starts_with("http://"))
+            .then(|| format!("{}/commit/{}", url, self.hash))
+    }
+
+    /// A commit by Alice to acme/widgets, for tests to adjust with struct
+    /// update syntax.
+    #[cfg(test)]
+    pub(crate) fn fixture(hash: &str) -> Self {
+        CommitInfo {
+            hash: hash.to_string(),
+            short_hash: hash.chars().take(7).collect(),
+            author_name: "Alice".to_string(),
+            author_email: "alice@example.com".to_string(),
+            committer_name: "Alice".to_string(),
+            committer_email: "alice@example.com".to_string(),
+            message: "Fix bug".to_string(),
+            timestamp: 1700000000,
+            repository_url: "https://github.com/acme/widgets".to_string(),
+            branch: "main".to_string(),
+            files_changed: vec![],
+            parents: vec![],
+            stats: None,
+            signature: None,
+        }
+    }
+}
+
+/// Size of a commit's change against its first parent.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub struct DiffStats {
+    pub files_changed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureFormat {
+    Gpg,
+    Ssh,
+    X509,
+}
+
+impl SignatureFormat {
+    fn detect(signature: &[u8]) -> Option<Self> {
+        let signature = String::from_utf8_lossy(signature);
+        let signature = signature.trim_start();
+        if signature.starts_with("-----BEGIN PGP SIGNATURE-----") {
+            Some(SignatureFormat::Gpg)
+        } else if signature.starts_with("-----BEGIN SSH SIGNATURE-----") {
+            Some(SignatureFormat::Ssh)
+        } else if signature.starts_with("-----BEGIN SIGNED MESSAGE-----") {
+            Some(SignatureFormat::X509)
+        } else {
+            None
+        }
+    }
 }
 
 pub struct GitRepository {
@@ -43,7 +123,7 @@ impl GitRepository {
         let branch = self
             .get_current_branch()
             .unwrap_or_else(|| "unknown".to_string());
-        let files_changed = self.get_changed_files(&commit)?;
+        let (files_changed, stats) = self.get_changes(&commit)?;
 
         let author = commit.author();
         let committer = commit.committer();
@@ -60,9 +140,41 @@ impl GitRepository {
             repository_url,
             branch,
             files_changed,
+            parents: commit.parent_ids().map(|id| id.to_string()).collect(),
+            stats: Some(stats),
+            signature: self.get_signature_format(commit.id()),
         })
     }
 
+    /// Fill in the changed files, stats, parents and signature of a commit
+    /// received without them. Returns false if the commit is not in this
+    /// repository.
+    pub fn enrich(&self, commit_info: &mut CommitInfo) -> Result<bool> {
+        let Ok(oid) = Oid::from_str(&commit_info.hash) else {
+            return Ok(false);
+        };
+        let commit = match self.repo.find_commit(oid) {
+            Ok(commit) => commit,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+
+        if commit_info.files_changed.is_empty() || commit_info.stats.is_none() {
+            let (files_changed, stats) = self.get_changes(&commit)?;
+            if commit_info.files_changed.is_empty() {
+                commit_info.files_changed = files_changed;
+            }
+            commit_info.stats.get_or_insert(stats);
+        }
+        if commit_info.parents.is_empty() {
+            commit_info.parents = commit.parent_ids().map(|id| id.to_string()).collect();
+        }
+        if commit_info.signature.is_none() {
+            commit_info.signature = self.get_signature_format(oid);
+        }
+        Ok(true)
+    }
+
     pub fn get_head_commit_info(&self) -> Result<CommitInfo> {
         let head = self.repo.head()?;
         let commit = head.peel_to_commit()?;
@@ -83,7 +195,7 @@ impl GitRepository {
             .and_then(|head| head.shorthand().map(|name| name.to_string()))
     }
 
-    fn get_changed_files(&self, commit: &Commit) -> Result<Vec<String>> {
+    fn get_changes(&self, commit: &Commit) -> Result<(Vec<String>, DiffStats)> {
         let mut files = Vec::new();
 
         let tree = commit.tree()?;
@@ -112,7 +224,20 @@ impl GitRepository {
             None,
         )?;
 
-        Ok(files)
+        let stats = diff.stats()?;
+        Ok((
+            files,
+            DiffStats {
+                files_changed: stats.files_changed(),
+                insertions: stats.insertions(),
+                deletions: stats.deletions(),
+            },
+        ))
+    }
+
+    fn get_signature_format(&self, oid: Oid) -> Option<SignatureFormat> {
+        let (signature, _) = self.repo.extract_signature(&oid, None).ok()?;
+        SignatureFormat::detect(&signature)
     }
 }
 
@@ -189,13 +314,13 @@ pub fn get_git_info_from_github_actions() -> Result<CommitInfo> {
             committer_name: author_name,
             committer_email: author_email,
             message,
-            timestamp: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64,
+            timestamp: unix_now(),
             repository_url,
             branch,
             files_changed: vec![], // Could be populated from GitHub API if needed
+            parents: vec![],
+            stats: None,
+            signature: None,
         })
     }
 }
@@ -255,10 +380,28 @@ mod tests {
     #[test]
     fn test_git_repository_creation() {
         let temp_dir = TempDir::new().unwrap();
-        let repo = Repository::init(temp_dir.path()).unwrap();
+        Repository::init(temp_dir.path()).unwrap();
 
         // This would normally fail because there are no commits
         // But we can test the basic functionality
         assert!(GitRepository::open(temp_dir.path()).is_ok());
     }
+
+    #[test]
+    fn test_signature_format() {
+        let detect = |s: &str| SignatureFormat::detect(s.as_bytes());
+        assert_eq!(
+            detect("-----BEGIN PGP SIGNATURE-----\n\niQ..."),
+            Some(SignatureFormat::Gpg)
+        );
+        assert_eq!(
+            detect("-----BEGIN SSH SIGNATURE-----\nU1NI..."),
+            Some(SignatureFormat::Ssh)
+        );
+        assert_eq!(
+            detect("-----BEGIN SIGNED MESSAGE-----\nMII..."),
+            Some(SignatureFormat::X509)
+        );
+        assert_eq!(detect("garbage"), None);
+    }
 }
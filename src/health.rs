@@ -0,0 +1,173 @@
+use crate::publish::PublisherHealth;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use tokio::sync::Notify;
+use tokio::time::{timeout, Duration};
+
+/// Process state behind the readiness endpoint and graceful shutdown.
+pub struct Health {
+    config_error: RwLock<Option<String>>,
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+}
+
+/// Marks a request that must finish before shutdown; released on drop.
+pub struct InFlight {
+    health: Arc<Health>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.health.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.health.idle.notify_waiters();
+        }
+    }
+}
+
+/// Result of the readiness checks, as served on `/ready`.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Readiness {
+    pub ready: bool,
+    pub publishers: Vec<PublisherHealth>,
+    /// Why the last configuration reload failed, if it did
+    pub config_error: Option<String>,
+    pub shutting_down: bool,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Health {
+            config_error: RwLock::new(None),
+            draining: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            idle: Notify::new(),
+        }
+    }
+
+    /// Record the outcome of the latest configuration load.
+    pub fn set_config_error(&self, error: Option<String>) {
+        *self.config_error.write().unwrap() = error;
+    }
+
+    /// Ready while every required publisher is connected, the configuration
+    /// loaded and the process is not shutting down.
+    pub fn readiness(&self, publishers: Vec<PublisherHealth>) -> Readiness {
+        let config_error = self.config_error.read().unwrap().clone();
+        let shutting_down = self.is_draining();
+        let connected = publishers.iter().all(|p| p.connected || !p.required);
+        Readiness {
+            ready: connected && config_error.is_none() && !shutting_down,
+            publishers,
+            config_error,
+            shutting_down,
+        }
+    }
+
+    /// Refuse new requests from now on.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Release);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Register a request, unless the process is shutting down.
+    pub fn begin_request(self: &Arc<Self>) -> Option<InFlight> {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        let guard = InFlight {
+            health: self.clone(),
+        };
+        // Checked after registering, so `drained` cannot miss the request
+        (!self.is_draining()).then_some(guard)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Wait until no requests are in flight, returning false on timeout.
+    pub async fn drained(&self, wait: Duration) -> bool {
+        timeout(wait, async {
+            loop {
+                let idle = self.idle.notified();
+                if self.in_flight() == 0 {
+                    break;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PublisherTransport;
+
+    fn publisher(name: &str, required: bool, connected: bool) -> PublisherHealth {
+        PublisherHealth {
+            name: name.to_string(),
+            transport: PublisherTransport::Mqtt,
+            required,
+            tenant: None,
+            connected,
+            last_error: None,
+        }
+    }
+
+    #[test]
+    fn test_readiness() {
+        let health = Health::new();
+        let up = || vec![publisher("mqtt", true, true)];
+        assert!(health.readiness(up()).ready);
+        assert!(!health.readiness(vec![publisher("mqtt", true, false)]).ready);
+        assert!(
+            health
+                .readiness(vec![
+                    publisher("mqtt", true, true),
+                    publisher("cache", false, false)
+                ])
+                .ready
+        );
+
+        health.set_config_error(Some("bad bind address".to_string()));
+        let readiness = health.readiness(up());
+        assert!(!readiness.ready);
+        assert_eq!(readiness.config_error.as_deref(), Some("bad bind address"));
+
+        health.set_config_error(None);
+        health.start_draining();
+        assert!(health.readiness(up()).shutting_down);
+        assert!(!health.readiness(up()).ready);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_in_flight_requests() {
+        let health = Arc::new(Health::new());
+        let request = health.begin_request().unwrap();
+
+        health.start_draining();
+        assert!(health.begin_request().is_none());
+        assert!(!health.drained(Duration::from_millis(20)).await);
+
+        let waiter = tokio::spawn({
+            let health = health.clone();
+            async move { health.drained(Duration::from_secs(2)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(request);
+        assert!(waiter.await.unwrap());
+        assert_eq!(health.in_flight(), 0);
+    }
+}
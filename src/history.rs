@@ -0,0 +1,575 @@
+use crate::clock::unix_now;
+use crate::db::has_column;
+use crate::errors::Result;
+use crate::git::CommitInfo;
+use crate::tenant::TenantScope;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Mutex;
+
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS commits (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    received_at INTEGER NOT NULL,
+    username TEXT,
+    hash TEXT NOT NULL,
+    short_hash TEXT NOT NULL,
+    author_name TEXT NOT NULL,
+    author_email TEXT NOT NULL,
+    committer_name TEXT NOT NULL,
+    committer_email TEXT NOT NULL,
+    message TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    repository_url TEXT NOT NULL,
+    branch TEXT NOT NULL,
+    files_changed TEXT NOT NULL,
+    tenant TEXT,
+    parents TEXT NOT NULL DEFAULT '[]',
+    stats TEXT,
+    signature TEXT
+);
+CREATE INDEX IF NOT EXISTS commits_repository_url ON commits (repository_url);
+CREATE INDEX IF NOT EXISTS commits_author_name ON commits (author_name);
+CREATE INDEX IF NOT EXISTS commits_timestamp ON commits (timestamp);
+CREATE INDEX IF NOT EXISTS commits_hash ON commits (hash);
+";
+
+const SELECT_COLUMNS: &str = "id, received_at, username, hash, short_hash, author_name, \
+     author_email, committer_name, committer_email, message, timestamp, repository_url, \
+     branch, files_changed, tenant, parents, stats, signature";
+
+/// Columns added after the first release, with their definitions.
+const ADDED_COLUMNS: &[(&str, &str)] = &[
+    ("tenant", "TEXT"),
+    ("parents", "TEXT NOT NULL DEFAULT '[]'"),
+    ("stats", "TEXT"),
+    ("signature", "TEXT"),
+];
+
+/// A commit event as it was accepted by gf-server.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredCommit {
+    pub id: i64,
+    pub received_at: i64,
+    pub username: Option<String>,
+    /// Tenant of the user who sent the commit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub commit: CommitInfo,
+}
+
+/// Filters accepted by `/api/commits`. Text filters are substring matches,
+/// `since` and `until` are unix timestamps compared against the commit time.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CommitQuery {
+    pub repo: Option<String>,
+    /// Exact repository id, as in topics (`github.com_acme_widgets`)
+    pub repo_id: Option<String>,
+    pub author: Option<String>,
+    pub user: Option<String>,
+    pub branch: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub q: Option<String>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+    /// Tenants whose commits are visible, set from the reader's identity
+    #[serde(skip)]
+    pub scope: TenantScope,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CommitPage {
+    pub commits: Vec<StoredCommit>,
+    pub total: u64,
+    pub limit: u32,
+    pub offset: u32,
+    pub next_offset: Option<u32>,
+}
+
+pub struct HistoryStore {
+    conn: Mutex<Connection>,
+    /// Number of most recent commits kept, all when unset
+    capacity: Option<u32>,
+}
+
+impl HistoryStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    /// An in-memory store that only keeps the `capacity` most recent commits,
+    /// for feeds and the dashboard when no history database is configured.
+    pub fn open_recent(capacity: u32) -> Result<Self> {
+        let mut store = Self::open_in_memory()?;
+        store.capacity = Some(capacity);
+        Ok(store)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        for (column, definition) in ADDED_COLUMNS {
+            if !has_column(&conn, "commits", column)? {
+                conn.execute_batch(&format!(
+                    "ALTER TABLE commits ADD COLUMN {} {}",
+                    column, definition
+                ))?;
+            }
+        }
+        Ok(HistoryStore {
+            conn: Mutex::new(conn),
+            capacity: None,
+        })
+    }
+
+    /// Store an accepted commit. A retried webhook, the same commit on the
+    /// same branch from the same user and tenant, is not stored again; its
+    /// first record is returned instead.
+    pub fn record(
+        &self,
+        commit_info: &CommitInfo,
+        username: Option<&str>,
+        tenant: Option<&str>,
+    ) -> Result<StoredCommit> {
+        let received_at = unix_now();
+        let files_changed = serde_json::to_string(&commit_info.files_changed)?;
+        let parents = serde_json::to_string(&commit_info.parents)?;
+        let stats = commit_info
+            .stats
+            .map(|s| serde_json::to_string(&s))
+            .transpose()?;
+        let signature = commit_info
+            .signature
+            .map(|s| serde_json::to_string(&s))
+            .transpose()?;
+
+        let conn = self.conn.lock().unwrap();
+        // A webhook retried after a failed publish finds its first record
+        let recorded = conn
+            .query_row(
+                &format!(
+                    "SELECT {} FROM commits WHERE repository_url = ?1 AND hash = ?2 \
+                     AND branch = ?3 AND username IS ?4 AND tenant IS ?5",
+                    SELECT_COLUMNS
+                ),
+                params![
+                    commit_info.repository_url,
+                    commit_info.hash,
+                    commit_info.branch,
+                    username,
+                    tenant
+                ],
+                row_to_stored_commit,
+            )
+            .optional()?;
+        if let Some(stored) = recorded {
+            return Ok(stored);
+        }
+
+        conn.execute(
+            "INSERT INTO commits (received_at, username, hash, short_hash, author_name, \
+             author_email, committer_name, committer_email, message, timestamp, \
+             repository_url, branch, files_changed, tenant, parents, stats, signature) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+            params![
+                received_at,
+                username,
+                commit_info.hash,
+                commit_info.short_hash,
+                commit_info.author_name,
+                commit_info.author_email,
+                commit_info.committer_name,
+                commit_info.committer_email,
+                commit_info.message,
+                commit_info.timestamp,
+                commit_info.repository_url,
+                commit_info.branch,
+                files_changed,
+                tenant,
+                parents,
+                stats,
+                signature,
+            ],
+        )?;
+
+        let id = conn.last_insert_rowid();
+        if let Some(capacity) = self.capacity {
+            conn.execute(
+                "DELETE FROM commits WHERE id <= ?1",
+                params![id - capacity as i64],
+            )?;
+        }
+
+        Ok(StoredCommit {
+            id,
+            received_at,
+            username: username.map(|s| s.to_string()),
+            tenant: tenant.map(|s| s.to_string()),
+            commit: commit_info.clone(),
+        })
+    }
+
+    pub fn query(&self, query: &CommitQuery) -> Result<CommitPage> {
+        let mut clauses = Vec::new();
+        let mut values: Vec<rusqlite::types::Value> = Vec::new();
+
+        if let Some(repo) = &query.repo {
+            clauses.push("repository_url LIKE ? ESCAPE '\\'");
+            values.push(like_pattern(repo).into());
+        }
+        if let Some(repo_id) = &query.repo_id {
+            // Same replacements as `mqtt::repository_id`
+            clauses.push(
+                "replace(replace(replace(repository_url, 'https://', ''), 'http://', ''), '/', '_') = ?",
+            );
+            values.push(repo_id.clone().into());
+        }
+        if let Some(author) = &query.author {
+            clauses.push("(author_name LIKE ? ESCAPE '\\' OR author_email LIKE ? ESCAPE '\\')");
+            values.push(like_pattern(author).into());
+            values.push(like_pattern(author).into());
+        }
+        if let Some(user) = &query.user {
+            clauses.push("username = ?");
+            values.push(user.clone().into());
+        }
+        if let Some(branch) = &query.branch {
+            clauses.push("branch = ?");
+            values.push(branch.clone().into());
+        }
+        if let Some(since) = query.since {
+            clauses.push("timestamp >= ?");
+            values.push(since.into());
+        }
+        if let Some(until) = query.until {
+            clauses.push("timestamp <= ?");
+            values.push(until.into());
+        }
+        if let Some(q) = &query.q {
+            clauses.push("message LIKE ? ESCAPE '\\'");
+            values.push(like_pattern(q).into());
+        }
+        match &query.scope {
+            TenantScope::All => {}
+            TenantScope::Untenanted => clauses.push("tenant IS NULL"),
+            TenantScope::Tenant(tenant) => {
+                clauses.push("tenant = ?");
+                values.push(tenant.clone().into());
+            }
+        }
+
+        let where_clause = if clauses.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", clauses.join(" AND "))
+        };
+
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let offset = query.offset.unwrap_or(0);
+
+        let conn = self.conn.lock().unwrap();
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM commits {}", where_clause),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM commits {} ORDER BY id DESC LIMIT {} OFFSET {}",
+            SELECT_COLUMNS, where_clause, limit, offset
+        ))?;
+        let commits = stmt
+            .query_map(params_from_iter(values.iter()), row_to_stored_commit)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let total = total as u64;
+        let next_offset = if (offset as u64 + commits.len() as u64) < total {
+            Some(offset + commits.len() as u32)
+        } else {
+            None
+        };
+
+        Ok(CommitPage {
+            commits,
+            total,
+            limit,
+            offset,
+            next_offset,
+        })
+    }
+
+    /// Events recorded after `after_id`, oldest first.
+    pub fn events_after(&self, after_id: i64, limit: u32) -> Result<Vec<StoredCommit>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM commits WHERE id > ?1 ORDER BY id ASC LIMIT ?2",
+            SELECT_COLUMNS
+        ))?;
+        let commits = stmt
+            .query_map(params![after_id, limit], row_to_stored_commit)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(commits)
+    }
+}
+
+fn row_to_stored_commit(row: &Row) -> rusqlite::Result<StoredCommit> {
+    Ok(StoredCommit {
+        id: row.get(0)?,
+        received_at: row.get(1)?,
+        username: row.get(2)?,
+        tenant: row.get(14)?,
+        commit: CommitInfo {
+            hash: row.get(3)?,
+            short_hash: row.get(4)?,
+            author_name: row.get(5)?,
+            author_email: row.get(6)?,
+            committer_name: row.get(7)?,
+            committer_email: row.get(8)?,
+            message: row.get(9)?,
+            timestamp: row.get(10)?,
+            repository_url: row.get(11)?,
+            branch: row.get(12)?,
+            files_changed: json_column(row, 13)?,
+            parents: json_column(row, 15)?,
+            stats: json_column(row, 16)?,
+            signature: json_column(row, 17)?,
+        },
+    })
+}
+
+/// Parse a column holding JSON; NULL parses as `null`.
+fn json_column<T: serde::de::DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let json: Option<String> = row.get(index)?;
+    serde_json::from_str(json.as_deref().unwrap_or("null")).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn like_pattern(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::{DiffStats, SignatureFormat};
+
+    fn commit(hash: &str, author: &str, repo: &str, branch: &str, timestamp: i64) -> CommitInfo {
+        let email = format!("{}@example.com", author.to_lowercase());
+        CommitInfo {
+            author_name: author.to_string(),
+            author_email: email.clone(),
+            committer_name: author.to_string(),
+            committer_email: email,
+            message: format!("Commit {} by {}", hash, author),
+            timestamp,
+            repository_url: repo.to_string(),
+            branch: branch.to_string(),
+            files_changed: vec!["src/lib.rs".to_string()],
+            ..CommitInfo::fixture(hash)
+        }
+    }
+
+    #[test]
+    fn test_record_and_filter() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        store
+            .record(
+                &commit(
+                    "aaaaaaa1",
+                    "Bob",
+                    "https://github.com/acme/widgets",
+                    "main",
+                    100,
+                ),
+                Some("bob"),
+                None,
+            )
+            .unwrap();
+        let gadget = commit(
+            "bbbbbbb2",
+            "Alice",
+            "https://github.com/acme/gadgets",
+            "dev",
+            200,
+        );
+        let first = store.record(&gadget, Some("alice"), Some("acme")).unwrap();
+        // Retried webhooks do not add the commit twice
+        let retried = store.record(&gadget, Some("alice"), Some("acme")).unwrap();
+        assert_eq!(retried.id, first.id);
+
+        let page = store
+            .query(&CommitQuery {
+                author: Some("bob".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.commits[0].commit.hash, "aaaaaaa1");
+        assert_eq!(page.commits[0].commit.files_changed, vec!["src/lib.rs"]);
+
+        let page = store
+            .query(&CommitQuery {
+                repo: Some("acme/gadgets".to_string()),
+                since: Some(150),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.commits[0].username.as_deref(), Some("alice"));
+
+        let page = store
+            .query(&CommitQuery {
+                repo_id: Some("github.com_acme_widgets".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 1);
+        assert_eq!(page.commits[0].commit.hash, "aaaaaaa1");
+
+        for (scope, hash) in [
+            (TenantScope::Tenant("acme".to_string()), "bbbbbbb2"),
+            (TenantScope::Untenanted, "aaaaaaa1"),
+        ] {
+            let page = store
+                .query(&CommitQuery {
+                    scope,
+                    ..Default::default()
+                })
+                .unwrap();
+            assert_eq!(page.total, 1);
+            assert_eq!(page.commits[0].commit.hash, hash);
+        }
+
+        let page = store
+            .query(&CommitQuery {
+                q: Some("100%".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 0);
+    }
+
+    #[test]
+    fn test_record_stores_each_push() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        let fix = commit("ccccccc3", "Bob", "repo", "dev", 100);
+        let first = store.record(&fix, Some("bob"), None).unwrap();
+        assert_eq!(store.record(&fix, Some("bob"), None).unwrap().id, first.id);
+
+        let merged = CommitInfo {
+            branch: "main".to_string(),
+            ..fix.clone()
+        };
+        let pushes = [
+            store.record(&merged, Some("bob"), None).unwrap(),
+            store.record(&fix, Some("alice"), None).unwrap(),
+            store.record(&fix, None, None).unwrap(),
+            store.record(&fix, Some("bob"), Some("acme")).unwrap(),
+        ];
+        for (i, push) in pushes.iter().enumerate() {
+            assert_eq!(push.id, first.id + 1 + i as i64);
+        }
+        assert_eq!(store.query(&CommitQuery::default()).unwrap().total, 5);
+    }
+
+    #[test]
+    fn test_enriched_details_roundtrip() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        let enriched = CommitInfo {
+            parents: vec!["aaaaaaa1".to_string(), "bbbbbbb2".to_string()],
+            stats: Some(DiffStats {
+                files_changed: 1,
+                insertions: 2,
+                deletions: 3,
+            }),
+            signature: Some(SignatureFormat::Ssh),
+            ..commit("ddddddd4", "Bob", "repo", "main", 100)
+        };
+        store.record(&enriched, None, None).unwrap();
+        store
+            .record(&commit("eeeeeee5", "Bob", "repo", "main", 200), None, None)
+            .unwrap();
+
+        let stored = store.events_after(0, 10).unwrap();
+        assert_eq!(stored[0].commit.parents, enriched.parents);
+        assert_eq!(stored[0].commit.stats, enriched.stats);
+        assert_eq!(stored[0].commit.signature, enriched.signature);
+        assert!(stored[1].commit.parents.is_empty());
+        assert_eq!(stored[1].commit.stats, None);
+        assert_eq!(stored[1].commit.signature, None);
+    }
+
+    #[test]
+    fn test_pagination_newest_first() {
+        let store = HistoryStore::open_in_memory().unwrap();
+        for i in 0..5 {
+            store
+                .record(
+                    &commit(&format!("{:07}", i), "Bob", "repo", "main", i),
+                    None,
+                    None,
+                )
+                .unwrap();
+        }
+
+        let page = store
+            .query(&CommitQuery {
+                limit: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.total, 5);
+        assert_eq!(page.commits[0].commit.hash, "0000004");
+        assert_eq!(page.next_offset, Some(2));
+
+        let page = store
+            .query(&CommitQuery {
+                limit: Some(2),
+                offset: Some(4),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.commits.len(), 1);
+        assert_eq!(page.next_offset, None);
+
+        let resumed = store.events_after(2, 10).unwrap();
+        assert_eq!(resumed.len(), 3);
+        assert_eq!(resumed[0].commit.hash, "0000002");
+    }
+
+    #[test]
+    fn test_recent_store_keeps_newest() {
+        let store = HistoryStore::open_recent(3).unwrap();
+        for i in 0..5 {
+            store
+                .record(
+                    &commit(&format!("{:07}", i), "Bob", "repo", "main", i),
+                    None,
+                    None,
+                )
+                .unwrap();
+        }
+
+        let page = store.query(&CommitQuery::default()).unwrap();
+        assert_eq!(page.total, 3);
+        assert_eq!(page.commits[0].commit.hash, "0000004");
+        assert_eq!(page.commits[2].commit.hash, "0000002");
+    }
+}
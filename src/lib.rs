@@ -1,8 +1,32 @@
+pub mod audit;
 pub mod auth;
+pub mod broker;
+pub mod clock;
 pub mod config;
+pub mod dashboard;
+pub mod db;
+pub mod doctor;
 pub mod errors;
+pub mod event;
+pub mod feed;
 pub mod git;
+pub mod health;
+pub mod history;
+pub mod metrics;
+pub mod mirror;
 pub mod mqtt;
+pub mod oidc;
+pub mod outbound;
+pub mod publish;
+pub mod ratelimit;
+pub mod reload;
+pub mod repository;
+pub mod session;
+pub mod signing;
+pub mod stream;
+pub mod tenant;
+pub mod tls;
+pub mod tokens;
 
 pub use config::Config;
 pub use errors::{GitFriendsError, Result};
@@ -0,0 +1,189 @@
+use crate::errors::Result;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Prometheus metrics exported by gf-server on `/metrics`.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    webhooks_received: IntCounter,
+    webhooks_accepted: IntCounterVec,
+    webhooks_rejected: IntCounterVec,
+    mqtt_publish: IntCounterVec,
+    mqtt_publish_duration: Histogram,
+    mqtt_connected: IntGauge,
+    publishes: IntCounterVec,
+    publisher_connected: IntGaugeVec,
+    outbound_deliveries: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("git_friends".to_string()), None)?;
+
+        let webhooks_received = IntCounter::new(
+            "webhooks_received_total",
+            "Webhook requests received by gf-server",
+        )?;
+        let webhooks_accepted = IntCounterVec::new(
+            Opts::new(
+                "webhooks_accepted_total",
+                "Webhook requests accepted, by authenticated user",
+            ),
+            &["user"],
+        )?;
+        let webhooks_rejected = IntCounterVec::new(
+            Opts::new(
+                "webhooks_rejected_total",
+                "Webhook requests rejected, by reason",
+            ),
+            &["reason"],
+        )?;
+        let mqtt_publish = IntCounterVec::new(
+            Opts::new("mqtt_publish_total", "MQTT publish attempts, by result"),
+            &["result"],
+        )?;
+        let mqtt_publish_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "mqtt_publish_duration_seconds",
+                "Time taken to hand a commit to the MQTT client",
+            )
+            .buckets(vec![0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+        )?;
+        let mqtt_connected = IntGauge::new(
+            "mqtt_connected",
+            "Whether gf-server is connected to the MQTT broker (1) or not (0)",
+        )?;
+        let publishes = IntCounterVec::new(
+            Opts::new(
+                "publish_total",
+                "Events handed to publishers, by publisher and result",
+            ),
+            &["publisher", "result"],
+        )?;
+        let publisher_connected = IntGaugeVec::new(
+            Opts::new(
+                "publisher_connected",
+                "Whether a publisher is connected (1) or not (0)",
+            ),
+            &["publisher"],
+        )?;
+        let outbound_deliveries = IntCounterVec::new(
+            Opts::new(
+                "outbound_deliveries_total",
+                "Events forwarded to outbound webhooks, by endpoint and result",
+            ),
+            &["endpoint", "result"],
+        )?;
+
+        registry.register(Box::new(webhooks_received.clone()))?;
+        registry.register(Box::new(webhooks_accepted.clone()))?;
+        registry.register(Box::new(webhooks_rejected.clone()))?;
+        registry.register(Box::new(mqtt_publish.clone()))?;
+        registry.register(Box::new(mqtt_publish_duration.clone()))?;
+        registry.register(Box::new(mqtt_connected.clone()))?;
+        registry.register(Box::new(publishes.clone()))?;
+        registry.register(Box::new(publisher_connected.clone()))?;
+        registry.register(Box::new(outbound_deliveries.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            webhooks_received,
+            webhooks_accepted,
+            webhooks_rejected,
+            mqtt_publish,
+            mqtt_publish_duration,
+            mqtt_connected,
+            publishes,
+            publisher_connected,
+            outbound_deliveries,
+        })
+    }
+
+    pub fn webhook_received(&self) {
+        self.webhooks_received.inc();
+    }
+
+    pub fn webhook_accepted(&self, user: &str) {
+        self.webhooks_accepted.with_label_values(&[user]).inc();
+    }
+
+    pub fn webhook_rejected(&self, reason: &str) {
+        self.webhooks_rejected.with_label_values(&[reason]).inc();
+    }
+
+    pub fn mqtt_published(&self, success: bool, elapsed: Duration) {
+        let result = if success { "success" } else { "failure" };
+        self.mqtt_publish.with_label_values(&[result]).inc();
+        self.mqtt_publish_duration.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn set_mqtt_connected(&self, connected: bool) {
+        self.mqtt_connected.set(connected as i64);
+    }
+
+    pub fn published(&self, publisher: &str, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.publishes.with_label_values(&[publisher, result]).inc();
+    }
+
+    pub fn set_publisher_connected(&self, publisher: &str, connected: bool) {
+        self.publisher_connected
+            .with_label_values(&[publisher])
+            .set(connected as i64);
+    }
+
+    pub fn outbound_delivered(&self, endpoint: &str, delivered: bool) {
+        let result = if delivered {
+            "delivered"
+        } else {
+            "dead_lettered"
+        };
+        self.outbound_deliveries
+            .with_label_values(&[endpoint, result])
+            .inc();
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let metrics = Metrics::new().unwrap();
+        metrics.webhook_received();
+        metrics.webhook_accepted("alice");
+        metrics.webhook_rejected("invalid_token");
+        metrics.mqtt_published(true, Duration::from_millis(2));
+        metrics.set_mqtt_connected(true);
+        metrics.published("cache", false);
+        metrics.set_publisher_connected("cache", false);
+        metrics.outbound_delivered("chat", false);
+
+        let output = metrics.render().unwrap();
+        assert!(output.contains("git_friends_webhooks_received_total 1"));
+        assert!(output.contains("git_friends_webhooks_accepted_total{user=\"alice\"} 1"));
+        assert!(output.contains("git_friends_webhooks_rejected_total{reason=\"invalid_token\"} 1"));
+        assert!(output.contains("git_friends_mqtt_publish_total{result=\"success\"} 1"));
+        assert!(output.contains("git_friends_mqtt_publish_duration_seconds_count 1"));
+        assert!(output.contains("git_friends_mqtt_connected 1"));
+        assert!(
+            output.contains("git_friends_publish_total{publisher=\"cache\",result=\"failure\"} 1")
+        );
+        assert!(output.contains("git_friends_publisher_connected{publisher=\"cache\"} 0"));
+        assert!(output.contains(
+            "git_friends_outbound_deliveries_total{endpoint=\"chat\",result=\"dead_lettered\"} 1"
+        ));
+    }
+}
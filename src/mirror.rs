@@ -0,0 +1,339 @@
+use crate::config::MirrorConfig;
+use crate::errors::Result;
+use crate::git::{CommitInfo, GitRepository};
+use crate::repository::normalize_url;
+use git2::{AutotagOption, Cred, CredentialType, FetchOptions, FetchPrune, RemoteCallbacks};
+use log::{debug, error, info, warn};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Refs copied from the source. Mirrors are bare, so nothing is checked out.
+const FETCH_REFSPECS: &[&str] = &["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"];
+
+/// Commits a mirror does not have trigger at most one fetch in this period.
+pub const ON_DEMAND_FETCH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often a fetch may ask for credentials before giving up.
+const MAX_CREDENTIAL_ATTEMPTS: u32 = 3;
+
+/// Directory name of the mirror serving `url`.
+fn mirror_dir_name(url: &str) -> String {
+    let name: String = normalize_url(url)
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.git", name)
+}
+
+struct Mirror {
+    /// Normalized `repository_url` of the commits it serves
+    url: String,
+    source: String,
+    path: PathBuf,
+    /// When the mirror was last fetched; held while fetching
+    last_fetch: Mutex<Option<Instant>>,
+    /// Set when a commit was missing, cleared by `fetch_wanted`
+    wanted: AtomicBool,
+}
+
+impl Mirror {
+    fn repository(&self) -> Result<GitRepository> {
+        GitRepository::open(&self.path)
+    }
+
+    fn fetch(&self) -> Result<()> {
+        let mut last_fetch = self.last_fetch.lock().unwrap();
+        self.fetch_locked(&mut last_fetch)
+    }
+
+    /// Fetch unless another fetch finished within `interval`.
+    fn fetch_if_stale(&self, interval: Duration) -> Result<()> {
+        let mut last_fetch = self.last_fetch.lock().unwrap();
+        if last_fetch.is_some_and(|fetched| fetched.elapsed() < interval) {
+            return Ok(());
+        }
+        self.fetch_locked(&mut last_fetch)
+    }
+
+    fn fetch_locked(&self, last_fetch: &mut Option<Instant>) -> Result<()> {
+        let repo = git2::Repository::open_bare(&self.path)?;
+        let mut remote = repo.remote_anonymous(&self.source)?;
+
+        let mut attempts = 0;
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(|url, username, allowed| {
+            attempts += 1;
+            if attempts > MAX_CREDENTIAL_ATTEMPTS {
+                return Err(git2::Error::from_str("no working credentials"));
+            }
+            credentials(url, username, allowed)
+        });
+        let mut options = FetchOptions::new();
+        options
+            .remote_callbacks(callbacks)
+            .prune(FetchPrune::On)
+            .download_tags(AutotagOption::None);
+
+        let started = Instant::now();
+        remote.fetch(FETCH_REFSPECS, Some(&mut options), None)?;
+        *last_fetch = Some(Instant::now());
+        debug!(
+            "Fetched mirror of {} in {:?}",
+            self.source,
+            started.elapsed()
+        );
+        Ok(())
+    }
+}
+
+/// Credentials from the SSH agent, or from git's credential helpers.
+fn credentials(
+    url: &str,
+    username: Option<&str>,
+    allowed: CredentialType,
+) -> std::result::Result<Cred, git2::Error> {
+    if allowed.contains(CredentialType::SSH_KEY) {
+        Cred::ssh_key_from_agent(username.unwrap_or("git"))
+    } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+        Cred::credential_helper(&git2::Config::open_default()?, url, username)
+    } else {
+        Cred::default()
+    }
+}
+
+/// Bare mirrors of the configured repositories, used to fill in commit
+/// details that producers left out.
+pub struct Mirrors {
+    mirrors: Vec<Mirror>,
+    wanted: Notify,
+}
+
+impl Mirrors {
+    /// Create any missing mirror repositories. They are filled by the first
+    /// fetch.
+    pub fn open(config: &MirrorConfig) -> Result<Self> {
+        fs::create_dir_all(&config.path)?;
+
+        let mut mirrors = Vec::new();
+        for repository in &config.repositories {
+            let path = PathBuf::from(&config.path).join(mirror_dir_name(&repository.url));
+            if !path.exists() {
+                git2::Repository::init_bare(&path)?;
+                info!("Created mirror of {} in {}", repository.url, path.display());
+            }
+            mirrors.push(Mirror {
+                url: normalize_url(&repository.url).to_lowercase(),
+                source: repository
+                    .source
+                    .clone()
+                    .unwrap_or_else(|| repository.url.clone()),
+                path,
+                last_fetch: Mutex::new(None),
+                wanted: AtomicBool::new(false),
+            });
+        }
+        Ok(Mirrors {
+            mirrors,
+            wanted: Notify::new(),
+        })
+    }
+
+    /// Fetch every mirror, logging the ones that fail.
+    pub fn fetch_all(&self) {
+        for mirror in &self.mirrors {
+            if let Err(e) = mirror.fetch() {
+                error!("Failed to fetch mirror of {}: {}", mirror.source, e);
+            }
+        }
+    }
+
+    /// Fetch the mirrors that were missing a commit, unless they were
+    /// fetched within `interval`.
+    pub fn fetch_wanted(&self, interval: Duration) {
+        for mirror in self
+            .mirrors
+            .iter()
+            .filter(|m| m.wanted.swap(false, Ordering::AcqRel))
+        {
+            if let Err(e) = mirror.fetch_if_stale(interval) {
+                error!("Failed to fetch mirror of {}: {}", mirror.source, e);
+            }
+        }
+    }
+
+    /// Wait until `enrich` misses a commit.
+    pub async fn wanted(&self) {
+        self.wanted.notified().await
+    }
+
+    /// Fill in the changed files and stats of a commit that arrived without
+    /// them, from what is already mirrored. Never fetches: a missing commit
+    /// marks its mirror for `fetch_wanted` instead. Returns whether the
+    /// mirror had the commit.
+    pub fn enrich(&self, commit_info: &mut CommitInfo) -> Result<bool> {
+        if !commit_info.files_changed.is_empty() && commit_info.stats.is_some() {
+            return Ok(false);
+        }
+        let url = normalize_url(&commit_info.repository_url).to_lowercase();
+        let Some(mirror) = self.mirrors.iter().find(|m| m.url == url) else {
+            return Ok(false);
+        };
+
+        if mirror.repository()?.enrich(commit_info)? {
+            return Ok(true);
+        }
+        // Probably pushed moments ago; the next commit will find it
+        mirror.wanted.store(true, Ordering::Release);
+        self.wanted.notify_one();
+        Ok(false)
+    }
+}
+
+/// Run a blocking `enrich` over a copy of `commit_info`, keeping the original
+/// if it fails or does not finish within `wait`.
+pub async fn enrich_within<F>(commit_info: CommitInfo, wait: Duration, enrich: F) -> CommitInfo
+where
+    F: FnOnce(&mut CommitInfo) -> Result<bool> + Send + 'static,
+{
+    let mut enriched = commit_info.clone();
+    let task =
+        tokio::task::spawn_blocking(move || enrich(&mut enriched).map(|found| (found, enriched)));
+    match tokio::time::timeout(wait, task).await {
+        Ok(Ok(Ok((true, enriched)))) => return enriched,
+        Ok(Ok(Ok((false, _)))) => {}
+        Ok(Ok(Err(e))) => warn!(
+            "Failed to enrich commit {} from its mirror: {}",
+            commit_info.short_hash, e
+        ),
+        Ok(Err(e)) => error!("Mirror task failed: {}", e),
+        Err(_) => warn!(
+            "Reading commit {} from its mirror timed out after {:?}",
+            commit_info.short_hash, wait
+        ),
+    }
+    commit_info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MirrorRepositoryConfig;
+    use git2::{Repository, Signature};
+    use tempfile::TempDir;
+
+    fn commit_file(repo: &Repository, name: &str, contents: &str) -> git2::Oid {
+        let workdir = repo.workdir().unwrap();
+        fs::write(workdir.join(name), contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(std::path::Path::new(name)).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("Alice", "alice@example.com").unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            name,
+            &tree,
+            &parent.iter().collect::<Vec<_>>(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_enrich_only_reads_fetched_commits() {
+        let source_dir = TempDir::new().unwrap();
+        let source = Repository::init(source_dir.path()).unwrap();
+        let first = commit_file(&source, "README.md", "hello\n");
+        let second = commit_file(&source, "src.rs", "fn main() {}\nfn other() {}\n");
+
+        let mirror_dir = TempDir::new().unwrap();
+        let config = MirrorConfig {
+            enabled: true,
+            path: mirror_dir.path().to_str().unwrap().to_string(),
+            fetch_interval_secs: 300,
+            repositories: vec![MirrorRepositoryConfig {
+                url: "https://github.com/acme/widgets".to_string(),
+                source: Some(source_dir.path().to_str().unwrap().to_string()),
+            }],
+        };
+        let mirrors = Mirrors::open(&config).unwrap();
+        assert!(mirror_dir
+            .path()
+            .join("github.com_acme_widgets.git")
+            .is_dir());
+
+        let mut commit_info = CommitInfo {
+            message: "src.rs".to_string(),
+            repository_url: "https://github.com/Acme/widgets.git".to_string(),
+            ..CommitInfo::fixture(&second.to_string())
+        };
+        assert!(!mirrors.enrich(&mut commit_info).unwrap());
+        assert!(commit_info.files_changed.is_empty());
+
+        mirrors.fetch_wanted(ON_DEMAND_FETCH_INTERVAL);
+        // A fetch in progress holds the lock; enriching must not wait for it
+        let fetching = mirrors.mirrors[0].last_fetch.lock().unwrap();
+        assert!(mirrors.enrich(&mut commit_info).unwrap());
+        drop(fetching);
+        assert_eq!(commit_info.files_changed, vec!["src.rs"]);
+        assert_eq!(commit_info.parents, vec![first.to_string()]);
+        let stats = commit_info.stats.unwrap();
+        assert_eq!((stats.files_changed, stats.insertions), (1, 2));
+        assert_eq!(commit_info.signature, None);
+
+        // gf-hook usually reports the ssh remote
+        let mut from_hook = CommitInfo {
+            repository_url: "git@github.com:acme/widgets.git".to_string(),
+            ..CommitInfo::fixture(&second.to_string())
+        };
+        assert!(mirrors.enrich(&mut from_hook).unwrap());
+        assert_eq!(from_hook.files_changed, vec!["src.rs"]);
+
+        commit_info.repository_url = "https://github.com/acme/other".to_string();
+        commit_info.files_changed.clear();
+        assert!(!mirrors.enrich(&mut commit_info).unwrap());
+        assert!(commit_info.files_changed.is_empty());
+    }
+
+    #[test]
+    fn test_mirror_names() {
+        for url in [
+            "https://github.com/Acme/Widgets.git/",
+            "git@github.com:acme/widgets.git",
+        ] {
+            assert_eq!(mirror_dir_name(url), "github.com_acme_widgets.git");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_mirrors_leave_commits_as_sent() {
+        let commit = CommitInfo::fixture("abcdef1234567890");
+        let enrich = |delay| {
+            move |commit: &mut CommitInfo| {
+                std::thread::sleep(delay);
+                commit.files_changed = vec!["src/lib.rs".to_string()];
+                Ok(true)
+            }
+        };
+
+        let slow = enrich(Duration::from_millis(500));
+        let sent = enrich_within(commit.clone(), Duration::from_millis(10), slow).await;
+        assert!(sent.files_changed.is_empty());
+
+        let quick = enrich(Duration::ZERO);
+        let enriched = enrich_within(commit, Duration::from_secs(5), quick).await;
+        assert_eq!(enriched.files_changed, vec!["src/lib.rs"]);
+    }
+}
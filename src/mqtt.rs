@@ -1,15 +1,21 @@
 use crate::config::MqttConfig;
 use crate::errors::Result;
+use crate::event::EventEnvelope;
 use crate::git::CommitInfo;
 use log::{error, info};
 use rumqttc::{AsyncClient, MqttOptions, QoS};
 use serde_json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::time::{timeout, Duration};
 
 pub struct MqttClient {
     client: AsyncClient,
     config: MqttConfig,
+    connected: Arc<AtomicBool>,
+    /// Set once the disconnect packet went out and the event loop stopped
+    closed: Arc<AtomicBool>,
 }
 
 impl MqttClient {
@@ -29,16 +35,38 @@ impl MqttClient {
         // Create a channel for events
         let (tx, rx) = mpsc::channel(100);
 
+        let connected = Arc::new(AtomicBool::new(false));
+        let connection_state = connected.clone();
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed_state = closed.clone();
+
         // Spawn event loop
         tokio::spawn(async move {
             loop {
                 match eventloop.poll().await {
                     Ok(event) => {
+                        match &event {
+                            rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => {
+                                connection_state.store(true, Ordering::Relaxed);
+                            }
+                            rumqttc::Event::Incoming(rumqttc::Packet::Disconnect) => {
+                                connection_state.store(false, Ordering::Relaxed);
+                            }
+                            rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect) => {
+                                connection_state.store(false, Ordering::Relaxed);
+                                closed_state.store(true, Ordering::Release);
+                                info!("Disconnected from MQTT broker");
+                                let _ = tx.send(event).await;
+                                break;
+                            }
+                            _ => {}
+                        }
                         if tx.send(event).await.is_err() {
                             break;
                         }
                     }
                     Err(e) => {
+                        connection_state.store(false, Ordering::Relaxed);
                         error!("MQTT connection error: {}", e);
                         tokio::time::sleep(Duration::from_secs(5)).await;
                     }
@@ -46,7 +74,15 @@ impl MqttClient {
             }
         });
 
-        Ok((MqttClient { client, config }, rx))
+        Ok((
+            MqttClient {
+                client,
+                config,
+                connected,
+                closed,
+            },
+            rx,
+        ))
     }
 
     pub async fn publish_commit(
@@ -54,42 +90,60 @@ impl MqttClient {
         commit_info: &CommitInfo,
         username: Option<&str>,
     ) -> Result<()> {
-        let topic = if let Some(username) = username {
-            format!(
-                "{}/{}/{}/{}",
-                self.config.topic_prefix,
-                username,
-                commit_info
-                    .repository_url
-                    .replace("https://", "")
-                    .replace("http://", "")
-                    .replace("/", "_"),
-                commit_info.committer_name
-            )
-        } else {
-            format!(
-                "{}/{}/{}",
-                self.config.topic_prefix,
-                commit_info
-                    .repository_url
-                    .replace("https://", "")
-                    .replace("http://", "")
-                    .replace("/", "_"),
-                commit_info.committer_name
-            )
-        };
+        self.publish_event(&self.commit_envelope(commit_info), username)
+            .await
+    }
+
+    /// Wrap a commit in an envelope produced by this client, so other sinks
+    /// can share its event id.
+    pub fn commit_envelope(&self, commit_info: &CommitInfo) -> EventEnvelope {
+        EventEnvelope::commit(commit_info.clone(), &self.config.client_id)
+    }
+
+    pub async fn publish_event(
+        &self,
+        envelope: &EventEnvelope,
+        username: Option<&str>,
+    ) -> Result<()> {
+        let topic = commit_topic(&self.config.topic_prefix, &envelope.payload, username);
+        self.publish_to(&topic, envelope).await
+    }
 
-        let payload = serde_json::to_string(commit_info)?;
+    /// Publish an event on a topic the caller has already worked out.
+    pub async fn publish_to(&self, topic: &str, envelope: &EventEnvelope) -> Result<()> {
+        let payload = mqtt_payload(envelope, self.config.event_envelope)?;
 
         info!("Publishing commit to topic: {}", topic);
 
         self.client
-            .publish(&topic, QoS::AtMostOnce, false, payload)
+            .publish(topic, QoS::AtMostOnce, false, payload)
             .await?;
 
         Ok(())
     }
 
+    /// Whether the broker has acknowledged the current connection.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Disconnect from the broker. Publishes queued before the call are
+    /// sent first; gives up after `wait` if the broker is unreachable.
+    pub async fn disconnect(&self, wait: Duration) -> Result<()> {
+        self.client.disconnect().await?;
+        timeout(wait, async {
+            while !self.closed.load(Ordering::Acquire) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .map_err(|_| {
+            crate::errors::GitFriendsError::ConnectionFailed(
+                "MQTT disconnect timed out".to_string(),
+            )
+        })
+    }
+
     pub async fn subscribe_to_topics(&self, topics: &[String]) -> Result<()> {
         for topic in topics {
             info!("Subscribing to topic: {}", topic);
@@ -98,6 +152,14 @@ impl MqttClient {
         Ok(())
     }
 
+    pub async fn unsubscribe_from_topics(&self, topics: &[String]) -> Result<()> {
+        for topic in topics {
+            info!("Unsubscribing from topic: {}", topic);
+            self.client.unsubscribe(topic).await?;
+        }
+        Ok(())
+    }
+
     pub async fn wait_for_connection(&self) -> Result<()> {
         // Wait for connection to be established
         timeout(Duration::from_secs(10), async {
@@ -121,21 +183,80 @@ impl MqttClient {
     }
 }
 
+/// Identifier of a repository in topics and gf-server URLs.
+pub fn repository_id(repository_url: &str) -> String {
+    repository_url
+        .replace("https://", "")
+        .replace("http://", "")
+        .replace("/", "_")
+}
+
+/// Topic a commit is published on, optionally namespaced by the authenticated user.
+pub fn commit_topic(
+    topic_prefix: &str,
+    commit_info: &CommitInfo,
+    username: Option<&str>,
+) -> String {
+    let repo = repository_id(&commit_info.repository_url);
+
+    if let Some(username) = username {
+        format!(
+            "{}/{}/{}/{}",
+            topic_prefix, username, repo, commit_info.committer_name
+        )
+    } else {
+        format!("{}/{}/{}", topic_prefix, repo, commit_info.committer_name)
+    }
+}
+
+/// Match a topic against an MQTT subscription filter (`+` and `#` wildcards).
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// MQTT payload of an event: the envelope, or the bare `CommitInfo` JSON of
+/// older versions unless `mqtt.event_envelope` is set.
+pub fn mqtt_payload(envelope: &EventEnvelope, enveloped: bool) -> Result<String> {
+    Ok(if enveloped {
+        serde_json::to_string(envelope)?
+    } else {
+        serde_json::to_string(&envelope.payload)?
+    })
+}
+
+/// Extract the commit from an MQTT payload, accepting both enveloped events
+/// and the bare `CommitInfo` JSON published by older gf-server versions.
 pub fn extract_commit_from_mqtt_message(payload: &[u8]) -> Result<CommitInfo> {
     let payload_str = String::from_utf8_lossy(payload);
-    let commit_info: CommitInfo = serde_json::from_str(&payload_str)?;
-    Ok(commit_info)
+    Ok(EventEnvelope::from_json(&payload_str)?.payload)
 }
 
-pub fn format_commit_for_irc(commit_info: &CommitInfo) -> String {
+/// One-line IRC announcement of a commit. `repository_name` is the display
+/// name from the repository mappings; the last URL segment is used without one.
+pub fn format_commit_for_irc(commit_info: &CommitInfo, repository_name: Option<&str>) -> String {
     format!(
         "[{}] {} by {} ({}): {} - {}",
         commit_info.short_hash,
-        commit_info
+        repository_name.unwrap_or_else(|| commit_info
             .repository_url
             .split('/')
             .next_back()
-            .unwrap_or("unknown"),
+            .unwrap_or("unknown")),
         commit_info.author_name,
         commit_info.branch,
         commit_info.message.lines().next().unwrap_or("").trim(),
@@ -150,27 +271,63 @@ pub fn format_commit_for_irc(commit_info: &CommitInfo) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::MqttConfig;
 
     #[test]
     fn test_commit_formatting() {
         let commit_info = CommitInfo {
-            hash: "abcdef1234567890".to_string(),
-            short_hash: "abcdef1".to_string(),
             author_name: "John Doe".to_string(),
-            author_email: "john@example.com".to_string(),
-            committer_name: "John Doe".to_string(),
-            committer_email: "john@example.com".to_string(),
             message: "Fix bug in authentication\n\nThis fixes the issue where...".to_string(),
-            timestamp: 1234567890,
             repository_url: "https://github.com/user/repo".to_string(),
-            branch: "main".to_string(),
             files_changed: vec!["src/auth.rs".to_string(), "tests/auth_test.rs".to_string()],
+            ..CommitInfo::fixture("abcdef1234567890")
         };
 
-        let formatted = format_commit_for_irc(&commit_info);
+        let formatted = format_commit_for_irc(&commit_info, None);
         assert!(formatted.contains("abcdef1"));
         assert!(formatted.contains("John Doe"));
         assert!(formatted.contains("Fix bug in authentication"));
+        assert!(formatted.contains("] repo by"));
+
+        let formatted = format_commit_for_irc(&commit_info, Some("user/repo"));
+        assert!(formatted.contains("] user/repo by"));
+    }
+
+    #[test]
+    fn test_extract_enveloped_and_legacy_messages() {
+        let commit_info = CommitInfo::fixture("abcdef1234567890");
+
+        let legacy = serde_json::to_vec(&commit_info).unwrap();
+        let envelope = EventEnvelope::commit(commit_info, "git-friends/server");
+        let enveloped = serde_json::to_vec(&envelope).unwrap();
+
+        for payload in [legacy, enveloped] {
+            let extracted = extract_commit_from_mqtt_message(&payload).unwrap();
+            assert_eq!(extracted.short_hash, "abcdef1");
+        }
+
+        let bare = mqtt_payload(&envelope, false).unwrap();
+        assert!(!bare.contains("schema_version"));
+        let wrapped = mqtt_payload(&envelope, true).unwrap();
+        assert!(wrapped.contains("schema_version"));
+        assert_eq!(
+            extract_commit_from_mqtt_message(bare.as_bytes())
+                .unwrap()
+                .hash,
+            envelope.payload.hash
+        );
+    }
+
+    #[test]
+    fn test_topic_matching() {
+        assert!(topic_matches("git-friends/+/+", "git-friends/repo/alice"));
+        assert!(!topic_matches(
+            "git-friends/+/+",
+            "git-friends/bob/repo/alice"
+        ));
+        assert!(topic_matches("git-friends/#", "git-friends/bob/repo/alice"));
+        assert!(topic_matches("git-friends/#", "git-friends"));
+        assert!(topic_matches("#", "git-friends/repo/alice"));
+        assert!(!topic_matches("other/#", "git-friends/repo/alice"));
+        assert!(!topic_matches("#", "$SYS/test"));
     }
 }
@@ -0,0 +1,306 @@
+use crate::auth::wildcard_match;
+use crate::config::{OidcConfig, TokenConfig};
+use crate::errors::{GitFriendsError, Result};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{debug, error};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Signature algorithms accepted for ID tokens. Symmetric algorithms are
+/// excluded: a JWKS only ever holds public keys.
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// Why an ID token was not accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OidcError {
+    Expired,
+    Invalid(String),
+}
+
+/// A verified ID token, mapped to a user by its issuer's entry.
+#[derive(Debug, Clone)]
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub token: TokenConfig,
+}
+
+/// Whether a bearer credential looks like a JWT rather than a token secret.
+pub fn is_jwt(token: &str) -> bool {
+    token.starts_with("eyJ") && token.split('.').count() == 3
+}
+
+/// JSON Web Key Sets of the configured issuers, keyed by their `jwks` source.
+pub struct JwksStore {
+    sets: RwLock<HashMap<String, JwkSet>>,
+}
+
+impl JwksStore {
+    pub fn new() -> Self {
+        JwksStore {
+            sets: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Load the key sets of all issuers. A source that fails to load keeps
+    /// its previous keys.
+    pub async fn refresh(&self, issuers: &[OidcConfig]) {
+        let mut sources: Vec<&str> = issuers.iter().map(|oidc| oidc.jwks.as_str()).collect();
+        sources.sort_unstable();
+        sources.dedup();
+
+        for source in sources {
+            match load_jwks(source).await {
+                Ok(set) => {
+                    debug!("Loaded {} keys from {}", set.keys.len(), source);
+                    self.sets.write().unwrap().insert(source.to_string(), set);
+                }
+                Err(e) => error!("Failed to load JSON Web Key Set {}: {}", source, e),
+            }
+        }
+    }
+
+    /// Verify an ID token against the first configured issuer it satisfies.
+    pub fn verify(
+        &self,
+        issuers: &[OidcConfig],
+        token: &str,
+    ) -> std::result::Result<OidcIdentity, OidcError> {
+        let header = decode_header(token).map_err(|e| OidcError::Invalid(e.to_string()))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(OidcError::Invalid(format!(
+                "unsupported algorithm {:?}",
+                header.alg
+            )));
+        }
+
+        let sets = self.sets.read().unwrap();
+        let mut last_error = OidcError::Invalid("no matching issuer".to_string());
+        for oidc in issuers {
+            let Some(set) = sets.get(&oidc.jwks) else {
+                continue;
+            };
+            let keys = match &header.kid {
+                Some(kid) => set.find(kid).into_iter().collect(),
+                None => set.keys.iter().collect::<Vec<_>>(),
+            };
+
+            for jwk in keys {
+                let Ok(key) = DecodingKey::from_jwk(jwk) else {
+                    continue;
+                };
+                let mut validation = Validation::new(header.alg);
+                validation.set_issuer(&[&oidc.issuer]);
+                validation.set_audience(&[&oidc.audience]);
+                validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+                match decode::<Map<String, Value>>(token, &key, &validation) {
+                    Ok(data) => match identity(oidc, &data.claims) {
+                        Ok(identity) => return Ok(identity),
+                        Err(e) => last_error = e,
+                    },
+                    Err(e) if *e.kind() == ErrorKind::ExpiredSignature => {
+                        return Err(OidcError::Expired)
+                    }
+                    Err(e) => last_error = OidcError::Invalid(e.to_string()),
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+impl Default for JwksStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn load_jwks(source: &str) -> Result<JwkSet> {
+    if source.starts_with("https://") || source.starts_with("http://") {
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| GitFriendsError::ConnectionFailed(e.to_string()))?
+            .get(source)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| GitFriendsError::ConnectionFailed(e.to_string()))?;
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| GitFriendsError::ConnectionFailed(e.to_string()))?;
+        Ok(serde_json::from_slice(&body)?)
+    } else {
+        let json = tokio::fs::read(source).await?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+/// Check the issuer's claim patterns and build the user's token entry.
+fn identity(
+    oidc: &OidcConfig,
+    claims: &Map<String, Value>,
+) -> std::result::Result<OidcIdentity, OidcError> {
+    for (claim, pattern) in &oidc.claims {
+        let value = claim_value(claims, claim);
+        if !value.is_some_and(|value| wildcard_match(pattern, &value)) {
+            return Err(OidcError::Invalid(format!(
+                "claim '{}' does not match '{}'",
+                claim, pattern
+            )));
+        }
+    }
+
+    let subject = claim_value(claims, "sub").unwrap_or_default();
+    let username = expand_username(&oidc.username, claims)?;
+
+    Ok(OidcIdentity {
+        issuer: oidc.issuer.clone(),
+        token: TokenConfig {
+            token: format!("oidc:{}:{}", oidc.issuer, subject),
+            username,
+            rate_limit_per_minute: oidc.rate_limit_per_minute,
+            scopes: oidc.scopes.clone(),
+            ..Default::default()
+        },
+        subject,
+    })
+}
+
+fn claim_value(claims: &Map<String, Value>, claim: &str) -> Option<String> {
+    match claims.get(claim)? {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Replace every `{claim}` in `template` with the claim's value.
+fn expand_username(
+    template: &str,
+    claims: &Map<String, Value>,
+) -> std::result::Result<String, OidcError> {
+    let mut username = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| OidcError::Invalid("unterminated username template".to_string()))?;
+        let claim = &rest[start + 1..end];
+        let value = claim_value(claims, claim)
+            .ok_or_else(|| OidcError::Invalid(format!("claim '{}' is missing", claim)))?;
+        username.push_str(&rest[..start]);
+        username.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    username.push_str(rest);
+    Ok(username)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::unix_now;
+    use base64::{engine::general_purpose, Engine as _};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    fn issuer(jwks: &str) -> OidcConfig {
+        OidcConfig {
+            issuer: "https://token.actions.githubusercontent.com".to_string(),
+            audience: "git-friends".to_string(),
+            jwks: jwks.to_string(),
+            claims: HashMap::from([("repository".to_string(), "acme/*".to_string())]),
+            username: "gha-{repository_owner}".to_string(),
+            rate_limit_per_minute: None,
+            scopes: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_id_token() {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let point = key.public_key_raw();
+        let jwks = json!({"keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "key-1",
+            "alg": "ES256",
+            "x": general_purpose::URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": general_purpose::URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]});
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("jwks.json");
+        std::fs::write(&path, jwks.to_string()).unwrap();
+
+        let issuers = vec![issuer(path.to_str().unwrap())];
+        let store = JwksStore::new();
+        store.refresh(&issuers).await;
+
+        let encoding_key = EncodingKey::from_ec_pem(key.serialize_pem().as_bytes()).unwrap();
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("key-1".to_string());
+        let now = unix_now() as u64;
+        let sign = |repository: &str, aud: &str, exp: u64| {
+            let claims = json!({
+                "iss": "https://token.actions.githubusercontent.com",
+                "aud": aud,
+                "sub": format!("repo:{}:ref:refs/heads/main", repository),
+                "exp": exp,
+                "repository": repository,
+                "repository_owner": repository.split('/').next().unwrap(),
+            });
+            encode(&header, &claims, &encoding_key).unwrap()
+        };
+
+        let token = sign("acme/widgets", "git-friends", now + 300);
+        assert!(is_jwt(&token));
+        let identity = store.verify(&issuers, &token).unwrap();
+        assert_eq!(identity.token.username, "gha-acme");
+        assert_eq!(identity.subject, "repo:acme/widgets:ref:refs/heads/main");
+
+        assert!(store
+            .verify(&issuers, &sign("other/widgets", "git-friends", now + 300))
+            .is_err());
+        assert!(store
+            .verify(&issuers, &sign("acme/widgets", "someone-else", now + 300))
+            .is_err());
+        assert_eq!(
+            store
+                .verify(&issuers, &sign("acme/widgets", "git-friends", now - 600))
+                .unwrap_err(),
+            OidcError::Expired
+        );
+    }
+
+    #[test]
+    fn test_expand_username() {
+        let claims = json!({"repository_owner": "acme", "run_number": 7});
+        let claims = claims.as_object().unwrap();
+
+        assert_eq!(
+            expand_username("ci-{repository_owner}-{run_number}", claims).unwrap(),
+            "ci-acme-7"
+        );
+        assert_eq!(expand_username("ci", claims).unwrap(), "ci");
+        assert!(expand_username("{missing}", claims).is_err());
+    }
+}
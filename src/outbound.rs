@@ -0,0 +1,539 @@
+use crate::auth::wildcard_match;
+use crate::clock::unix_now;
+use crate::config::{OutboundFilter, OutboundWebhookConfig};
+use crate::errors::{GitFriendsError, Result};
+use crate::event::EventEnvelope;
+use crate::git::CommitInfo;
+use crate::metrics::Metrics;
+use crate::signing::RequestSignature;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Placeholders a payload template may use as `{{field}}`. `event` is the
+/// whole envelope as JSON; `subject` is the first line of the message.
+pub const TEMPLATE_FIELDS: &[&str] = &[
+    "event",
+    "event_id",
+    "event_type",
+    "received_at",
+    "hash",
+    "short_hash",
+    "author_name",
+    "author_email",
+    "committer_name",
+    "committer_email",
+    "message",
+    "subject",
+    "repository_url",
+    "branch",
+    "timestamp",
+    "user",
+    "topic",
+];
+
+pub const HEADER_EVENT_ID: &str = "x-gf-event-id";
+pub const HEADER_EVENT_TYPE: &str = "x-gf-event-type";
+/// Stays the same across retries, so receivers can drop duplicates
+pub const HEADER_DELIVERY: &str = "x-gf-delivery";
+
+impl OutboundFilter {
+    pub fn matches(&self, event_type: &str, commit: &CommitInfo) -> bool {
+        let any = |patterns: &[String], values: &[&str]| {
+            patterns.is_empty()
+                || patterns
+                    .iter()
+                    .any(|p| values.iter().any(|v| wildcard_match(p, v)))
+        };
+        any(&self.event_types, &[event_type])
+            && any(&self.repositories, &[&commit.repository_url])
+            && any(&self.branches, &[&commit.branch])
+            && any(&self.authors, &[&commit.author_name, &commit.author_email])
+    }
+}
+
+/// Check that every placeholder in `template` is closed and known.
+pub fn check_template(template: &str) -> std::result::Result<(), String> {
+    expand_template(template, |field| {
+        TEMPLATE_FIELDS
+            .contains(&field)
+            .then(String::new)
+            .ok_or_else(|| format!("unknown field '{}'", field))
+    })
+    .map(|_| ())
+}
+
+fn expand_template(
+    template: &str,
+    mut value: impl FnMut(&str) -> std::result::Result<String, String>,
+) -> std::result::Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end)
+            .ok_or_else(|| "unterminated placeholder".to_string())?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&value(rest[start + 2..end].trim())?);
+        rest = &rest[end + 2..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// The request body for `endpoint`. Values are JSON-escaped when the
+/// endpoint's content type is JSON, so templates can quote them.
+pub fn render_body(
+    endpoint: &OutboundWebhookConfig,
+    envelope: &EventEnvelope,
+    username: Option<&str>,
+    topic: &str,
+) -> Result<String> {
+    let event = serde_json::to_string(envelope)?;
+    let Some(template) = &endpoint.template else {
+        return Ok(event);
+    };
+
+    let json = endpoint.content_type.contains("json");
+    let commit = &envelope.payload;
+    expand_template(template, |field| {
+        let value = match field {
+            "event" => return Ok(event.clone()),
+            "event_id" => envelope.event_id.clone(),
+            "event_type" => envelope.event_type.clone(),
+            "received_at" => envelope.received_at.to_string(),
+            "hash" => commit.hash.clone(),
+            "short_hash" => commit.short_hash.clone(),
+            "author_name" => commit.author_name.clone(),
+            "author_email" => commit.author_email.clone(),
+            "committer_name" => commit.committer_name.clone(),
+            "committer_email" => commit.committer_email.clone(),
+            "message" => commit.message.clone(),
+            "subject" => commit.message.lines().next().unwrap_or("").to_string(),
+            "repository_url" => commit.repository_url.clone(),
+            "branch" => commit.branch.clone(),
+            "timestamp" => commit.timestamp.to_string(),
+            "user" => username.unwrap_or("").to_string(),
+            "topic" => topic.to_string(),
+            _ => return Err(format!("unknown field '{}'", field)),
+        };
+        Ok(if json { json_escape(&value) } else { value })
+    })
+    .map_err(GitFriendsError::InvalidConfig)
+}
+
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::Value::String(value.to_string()).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// A delivery that could not be made, as appended to the dead-letter file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub time: i64,
+    pub endpoint: String,
+    pub url: String,
+    pub event_id: String,
+    pub attempts: u32,
+    pub error: String,
+    pub body: String,
+}
+
+/// Why a delivery gave up.
+#[derive(Debug)]
+struct DeliveryFailed {
+    attempts: u32,
+    error: String,
+}
+
+/// Deliveries still running, waited for on shutdown.
+#[derive(Default)]
+struct Pending {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+/// Marks a running delivery; released on drop.
+struct PendingDelivery(Arc<Pending>);
+
+impl PendingDelivery {
+    fn new(pending: &Arc<Pending>) -> Self {
+        pending.count.fetch_add(1, Ordering::AcqRel);
+        PendingDelivery(pending.clone())
+    }
+}
+
+impl Drop for PendingDelivery {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Forwards accepted events to the configured HTTP endpoints.
+pub struct Outbound {
+    client: reqwest::Client,
+    metrics: Metrics,
+    pending: Arc<Pending>,
+}
+
+impl Outbound {
+    pub fn new(metrics: Metrics) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("git-friends/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| GitFriendsError::ConnectionFailed(e.to_string()))?;
+        Ok(Outbound {
+            client,
+            metrics,
+            pending: Arc::default(),
+        })
+    }
+
+    /// Number of deliveries still running or retrying.
+    pub fn pending(&self) -> usize {
+        self.pending.count.load(Ordering::Acquire)
+    }
+
+    /// Wait until no deliveries are running, returning false on timeout.
+    /// Deliveries still retrying after `wait` are lost when the process exits.
+    pub async fn drained(&self, wait: Duration) -> bool {
+        tokio::time::timeout(wait, async {
+            loop {
+                let idle = self.pending.idle.notified();
+                if self.pending() == 0 {
+                    break;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Start delivering the event to every endpoint whose filter matches,
+    /// returning how many deliveries were started. Deliveries retry in the
+    /// background; see `drained` for shutdown.
+    pub fn dispatch(
+        &self,
+        endpoints: &[OutboundWebhookConfig],
+        envelope: &EventEnvelope,
+        username: Option<&str>,
+        topic: &str,
+    ) -> usize {
+        let mut started = 0;
+        for endpoint in endpoints
+            .iter()
+            .filter(|e| e.filter.matches(&envelope.event_type, &envelope.payload))
+        {
+            let body = match render_body(endpoint, envelope, username, topic) {
+                Ok(body) => body,
+                Err(e) => {
+                    error!("Failed to render payload for {}: {}", endpoint.name, e);
+                    continue;
+                }
+            };
+
+            let client = self.client.clone();
+            let metrics = self.metrics.clone();
+            let endpoint = endpoint.clone();
+            let event_id = envelope.event_id.clone();
+            let pending = PendingDelivery::new(&self.pending);
+            tokio::spawn(async move {
+                let _pending = pending;
+                match deliver(&client, &endpoint, &event_id, &body).await {
+                    Ok(attempts) => {
+                        info!(
+                            "Delivered event {} to {} after {} attempt(s)",
+                            event_id, endpoint.name, attempts
+                        );
+                        metrics.outbound_delivered(&endpoint.name, true);
+                    }
+                    Err(failed) => {
+                        error!(
+                            "Giving up on event {} for {} after {} attempt(s): {}",
+                            event_id, endpoint.name, failed.attempts, failed.error
+                        );
+                        metrics.outbound_delivered(&endpoint.name, false);
+                        let letter = DeadLetter {
+                            time: unix_now(),
+                            endpoint: endpoint.name.clone(),
+                            url: endpoint.url.clone(),
+                            event_id,
+                            attempts: failed.attempts,
+                            error: failed.error,
+                            body,
+                        };
+                        if let Some(path) = &endpoint.dead_letter_path {
+                            if let Err(e) = write_dead_letter(path, &letter).await {
+                                error!("Failed to write dead letter to {}: {}", path, e);
+                            }
+                        }
+                    }
+                }
+            });
+            started += 1;
+        }
+        started
+    }
+}
+
+/// Delay before retry number `retry` (1-based), doubling up to the maximum.
+fn backoff(endpoint: &OutboundWebhookConfig, retry: u32) -> Duration {
+    let initial = Duration::from_millis(endpoint.initial_backoff_ms);
+    initial
+        .saturating_mul(2u32.saturating_pow(retry - 1))
+        .min(Duration::from_secs(endpoint.max_backoff_secs))
+}
+
+/// POST `body` until the endpoint accepts it. Connection errors, timeouts,
+/// 408, 429 and 5xx responses are retried; other responses are final.
+async fn deliver(
+    client: &reqwest::Client,
+    endpoint: &OutboundWebhookConfig,
+    event_id: &str,
+    body: &str,
+) -> std::result::Result<u32, DeliveryFailed> {
+    let target = reqwest::Url::parse(&endpoint.url)
+        .map(|url| match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        })
+        .map_err(|e| DeliveryFailed {
+            attempts: 0,
+            error: e.to_string(),
+        })?;
+    let delivery = Uuid::new_v4().to_string();
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let mut request = client
+            .post(&endpoint.url)
+            .timeout(Duration::from_secs(endpoint.timeout_secs))
+            .header("content-type", &endpoint.content_type)
+            .header(HEADER_EVENT_ID, event_id)
+            .header(HEADER_EVENT_TYPE, crate::event::EVENT_TYPE_COMMIT)
+            .header(HEADER_DELIVERY, &delivery);
+        if let Some(secret) = &endpoint.signing_secret {
+            let signature = RequestSignature::sign(
+                secret,
+                "POST",
+                &target,
+                unix_now(),
+                &Uuid::new_v4().to_string(),
+                body.as_bytes(),
+            );
+            for (name, value) in signature.headers() {
+                request = request.header(name, value);
+            }
+        }
+
+        let (error, retryable) = match request.body(body.to_string()).send().await {
+            Ok(response) if response.status().is_success() => return Ok(attempt),
+            Ok(response) => {
+                let status = response.status();
+                let retryable = status.is_server_error()
+                    || status == reqwest::StatusCode::REQUEST_TIMEOUT
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+                (format!("HTTP {}", status), retryable)
+            }
+            Err(e) => (e.to_string(), true),
+        };
+
+        if !retryable || attempt >= endpoint.max_attempts {
+            return Err(DeliveryFailed {
+                attempts: attempt,
+                error,
+            });
+        }
+        let delay = backoff(endpoint, attempt);
+        warn!(
+            "Delivery of event {} to {} failed ({}), retrying in {:?}",
+            event_id, endpoint.name, error, delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn write_dead_letter(path: &str, letter: &DeadLetter) -> Result<()> {
+    let mut line = serde_json::to_string(letter)?;
+    line.push('\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    // tokio completes writes in the background; make sure this one landed
+    file.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use warp::Filter;
+
+    fn commit() -> CommitInfo {
+        CommitInfo {
+            message: "Fix \"quoted\" bug\n\nDetails".to_string(),
+            ..CommitInfo::fixture("abc123def456")
+        }
+    }
+
+    fn endpoint(url: &str) -> OutboundWebhookConfig {
+        OutboundWebhookConfig {
+            name: "chat".to_string(),
+            url: url.to_string(),
+            filter: OutboundFilter::default(),
+            template: None,
+            content_type: "application/json".to_string(),
+            signing_secret: None,
+            max_attempts: 3,
+            initial_backoff_ms: 1,
+            max_backoff_secs: 1,
+            timeout_secs: 5,
+            dead_letter_path: None,
+        }
+    }
+
+    #[test]
+    fn test_filter_and_template() {
+        let commit = commit();
+        let filter = OutboundFilter {
+            repositories: vec!["https://github.com/acme/*".to_string()],
+            branches: vec!["main".to_string(), "release/*".to_string()],
+            authors: vec!["*@example.com".to_string()],
+            event_types: vec![],
+        };
+        assert!(filter.matches("commit", &commit));
+        assert!(!OutboundFilter {
+            branches: vec!["release/*".to_string()],
+            ..filter.clone()
+        }
+        .matches("commit", &commit));
+        assert!(!OutboundFilter {
+            event_types: vec!["ref".to_string()],
+            ..filter
+        }
+        .matches("commit", &commit));
+
+        let envelope = EventEnvelope::commit(commit, "git-friends/server");
+        let mut chat = endpoint("https://chat.example.com/hook");
+        chat.template = Some(r#"{"text": "{{user}}: {{ subject }} ({{short_hash}})"}"#.to_string());
+        let body = render_body(&chat, &envelope, Some("bob"), "t").unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["text"], r#"bob: Fix "quoted" bug (abc123d)"#);
+
+        chat.template = None;
+        let body = render_body(&chat, &envelope, Some("bob"), "t").unwrap();
+        assert!(EventEnvelope::from_json(&body).is_ok());
+
+        assert!(check_template("{{hash}} {{event}}").is_ok());
+        assert!(check_template("{{password}}").is_err());
+        assert!(check_template("{{hash").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drained_waits_for_retrying_deliveries() {
+        let outbound = Outbound::new(Metrics::new().unwrap()).unwrap();
+        let mut unreachable = endpoint("http://127.0.0.1:1/hook");
+        unreachable.initial_backoff_ms = 200;
+        let envelope = EventEnvelope::commit(commit(), "git-friends/server");
+        assert_eq!(outbound.dispatch(&[unreachable], &envelope, None, "t"), 1);
+
+        assert_eq!(outbound.pending(), 1);
+        assert!(!outbound.drained(Duration::from_millis(20)).await);
+        assert!(outbound.drained(Duration::from_secs(5)).await);
+        assert_eq!(outbound.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries_and_gives_up() {
+        let hits = Arc::new(AtomicU32::new(0));
+        let counter = hits.clone();
+        let route = warp::path!(u16)
+            .and(warp::header::optional::<String>("x-gf-signature"))
+            .map(move |status: u16, signature: Option<String>| {
+                // Fail the first request of every endpoint, then answer as asked
+                let status = if counter.fetch_add(1, Ordering::SeqCst).is_multiple_of(2) {
+                    503
+                } else if signature.is_none() {
+                    401
+                } else {
+                    status
+                };
+                warp::reply::with_status("", warp::http::StatusCode::from_u16(status).unwrap())
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let client = reqwest::Client::new();
+
+        let mut ok = endpoint(&format!("http://{}/200", addr));
+        ok.signing_secret = Some("0123456789abcdef".to_string());
+        assert_eq!(deliver(&client, &ok, "e1", "{}").await.unwrap(), 2);
+
+        // 400 is not retried
+        let mut rejected = endpoint(&format!("http://{}/400", addr));
+        rejected.signing_secret = ok.signing_secret.clone();
+        assert_eq!(
+            deliver(&client, &rejected, "e2", "{}")
+                .await
+                .unwrap_err()
+                .attempts,
+            2
+        );
+
+        let unreachable = endpoint("http://127.0.0.1:1/hook");
+        let failed = deliver(&client, &unreachable, "e3", "{}")
+            .await
+            .unwrap_err();
+        assert_eq!(failed.attempts, 3);
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("dead.jsonl");
+        let letter = DeadLetter {
+            time: 1,
+            endpoint: "chat".to_string(),
+            url: unreachable.url,
+            event_id: "e3".to_string(),
+            attempts: failed.attempts,
+            error: failed.error,
+            body: "{}".to_string(),
+        };
+        write_dead_letter(path.to_str().unwrap(), &letter)
+            .await
+            .unwrap();
+        write_dead_letter(path.to_str().unwrap(), &letter)
+            .await
+            .unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+
+        let backoffs: Vec<_> = (1..=4)
+            .map(|retry| {
+                backoff(
+                    &OutboundWebhookConfig {
+                        initial_backoff_ms: 400,
+                        ..ok.clone()
+                    },
+                    retry,
+                )
+            })
+            .collect();
+        assert_eq!(
+            backoffs,
+            [400, 800, 1000, 1000].map(Duration::from_millis).to_vec()
+        );
+    }
+}
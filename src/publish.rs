@@ -0,0 +1,963 @@
+use crate::config::{MqttConfig, PublisherConfig, PublisherTransport, TenantConfig};
+use crate::errors::{GitFriendsError, Result};
+use crate::event::EventEnvelope;
+use crate::metrics::Metrics;
+use crate::mqtt::MqttClient;
+use crate::stream::{EventBus, StreamEvent};
+use futures_util::future::{join_all, BoxFuture, FutureExt};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+/// How long a publisher may take to accept an event before it counts as failed.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(feature = "nats")]
+pub use self::nats::NatsPublisher;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisPublisher;
+
+/// An accepted event, as handed to every publisher.
+#[derive(Debug, Clone)]
+pub struct Publication {
+    /// History store id, present when gf-server keeps a history database
+    pub id: Option<i64>,
+    /// MQTT topic of the event; other transports derive their channel from it
+    pub topic: String,
+    pub username: Option<String>,
+    /// Tenant of the user who sent the event
+    pub tenant: Option<String>,
+    pub envelope: EventEnvelope,
+}
+
+/// A transport accepted events are published to.
+pub trait Publisher: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn transport(&self) -> PublisherTransport;
+
+    /// Wait until the transport is connected, giving up after `wait`.
+    fn connect(&self, wait: Duration) -> BoxFuture<'_, Result<()>>;
+
+    fn publish<'a>(&'a self, publication: &'a Publication) -> BoxFuture<'a, Result<()>>;
+
+    fn is_connected(&self) -> bool;
+
+    /// The latest connection or publish error, cleared by the next success.
+    fn last_error(&self) -> Option<String>;
+
+    /// Send queued events and disconnect, giving up after `wait`.
+    fn close(&self, wait: Duration) -> BoxFuture<'_, Result<()>>;
+}
+
+/// State of one publisher, as reported on `/ready`.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct PublisherHealth {
+    pub name: String,
+    pub transport: PublisherTransport,
+    pub required: bool,
+    /// Tenant whose events the publisher carries; all others when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub connected: bool,
+    pub last_error: Option<String>,
+}
+
+/// Remembers the latest error of a publisher.
+#[derive(Default)]
+struct LastError(RwLock<Option<String>>);
+
+impl LastError {
+    fn record<T>(&self, result: &Result<T>) {
+        *self.0.write().unwrap() = result.as_ref().err().map(|e| e.to_string());
+    }
+
+    fn get(&self) -> Option<String> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+/// Publishes to the broker in `[mqtt]`, or to the embedded broker.
+pub struct MqttPublisher {
+    name: String,
+    client: MqttClient,
+    last_error: LastError,
+}
+
+impl MqttPublisher {
+    pub fn new(name: &str, config: MqttConfig) -> Result<Self> {
+        let (client, mut events) = MqttClient::new(config)?;
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                match event {
+                    rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => {
+                        info!("MQTT connection acknowledged");
+                    }
+                    rumqttc::Event::Incoming(rumqttc::Packet::Disconnect) => {
+                        warn!("MQTT disconnected");
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Ok(MqttPublisher {
+            name: name.to_string(),
+            client,
+            last_error: LastError::default(),
+        })
+    }
+}
+
+impl Publisher for MqttPublisher {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn transport(&self) -> PublisherTransport {
+        PublisherTransport::Mqtt
+    }
+
+    fn connect(&self, wait: Duration) -> BoxFuture<'_, Result<()>> {
+        async move {
+            timeout(wait, self.client.wait_for_connection())
+                .await
+                .unwrap_or_else(|_| {
+                    Err(GitFriendsError::ConnectionFailed(
+                        "MQTT connection timeout".to_string(),
+                    ))
+                })
+        }
+        .boxed()
+    }
+
+    fn publish<'a>(&'a self, publication: &'a Publication) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let result = self
+                .client
+                .publish_to(&publication.topic, &publication.envelope)
+                .await;
+            self.last_error.record(&result);
+            result
+        }
+        .boxed()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
+    fn last_error(&self) -> Option<String> {
+        self.last_error.get()
+    }
+
+    fn close(&self, wait: Duration) -> BoxFuture<'_, Result<()>> {
+        self.client.disconnect(wait).boxed()
+    }
+}
+
+/// Delivers events to an in-process `EventBus`, such as the one behind
+/// gf-server's live event stream.
+pub struct MemoryPublisher {
+    name: String,
+    bus: EventBus,
+}
+
+impl MemoryPublisher {
+    pub fn new(name: &str, bus: EventBus) -> Self {
+        MemoryPublisher {
+            name: name.to_string(),
+            bus,
+        }
+    }
+}
+
+impl Publisher for MemoryPublisher {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn transport(&self) -> PublisherTransport {
+        PublisherTransport::Memory
+    }
+
+    fn connect(&self, _wait: Duration) -> BoxFuture<'_, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+
+    fn publish<'a>(&'a self, publication: &'a Publication) -> BoxFuture<'a, Result<()>> {
+        self.bus.publish(StreamEvent {
+            id: publication.id,
+            topic: publication.topic.clone(),
+            username: publication.username.clone(),
+            commit: publication.envelope.payload.clone(),
+            repository: None,
+        });
+        async { Ok(()) }.boxed()
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn last_error(&self) -> Option<String> {
+        None
+    }
+
+    fn close(&self, _wait: Duration) -> BoxFuture<'_, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+}
+
+struct Entry {
+    publisher: Arc<dyn Publisher>,
+    required: bool,
+    tenant: Option<String>,
+}
+
+/// Every publisher an accepted event goes to.
+pub struct Publishers {
+    entries: Vec<Entry>,
+    metrics: Metrics,
+}
+
+impl Publishers {
+    pub fn new(metrics: Metrics) -> Self {
+        Publishers {
+            entries: Vec::new(),
+            metrics,
+        }
+    }
+
+    /// Create the configured publishers. The in-memory bus always receives
+    /// events, so that `bus` can serve live streams; configuring a memory
+    /// publisher only names it. MQTT publishers connect with `mqtt`; tenants
+    /// with their own MQTT credentials get their own connection.
+    pub async fn from_config(
+        configs: &[PublisherConfig],
+        mqtt: &MqttConfig,
+        tenants: &[TenantConfig],
+        bus: &EventBus,
+        metrics: Metrics,
+    ) -> Result<Self> {
+        let mut publishers = Publishers::new(metrics);
+        for config in configs {
+            let publisher: Arc<dyn Publisher> = match config.transport {
+                PublisherTransport::Mqtt => {
+                    Arc::new(MqttPublisher::new(&config.name, mqtt.clone())?)
+                }
+                PublisherTransport::Memory => {
+                    Arc::new(MemoryPublisher::new(&config.name, bus.clone()))
+                }
+                #[cfg(feature = "redis")]
+                PublisherTransport::Redis => RedisPublisher::new(config)?,
+                #[cfg(feature = "nats")]
+                PublisherTransport::Nats => Arc::new(NatsPublisher::new(config).await?),
+                #[allow(unreachable_patterns)]
+                transport => {
+                    return Err(GitFriendsError::InvalidConfig(format!(
+                        "publisher '{}' uses {}, which this build does not include",
+                        config.name,
+                        transport.as_str()
+                    )))
+                }
+            };
+            info!("Publisher {}: {}", config.name, config.transport.as_str());
+            publishers.add(publisher, config.required);
+
+            if config.transport == PublisherTransport::Mqtt {
+                for tenant in tenants.iter().filter(|t| t.mqtt_username.is_some()) {
+                    let name = format!("{}-{}", config.name, tenant.name);
+                    let tenant_mqtt = MqttConfig {
+                        client_id: format!("{}-{}", mqtt.client_id, tenant.name),
+                        username: tenant.mqtt_username.clone(),
+                        password: tenant.mqtt_password.clone(),
+                        ..mqtt.clone()
+                    };
+                    info!("Publisher {}: mqtt for tenant {}", name, tenant.name);
+                    publishers.add_for_tenant(
+                        &tenant.name,
+                        Arc::new(MqttPublisher::new(&name, tenant_mqtt)?),
+                        config.required,
+                    );
+                }
+            }
+        }
+
+        if !configs
+            .iter()
+            .any(|c| c.transport == PublisherTransport::Memory)
+        {
+            publishers.add(Arc::new(MemoryPublisher::new("stream", bus.clone())), false);
+        }
+        Ok(publishers)
+    }
+
+    pub fn add(&mut self, publisher: Arc<dyn Publisher>, required: bool) {
+        self.entries.push(Entry {
+            publisher,
+            required,
+            tenant: None,
+        });
+    }
+
+    /// Add a publisher that carries only the events of `tenant`, in place
+    /// of the shared publisher of the same transport.
+    pub fn add_for_tenant(&mut self, tenant: &str, publisher: Arc<dyn Publisher>, required: bool) {
+        self.entries.push(Entry {
+            publisher,
+            required,
+            tenant: Some(tenant.to_string()),
+        });
+    }
+
+    /// The publishers an event of `tenant` goes to.
+    fn entries_for(&self, tenant: Option<&str>) -> Vec<&Entry> {
+        let own = |entry: &Entry| tenant.is_some() && entry.tenant.as_deref() == tenant;
+        self.entries
+            .iter()
+            .filter(|entry| match &entry.tenant {
+                Some(_) => own(entry),
+                None => !self.entries.iter().any(|other| {
+                    own(other) && other.publisher.transport() == entry.publisher.transport()
+                }),
+            })
+            .collect()
+    }
+
+    /// Wait for the required publishers to connect, failing if one does not
+    /// within `wait`. The others connect in the background.
+    pub async fn connect(&self, wait: Duration) -> Result<()> {
+        let (required, optional): (Vec<&Entry>, Vec<&Entry>) =
+            self.entries.iter().partition(|e| e.required);
+
+        for entry in optional {
+            let publisher = entry.publisher.clone();
+            tokio::spawn(async move {
+                match publisher.connect(wait).await {
+                    Ok(()) => info!("Publisher {} connected", publisher.name()),
+                    Err(e) => warn!("Publisher {} is not connected yet: {}", publisher.name(), e),
+                }
+            });
+        }
+
+        let results = join_all(required.iter().map(|e| e.publisher.connect(wait))).await;
+        for (entry, result) in required.iter().zip(results) {
+            let name = entry.publisher.name();
+            match result {
+                Ok(()) => info!("Publisher {} connected", name),
+                Err(e) => {
+                    return Err(GitFriendsError::ConnectionFailed(format!(
+                        "publisher {}: {}",
+                        name, e
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hand an event to every publisher at once. Fails if a required
+    /// publisher failed; failures of the others are only logged.
+    pub async fn publish(&self, publication: &Publication) -> Result<()> {
+        let entries = self.entries_for(publication.tenant.as_deref());
+        let results = join_all(entries.iter().map(|entry| async move {
+            let started = Instant::now();
+            let result = timeout(PUBLISH_TIMEOUT, entry.publisher.publish(publication))
+                .await
+                .unwrap_or_else(|_| Err(GitFriendsError::Publish("timed out".to_string())));
+            (result, started.elapsed())
+        }))
+        .await;
+
+        let mut failed = Vec::new();
+        for (entry, (result, elapsed)) in entries.iter().zip(results) {
+            let name = entry.publisher.name();
+            self.metrics.published(name, result.is_ok());
+            if entry.publisher.transport() == PublisherTransport::Mqtt {
+                self.metrics.mqtt_published(result.is_ok(), elapsed);
+            }
+            if let Err(e) = result {
+                error!("Publisher {} failed to publish: {}", name, e);
+                if entry.required {
+                    failed.push(name);
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(GitFriendsError::Publish(format!(
+                "required publishers failed: {}",
+                failed.join(", ")
+            )))
+        }
+    }
+
+    pub fn health(&self) -> Vec<PublisherHealth> {
+        self.entries
+            .iter()
+            .map(|entry| PublisherHealth {
+                name: entry.publisher.name().to_string(),
+                transport: entry.publisher.transport(),
+                required: entry.required,
+                tenant: entry.tenant.clone(),
+                connected: entry.publisher.is_connected(),
+                last_error: entry.publisher.last_error(),
+            })
+            .collect()
+    }
+
+    /// Close every publisher, each given up to `wait`.
+    pub async fn close(&self, wait: Duration) {
+        let results = join_all(self.entries.iter().map(|e| e.publisher.close(wait))).await;
+        for (entry, result) in self.entries.iter().zip(results) {
+            if let Err(e) = result {
+                warn!(
+                    "Publisher {} did not shut down cleanly: {}",
+                    entry.publisher.name(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// NATS subject for an MQTT topic: levels become tokens, and characters
+/// NATS gives a meaning to inside a token are replaced by `_`.
+pub fn nats_subject(topic: &str) -> String {
+    topic
+        .split('/')
+        .map(|level| {
+            if level.is_empty() {
+                return "_".to_string();
+            }
+            level
+                .chars()
+                .map(|c| match c {
+                    '.' | '*' | '>' => '_',
+                    c if c.is_whitespace() => '_',
+                    c => c,
+                })
+                .collect()
+        })
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+#[cfg(feature = "redis")]
+mod redis {
+    use super::{LastError, Publication, Publisher};
+    use crate::config::{PublisherConfig, PublisherTransport, RedisMode};
+    use crate::errors::{GitFriendsError, Result};
+    use futures_util::future::{BoxFuture, FutureExt};
+    use log::info;
+    use redis::aio::MultiplexedConnection;
+    use redis::AsyncConnectionConfig;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Weak};
+    use std::time::Duration;
+    use tokio::sync::Mutex;
+    use tokio::time::timeout;
+
+    /// How often the connection is checked, and re-established when lost.
+    const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+    const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Publishes to Redis pub/sub channels named after the topic, or appends
+    /// to a Redis stream.
+    pub struct RedisPublisher {
+        name: String,
+        client: redis::Client,
+        mode: RedisMode,
+        stream_key: String,
+        stream_max_len: u64,
+        connection: Mutex<Option<MultiplexedConnection>>,
+        /// Whether the latest command succeeded
+        connected: AtomicBool,
+        last_error: LastError,
+    }
+
+    impl RedisPublisher {
+        /// Create the publisher; it connects on first use and keeps its
+        /// connection alive in the background.
+        pub fn new(config: &PublisherConfig) -> Result<Arc<Self>> {
+            let url = config.url.as_deref().unwrap_or_default();
+            let client = redis::Client::open(url).map_err(publish_error)?;
+            let publisher = Arc::new(RedisPublisher {
+                name: config.name.clone(),
+                client,
+                mode: config.redis_mode,
+                stream_key: config.stream_key.clone(),
+                stream_max_len: config.stream_max_len,
+                connection: Mutex::new(None),
+                connected: AtomicBool::new(false),
+                last_error: LastError::default(),
+            });
+
+            let weak = Arc::downgrade(&publisher);
+            tokio::spawn(keep_alive(weak));
+            Ok(publisher)
+        }
+
+        async fn connection(&self) -> Result<MultiplexedConnection> {
+            let mut connection = self.connection.lock().await;
+            if let Some(connection) = connection.as_ref() {
+                return Ok(connection.clone());
+            }
+            let config = AsyncConnectionConfig::new()
+                .set_connection_timeout(COMMAND_TIMEOUT)
+                .set_response_timeout(COMMAND_TIMEOUT);
+            let established = self
+                .client
+                .get_multiplexed_async_connection_with_config(&config)
+                .await
+                .map_err(publish_error)?;
+            info!("Publisher {} connected to Redis", self.name);
+            *connection = Some(established.clone());
+            Ok(established)
+        }
+
+        /// Run a command, dropping the connection when it fails so the next
+        /// command reconnects.
+        async fn run(&self, cmd: &redis::Cmd) -> Result<()> {
+            let result = async {
+                let mut connection = self.connection().await?;
+                cmd.query_async::<redis::Value>(&mut connection)
+                    .await
+                    .map_err(publish_error)?;
+                Ok(())
+            }
+            .await;
+            if result.is_err() {
+                self.connection.lock().await.take();
+            }
+            self.connected.store(result.is_ok(), Ordering::Relaxed);
+            self.last_error.record(&result);
+            result
+        }
+    }
+
+    async fn keep_alive(publisher: Weak<RedisPublisher>) {
+        loop {
+            tokio::time::sleep(PING_INTERVAL).await;
+            let Some(publisher) = publisher.upgrade() else {
+                break;
+            };
+            let _ = publisher.run(&redis::cmd("PING")).await;
+        }
+    }
+
+    fn publish_error(e: redis::RedisError) -> GitFriendsError {
+        GitFriendsError::Publish(format!("Redis: {}", e))
+    }
+
+    impl Publisher for RedisPublisher {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn transport(&self) -> PublisherTransport {
+            PublisherTransport::Redis
+        }
+
+        fn connect(&self, wait: Duration) -> BoxFuture<'_, Result<()>> {
+            async move {
+                timeout(wait, self.run(&redis::cmd("PING")))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(GitFriendsError::ConnectionFailed(
+                            "Redis connection timeout".to_string(),
+                        ))
+                    })
+            }
+            .boxed()
+        }
+
+        fn publish<'a>(&'a self, publication: &'a Publication) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let payload = serde_json::to_string(&publication.envelope)?;
+                let cmd = match self.mode {
+                    RedisMode::Pubsub => {
+                        let mut cmd = redis::cmd("PUBLISH");
+                        cmd.arg(&publication.topic).arg(payload);
+                        cmd
+                    }
+                    RedisMode::Stream => {
+                        let mut cmd = redis::cmd("XADD");
+                        cmd.arg(&self.stream_key);
+                        if self.stream_max_len > 0 {
+                            cmd.arg("MAXLEN").arg("~").arg(self.stream_max_len);
+                        }
+                        cmd.arg("*")
+                            .arg("topic")
+                            .arg(&publication.topic)
+                            .arg("event")
+                            .arg(payload);
+                        cmd
+                    }
+                };
+                self.run(&cmd).await
+            }
+            .boxed()
+        }
+
+        fn is_connected(&self) -> bool {
+            self.connected.load(Ordering::Relaxed)
+        }
+
+        fn last_error(&self) -> Option<String> {
+            self.last_error.get()
+        }
+
+        fn close(&self, _wait: Duration) -> BoxFuture<'_, Result<()>> {
+            async move {
+                // Commands are answered before `publish` returns, so nothing is queued
+                self.connection.lock().await.take();
+                self.connected.store(false, Ordering::Relaxed);
+                Ok(())
+            }
+            .boxed()
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+mod nats {
+    use super::{nats_subject, LastError, Publication, Publisher};
+    use crate::config::{PublisherConfig, PublisherTransport};
+    use crate::errors::{GitFriendsError, Result};
+    use async_nats::connection::State;
+    use futures_util::future::{BoxFuture, FutureExt};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    /// Publishes to NATS subjects derived from the topic, see `nats_subject`.
+    pub struct NatsPublisher {
+        name: String,
+        client: async_nats::Client,
+        last_error: LastError,
+    }
+
+    impl NatsPublisher {
+        /// Create the publisher; the client connects, and reconnects, in
+        /// the background.
+        pub async fn new(config: &PublisherConfig) -> Result<Self> {
+            let url = config.url.as_deref().unwrap_or_default();
+            let client = async_nats::ConnectOptions::new()
+                .name(format!("git-friends/{}", config.name))
+                .retry_on_initial_connect()
+                .connect(url)
+                .await
+                .map_err(|e| GitFriendsError::ConnectionFailed(format!("NATS: {}", e)))?;
+
+            Ok(NatsPublisher {
+                name: config.name.clone(),
+                client,
+                last_error: LastError::default(),
+            })
+        }
+    }
+
+    impl Publisher for NatsPublisher {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn transport(&self) -> PublisherTransport {
+            PublisherTransport::Nats
+        }
+
+        fn connect(&self, wait: Duration) -> BoxFuture<'_, Result<()>> {
+            async move {
+                timeout(wait, async {
+                    while !self.is_connected() {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                    }
+                })
+                .await
+                .map_err(|_| {
+                    GitFriendsError::ConnectionFailed("NATS connection timeout".to_string())
+                })
+            }
+            .boxed()
+        }
+
+        fn publish<'a>(&'a self, publication: &'a Publication) -> BoxFuture<'a, Result<()>> {
+            async move {
+                let result = async {
+                    // Publishing would wait for the connection to come back
+                    if !self.is_connected() {
+                        return Err(GitFriendsError::Publish("NATS: not connected".to_string()));
+                    }
+                    let payload = serde_json::to_vec(&publication.envelope)?;
+                    self.client
+                        .publish(nats_subject(&publication.topic), payload.into())
+                        .await
+                        .map_err(|e| GitFriendsError::Publish(format!("NATS: {}", e)))?;
+                    // Wait for the server to have the event, as MQTT and Redis do
+                    self.client
+                        .flush()
+                        .await
+                        .map_err(|e| GitFriendsError::Publish(format!("NATS: {}", e)))
+                }
+                .await;
+                self.last_error.record(&result);
+                result
+            }
+            .boxed()
+        }
+
+        fn is_connected(&self) -> bool {
+            self.client.connection_state() == State::Connected
+        }
+
+        fn last_error(&self) -> Option<String> {
+            self.last_error.get()
+        }
+
+        fn close(&self, wait: Duration) -> BoxFuture<'_, Result<()>> {
+            async move {
+                timeout(wait, self.client.drain())
+                    .await
+                    .map_err(|_| {
+                        GitFriendsError::ConnectionFailed("NATS drain timed out".to_string())
+                    })?
+                    .map_err(|e| GitFriendsError::ConnectionFailed(format!("NATS: {}", e)))
+            }
+            .boxed()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::CommitInfo;
+    use futures_util::StreamExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn publication() -> Publication {
+        let commit = CommitInfo {
+            author_name: "Bob Smith".to_string(),
+            ..CommitInfo::fixture("abcdef1234567890")
+        };
+        Publication {
+            id: Some(7),
+            topic: "git-friends/bob/github.com_acme_widgets/Bob Smith".to_string(),
+            username: Some("bob".to_string()),
+            tenant: None,
+            envelope: EventEnvelope::commit(commit, "git-friends/server"),
+        }
+    }
+
+    /// A publisher that is never connected.
+    struct Down;
+
+    impl Publisher for Down {
+        fn name(&self) -> &str {
+            "down"
+        }
+
+        fn transport(&self) -> PublisherTransport {
+            PublisherTransport::Redis
+        }
+
+        fn connect(&self, _wait: Duration) -> BoxFuture<'_, Result<()>> {
+            async { Err(GitFriendsError::ConnectionFailed("refused".to_string())) }.boxed()
+        }
+
+        fn publish<'a>(&'a self, _publication: &'a Publication) -> BoxFuture<'a, Result<()>> {
+            async { Err(GitFriendsError::Publish("refused".to_string())) }.boxed()
+        }
+
+        fn is_connected(&self) -> bool {
+            false
+        }
+
+        fn last_error(&self) -> Option<String> {
+            Some("refused".to_string())
+        }
+
+        fn close(&self, _wait: Duration) -> BoxFuture<'_, Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+    }
+
+    /// A publisher whose first publish fails.
+    #[derive(Default)]
+    struct Flaky {
+        failed: AtomicBool,
+    }
+
+    impl Publisher for Flaky {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn transport(&self) -> PublisherTransport {
+            PublisherTransport::Redis
+        }
+
+        fn connect(&self, _wait: Duration) -> BoxFuture<'_, Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn publish<'a>(&'a self, _publication: &'a Publication) -> BoxFuture<'a, Result<()>> {
+            let first = !self.failed.swap(true, Ordering::SeqCst);
+            async move {
+                if first {
+                    Err(GitFriendsError::Publish("timed out".to_string()))
+                } else {
+                    Ok(())
+                }
+            }
+            .boxed()
+        }
+
+        fn is_connected(&self) -> bool {
+            true
+        }
+
+        fn last_error(&self) -> Option<String> {
+            None
+        }
+
+        fn close(&self, _wait: Duration) -> BoxFuture<'_, Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_memory_and_optional_publishers() {
+        let bus = EventBus::new(16);
+        let subscription = bus.subscribe(vec!["git-friends/bob/#".to_string()]);
+        let mut publishers = Publishers::from_config(
+            &[],
+            &crate::Config::default().mqtt,
+            &[],
+            &bus,
+            Metrics::new().unwrap(),
+        )
+        .await
+        .unwrap();
+        publishers.add(Arc::new(Down), false);
+
+        publishers.connect(Duration::from_millis(10)).await.unwrap();
+        publishers.publish(&publication()).await.unwrap();
+        let event = Box::pin(subscription.into_stream(vec![]))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(event.id, Some(7));
+        assert_eq!(event.commit.short_hash, "abcdef1");
+
+        let health = publishers.health();
+        assert_eq!(health.len(), 2);
+        assert!(health[0].connected);
+        assert_eq!(health[1].last_error.as_deref(), Some("refused"));
+
+        // Only the tenant's own events depend on its publisher
+        publishers.add_for_tenant("acme", Arc::new(Down), true);
+        publishers.publish(&publication()).await.unwrap();
+        let mut tenant_publication = publication();
+        tenant_publication.tenant = Some("acme".to_string());
+        assert!(publishers.publish(&tenant_publication).await.is_err());
+
+        publishers.add(Arc::new(Down), true);
+        assert!(publishers.connect(Duration::from_millis(10)).await.is_err());
+        assert!(publishers.publish(&publication()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_required_publisher_failure_can_be_retried() {
+        let mut publishers = Publishers::new(Metrics::new().unwrap());
+        publishers.add(Arc::new(Flaky::default()), true);
+
+        assert!(matches!(
+            publishers.publish(&publication()).await,
+            Err(GitFriendsError::Publish(_))
+        ));
+        publishers.publish(&publication()).await.unwrap();
+    }
+
+    #[test]
+    fn test_nats_subject() {
+        assert_eq!(
+            nats_subject("git-friends/bob/github.com_acme_widgets/Bob Smith"),
+            "git-friends.bob.github_com_acme_widgets.Bob_Smith"
+        );
+        assert_eq!(nats_subject("a//b*>"), "a._.b__");
+    }
+
+    /// Runs against a local server: `redis-server`, then
+    /// `cargo test -- --ignored`. Set GIT_FRIENDS_TEST_REDIS_URL to use another.
+    #[cfg(feature = "redis")]
+    #[tokio::test]
+    #[ignore]
+    async fn test_redis_stream_publisher() {
+        let mut config = PublisherConfig::new("redis", PublisherTransport::Redis);
+        config.url = Some(
+            std::env::var("GIT_FRIENDS_TEST_REDIS_URL")
+                .unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string()),
+        );
+        config.redis_mode = crate::config::RedisMode::Stream;
+        config.stream_key = format!("git-friends:test:{}", std::process::id());
+
+        let publisher = RedisPublisher::new(&config).unwrap();
+        publisher.connect(Duration::from_secs(5)).await.unwrap();
+        publisher.publish(&publication()).await.unwrap();
+        assert!(publisher.is_connected());
+
+        let client = ::redis::Client::open(config.url.unwrap()).unwrap();
+        let mut connection = client.get_multiplexed_async_connection().await.unwrap();
+        let length: u64 = ::redis::cmd("XLEN")
+            .arg(&config.stream_key)
+            .query_async(&mut connection)
+            .await
+            .unwrap();
+        let _: () = ::redis::cmd("DEL")
+            .arg(&config.stream_key)
+            .query_async(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(length, 1);
+    }
+
+    /// Runs against a local server: `nats-server`, then
+    /// `cargo test -- --ignored`. Set GIT_FRIENDS_TEST_NATS_URL to use another.
+    #[cfg(feature = "nats")]
+    #[tokio::test]
+    #[ignore]
+    async fn test_nats_publisher() {
+        let mut config = PublisherConfig::new("nats", PublisherTransport::Nats);
+        config.url = Some(
+            std::env::var("GIT_FRIENDS_TEST_NATS_URL")
+                .unwrap_or_else(|_| "nats://127.0.0.1:4222".to_string()),
+        );
+
+        let subscriber = async_nats::connect(config.url.as_deref().unwrap())
+            .await
+            .unwrap();
+        let mut messages = subscriber.subscribe("git-friends.bob.>").await.unwrap();
+        subscriber.flush().await.unwrap();
+
+        let publisher = NatsPublisher::new(&config).await.unwrap();
+        publisher.connect(Duration::from_secs(5)).await.unwrap();
+        publisher.publish(&publication()).await.unwrap();
+
+        let message = timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            message.subject.as_str(),
+            "git-friends.bob.github_com_acme_widgets.Bob_Smith"
+        );
+        publisher.close(Duration::from_secs(5)).await.unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use crate::config::RateLimitConfig;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Which limit rejected a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitScope {
+    Global,
+    Token,
+    User,
+    Repository,
+}
+
+impl LimitScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LimitScope::Global => "global",
+            LimitScope::Token => "token",
+            LimitScope::User => "user",
+            LimitScope::Repository => "repository",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    pub scope: LimitScope,
+    pub retry_after: Duration,
+}
+
+/// Every bucket refills completely within this time, after which it is no
+/// different from a new one and can be dropped.
+const IDLE_AFTER: Duration = Duration::from_secs(60);
+
+/// A bucket holding up to one minute's worth of requests, refilled continuously.
+struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    refill_per_sec: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(limit: u32, now: Instant) -> Self {
+        TokenBucket {
+            capacity: limit as f64,
+            available: limit as f64,
+            refill_per_sec: limit as f64 / 60.0,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.refill_per_sec).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Time until `cost` units are available, or `None` if they are available now.
+    fn wait_time(&self, cost: f64) -> Option<Duration> {
+        if self.available >= cost {
+            None
+        } else if self.refill_per_sec <= 0.0 || cost > self.capacity {
+            Some(Duration::from_secs(60))
+        } else {
+            Some(Duration::from_secs_f64(
+                (cost - self.available) / self.refill_per_sec,
+            ))
+        }
+    }
+}
+
+#[derive(Default)]
+struct LimiterState {
+    global: Option<TokenBucket>,
+    tokens: HashMap<String, TokenBucket>,
+    users: HashMap<String, TokenBucket>,
+    repos: HashMap<String, TokenBucket>,
+    /// When idle buckets were last dropped
+    pruned: Option<Instant>,
+}
+
+impl LimiterState {
+    /// Drop the buckets not used for `IDLE_AFTER`, at most once per
+    /// `IDLE_AFTER`, so keys such as repository URLs do not pile up.
+    fn prune(&mut self, now: Instant) {
+        if self
+            .pruned
+            .is_some_and(|pruned| now.saturating_duration_since(pruned) < IDLE_AFTER)
+        {
+            return;
+        }
+        self.pruned = Some(now);
+        for buckets in [&mut self.tokens, &mut self.users, &mut self.repos] {
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < IDLE_AFTER);
+        }
+    }
+}
+
+/// Token-bucket limits applied to accepted webhooks, keyed globally, per
+/// token, per user and per repository (counted in commits).
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            state: Mutex::new(LimiterState::default()),
+        }
+    }
+
+    /// Check all applicable limits and consume from them only if every one
+    /// has capacity, so a rejected request never counts against any bucket.
+    pub fn check(
+        &self,
+        token: Option<(&str, Option<u32>)>,
+        username: &str,
+        repository: &str,
+        commits: u32,
+    ) -> std::result::Result<(), RateLimited> {
+        self.check_at(token, username, repository, commits, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        token: Option<(&str, Option<u32>)>,
+        username: &str,
+        repository: &str,
+        commits: u32,
+        now: Instant,
+    ) -> std::result::Result<(), RateLimited> {
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+        let LimiterState {
+            global,
+            tokens,
+            users,
+            repos,
+            ..
+        } = &mut *state;
+
+        let mut buckets: Vec<(LimitScope, &mut TokenBucket, f64)> = Vec::new();
+
+        if let Some(limit) = self.config.global_per_minute {
+            let bucket = global.get_or_insert_with(|| TokenBucket::per_minute(limit, now));
+            buckets.push((LimitScope::Global, bucket, 1.0));
+        }
+        if let Some((token, override_limit)) = token {
+            if let Some(limit) = override_limit.or(self.config.per_token_per_minute) {
+                let bucket = tokens
+                    .entry(token.to_string())
+                    .or_insert_with(|| TokenBucket::per_minute(limit, now));
+                buckets.push((LimitScope::Token, bucket, 1.0));
+            }
+        }
+        if let Some(limit) = self.config.per_user_per_minute {
+            let bucket = users
+                .entry(username.to_string())
+                .or_insert_with(|| TokenBucket::per_minute(limit, now));
+            buckets.push((LimitScope::User, bucket, 1.0));
+        }
+        if let Some(limit) = self.config.per_repo_commits_per_minute {
+            let bucket = repos
+                .entry(repository.to_string())
+                .or_insert_with(|| TokenBucket::per_minute(limit, now));
+            buckets.push((LimitScope::Repository, bucket, commits as f64));
+        }
+
+        let mut rejected: Option<RateLimited> = None;
+        for (scope, bucket, cost) in buckets.iter_mut() {
+            bucket.refill(now);
+            if let Some(wait) = bucket.wait_time(*cost) {
+                if rejected.is_none_or(|r| wait > r.retry_after) {
+                    rejected = Some(RateLimited {
+                        scope: *scope,
+                        retry_after: wait,
+                    });
+                }
+            }
+        }
+
+        if let Some(rejected) = rejected {
+            return Err(rejected);
+        }
+
+        for (_, bucket, cost) in buckets {
+            bucket.available -= cost;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            per_token_per_minute: Some(2),
+            per_repo_commits_per_minute: Some(3),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_token_limit_and_refill() {
+        let limiter = RateLimiter::new(config());
+        let start = Instant::now();
+
+        assert!(limiter
+            .check_at(Some(("tok", None)), "alice", "a", 1, start)
+            .is_ok());
+        assert!(limiter
+            .check_at(Some(("tok", None)), "alice", "b", 1, start)
+            .is_ok());
+
+        let rejected = limiter
+            .check_at(Some(("tok", None)), "alice", "c", 1, start)
+            .unwrap_err();
+        assert_eq!(rejected.scope, LimitScope::Token);
+        assert_eq!(rejected.retry_after, Duration::from_secs(30));
+
+        // Another token is unaffected, and the first refills after 30 seconds
+        assert!(limiter
+            .check_at(Some(("other", None)), "bob", "c", 1, start)
+            .is_ok());
+        assert!(limiter
+            .check_at(
+                Some(("tok", None)),
+                "alice",
+                "c",
+                1,
+                start + Duration::from_secs(30)
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_rejection_consumes_nothing() {
+        let limiter = RateLimiter::new(config());
+        let now = Instant::now();
+
+        // Repository budget is exhausted first; the token budget must survive
+        assert!(limiter
+            .check_at(Some(("tok", None)), "alice", "repo", 3, now)
+            .is_ok());
+        let rejected = limiter
+            .check_at(Some(("tok", None)), "alice", "repo", 1, now)
+            .unwrap_err();
+        assert_eq!(rejected.scope, LimitScope::Repository);
+        assert!(limiter
+            .check_at(Some(("tok", None)), "alice", "other", 1, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_idle_buckets_are_dropped() {
+        let limiter = RateLimiter::new(config());
+        let start = Instant::now();
+        for repo in ["a", "b", "c"] {
+            assert!(limiter.check_at(None, "alice", repo, 3, start).is_ok());
+        }
+        assert_eq!(limiter.state.lock().unwrap().repos.len(), 3);
+
+        // Only the bucket used in the last minute survives
+        let later = start + Duration::from_secs(61);
+        assert!(limiter.check_at(None, "alice", "d", 1, later).is_ok());
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.repos.keys().collect::<Vec<_>>(), ["d"]);
+    }
+
+    #[test]
+    fn test_token_override() {
+        let limiter = RateLimiter::new(config());
+        let now = Instant::now();
+
+        assert!(limiter
+            .check_at(Some(("ci", Some(1))), "ci", "a", 1, now)
+            .is_ok());
+        assert_eq!(
+            limiter
+                .check_at(Some(("ci", Some(1))), "ci", "b", 1, now)
+                .unwrap_err()
+                .scope,
+            LimitScope::Token
+        );
+    }
+}
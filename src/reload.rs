@@ -0,0 +1,151 @@
+use log::{error, info};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration};
+
+/// A value that can be replaced atomically while readers keep using the
+/// snapshot they loaded.
+pub struct Swappable<T> {
+    current: RwLock<Arc<T>>,
+}
+
+impl<T> Swappable<T> {
+    pub fn new(value: T) -> Self {
+        Swappable {
+            current: RwLock::new(Arc::new(value)),
+        }
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn store(&self, value: T) {
+        *self.current.write().unwrap() = Arc::new(value);
+    }
+
+    /// Replace the value with one derived from the current value. Updates
+    /// are serialised, so concurrent updates never overwrite each other.
+    pub fn update<E>(&self, f: impl FnOnce(&T) -> Result<T, E>) -> Result<(), E> {
+        let mut current = self.current.write().unwrap();
+        *current = Arc::new(f(&current)?);
+        Ok(())
+    }
+}
+
+/// Why a configuration reload was requested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReloadTrigger {
+    Signal,
+    FileChanged,
+}
+
+impl std::fmt::Display for ReloadTrigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReloadTrigger::Signal => write!(f, "SIGHUP"),
+            ReloadTrigger::FileChanged => write!(f, "configuration file change"),
+        }
+    }
+}
+
+/// Emit a trigger on SIGHUP and, when `watch_interval` is set, whenever the
+/// modification time of one of `paths` changes.
+pub fn spawn_reload_watcher(
+    paths: Vec<PathBuf>,
+    watch_interval: Option<Duration>,
+) -> mpsc::Receiver<ReloadTrigger> {
+    let (tx, rx) = mpsc::channel(4);
+
+    #[cfg(unix)]
+    {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    error!("Failed to install SIGHUP handler: {}", e);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                if tx.send(ReloadTrigger::Signal).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(watch_interval) = watch_interval.filter(|_| !paths.is_empty()) {
+        for path in &paths {
+            info!("Watching {} for configuration changes", path.display());
+        }
+        tokio::spawn(async move {
+            let mut last_modified = modified(&paths);
+            let mut ticker = interval(watch_interval);
+            loop {
+                ticker.tick().await;
+                let current = modified(&paths);
+                if current != last_modified {
+                    last_modified = current;
+                    if tx.send(ReloadTrigger::FileChanged).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    rx
+}
+
+fn modified(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swappable_keeps_loaded_snapshot() {
+        let value = Swappable::new(vec!["#old".to_string()]);
+        let snapshot = value.load();
+
+        value.store(vec!["#new".to_string()]);
+
+        assert_eq!(snapshot[0], "#old");
+        assert_eq!(value.load()[0], "#new");
+    }
+
+    #[tokio::test]
+    async fn test_file_change_triggers_reload() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("git-friends.toml");
+        std::fs::write(&path, "").unwrap();
+
+        let mut triggers =
+            spawn_reload_watcher(vec![path.clone()], Some(Duration::from_millis(10)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let later = SystemTime::now() + Duration::from_secs(5);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        let trigger = tokio::time::timeout(Duration::from_secs(2), triggers.recv())
+            .await
+            .unwrap();
+        assert_eq!(trigger, Some(ReloadTrigger::FileChanged));
+    }
+}
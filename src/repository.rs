@@ -0,0 +1,298 @@
+use crate::config::{GitConfig, RepositoryRule, Visibility};
+use log::warn;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// Repository URL without scheme, user, trailing slash and `.git`, with the
+/// scp-like `git@host:path` form turned into `host/path`.
+pub fn normalize_url(url: &str) -> String {
+    let url = url.trim().trim_end_matches('/');
+    let (rest, scp_like) = match url.split_once("://") {
+        Some((_, rest)) => (rest, false),
+        None => (url, true),
+    };
+    let rest = match rest.split_once('@') {
+        Some((user, host)) if !user.contains('/') => host,
+        _ => rest,
+    };
+    let normalized = if scp_like {
+        rest.replacen(':', "/", 1)
+    } else {
+        rest.to_string()
+    };
+    normalized
+        .strip_suffix(".git")
+        .unwrap_or(&normalized)
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Last path segment of a normalized URL.
+fn last_segment(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Anchored, case-insensitive regex for a glob over normalized URLs.
+fn glob_regex(pattern: &str) -> String {
+    let mut regex = String::from("(?i)^");
+    let pattern = normalize_url(pattern);
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str("(.*)");
+            }
+            '*' => regex.push_str("([^/]*)"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn matcher(rule: &RepositoryRule) -> Result<Regex, String> {
+    let source = match (&rule.pattern, &rule.regex) {
+        (Some(pattern), None) => glob_regex(pattern),
+        (None, Some(regex)) => regex.clone(),
+        _ => return Err("needs either a pattern or a regex".to_string()),
+    };
+    Regex::new(&source).map_err(|e| format!("has an invalid regex: {}", e))
+}
+
+/// Replace the `{...}` placeholders of `template` with what `lookup`
+/// returns for them.
+fn expand(template: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| "name has an unclosed '{'".to_string())?
+            + start;
+        let key = &rest[start + 1..end];
+        let value =
+            lookup(key).ok_or_else(|| format!("name has unknown placeholder '{{{}}}'", key))?;
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Check that a rule has one valid matcher and that its name only refers
+/// to captures the matcher has.
+pub fn check_rule(rule: &RepositoryRule) -> Result<(), String> {
+    let regex = matcher(rule)?;
+    if let Some(name) = &rule.name {
+        expand(name, |key| {
+            let known = match key.parse::<usize>() {
+                Ok(index) => index < regex.captures_len(),
+                Err(_) => {
+                    regex.capture_names().any(|n| n == Some(key)) || key == "name" || key == "path"
+                }
+            };
+            known.then(String::new)
+        })?;
+    }
+    Ok(())
+}
+
+/// What the rules say about one repository.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepositoryInfo {
+    /// Display name, when a mapping gives one
+    pub name: Option<String>,
+    pub visibility: Visibility,
+    pub channel: Option<String>,
+}
+
+struct CompiledRule {
+    regex: Regex,
+    rule: RepositoryRule,
+}
+
+/// `git.repository_mappings` and `git.repository_rules`, ready to resolve
+/// repository URLs.
+#[derive(Default)]
+pub struct RepositoryRules {
+    /// Display names by normalized URL
+    exact: HashMap<String, String>,
+    rules: Vec<CompiledRule>,
+}
+
+impl RepositoryRules {
+    /// Compile the mappings of `git`. Invalid rules, which `Config::validate`
+    /// reports, are skipped.
+    pub fn new(git: &GitConfig) -> Self {
+        let exact = git
+            .repository_mappings
+            .iter()
+            .map(|(url, name)| (normalize_url(url).to_lowercase(), name.clone()))
+            .collect();
+        let rules = git
+            .repository_rules
+            .iter()
+            .enumerate()
+            .filter_map(|(i, rule)| match matcher(rule) {
+                Ok(regex) => Some(CompiledRule {
+                    regex,
+                    rule: rule.clone(),
+                }),
+                Err(e) => {
+                    warn!("Skipping git.repository_rules[{}], which {}", i, e);
+                    None
+                }
+            })
+            .collect();
+        RepositoryRules { exact, rules }
+    }
+
+    /// Attributes of the exact mapping or the first rule that matches
+    /// `repository_url`; defaults when none does.
+    pub fn resolve(&self, repository_url: &str) -> RepositoryInfo {
+        let path = normalize_url(repository_url);
+        if let Some(name) = self.exact.get(&path.to_lowercase()) {
+            return RepositoryInfo {
+                name: Some(name.clone()),
+                ..Default::default()
+            };
+        }
+
+        for CompiledRule { regex, rule } in &self.rules {
+            let Some(captures) = regex.captures(&path) else {
+                continue;
+            };
+            let name = rule.name.as_ref().and_then(|template| {
+                expand(template, |key| {
+                    let value = match key.parse::<usize>() {
+                        Ok(index) if index < captures.len() => {
+                            Some(captures.get(index).map_or("", |m| m.as_str()))
+                        }
+                        Ok(_) => None,
+                        Err(_) => captures.name(key).map(|m| m.as_str()).or(match key {
+                            "name" => Some(last_segment(&path)),
+                            "path" => Some(path.as_str()),
+                            _ => None,
+                        }),
+                    };
+                    value.map(str::to_string)
+                })
+                .ok()
+            });
+            return RepositoryInfo {
+                name,
+                visibility: rule.visibility,
+                channel: rule.channel.clone(),
+            };
+        }
+        RepositoryInfo::default()
+    }
+
+    /// Channels named by the rules, for gf-irc to join.
+    pub fn channels(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .filter_map(|compiled| compiled.rule.channel.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: Option<&str>, regex: Option<&str>, name: &str) -> RepositoryRule {
+        RepositoryRule {
+            pattern: pattern.map(str::to_string),
+            regex: regex.map(str::to_string),
+            name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_normalize_url() {
+        for url in [
+            "https://github.com/acme/widgets",
+            "https://github.com/acme/widgets.git/",
+            "git@github.com:acme/widgets.git",
+            "ssh://git@github.com/acme/widgets",
+        ] {
+            assert_eq!(normalize_url(url), "github.com/acme/widgets", "{}", url);
+        }
+        assert_eq!(
+            normalize_url("ssh://git@example.com:2222/x"),
+            "example.com:2222/x"
+        );
+    }
+
+    #[test]
+    fn test_resolve_rules_in_order() {
+        let git = GitConfig {
+            hook_script_path: None,
+            repository_mappings: HashMap::from([(
+                "https://github.com/acme/legacy".to_string(),
+                "Legacy".to_string(),
+            )]),
+            repository_rules: vec![
+                RepositoryRule {
+                    visibility: Visibility::Private,
+                    channel: Some("#acme-secret".to_string()),
+                    ..rule(Some("github.com/acme/secret-*"), None, "secret {1}")
+                },
+                rule(Some("https://github.com/acme/*"), None, "acme/{name}"),
+                rule(
+                    None,
+                    Some(r"^gitlab\.com/(?P<group>[^/]+)/(.+)$"),
+                    "{group}: {2}",
+                ),
+                rule(Some("**/tools"), None, "tools in {1}"),
+            ],
+        };
+        let rules = RepositoryRules::new(&git);
+
+        let secret = rules.resolve("git@github.com:acme/secret-sauce.git");
+        assert_eq!(secret.name.as_deref(), Some("secret sauce"));
+        assert_eq!(secret.visibility, Visibility::Private);
+        assert_eq!(secret.channel.as_deref(), Some("#acme-secret"));
+
+        let widgets = rules.resolve("https://GitHub.com/Acme/Widgets");
+        assert_eq!(widgets.name.as_deref(), Some("acme/Widgets"));
+        assert_eq!(widgets.visibility, Visibility::Public);
+        assert_eq!(
+            rules
+                .resolve("git@github.com:acme/legacy.git")
+                .name
+                .as_deref(),
+            Some("Legacy")
+        );
+        assert_eq!(
+            rules
+                .resolve("https://gitlab.com/infra/deploy/scripts")
+                .name
+                .as_deref(),
+            Some("infra: deploy/scripts")
+        );
+        assert_eq!(
+            rules
+                .resolve("https://example.com/a/b/tools")
+                .name
+                .as_deref(),
+            Some("tools in example.com/a/b")
+        );
+        assert_eq!(
+            rules.resolve("https://example.com/other"),
+            RepositoryInfo::default()
+        );
+        assert_eq!(rules.channels().collect::<Vec<_>>(), ["#acme-secret"]);
+
+        assert!(check_rule(&rule(Some("a/*"), None, "{1} {name} {path}")).is_ok());
+        assert!(check_rule(&rule(Some("a/*"), None, "{2}"))
+            .unwrap_err()
+            .contains("unknown placeholder '{2}'"));
+        assert!(check_rule(&rule(None, Some("("), "x")).is_err());
+        assert!(check_rule(&rule(Some("a"), Some("a"), "x")).is_err());
+    }
+}
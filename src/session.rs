@@ -0,0 +1,67 @@
+use crate::auth::AuthManager;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// How long a dashboard session lasts after sign-in.
+pub const SESSION_TTL_SECS: i64 = 12 * 60 * 60;
+
+/// Dashboard sessions, kept in memory and lost on restart. The cookie only
+/// carries a random session id; the token it stands for never leaves the
+/// server.
+pub struct Sessions {
+    /// Token and expiry by session id
+    sessions: Mutex<HashMap<String, (String, i64)>>,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        Sessions {
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start a session for `token`, returning its id.
+    pub fn create(&self, token: &str, now: i64) -> String {
+        let id = AuthManager::generate_token();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (_, expires_at)| *expires_at > now);
+        sessions.insert(id.clone(), (token.to_string(), now + SESSION_TTL_SECS));
+        id
+    }
+
+    /// The token of a session that has not expired.
+    pub fn token(&self, id: &str, now: i64) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        let (token, expires_at) = sessions.get(id)?;
+        (*expires_at > now).then(|| token.clone())
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions_expire_and_end() {
+        let sessions = Sessions::new();
+        let id = sessions.create("tok", 1000);
+        assert_ne!(id, "tok");
+        assert_eq!(sessions.token(&id, 1000).as_deref(), Some("tok"));
+        assert_eq!(sessions.token(&id, 1000 + SESSION_TTL_SECS), None);
+        assert_eq!(sessions.token("unknown", 1000), None);
+
+        let id = sessions.create("tok", 2000);
+        sessions.remove(&id);
+        assert_eq!(sessions.token(&id, 2000), None);
+    }
+}
@@ -0,0 +1,184 @@
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const HEADER_KEY_ID: &str = "x-gf-key-id";
+pub const HEADER_TIMESTAMP: &str = "x-gf-timestamp";
+pub const HEADER_NONCE: &str = "x-gf-nonce";
+pub const HEADER_SIGNATURE: &str = "x-gf-signature";
+
+/// Default for `auth.signature_max_skew_secs`.
+pub const DEFAULT_MAX_SKEW_SECS: u64 = 300;
+
+/// Public identifier of a signing secret, sent with every signed request so
+/// the server can find the secret without the client naming a user.
+pub fn key_id(secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"signing-key");
+    hasher.update(secret.as_bytes());
+    general_purpose::URL_SAFE_NO_PAD.encode(&hasher.finalize()[..9])
+}
+
+/// The string that is signed: method, request target (path and query),
+/// timestamp, nonce and the base64 SHA-256 of the body, one per line.
+pub fn canonical_request(
+    method: &str,
+    target: &str,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}",
+        method.to_ascii_uppercase(),
+        target,
+        timestamp,
+        nonce,
+        general_purpose::STANDARD.encode(Sha256::digest(body))
+    )
+}
+
+fn mac(secret: &str, canonical: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(canonical.as_bytes());
+    mac
+}
+
+/// Signature headers of a request, as sent by gf-hook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestSignature {
+    pub key_id: String,
+    pub timestamp: i64,
+    pub nonce: String,
+    pub signature: String,
+}
+
+impl RequestSignature {
+    /// Sign a request with `secret`.
+    pub fn sign(
+        secret: &str,
+        method: &str,
+        target: &str,
+        timestamp: i64,
+        nonce: &str,
+        body: &[u8],
+    ) -> Self {
+        let canonical = canonical_request(method, target, timestamp, nonce, body);
+        RequestSignature {
+            key_id: key_id(secret),
+            timestamp,
+            nonce: nonce.to_string(),
+            signature: general_purpose::STANDARD
+                .encode(mac(secret, &canonical).finalize().into_bytes()),
+        }
+    }
+
+    /// Check the signature against `secret` in constant time.
+    pub fn verify(&self, secret: &str, method: &str, target: &str, body: &[u8]) -> bool {
+        let Ok(signature) = general_purpose::STANDARD.decode(&self.signature) else {
+            return false;
+        };
+        let canonical = canonical_request(method, target, self.timestamp, &self.nonce, body);
+        mac(secret, &canonical).verify_slice(&signature).is_ok()
+    }
+
+    /// Whether the timestamp lies within `max_skew_secs` of `now`.
+    pub fn is_fresh(&self, now: i64, max_skew_secs: u64) -> bool {
+        now.abs_diff(self.timestamp) <= max_skew_secs
+    }
+
+    pub fn headers(&self) -> [(&'static str, String); 4] {
+        [
+            (HEADER_KEY_ID, self.key_id.clone()),
+            (HEADER_TIMESTAMP, self.timestamp.to_string()),
+            (HEADER_NONCE, self.nonce.clone()),
+            (HEADER_SIGNATURE, self.signature.clone()),
+        ]
+    }
+}
+
+/// Nonces of recently accepted signed requests. A nonce only has to be
+/// remembered while its timestamp is fresh; older requests are rejected as
+/// stale anyway.
+pub struct NonceCache {
+    seen: Mutex<HashMap<(String, String), i64>>,
+}
+
+impl NonceCache {
+    pub fn new() -> Self {
+        NonceCache {
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record the request's nonce, returning false if it was already used
+    /// with the same key.
+    pub fn insert(&self, signature: &RequestSignature, now: i64, max_skew_secs: u64) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, timestamp| now.abs_diff(*timestamp) <= max_skew_secs);
+
+        let key = (signature.key_id.clone(), signature.nonce.clone());
+        if seen.contains_key(&key) {
+            return false;
+        }
+        seen.insert(key, signature.timestamp);
+        true
+    }
+
+    /// Whether the request's nonce was already used with the same key,
+    /// without recording it.
+    pub fn contains(&self, signature: &RequestSignature) -> bool {
+        let key = (signature.key_id.clone(), signature.nonce.clone());
+        self.seen.lock().unwrap().contains_key(&key)
+    }
+}
+
+impl Default for NonceCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let body = br#"{"hash":"abc"}"#;
+        let signature = RequestSignature::sign("s3cret", "POST", "/webhook", 1000, "n1", body);
+
+        assert_eq!(signature.key_id, key_id("s3cret"));
+        assert!(signature.verify("s3cret", "POST", "/webhook", body));
+        assert!(!signature.verify("other", "POST", "/webhook", body));
+        assert!(!signature.verify("s3cret", "POST", "/webhook", b"{}"));
+        assert!(!signature.verify("s3cret", "POST", "/api/commits", body));
+
+        let mut tampered = signature.clone();
+        tampered.timestamp += 1;
+        assert!(!tampered.verify("s3cret", "POST", "/webhook", body));
+
+        assert!(signature.is_fresh(1200, 300));
+        assert!(!signature.is_fresh(1301, 300));
+        assert!(!signature.is_fresh(699, 300));
+    }
+
+    #[test]
+    fn test_nonce_cache_rejects_replays() {
+        let cache = NonceCache::new();
+        let first = RequestSignature::sign("s3cret", "POST", "/webhook", 1000, "n1", b"");
+        let second = RequestSignature::sign("s3cret", "POST", "/webhook", 1001, "n2", b"");
+
+        assert!(cache.insert(&first, 1000, 300));
+        assert!(!cache.insert(&first, 1010, 300));
+        assert!(cache.insert(&second, 1010, 300));
+
+        // Expired nonces are forgotten; the timestamp check rejects them
+        assert!(cache.insert(&first, 2000, 300));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::git::CommitInfo;
+use crate::mqtt::{repository_id, topic_matches};
+use crate::repository::RepositoryRules;
+use futures_util::stream::{self, Stream, StreamExt};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// A commit as delivered to live stream subscribers.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StreamEvent {
+    /// History store id, present when gf-server keeps a history database.
+    pub id: Option<i64>,
+    pub topic: String,
+    pub username: Option<String>,
+    pub commit: CommitInfo,
+    /// How the repository is shown, filled in when the event is sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<RepositoryLabel>,
+}
+
+/// Id and display name of a commit's repository.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RepositoryLabel {
+    /// As used in `/dashboard/repos/<id>` and commit queries
+    pub id: String,
+    /// The configured name, or the id when there is none
+    pub name: String,
+}
+
+impl StreamEvent {
+    /// Name the repository as `repositories` does.
+    pub fn labelled(mut self, repositories: &RepositoryRules) -> Self {
+        let id = repository_id(&self.commit.repository_url);
+        let name = repositories
+            .resolve(&self.commit.repository_url)
+            .name
+            .unwrap_or_else(|| id.clone());
+        self.repository = Some(RepositoryLabel { id, name });
+        self
+    }
+}
+
+/// In-process fan-out of accepted commits to SSE and WebSocket subscribers.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<StreamEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus { sender }
+    }
+
+    pub fn publish(&self, event: StreamEvent) {
+        // An error only means nobody is listening right now
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribe to events whose topic matches any of `filters` (all events
+    /// when empty). Subscribe before loading a resume backlog so that no
+    /// event falls between the backlog and the live feed.
+    pub fn subscribe(&self, filters: Vec<String>) -> Subscription {
+        Subscription {
+            receiver: self.sender.subscribe(),
+            filters,
+        }
+    }
+}
+
+pub struct Subscription {
+    receiver: broadcast::Receiver<StreamEvent>,
+    filters: Vec<String>,
+}
+
+impl Subscription {
+    /// Replay `backlog`, then follow the live feed. Live events with an id at
+    /// or below the highest replayed id are skipped so a resume never repeats.
+    /// Live ids are not compared with each other: concurrent webhooks may
+    /// publish them out of order.
+    pub fn into_stream(self, backlog: Vec<StreamEvent>) -> impl Stream<Item = StreamEvent> {
+        let filters = self.filters;
+        let replayed_id = backlog.iter().filter_map(|e| e.id).max();
+        let backlog: Vec<StreamEvent> = backlog
+            .into_iter()
+            .filter(|e| matches_filters(&filters, &e.topic))
+            .collect();
+
+        let live = stream::unfold(self.receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Stream subscriber lagged, skipped {} events", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+        .filter(move |event| {
+            let keep = matches_filters(&filters, &event.topic)
+                && match (event.id, replayed_id) {
+                    (Some(id), Some(replayed)) => id > replayed,
+                    _ => true,
+                };
+            futures_util::future::ready(keep)
+        });
+
+        stream::iter(backlog).chain(live)
+    }
+}
+
+fn matches_filters(filters: &[String], topic: &str) -> bool {
+    filters.is_empty() || filters.iter().any(|f| topic_matches(f, topic))
+}
+
+/// Parse a comma-separated list of topic filters as accepted on the query string.
+pub fn parse_topic_filters(topics: Option<&str>) -> Vec<String> {
+    topics
+        .map(|t| {
+            t.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(id: i64, topic: &str) -> StreamEvent {
+        StreamEvent {
+            id: Some(id),
+            topic: topic.to_string(),
+            username: None,
+            repository: None,
+            commit: CommitInfo {
+                repository_url: "repo".to_string(),
+                ..CommitInfo::fixture(&format!("{:07}", id))
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscription_filters_and_resumes() {
+        let bus = EventBus::new(16);
+        let subscription = bus.subscribe(vec!["git-friends/bob/#".to_string()]);
+
+        // Event 2 is both in the backlog and delivered live
+        bus.publish(event(2, "git-friends/bob/repo/Bob"));
+        bus.publish(event(3, "git-friends/alice/repo/Alice"));
+        bus.publish(event(4, "git-friends/bob/repo/Bob"));
+
+        let backlog = vec![
+            event(1, "git-friends/bob/repo/Bob"),
+            event(2, "git-friends/bob/repo/Bob"),
+        ];
+        let ids: Vec<i64> = subscription
+            .into_stream(backlog)
+            .take(3)
+            .map(|e| e.id.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(ids, vec![1, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_live_events_out_of_order() {
+        let bus = EventBus::new(16);
+        let subscription = bus.subscribe(vec![]);
+
+        // Two webhooks recorded as 1 and 2 may publish in either order
+        bus.publish(event(2, "git-friends/bob/repo/Bob"));
+        bus.publish(event(1, "git-friends/bob/repo/Bob"));
+
+        let ids: Vec<i64> = subscription
+            .into_stream(vec![])
+            .take(2)
+            .map(|e| e.id.unwrap())
+            .collect()
+            .await;
+
+        assert_eq!(ids, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_events_name_their_repository() {
+        let mut git = crate::config::Config::default().git;
+        git.repository_mappings.insert(
+            "https://github.com/acme/widgets".to_string(),
+            "Widgets".to_string(),
+        );
+        let repositories = RepositoryRules::new(&git);
+
+        let mut mapped = event(1, "git-friends/bob/repo/Bob");
+        mapped.commit.repository_url = "https://github.com/acme/widgets".to_string();
+        let label = mapped.labelled(&repositories).repository.unwrap();
+        assert_eq!(label.id, "github.com_acme_widgets");
+        assert_eq!(label.name, "Widgets");
+
+        let unmapped = event(2, "git-friends/bob/repo/Bob").labelled(&repositories);
+        assert_eq!(unmapped.repository.unwrap().name, "repo");
+    }
+
+    #[test]
+    fn test_parse_topic_filters() {
+        assert_eq!(
+            parse_topic_filters(Some("a/+, b/#,")),
+            vec!["a/+".to_string(), "b/#".to_string()]
+        );
+        assert!(parse_topic_filters(None).is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+use crate::auth::wildcard_match;
+use crate::config::{Config, TenantConfig, Visibility};
+use crate::repository::{normalize_url, RepositoryInfo};
+
+/// Whether `topic` is `prefix` itself or lies below it.
+pub fn topic_within(prefix: &str, topic: &str) -> bool {
+    topic
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Whether one namespace contains the other.
+pub fn prefixes_overlap(a: &str, b: &str) -> bool {
+    topic_within(a, b) || topic_within(b, a)
+}
+
+impl TenantConfig {
+    pub fn has_user(&self, username: &str) -> bool {
+        self.users
+            .iter()
+            .any(|pattern| wildcard_match(pattern, username))
+    }
+
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admin_users.iter().any(|admin| admin == username)
+    }
+
+    /// Whether the tenant lists `repository_url` among its repositories.
+    /// Both sides are normalized, so any spelling of a URL matches.
+    pub fn owns_repository(&self, repository_url: &str) -> bool {
+        let url = normalize_url(repository_url).to_lowercase();
+        self.repositories
+            .iter()
+            .any(|pattern| wildcard_match(&normalize_url(pattern).to_lowercase(), &url))
+    }
+
+    pub fn owns_topic(&self, topic: &str) -> bool {
+        topic_within(&self.topic_prefix, topic)
+    }
+
+    /// MQTT filter matching every event of the tenant.
+    pub fn topic_filter(&self) -> String {
+        format!("{}/#", self.topic_prefix)
+    }
+}
+
+/// The first tenant `username` belongs to.
+pub fn tenant_for_user<'a>(
+    tenants: &'a [TenantConfig],
+    username: &str,
+) -> Option<&'a TenantConfig> {
+    tenants.iter().find(|tenant| tenant.has_user(username))
+}
+
+pub fn tenant_for_topic<'a>(tenants: &'a [TenantConfig], topic: &str) -> Option<&'a TenantConfig> {
+    tenants.iter().find(|tenant| tenant.owns_topic(topic))
+}
+
+pub fn find_tenant<'a>(tenants: &'a [TenantConfig], name: &str) -> Option<&'a TenantConfig> {
+    tenants.iter().find(|tenant| tenant.name == name)
+}
+
+/// Topic prefix events of the tenant named `tenant` are published under,
+/// `mqtt.topic_prefix` for users outside every tenant.
+pub fn topic_prefix<'a>(config: &'a Config, tenant: Option<&str>) -> &'a str {
+    tenant
+        .and_then(|name| find_tenant(&config.tenants, name))
+        .map_or(&config.mqtt.topic_prefix, |tenant| &tenant.topic_prefix)
+}
+
+/// Channels gf-irc announces an event on `topic` in. A tenant's events only
+/// go to the tenant's own channels.
+pub fn irc_channels<'a>(config: &'a Config, topic: &str) -> &'a [String] {
+    match tenant_for_topic(&config.tenants, topic) {
+        Some(tenant) => &tenant.irc_channels,
+        None => &config.irc.channels,
+    }
+}
+
+/// Channels an event is announced in. A repository rule's channel replaces
+/// the default ones, except that a tenant's events stay in the tenant's
+/// channels. Private repositories are only announced in their own channel.
+pub fn announce_channels<'a>(
+    config: &'a Config,
+    topic: &str,
+    repository: &'a RepositoryInfo,
+) -> Vec<&'a str> {
+    let defaults = irc_channels(config, topic);
+    let in_tenant = tenant_for_topic(&config.tenants, topic).is_some();
+    match &repository.channel {
+        Some(channel) if !in_tenant || defaults.contains(channel) => vec![channel.as_str()],
+        _ if repository.visibility == Visibility::Private => vec![],
+        _ => defaults.iter().map(String::as_str).collect(),
+    }
+}
+
+/// Filters gf-irc subscribes to: `irc.topic_filters` and the namespaces of
+/// tenants that have channels.
+pub fn irc_topic_filters(config: &Config) -> Vec<String> {
+    let mut filters = config.irc.topic_filters.clone();
+    for tenant in config.tenants.iter().filter(|t| !t.irc_channels.is_empty()) {
+        let filter = tenant.topic_filter();
+        if !filters.contains(&filter) {
+            filters.push(filter);
+        }
+    }
+    filters
+}
+
+/// Which tenants' events a reader may see.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TenantScope {
+    #[default]
+    All,
+    /// Only events of users outside every tenant
+    Untenanted,
+    Tenant(String),
+}
+
+impl TenantScope {
+    /// Members of a tenant see the tenant's events. Everyone else sees the
+    /// events outside every tenant, except `auth.admin_users`, who see all.
+    pub fn for_user(config: &Config, username: &str) -> Self {
+        if let Some(tenant) = tenant_for_user(&config.tenants, username) {
+            TenantScope::Tenant(tenant.name.clone())
+        } else if config.tenants.is_empty()
+            || config
+                .auth
+                .admin_users
+                .iter()
+                .any(|admin| admin == username)
+        {
+            TenantScope::All
+        } else {
+            TenantScope::Untenanted
+        }
+    }
+
+    pub fn allows(&self, tenant: Option<&str>) -> bool {
+        match self {
+            TenantScope::All => true,
+            TenantScope::Untenanted => tenant.is_none(),
+            TenantScope::Tenant(name) => tenant == Some(name.as_str()),
+        }
+    }
+}
+
+/// Why a user may not publish events of a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TenantViolation {
+    /// The repository is not one of the user's tenant's repositories
+    Repository,
+    /// The repository belongs to another tenant
+    OtherTenant,
+}
+
+impl TenantViolation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TenantViolation::Repository => "tenant_repository",
+            TenantViolation::OtherTenant => "other_tenant",
+        }
+    }
+}
+
+/// Check that a user of `tenant`, or of no tenant, may publish events of
+/// `repository_url`.
+pub fn check_repository(
+    tenants: &[TenantConfig],
+    tenant: Option<&TenantConfig>,
+    repository_url: &str,
+) -> std::result::Result<(), TenantViolation> {
+    if let Some(tenant) = tenant {
+        if tenant.owns_repository(repository_url) {
+            return Ok(());
+        }
+        if !tenant.repositories.is_empty() {
+            return Err(TenantViolation::Repository);
+        }
+    }
+
+    let owned_elsewhere = tenants.iter().any(|other| {
+        tenant.is_none_or(|tenant| tenant.name != other.name)
+            && other.owns_repository(repository_url)
+    });
+    if owned_elsewhere {
+        Err(TenantViolation::OtherTenant)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant(name: &str, users: &[&str], repositories: &[&str]) -> TenantConfig {
+        TenantConfig {
+            name: name.to_string(),
+            topic_prefix: format!("tenants/{}", name),
+            users: users.iter().map(|s| s.to_string()).collect(),
+            repositories: repositories.iter().map(|s| s.to_string()).collect(),
+            admin_users: vec![],
+            mqtt_username: None,
+            mqtt_password: None,
+            irc_channels: vec![format!("#{}", name)],
+        }
+    }
+
+    #[test]
+    fn test_tenant_lookup() {
+        let config = Config {
+            tenants: vec![
+                tenant("acme", &["alice", "acme-*"], &[]),
+                tenant("globex", &["bob"], &[]),
+            ],
+            ..Config::default()
+        };
+
+        let tenants = &config.tenants;
+        assert_eq!(tenant_for_user(tenants, "acme-ci").unwrap().name, "acme");
+        assert!(tenant_for_user(tenants, "carol").is_none());
+        assert_eq!(topic_prefix(&config, Some("globex")), "tenants/globex");
+        assert_eq!(topic_prefix(&config, None), "git-friends");
+
+        assert_eq!(
+            irc_channels(&config, "tenants/acme/alice/r/Alice"),
+            ["#acme"]
+        );
+        assert_eq!(
+            irc_channels(&config, "tenants/acmeco/alice/r/Alice"),
+            ["#git-friends"]
+        );
+        assert!(prefixes_overlap("tenants/acme", "tenants/acme/ci"));
+        assert!(!prefixes_overlap("tenants/acme", "tenants/acmeco"));
+    }
+
+    #[test]
+    fn test_check_repository() {
+        let tenants = vec![
+            tenant("acme", &["alice"], &["https://github.com/acme/*"]),
+            tenant("globex", &["bob"], &[]),
+        ];
+        let (acme, globex) = (Some(&tenants[0]), Some(&tenants[1]));
+
+        assert!(check_repository(&tenants, acme, "https://github.com/acme/widgets").is_ok());
+        assert_eq!(
+            check_repository(&tenants, acme, "https://github.com/globex/x"),
+            Err(TenantViolation::Repository)
+        );
+        assert!(check_repository(&tenants, globex, "https://github.com/globex/x").is_ok());
+        assert_eq!(
+            check_repository(&tenants, globex, "https://github.com/acme/widgets"),
+            Err(TenantViolation::OtherTenant)
+        );
+        assert_eq!(
+            check_repository(&tenants, None, "https://github.com/acme/widgets"),
+            Err(TenantViolation::OtherTenant)
+        );
+        assert!(check_repository(&tenants, None, "https://example.com/x").is_ok());
+
+        // Other spellings of the same repository are still owned by acme
+        for url in [
+            "git@github.com:acme/widgets.git",
+            "http://github.com/acme/widgets",
+            "https://GitHub.com/Acme/Widgets",
+            "https://github.com/acme/widgets.git/",
+        ] {
+            assert!(check_repository(&tenants, acme, url).is_ok());
+            assert_eq!(
+                check_repository(&tenants, None, url),
+                Err(TenantViolation::OtherTenant)
+            );
+        }
+    }
+}
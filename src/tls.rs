@@ -0,0 +1,362 @@
+use crate::config::TlsConfig;
+use crate::errors::{GitFriendsError, Result};
+use hyper::server::conn::Http;
+use hyper::service::{service_fn, Service};
+use log::{debug, error, info};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::net::TcpListener;
+use tokio::time::{interval, timeout, Duration};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use warp::filters::BoxedFilter;
+use warp::reply::Response;
+use warp::Filter;
+use x509_parser::extensions::GeneralName;
+
+/// Details of the connection a request arrived on. `warp::addr::remote`
+/// is not available under `serve`, so filters read this request extension
+/// with `warp::ext::optional::<ConnectionInfo>()` instead.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+    /// Identities of the verified client certificate; empty without one
+    pub client_identities: Vec<String>,
+}
+
+/// Identities asserted by a DER certificate: the subject common name
+/// followed by its DNS, email and URI subject alternative names.
+pub fn certificate_identities(der: &[u8]) -> Vec<String> {
+    let Ok((_, cert)) = x509_parser::parse_x509_certificate(der) else {
+        return Vec::new();
+    };
+
+    let mut identities: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(name)
+                | GeneralName::RFC822Name(name)
+                | GeneralName::URI(name) => identities.push(name.to_string()),
+                _ => {}
+            }
+        }
+    }
+    identities
+}
+
+/// Build a rustls server configuration from the configured PEM files.
+pub fn load_server_config(tls: &TlsConfig) -> Result<ServerConfig> {
+    let provider = Arc::new(ring::default_provider());
+    let certs = read_certs(&tls.cert_path)?;
+    let key = read_private_key(&tls.key_path)?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| GitFriendsError::InvalidConfig(format!("TLS setup failed: {}", e)))?;
+
+    let builder = match &tls.client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca_path)? {
+                roots.add(cert).map_err(|e| {
+                    GitFriendsError::InvalidConfig(format!("Invalid client CA {}: {}", ca_path, e))
+                })?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if tls.require_client_cert {
+                verifier.build()
+            } else {
+                verifier.allow_unauthenticated().build()
+            }
+            .map_err(|e| {
+                GitFriendsError::InvalidConfig(format!("Invalid client CA {}: {}", ca_path, e))
+            })?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certs, key).map_err(|e| {
+        GitFriendsError::InvalidConfig(format!("Invalid TLS certificate or key: {}", e))
+    })?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path)?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice()).collect::<std::io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(GitFriendsError::InvalidConfig(format!(
+            "No certificates found in {}",
+            path
+        )));
+    }
+    Ok(certs)
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path)?;
+    rustls_pemfile::private_key(&mut pem.as_slice())?
+        .ok_or_else(|| GitFriendsError::InvalidConfig(format!("No private key found in {}", path)))
+}
+
+/// TLS server configuration that is rebuilt whenever the certificate, key or
+/// client CA files change on disk. Connections accepted after a reload use
+/// the new certificates; established connections are unaffected.
+pub struct TlsReloader {
+    tls: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+    loaded_files: Mutex<Vec<Vec<u8>>>,
+}
+
+impl TlsReloader {
+    pub fn new(tls: TlsConfig) -> Result<Self> {
+        let loaded_files = Self::read_files(&tls)?;
+        let current = RwLock::new(Arc::new(load_server_config(&tls)?));
+        Ok(TlsReloader {
+            tls,
+            current,
+            loaded_files: Mutex::new(loaded_files),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    /// Reload the certificates if any of the files changed. On error the
+    /// previous configuration stays in use.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let files = Self::read_files(&self.tls)?;
+        if *self.loaded_files.lock().unwrap() == files {
+            return Ok(false);
+        }
+
+        let config = load_server_config(&self.tls)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        *self.loaded_files.lock().unwrap() = files;
+        Ok(true)
+    }
+
+    /// Periodically check the certificate files according to `reload_interval_secs`.
+    pub fn spawn_watcher(self: Arc<Self>) {
+        if self.tls.reload_interval_secs == 0 {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(self.tls.reload_interval_secs));
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match self.reload_if_changed() {
+                    Ok(true) => info!("Reloaded TLS certificates"),
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to reload TLS certificates: {}", e),
+                }
+            }
+        });
+    }
+
+    fn read_files(tls: &TlsConfig) -> Result<Vec<Vec<u8>>> {
+        let mut files = vec![
+            std::fs::read(&tls.cert_path)?,
+            std::fs::read(&tls.key_path)?,
+        ];
+        if let Some(ca_path) = &tls.client_ca_path {
+            files.push(std::fs::read(ca_path)?);
+        }
+        Ok(files)
+    }
+}
+
+/// Clients that have not finished the TLS handshake by then are dropped,
+/// so idle connections cannot pile up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve `routes` over TLS on `addr`, using the reloader's current
+/// certificates for every new connection.
+pub async fn serve(
+    routes: BoxedFilter<(Response,)>,
+    addr: SocketAddr,
+    reloader: Arc<TlsReloader>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening for HTTPS on {}", addr);
+
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let acceptor = reloader.acceptor();
+        let service = warp::service(routes.clone());
+
+        tokio::spawn(async move {
+            let tls_stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(tls_stream)) => tls_stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", remote_addr, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", remote_addr);
+                    return;
+                }
+            };
+
+            let client_identities = tls_stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| certificate_identities(cert))
+                .unwrap_or_default();
+            let info = ConnectionInfo {
+                remote_addr,
+                client_identities,
+            };
+            let service = service_fn(move |mut request: hyper::Request<hyper::Body>| {
+                request.extensions_mut().insert(info.clone());
+                service.clone().call(request)
+            });
+            if let Err(e) = Http::new()
+                .serve_connection(tls_stream, service)
+                .with_upgrades()
+                .await
+            {
+                debug!("Connection from {} closed with error: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+/// Plain HTTP listener answering every request with a redirect to HTTPS.
+pub async fn serve_redirect(addr: SocketAddr, https_port: u16) {
+    let redirect = warp::path::full()
+        .and(warp::header::optional::<String>("host"))
+        .and(
+            warp::query::raw()
+                .map(|q: String| format!("?{}", q))
+                .or(warp::any().map(String::new))
+                .unify(),
+        )
+        .map(
+            move |path: warp::path::FullPath, host: Option<String>, query: String| {
+                let host = host_without_port(host.as_deref().unwrap_or("localhost"));
+                let location = format!("https://{}:{}{}{}", host, https_port, path.as_str(), query);
+                warp::http::Response::builder()
+                    .status(warp::http::StatusCode::PERMANENT_REDIRECT)
+                    .header("location", location)
+                    .body(String::new())
+            },
+        );
+
+    info!("Redirecting plain HTTP on {} to HTTPS", addr);
+    warp::serve(redirect).run(addr).await;
+}
+
+/// Strip the port from a `Host` header value, keeping IPv6 brackets intact.
+fn host_without_port(host: &str) -> &str {
+    let host = if let Some(end) = host.find(']') {
+        &host[..=end]
+    } else {
+        host.split(':').next().unwrap_or(host)
+    };
+    if host.is_empty() {
+        "localhost"
+    } else {
+        host
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_self_signed(dir: &TempDir, name: &str) -> TlsConfig {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.signing_key.serialize_pem()).unwrap();
+
+        TlsConfig {
+            cert_path: cert_path.to_string_lossy().to_string(),
+            key_path: key_path.to_string_lossy().to_string(),
+            client_ca_path: None,
+            require_client_cert: false,
+            reload_interval_secs: 60,
+            http_redirect_bind: None,
+        }
+    }
+
+    #[test]
+    fn test_load_and_reload() {
+        let dir = TempDir::new().unwrap();
+        let tls = write_self_signed(&dir, "localhost");
+
+        let reloader = TlsReloader::new(tls.clone()).unwrap();
+        assert!(!reloader.reload_if_changed().unwrap());
+
+        write_self_signed(&dir, "gf.example.com");
+        assert!(reloader.reload_if_changed().unwrap());
+
+        // A broken key keeps the previous configuration in place
+        std::fs::write(&tls.key_path, "not a key").unwrap();
+        assert!(reloader.reload_if_changed().is_err());
+    }
+
+    #[test]
+    fn test_host_without_port() {
+        assert_eq!(host_without_port("gf.example.com:8080"), "gf.example.com");
+        assert_eq!(host_without_port("[::1]:8080"), "[::1]");
+        assert_eq!(host_without_port(""), "localhost");
+    }
+
+    #[test]
+    fn test_certificate_identities() {
+        let mut params =
+            rcgen::CertificateParams::new(vec!["builder-01.ci.example.com".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "builder-01");
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+
+        assert_eq!(
+            certificate_identities(cert.der()),
+            vec!["builder-01", "builder-01.ci.example.com"]
+        );
+        assert!(certificate_identities(b"not a certificate").is_empty());
+    }
+
+    #[test]
+    fn test_client_ca_requires_certificates() {
+        let dir = TempDir::new().unwrap();
+        let mut tls = write_self_signed(&dir, "localhost");
+        tls.client_ca_path = Some(tls.cert_path.clone());
+        tls.require_client_cert = true;
+
+        assert!(load_server_config(&tls).is_ok());
+
+        tls.client_ca_path = Some(dir.path().join("missing.pem").to_string_lossy().to_string());
+        assert!(load_server_config(&tls).is_err());
+    }
+}
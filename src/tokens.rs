@@ -0,0 +1,584 @@
+use crate::auth::{hash_token, is_hashed_token, token_fingerprint, AuthManager, TokenValidity};
+use crate::clock::unix_now;
+use crate::config::{TenantConfig, TokenConfig, TokenScopes};
+use crate::db::has_column;
+use crate::errors::Result;
+use crate::tenant::tenant_for_user;
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tokens (
+    id TEXT PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    username TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER,
+    scopes TEXT,
+    name TEXT
+);
+CREATE TABLE IF NOT EXISTS token_usage (
+    fingerprint TEXT PRIMARY KEY,
+    last_used_at INTEGER NOT NULL,
+    last_used_ip TEXT
+);
+";
+
+const SELECT_COLUMNS: &str = "id, token_hash, username, name, created_at, expires_at, scopes";
+
+/// Prefix of the ids given to tokens defined in the configuration file,
+/// followed by the fingerprint of the entry so ids survive reordering.
+const CONFIG_ID_PREFIX: &str = "config-";
+
+/// Usage is written to the store at most this often per token, unless the
+/// client address changes.
+const USAGE_PERSIST_INTERVAL_SECS: i64 = 60;
+
+/// A token created through the admin API or `gf-server --generate-token --register`.
+#[derive(Debug, Clone)]
+pub struct StoredToken {
+    pub id: String,
+    /// Salted hash of the secret, as produced by `hash_token`
+    pub token_hash: String,
+    pub username: String,
+    /// Label set through the admin API, e.g. what the token is used for
+    pub name: Option<String>,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub scopes: TokenScopes,
+}
+
+impl StoredToken {
+    pub fn to_token_config(&self) -> TokenConfig {
+        TokenConfig {
+            token: self.token_hash.clone(),
+            username: self.username.clone(),
+            scopes: self.scopes.clone(),
+            expires_at: self.expires_at,
+            ..Default::default()
+        }
+    }
+}
+
+/// When and from where a token was last used.
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub last_used_at: i64,
+    pub last_used_ip: Option<String>,
+}
+
+/// Where a token is defined.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenSource {
+    Config,
+    Store,
+}
+
+/// A token as listed by the admin API, with the secret masked.
+#[derive(Debug, Serialize, Clone)]
+pub struct TokenSummary {
+    pub id: String,
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub token: String,
+    pub source: TokenSource,
+    pub created_at: Option<i64>,
+    pub not_before: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub expired: bool,
+    pub last_used_at: Option<i64>,
+    pub last_used_ip: Option<String>,
+    #[serde(skip_serializing_if = "TokenScopes::is_unrestricted")]
+    pub scopes: TokenScopes,
+    /// Tenant the token's user belongs to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+impl TokenSummary {
+    /// A token is stale when it has expired or has not been used for
+    /// `max_idle_secs`. Tokens that were never used count from their
+    /// creation, or are stale right away if that is unknown.
+    pub fn is_stale(&self, now: i64, max_idle_secs: i64) -> bool {
+        self.expired
+            || self
+                .last_used_at
+                .or(self.created_at)
+                .is_none_or(|active| now - active > max_idle_secs)
+    }
+}
+
+/// SQLite-backed tokens that are merged with the ones from the configuration.
+pub struct TokenStore {
+    conn: Mutex<Connection>,
+}
+
+impl TokenStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        if !has_column(&conn, "tokens", "expires_at")? {
+            conn.execute_batch("ALTER TABLE tokens ADD COLUMN expires_at INTEGER")?;
+        }
+        if !has_column(&conn, "tokens", "scopes")? {
+            conn.execute_batch("ALTER TABLE tokens ADD COLUMN scopes TEXT")?;
+        }
+        if !has_column(&conn, "tokens", "name")? {
+            conn.execute_batch("ALTER TABLE tokens ADD COLUMN name TEXT")?;
+        }
+        Ok(TokenStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Generate and store a new token for `username`. The secret is returned
+    /// alongside the stored entry and cannot be recovered later.
+    pub fn create(
+        &self,
+        username: &str,
+        expires_at: Option<i64>,
+        scopes: &TokenScopes,
+    ) -> Result<(StoredToken, String)> {
+        let conn = self.conn.lock().unwrap();
+        insert_token(&conn, username, None, expires_at, scopes)
+    }
+
+    pub fn list(&self) -> Result<Vec<StoredToken>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM tokens ORDER BY created_at",
+            SELECT_COLUMNS
+        ))?;
+        let tokens = stmt
+            .query_map([], row_to_stored_token)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(tokens)
+    }
+
+    /// Delete a token. Returns false if no token has this id.
+    pub fn revoke(&self, id: &str) -> Result<bool> {
+        let deleted = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM tokens WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    /// Change the label of a token. Returns false if no token has this id.
+    pub fn rename(&self, id: &str, name: &str) -> Result<bool> {
+        let updated = self.conn.lock().unwrap().execute(
+            "UPDATE tokens SET name = ?2 WHERE id = ?1",
+            params![id, name],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Replace a token with a new one for the same user, label and scopes. A token
+    /// that expires is replaced by one with the same lifetime, counted from
+    /// now. The old token keeps working until `old_expires_at` (or its own
+    /// expiry, if earlier) so clients can switch over. Returns `None` if no
+    /// token has this id.
+    pub fn rotate(&self, id: &str, old_expires_at: i64) -> Result<Option<(StoredToken, String)>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let token = tx
+            .query_row(
+                &format!("SELECT {} FROM tokens WHERE id = ?1", SELECT_COLUMNS),
+                params![id],
+                row_to_stored_token,
+            )
+            .optional()?;
+        let Some(token) = token else {
+            return Ok(None);
+        };
+        let expires_at = token
+            .expires_at
+            .map(|expires_at| unix_now() + (expires_at - token.created_at));
+
+        tx.execute(
+            "UPDATE tokens SET expires_at = MIN(COALESCE(expires_at, ?2), ?2) WHERE id = ?1",
+            params![id, old_expires_at],
+        )?;
+        let created = insert_token(
+            &tx,
+            &token.username,
+            token.name.as_deref(),
+            expires_at,
+            &token.scopes,
+        )?;
+        tx.commit()?;
+
+        Ok(Some(created))
+    }
+
+    pub fn token_configs(&self) -> Result<Vec<TokenConfig>> {
+        Ok(self
+            .list()?
+            .iter()
+            .map(StoredToken::to_token_config)
+            .collect())
+    }
+
+    pub fn record_usage(&self, fingerprint: &str, usage: &TokenUsage) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO token_usage (fingerprint, last_used_at, last_used_ip) \
+             VALUES (?1, ?2, ?3) \
+             ON CONFLICT (fingerprint) DO UPDATE SET \
+             last_used_at = excluded.last_used_at, last_used_ip = excluded.last_used_ip",
+            params![fingerprint, usage.last_used_at, usage.last_used_ip],
+        )?;
+        Ok(())
+    }
+
+    /// Last recorded usage of every token, keyed by `token_fingerprint`.
+    pub fn load_usage(&self) -> Result<HashMap<String, TokenUsage>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT fingerprint, last_used_at, last_used_ip FROM token_usage")?;
+        let usage = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    TokenUsage {
+                        last_used_at: row.get(1)?,
+                        last_used_ip: row.get(2)?,
+                    },
+                ))
+            })?
+            .collect::<std::result::Result<HashMap<_, _>, _>>()?;
+        Ok(usage)
+    }
+}
+
+fn insert_token(
+    conn: &Connection,
+    username: &str,
+    name: Option<&str>,
+    expires_at: Option<i64>,
+    scopes: &TokenScopes,
+) -> Result<(StoredToken, String)> {
+    let secret = AuthManager::generate_token();
+    let stored = StoredToken {
+        id: Uuid::new_v4().simple().to_string(),
+        token_hash: hash_token(&secret),
+        username: username.to_string(),
+        name: name.map(|n| n.to_string()),
+        created_at: unix_now(),
+        expires_at,
+        scopes: scopes.clone(),
+    };
+
+    // Unrestricted tokens store no scopes
+    let scopes = match scopes.is_unrestricted() {
+        true => None,
+        false => Some(serde_json::to_string(scopes)?),
+    };
+    conn.execute(
+        "INSERT INTO tokens (id, token_hash, username, name, created_at, expires_at, scopes) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            stored.id,
+            stored.token_hash,
+            stored.username,
+            stored.name,
+            stored.created_at,
+            stored.expires_at,
+            scopes
+        ],
+    )?;
+    Ok((stored, secret))
+}
+
+fn row_to_stored_token(row: &rusqlite::Row) -> rusqlite::Result<StoredToken> {
+    Ok(StoredToken {
+        id: row.get(0)?,
+        token_hash: row.get(1)?,
+        username: row.get(2)?,
+        name: row.get(3)?,
+        created_at: row.get(4)?,
+        expires_at: row.get(5)?,
+        scopes: scopes_from_sql(6, row.get(6)?)?,
+    })
+}
+
+/// Scopes stored as JSON in `column`; `NULL` is unrestricted.
+fn scopes_from_sql(column: usize, scopes: Option<String>) -> rusqlite::Result<TokenScopes> {
+    scopes.map_or(Ok(TokenScopes::default()), |json| {
+        serde_json::from_str(&json)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(column, Type::Text, Box::new(e)))
+    })
+}
+
+/// Last use of every token seen by this process, keyed by `token_fingerprint`.
+pub struct UsageTracker {
+    usage: Mutex<HashMap<String, (TokenUsage, i64)>>,
+}
+
+impl UsageTracker {
+    /// Start from previously persisted usage.
+    pub fn new(persisted: HashMap<String, TokenUsage>) -> Self {
+        let usage = persisted
+            .into_iter()
+            .map(|(fingerprint, usage)| {
+                let persisted_at = usage.last_used_at;
+                (fingerprint, (usage, persisted_at))
+            })
+            .collect();
+        UsageTracker {
+            usage: Mutex::new(usage),
+        }
+    }
+
+    /// Record a use of the token with `fingerprint`. Returns the usage to
+    /// persist when it is due to be written to the store.
+    pub fn record(&self, fingerprint: &str, ip: Option<String>, now: i64) -> Option<TokenUsage> {
+        let usage = TokenUsage {
+            last_used_at: now,
+            last_used_ip: ip,
+        };
+
+        let mut tracked = self.usage.lock().unwrap();
+        let persisted_at = match tracked.get(fingerprint) {
+            Some((previous, persisted_at))
+                if previous.last_used_ip == usage.last_used_ip
+                    && now - persisted_at < USAGE_PERSIST_INTERVAL_SECS =>
+            {
+                Some(*persisted_at)
+            }
+            _ => None,
+        };
+        tracked.insert(
+            fingerprint.to_string(),
+            (usage.clone(), persisted_at.unwrap_or(now)),
+        );
+
+        persisted_at.is_none().then_some(usage)
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, TokenUsage> {
+        self.usage
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fingerprint, (usage, _))| (fingerprint.clone(), usage.clone()))
+            .collect()
+    }
+}
+
+/// Whether `id` refers to a token from the configuration file, which can
+/// only be changed by editing that file.
+pub fn is_config_token_id(id: &str) -> bool {
+    id.starts_with(CONFIG_ID_PREFIX)
+}
+
+/// List configured and stored tokens together, masking every secret.
+pub fn summarize_tokens(
+    configured: &[TokenConfig],
+    stored: &[StoredToken],
+    usage: &HashMap<String, TokenUsage>,
+    tenants: &[TenantConfig],
+    now: i64,
+) -> Vec<TokenSummary> {
+    let summary = |id: String, tc: TokenConfig, name, source, created_at| {
+        let usage = usage.get(&token_fingerprint(&tc.token));
+        TokenSummary {
+            id,
+            name,
+            token: mask_token(&tc.token),
+            source,
+            created_at,
+            not_before: tc.not_before,
+            expires_at: tc.expires_at,
+            expired: tc.validity_at(now) == TokenValidity::Expired,
+            last_used_at: usage.map(|u| u.last_used_at),
+            last_used_ip: usage.and_then(|u| u.last_used_ip.clone()),
+            tenant: tenant_for_user(tenants, &tc.username).map(|t| t.name.clone()),
+            username: tc.username,
+            scopes: tc.scopes,
+        }
+    };
+
+    let configured = configured.iter().map(|tc| {
+        summary(
+            format!("{}{}", CONFIG_ID_PREFIX, token_fingerprint(&tc.token)),
+            tc.clone(),
+            None,
+            TokenSource::Config,
+            None,
+        )
+    });
+    let stored = stored.iter().map(|st| {
+        summary(
+            st.id.clone(),
+            st.to_token_config(),
+            st.name.clone(),
+            TokenSource::Store,
+            Some(st.created_at),
+        )
+    });
+
+    configured.chain(stored).collect()
+}
+
+/// Keep the first four characters of a plaintext token so it can be
+/// recognised; hashed entries reveal nothing.
+pub fn mask_token(token: &str) -> String {
+    if is_hashed_token(token) {
+        return "sha256$****".to_string();
+    }
+    if token.chars().count() < 12 {
+        return "****".to_string();
+    }
+    let visible: String = token.chars().take(4).collect();
+    format!("{}****", visible)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_rename_revoke() {
+        let store = TokenStore::open_in_memory().unwrap();
+        let (created, secret) = store
+            .create("alice", None, &TokenScopes::default())
+            .unwrap();
+        store.create("bob", None, &TokenScopes::default()).unwrap();
+        assert!(is_hashed_token(&created.token_hash));
+        assert!(!created.token_hash.contains(&secret));
+
+        assert!(store.rename(&created.id, "laptop").unwrap());
+        let tokens = store.list().unwrap();
+        assert_eq!(tokens.len(), 2);
+        let renamed = tokens.iter().find(|t| t.id == created.id).unwrap();
+        assert_eq!(renamed.name.as_deref(), Some("laptop"));
+        assert_eq!(renamed.username, "alice");
+
+        assert!(store.revoke(&created.id).unwrap());
+        assert!(!store.revoke(&created.id).unwrap());
+        assert!(!store.rename("missing", "desktop").unwrap());
+        assert_eq!(store.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rotation_overlaps() {
+        let store = TokenStore::open_in_memory().unwrap();
+        let scopes = TokenScopes {
+            repositories: vec!["https://github.com/acme/*".to_string()],
+            access: crate::config::TokenAccess::Write,
+            ..Default::default()
+        };
+        let (old, _) = store.create("ci", None, &scopes).unwrap();
+
+        let (new, _) = store.rotate(&old.id, 1_000).unwrap().unwrap();
+        assert_eq!(new.username, "ci");
+        assert_eq!(new.scopes, scopes);
+        assert_eq!(new.expires_at, None);
+        let configs = store.token_configs().unwrap();
+        assert!(configs.iter().all(|tc| tc.scopes == scopes));
+
+        let tokens = store.list().unwrap();
+        assert_eq!(tokens.len(), 2);
+        let old = tokens.iter().find(|t| t.id == old.id).unwrap();
+        assert_eq!(old.expires_at, Some(1_000));
+        assert!(store.rotate("missing", 1_000).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_rotation_keeps_lifetime() {
+        let store = TokenStore::open_in_memory().unwrap();
+        let lifetime = 90 * 24 * 60 * 60;
+        let (old, _) = store
+            .create("ci", Some(unix_now() + lifetime), &TokenScopes::default())
+            .unwrap();
+
+        let (new, _) = store.rotate(&old.id, unix_now()).unwrap().unwrap();
+        let new_lifetime = new.expires_at.unwrap() - new.created_at;
+        assert!((lifetime - 1..=lifetime + 1).contains(&new_lifetime));
+    }
+
+    #[test]
+    fn test_summaries_and_staleness() {
+        let store = TokenStore::open_in_memory().unwrap();
+        let (stored, _) = store
+            .create("alice", None, &TokenScopes::default())
+            .unwrap();
+        let configured = vec![
+            TokenConfig {
+                token: "short".to_string(),
+                username: "bob".to_string(),
+                ..Default::default()
+            },
+            TokenConfig {
+                token: "plaintext-secret".to_string(),
+                username: "carol".to_string(),
+                expires_at: Some(100),
+                ..Default::default()
+            },
+        ];
+
+        let tracker = UsageTracker::new(HashMap::new());
+        let fingerprint = token_fingerprint(&stored.token_hash);
+        let now = stored.created_at + 1_000;
+        assert!(tracker
+            .record(&fingerprint, Some("10.0.0.1".to_string()), now)
+            .is_some());
+        assert!(tracker
+            .record(&fingerprint, Some("10.0.0.1".to_string()), now + 1)
+            .is_none());
+
+        let summaries = summarize_tokens(
+            &configured,
+            &store.list().unwrap(),
+            &tracker.snapshot(),
+            &[TenantConfig {
+                name: "acme".to_string(),
+                topic_prefix: "acme".to_string(),
+                users: vec!["carol".to_string()],
+                repositories: vec![],
+                admin_users: vec![],
+                mqtt_username: None,
+                mqtt_password: None,
+                irc_channels: vec![],
+            }],
+            now + 1,
+        );
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(summaries[0].token, "****");
+        assert!(is_config_token_id(&summaries[0].id));
+        assert_eq!(
+            summaries[0].id,
+            format!("config-{}", token_fingerprint(&configured[0].token))
+        );
+        assert_eq!(summaries[1].token, "plai****");
+        assert!(summaries[1].expired);
+        assert_eq!(summaries[1].tenant.as_deref(), Some("acme"));
+        assert_eq!(summaries[0].tenant, None);
+        assert_eq!(summaries[2].source, TokenSource::Store);
+        assert_eq!(summaries[2].token, "sha256$****");
+        assert_eq!(summaries[2].last_used_ip.as_deref(), Some("10.0.0.1"));
+
+        let stale: Vec<_> = summaries
+            .iter()
+            .filter(|s| s.is_stale(now + 1, 60))
+            .map(|s| s.username.as_str())
+            .collect();
+        assert_eq!(stale, vec!["bob", "carol"]);
+    }
+}